@@ -0,0 +1,88 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![feature(test)]
+
+extern crate test;
+
+use std::path::Path;
+
+use test::Bencher;
+use toda::injector::{Injector, InjectorConfig, LiveInjector, Method, MultiInjector};
+use tokio::sync::RwLock;
+
+// These benchmarks measure the cost of matching the path of a request, which is
+// paid by every request on the mount, even the ones not injected at all.
+
+fn build(include: &str) -> MultiInjector {
+    let config: Vec<InjectorConfig> = serde_json::from_str(&format!(
+        r#"[{{"type": "fault", "include": [{}], "errno": 5}}]"#,
+        include
+    ))
+    .unwrap();
+    MultiInjector::build_with_root(config, Path::new("/var/db")).unwrap()
+}
+
+fn bench_inject(b: &mut Bencher, include: &str) {
+    let injector = build(include);
+    let path = Path::new("/var/db/data/000002.sst");
+    b.iter(|| futures::executor::block_on(injector.inject(&Method::READ, path)).unwrap());
+}
+
+#[bench]
+fn match_exact_path(b: &mut Bencher) {
+    bench_inject(b, r#""data/000001.sst""#);
+}
+
+#[bench]
+fn match_exact_paths(b: &mut Bencher) {
+    let paths: Vec<_> = (0..100)
+        .map(|i| format!(r#""data/{:06}.log""#, i))
+        .collect();
+    bench_inject(b, &paths.join(","));
+}
+
+#[bench]
+fn match_glob_path(b: &mut Bencher) {
+    bench_inject(b, r#""data/*.log""#);
+}
+
+#[bench]
+fn match_glob_paths(b: &mut Bencher) {
+    let paths: Vec<_> = (0..100)
+        .map(|i| format!(r#""data/{:06}*.log""#, i))
+        .collect();
+    bench_inject(b, &paths.join(","));
+}
+
+// The injectors of a mount are loaded once per request. These compare the read
+// path behind a RwLock, as it was, with loading them from a LiveInjector.
+
+#[bench]
+fn load_rwlock(b: &mut Bencher) {
+    let injector = RwLock::new(build(r#""data/*.log""#));
+    let path = Path::new("/var/db/data/000002.sst");
+    b.iter(|| {
+        futures::executor::block_on(async {
+            injector.read().await.inject(&Method::READ, path).await
+        })
+        .unwrap()
+    });
+}
+
+#[bench]
+fn load_live(b: &mut Bencher) {
+    let injector = LiveInjector::new(build(r#""data/*.log""#));
+    let path = Path::new("/var/db/data/000002.sst");
+    b.iter(|| futures::executor::block_on(injector.load().inject(&Method::READ, path)).unwrap());
+}
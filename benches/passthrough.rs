@@ -0,0 +1,174 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![feature(test)]
+
+extern crate test;
+
+use std::ffi::OsStr;
+use std::fs::{metadata, read, write, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use test::Bencher;
+use toda::hookfs::{AsyncFileSystem, HookFs};
+use toda::injector::MultiInjector;
+
+// These benchmarks compare the requests on a hookfs mount without any injector,
+// which is what `--mount-only` leaves behind, with the same requests on the
+// backend directory. The difference is the overhead every process on the path
+// pays during injection. They mount a fuse filesystem, so they need root.
+
+const SMALL: usize = 4 << 10;
+const LARGE: usize = 1 << 20;
+
+// Mount is a hookfs mounted over a backend directory, which is unmounted on
+// drop
+struct Mount {
+    path: PathBuf,
+    backend: PathBuf,
+    hookfs: Arc<HookFs>,
+    _session: fuser::BackgroundSession,
+}
+
+impl Mount {
+    fn new(name: &str) -> Mount {
+        let base: PathBuf = ["/tmp/bench_passthrough", name].iter().collect();
+        let path = base.join("mnt");
+        let backend = base.join("backend");
+        std::fs::remove_dir_all(&backend).ok();
+        for dir in [&path, &backend].iter() {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+
+        let hookfs = Arc::new(HookFs::new(
+            &path,
+            &backend,
+            MultiInjector::build(Vec::new()).unwrap(),
+        ));
+        hookfs.enable_injection();
+
+        let flags: Vec<_> = ["allow_other", "nonempty", "fsname=toda"]
+            .iter()
+            .flat_map(|item| vec![OsStr::new("-o"), OsStr::new(item)])
+            .collect();
+        let session =
+            fuser::spawn_mount(AsyncFileSystem::from(hookfs.clone()), &path, &flags).unwrap();
+        std::thread::sleep(std::time::Duration::from_secs(1));
+
+        Mount {
+            path,
+            backend,
+            hookfs,
+            _session: session,
+        }
+    }
+
+    fn file(&self, size: usize) -> (PathBuf, PathBuf) {
+        let name = format!("file_{}", size);
+        write(self.backend.join(&name), vec![0u8; size]).unwrap();
+        (self.path.join(&name), self.backend.join(&name))
+    }
+}
+
+fn bench_read(b: &mut Bencher, size: usize, through_mount: bool) {
+    let mount = Mount::new(&format!("read_{}_{}", size, through_mount));
+    let (mounted, original) = mount.file(size);
+    let path = if through_mount { mounted } else { original };
+    b.bytes = size as u64;
+    b.iter(|| read(&path).unwrap());
+}
+
+fn bench_write(b: &mut Bencher, size: usize, through_mount: bool) {
+    let mount = Mount::new(&format!("write_{}_{}", size, through_mount));
+    let (mounted, original) = mount.file(0);
+    let path = if through_mount { mounted } else { original };
+    let data = vec![1u8; size];
+    b.bytes = size as u64;
+    b.iter(|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&data).unwrap();
+    });
+}
+
+fn bench_stat(b: &mut Bencher, through_mount: bool) {
+    let mount = Mount::new(&format!("stat_{}", through_mount));
+    let (mounted, original) = mount.file(0);
+    let path = if through_mount { mounted } else { original };
+    b.iter(|| metadata(&path).unwrap());
+}
+
+#[bench]
+fn direct_read_small(b: &mut Bencher) {
+    bench_read(b, SMALL, false);
+}
+
+#[bench]
+fn passthrough_read_small(b: &mut Bencher) {
+    bench_read(b, SMALL, true);
+}
+
+#[bench]
+fn direct_read_large(b: &mut Bencher) {
+    bench_read(b, LARGE, false);
+}
+
+#[bench]
+fn passthrough_read_large(b: &mut Bencher) {
+    bench_read(b, LARGE, true);
+}
+
+#[bench]
+fn direct_write_small(b: &mut Bencher) {
+    bench_write(b, SMALL, false);
+}
+
+#[bench]
+fn passthrough_write_small(b: &mut Bencher) {
+    bench_write(b, SMALL, true);
+}
+
+#[bench]
+fn direct_write_large(b: &mut Bencher) {
+    bench_write(b, LARGE, false);
+}
+
+#[bench]
+fn passthrough_write_large(b: &mut Bencher) {
+    bench_write(b, LARGE, true);
+}
+
+#[bench]
+fn direct_stat(b: &mut Bencher) {
+    bench_stat(b, false);
+}
+
+#[bench]
+fn passthrough_stat(b: &mut Bencher) {
+    bench_stat(b, true);
+}
+
+// a paused mount resolves no path at all, which is the floor of the overhead
+#[bench]
+fn paused_read_small(b: &mut Bencher) {
+    let mount = Mount::new("paused_read");
+    mount.hookfs.pause_injection();
+    let (path, _) = mount.file(SMALL);
+    b.bytes = SMALL as u64;
+    b.iter(|| read(&path).unwrap());
+}
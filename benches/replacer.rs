@@ -0,0 +1,141 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![feature(test)]
+
+extern crate test;
+
+use std::fs::write;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use test::Bencher;
+use toda::replacer::{FdReplacer, Replacer, DEFAULT_BATCH_SIZE};
+
+// These benchmarks measure the time to replace the fds of the processes under a
+// path, which is the time the processes are stopped during injection. They
+// attach to every process on the host with ptrace, so they need CAP_SYS_PTRACE.
+
+fn init(name: &str, fds: usize) -> (PathBuf, PathBuf) {
+    let base: PathBuf = ["/tmp/bench_replacer", name].iter().collect();
+    let old_path = base.join("old");
+    let new_path = base.join("new");
+
+    std::fs::remove_dir_all(&base).ok();
+    for path in [&old_path, &new_path].iter() {
+        std::fs::create_dir_all(path).unwrap();
+        for i in 0..fds {
+            write(path.join(i.to_string()), b"").unwrap();
+        }
+    }
+
+    (old_path, new_path)
+}
+
+// Holder is a process which opens every file in a directory. It's started
+// outside the process tree of the benchmark, as the replacers skip the
+// descendants of the current process, and killed on drop.
+struct Holder {
+    pid: i32,
+}
+
+impl Holder {
+    fn spawn(dir: &Path, fds: usize) -> Holder {
+        let script = format!(
+            "{{ for i in $(seq 0 {}); do exec {{fd}}<\"{}/$i\"; done; exec sleep 1000; }} & echo $! >&2",
+            fds - 1,
+            dir.display()
+        );
+        let mut shell = Command::new("bash")
+            .arg("-c")
+            .arg(script)
+            .stdin(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut pid = String::new();
+        BufReader::new(shell.stderr.take().unwrap())
+            .read_line(&mut pid)
+            .unwrap();
+        shell.wait().unwrap();
+
+        let holder = Holder {
+            pid: pid.trim().parse().unwrap(),
+        };
+        // wait for the files to be opened, besides stdin, stdout and stderr
+        while std::fs::read_dir(format!("/proc/{}/fd", holder.pid))
+            .map(|fds| fds.count())
+            .unwrap_or_default()
+            < fds + 3
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        holder
+    }
+}
+
+impl Drop for Holder {
+    fn drop(&mut self) {
+        kill(Pid::from_raw(self.pid), Signal::SIGKILL).ok();
+    }
+}
+
+fn replace(detect_path: &Path, new_path: &Path, batch_size: usize) {
+    let mut replacer = FdReplacer::prepare(detect_path, new_path)
+        .unwrap()
+        .with_batch_size(batch_size);
+    replacer.run().unwrap();
+}
+
+// bench_replace moves the fds of `processes` processes, each holding `fds`
+// files, to the new path and back in every iteration
+fn bench_replace(b: &mut Bencher, name: &str, processes: usize, fds: usize, batch_size: usize) {
+    let (old_path, new_path) = init(name, fds);
+    let _holders: Vec<_> = (0..processes)
+        .map(|_| Holder::spawn(&old_path, fds))
+        .collect();
+
+    b.iter(|| {
+        replace(&old_path, &new_path, batch_size);
+        replace(&new_path, &old_path, batch_size);
+    });
+}
+
+#[bench]
+fn replace_1_process_1_fd(b: &mut Bencher) {
+    bench_replace(b, "1_process_1_fd", 1, 1, DEFAULT_BATCH_SIZE);
+}
+
+#[bench]
+fn replace_1_process_512_fds(b: &mut Bencher) {
+    bench_replace(b, "1_process_512_fds", 1, 512, DEFAULT_BATCH_SIZE);
+}
+
+#[bench]
+fn replace_1_process_512_fds_batch_32(b: &mut Bencher) {
+    bench_replace(b, "1_process_512_fds_batch_32", 1, 512, 32);
+}
+
+#[bench]
+fn replace_16_processes_1_fd(b: &mut Bencher) {
+    bench_replace(b, "16_processes_1_fd", 16, 1, DEFAULT_BATCH_SIZE);
+}
+
+#[bench]
+fn replace_16_processes_32_fds(b: &mut Bencher) {
+    bench_replace(b, "16_processes_32_fds", 16, 32, DEFAULT_BATCH_SIZE);
+}
@@ -1,10 +1,38 @@
-use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use anyhow::Context;
+use nix::fcntl::{open, OFlag};
+use nix::mount::{mount, MsFlags};
+use nix::sys::stat::{self, major, makedev, minor, mknod, Mode, SFlag};
+use nix::unistd::{close, getgid, getuid};
 use nix::Error as NixError;
+use thiserror::Error;
+use tracing::info;
+
+const FUSE_DEVICE: &str = "/dev/fuse";
+const FUSE_MAJOR: u64 = 10;
+const FUSE_MINOR: u64 = 229;
+
+#[derive(Error, Debug)]
+pub enum FuseDeviceError {
+    #[error(
+        "/dev/fuse doesn't exist and can't be created: {source}. The container may lack the fuse \
+         device, e.g. run it with `--device /dev/fuse` or privileged"
+    )]
+    Missing { source: NixError },
+
+    #[error("/dev/fuse is not the fuse device, its device number is {major}:{minor}")]
+    NotFuseDevice { major: u64, minor: u64 },
+
+    #[error("/dev/fuse can't be opened: {source}")]
+    Unusable { source: NixError },
+}
 
 pub fn mkfuse_node() -> anyhow::Result<()> {
     let mode = unsafe { Mode::from_bits_unchecked(0o666) };
-    let dev = makedev(10, 229);
-    match mknod("/dev/fuse", SFlag::S_IFCHR, mode, dev) {
+    let dev = makedev(FUSE_MAJOR, FUSE_MINOR);
+    match mknod(FUSE_DEVICE, SFlag::S_IFCHR, mode, dev) {
         Ok(()) => Ok(()),
         Err(NixError::Sys(errno)) => {
             if errno == nix::errno::Errno::EEXIST {
@@ -16,3 +44,81 @@ pub fn mkfuse_node() -> anyhow::Result<()> {
         Err(err) => Err(err.into()),
     }
 }
+
+// preflight makes sure the fuse device exists and can be opened, so a missing
+// device is reported before anything is mounted. The node is created if it's
+// missing.
+pub fn preflight() -> Result<(), FuseDeviceError> {
+    let created = mkfuse_node();
+    if let Err(err) = &created {
+        info!("fail to make {} node: {}", FUSE_DEVICE, err);
+    }
+
+    let stat = match stat::stat(FUSE_DEVICE) {
+        Ok(stat) => stat,
+        Err(err) => {
+            // the error of mknod tells why the device is missing, e.g. EPERM
+            let source = match created {
+                Err(created) => created.downcast::<NixError>().unwrap_or(err),
+                Ok(()) => err,
+            };
+            return Err(FuseDeviceError::Missing { source });
+        }
+    };
+    let file_type = SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT;
+    if file_type != SFlag::S_IFCHR
+        || major(stat.st_rdev) != FUSE_MAJOR
+        || minor(stat.st_rdev) != FUSE_MINOR
+    {
+        return Err(FuseDeviceError::NotFuseDevice {
+            major: major(stat.st_rdev),
+            minor: minor(stat.st_rdev),
+        });
+    }
+
+    let fd = open(FUSE_DEVICE, OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty())
+        .map_err(|source| FuseDeviceError::Unusable { source })?;
+    close(fd).ok();
+
+    Ok(())
+}
+
+// mount_fd mounts FUSE on `target` with a new fd of the fuse device, and returns
+// the fd. The mount is served by whoever reads the requests from the fd, so it
+// can be inherited by another process, e.g. one without CAP_SYS_ADMIN. The fd
+// is not closed on exec for that reason. `options` are the options of the FUSE
+// mount, in which `fsname` is the source of the mount.
+pub fn mount_fd(target: &Path, options: &[String]) -> anyhow::Result<RawFd> {
+    let fd = open(FUSE_DEVICE, OFlag::O_RDWR, Mode::empty())
+        .map_err(|source| FuseDeviceError::Unusable { source })?;
+
+    let result = stat::stat(target).and_then(|target_stat| {
+        let root_mode = target_stat.st_mode & SFlag::S_IFMT.bits();
+        let mut fsname = "fuse";
+        let mut data = vec![
+            format!("fd={}", fd),
+            format!("rootmode={:o}", root_mode),
+            format!("user_id={}", getuid()),
+            format!("group_id={}", getgid()),
+        ];
+        for option in options {
+            match option.strip_prefix("fsname=") {
+                Some(name) => fsname = name,
+                None => data.push(option.clone()),
+            }
+        }
+        mount(
+            Some(fsname),
+            target,
+            Some("fuse"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some(data.join(",").as_str()),
+        )
+    });
+    if let Err(err) = result {
+        close(fd).ok();
+        return Err(err).context(format!("mount FUSE on {} with fd", target.display()));
+    }
+
+    Ok(fd)
+}
@@ -9,19 +9,31 @@ use fuser::*;
 use tracing::trace_span;
 use tracing_futures::Instrument;
 
+use super::caller::Caller;
 use super::errors::Result;
 use super::reply::*;
-use super::runtime::spawn;
+use super::runtime::{acquire_request, spawn};
 
-pub fn spawn_reply<F, R, V>(id: u64, reply: R, f: F)
+// spawn_reply handles the request in a new task, in which the caller of the
+// request is available through `Caller::current`. It waits first if too many
+// requests are pending, see `runtime::set_max_pending_requests`
+pub fn spawn_reply<F, R, V>(req: &Request, reply: R, f: F)
 where
     F: Future<Output = Result<V>> + Send + 'static,
     R: FsReply<V> + Send + 'static,
     V: Debug,
 {
+    let id = req.unique();
+    let caller = Caller {
+        uid: req.uid(),
+        gid: req.gid(),
+        pid: req.pid(),
+    };
+    let permit = acquire_request();
     spawn(async move {
-        let result = f.instrument(trace_span!("request", id)).await;
+        let result = caller.scope(f.instrument(trace_span!("request", id))).await;
         reply.reply(result);
+        drop(permit);
     });
 }
 
@@ -237,9 +249,11 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     fn lookup(&mut self, req: &Request, parent: u64, name: &std::ffi::OsStr, reply: ReplyEntry) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.lookup(parent, name).await
-        });
+        spawn_reply(
+            req,
+            reply,
+            async move { async_impl.lookup(parent, name).await },
+        );
     }
 
     fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
@@ -253,11 +267,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
 
     fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
         let async_impl = self.0.clone();
-        spawn_reply(
-            req.unique(),
-            reply,
-            async move { async_impl.getattr(ino).await },
-        );
+        spawn_reply(req, reply, async move { async_impl.getattr(ino).await });
     }
 
     fn setattr(
@@ -279,7 +289,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyAttr,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl
                 .setattr(
                     ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
@@ -291,9 +301,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
 
     fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.readlink(ino).await
-        });
+        spawn_reply(req, reply, async move { async_impl.readlink(ino).await });
     }
     fn mknod(
         &mut self,
@@ -309,7 +317,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         let name = name.to_owned();
         let uid = req.uid();
         let gid = req.gid();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl
                 .mknod(parent, name, mode, umask, rdev, uid, gid)
                 .await
@@ -329,23 +337,27 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
 
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl.mkdir(parent, name, mode, umask, uid, gid).await
         });
     }
     fn unlink(&mut self, req: &Request, parent: u64, name: &std::ffi::OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.unlink(parent, name).await
-        });
+        spawn_reply(
+            req,
+            reply,
+            async move { async_impl.unlink(parent, name).await },
+        );
     }
     fn rmdir(&mut self, req: &Request, parent: u64, name: &std::ffi::OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.rmdir(parent, name).await
-        });
+        spawn_reply(
+            req,
+            reply,
+            async move { async_impl.rmdir(parent, name).await },
+        );
     }
     fn symlink(
         &mut self,
@@ -360,7 +372,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         let link = link.to_owned();
         let uid = req.uid();
         let gid = req.gid();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl.symlink(parent, name, link, uid, gid).await
         });
     }
@@ -377,7 +389,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         let async_impl = self.0.clone();
         let name = name.to_owned();
         let newname = newname.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl
                 .rename(parent, name, newparent, newname, flags)
                 .await
@@ -393,15 +405,13 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     ) {
         let async_impl = self.0.clone();
         let newname = newname.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl.link(ino, newparent, newname).await
         });
     }
     fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.open(ino, flags).await
-        });
+        spawn_reply(req, reply, async move { async_impl.open(ino, flags).await });
     }
     fn read(
         &mut self,
@@ -415,7 +425,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyData,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl
                 .read(ino, fh, offset, size, flags, lock_owner)
                 .await
@@ -435,7 +445,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     ) {
         let async_impl = self.0.clone();
         let data = data.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl
                 .write(ino, fh, offset, data, write_flags, flags, lock_owner)
                 .await
@@ -443,7 +453,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     }
     fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl.flush(ino, fh, lock_owner).await
         });
     }
@@ -458,21 +468,23 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl.release(ino, fh, flags, lock_owner, flush).await
         });
     }
     fn fsync(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl.fsync(ino, fh, datasync).await
         });
     }
     fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.opendir(ino, flags).await
-        });
+        spawn_reply(
+            req,
+            reply,
+            async move { async_impl.opendir(ino, flags).await },
+        );
     }
     fn readdir(
         &mut self,
@@ -492,23 +504,19 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     }
     fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl.releasedir(ino, fh, flags).await
         });
     }
     fn fsyncdir(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl.fsyncdir(ino, fh, datasync).await
         });
     }
     fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
         let async_impl = self.0.clone();
-        spawn_reply(
-            req.unique(),
-            reply,
-            async move { async_impl.statfs(ino).await },
-        );
+        spawn_reply(req, reply, async move { async_impl.statfs(ino).await });
     }
     fn setxattr(
         &mut self,
@@ -523,7 +531,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         let async_impl = self.0.clone();
         let name = name.to_owned();
         let value = value.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl.setxattr(ino, name, value, flags, position).await
         });
     }
@@ -537,28 +545,32 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     ) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl.getxattr(ino, name, size).await
         });
     }
     fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.listxattr(ino, size).await
-        });
+        spawn_reply(
+            req,
+            reply,
+            async move { async_impl.listxattr(ino, size).await },
+        );
     }
     fn removexattr(&mut self, req: &Request, ino: u64, name: &std::ffi::OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl.removexattr(ino, name).await
         });
     }
     fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.access(ino, mask).await
-        });
+        spawn_reply(
+            req,
+            reply,
+            async move { async_impl.access(ino, mask).await },
+        );
     }
     fn create(
         &mut self,
@@ -575,7 +587,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
 
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl
                 .create(parent, name, mode, umask, flags, uid, gid)
                 .await
@@ -594,7 +606,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyLock,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl
                 .getlk(ino, fh, lock_owner, start, end, typ, pid)
                 .await
@@ -614,7 +626,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req, reply, async move {
             async_impl
                 .setlk(ino, fh, lock_owner, start, end, typ, pid, sleep)
                 .await
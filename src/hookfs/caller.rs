@@ -0,0 +1,26 @@
+use std::future::Future;
+
+// Caller is the process which sent the FUSE request being handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
+
+tokio::task_local! {
+    static CALLER: Caller;
+}
+
+impl Caller {
+    // scope runs the future with `self` as the caller of the request
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CALLER.scope(self, f).await
+    }
+
+    // current returns the caller of the request being handled, or None outside
+    // of a request
+    pub fn current() -> Option<Caller> {
+        CALLER.try_with(|caller| *caller).ok()
+    }
+}
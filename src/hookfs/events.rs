@@ -0,0 +1,154 @@
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use super::errors::HookFsError;
+use super::Caller;
+use crate::injector::Method;
+
+// the count of events buffered before new ones are dropped
+pub const DEFAULT_EVENT_BUFFER: usize = 4096;
+
+// Event is an injection made on a request
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    // RFC 3339 timestamp
+    pub time: String,
+    // the process which sent the request, if it's known
+    pub pid: Option<u32>,
+    pub method: String,
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "camelCase")]
+pub enum Action {
+    Delayed {
+        #[serde(with = "humantime_serde")]
+        latency: Duration,
+    },
+    Failed {
+        errno: Option<i32>,
+        error: String,
+    },
+    // bytes of the data read or written are changed
+    Corrupted {
+        bytes: usize,
+    },
+}
+
+impl Action {
+    pub fn failed(err: &HookFsError) -> Action {
+        Action::Failed {
+            errno: match err {
+                HookFsError::Sys(errno) => Some(*errno as i32),
+                _ => None,
+            },
+            error: err.to_string(),
+        }
+    }
+
+    // corrupted counts the bytes changed from `original` to `data`, including
+    // the ones cut off or appended
+    pub fn corrupted(original: &[u8], data: &[u8]) -> Option<Action> {
+        let changed = original
+            .iter()
+            .zip(data.iter())
+            .filter(|(a, b)| a != b)
+            .count();
+        let bytes = changed + original.len().max(data.len()) - original.len().min(data.len());
+        if bytes == 0 {
+            return None;
+        }
+        Some(Action::Corrupted { bytes })
+    }
+}
+
+// EventSink writes the events as JSON lines from a background thread. Sending
+// never blocks the request: if the buffer is full, the event is dropped and
+// counted instead.
+#[derive(Debug)]
+pub struct EventSink {
+    tx: SyncSender<Event>,
+    dropped: AtomicU64,
+}
+
+impl EventSink {
+    // to_file appends the events to the file on `path`
+    pub fn to_file<P: AsRef<Path>>(path: P) -> Result<EventSink> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())
+            .with_context(|| format!("open event log {}", path.as_ref().display()))?;
+        Ok(EventSink::new(file, DEFAULT_EVENT_BUFFER))
+    }
+
+    pub fn new<W: Write + Send + 'static>(writer: W, capacity: usize) -> EventSink {
+        let (tx, rx) = sync_channel(capacity);
+        std::thread::spawn(move || write_events(rx, BufWriter::new(writer)));
+        EventSink {
+            tx,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn send(&self, method: &Method, path: &Path, action: Action) {
+        let event = Event {
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            pid: Caller::current().map(|caller| caller.pid),
+            method: method.name().unwrap_or("unknown").to_owned(),
+            path: path.to_owned(),
+            action,
+        };
+        // the writer thread lives as long as the sink, so only a full buffer
+        // fails the send
+        if self.tx.try_send(event).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // dropped returns the count of events dropped as the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for EventSink {
+    fn drop(&mut self) {
+        let dropped = self.dropped();
+        if dropped > 0 {
+            warn!("{} events are dropped from the event log", dropped);
+        }
+    }
+}
+
+// write_events writes the events until the sink is dropped. The writer is only
+// flushed when no event is waiting, so bursts are written in batches
+fn write_events<W: Write>(rx: Receiver<Event>, mut writer: W) {
+    while let Ok(event) = rx.recv() {
+        let mut event = Some(event);
+        while let Some(next) = event {
+            if let Err(err) = serde_json::to_writer(&mut writer, &next)
+                .map_err(std::io::Error::from)
+                .and_then(|_| writer.write_all(b"\n"))
+            {
+                error!("fail to write event: {}", err);
+            }
+            event = rx.try_recv().ok();
+        }
+        if let Err(err) = writer.flush() {
+            error!("fail to flush events: {}", err);
+        }
+    }
+}
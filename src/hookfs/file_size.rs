@@ -0,0 +1,23 @@
+use std::future::Future;
+
+// FileSize is the size of the opened file which the request being handled is
+// on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSize(pub u64);
+
+tokio::task_local! {
+    static FILE_SIZE: FileSize;
+}
+
+impl FileSize {
+    // scope runs the future with `self` as the size of the opened file
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        FILE_SIZE.scope(self, f).await
+    }
+
+    // current returns the size of the opened file of the request being handled,
+    // or None if the request is not on an opened file
+    pub fn current() -> Option<FileSize> {
+        FILE_SIZE.try_with(|size| *size).ok()
+    }
+}
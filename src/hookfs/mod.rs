@@ -1,7 +1,11 @@
 mod async_fs;
+mod caller;
 mod errors;
+mod events;
+mod file_size;
 mod reply;
 pub mod runtime;
+mod stats;
 mod utils;
 
 use std::collections::{HashMap, LinkedList};
@@ -9,12 +13,17 @@ use std::ffi::{CString, OsStr, OsString};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 pub use async_fs::{AsyncFileSystem, AsyncFileSystemImpl};
 use async_trait::async_trait;
+pub use caller::Caller;
 use derive_more::{Deref, DerefMut, From};
 pub use errors::{HookFsError as Error, Result};
+pub use events::{Action, Event, EventSink, DEFAULT_EVENT_BUFFER};
+pub use file_size::FileSize;
 use fuser::*;
 use libc::{c_void, lgetxattr, llistxattr, lremovexattr, lsetxattr};
 use nix::dir;
@@ -22,103 +31,193 @@ use nix::errno::Errno;
 use nix::fcntl::{open, readlink, renameat, OFlag};
 use nix::sys::{stat, statfs};
 use nix::unistd::{
-    close, fchownat, fsync, linkat, mkdir, symlinkat, truncate, unlink, AccessFlags, FchownatFlags,
-    Gid, LinkatFlags, Uid,
+    close, fchownat, fdatasync, fsync, linkat, mkdir, symlinkat, truncate, unlink, AccessFlags,
+    FchownatFlags, Gid, LinkatFlags, Uid,
 };
-pub use reply::Reply;
 use reply::*;
+pub use reply::{Data, Reply};
 use runtime::spawn_blocking;
 use slab::Slab;
+pub use stats::{CounterSnapshot, Counters};
 use tokio::sync::RwLock;
 use tracing::{debug, error, instrument, trace};
 use utils::*;
 
-use crate::injector::{Injector, Method, MultiInjector};
-
-// use fuse::consts::FOPEN_DIRECT_IO;
+use crate::injector::{Injection, Injector, LiveInjector, Method, MultiInjector};
 
 macro_rules! inject {
     ($self:ident, $method:ident, $path:expr) => {
-        if $self.enable_injection.load(Ordering::SeqCst) {
-            $self
+        if $self.injecting() {
+            $self.counters.intercept(&Method::$method);
+            let start = Instant::now();
+            let path = $self.rebuild_path($path)?;
+            let injection = $self
                 .injector
-                .read()
-                .await
-                .inject(&Method::$method, $self.rebuild_path($path)?.as_path())
-                .await?;
+                .load()
+                .inject(&Method::$method, path.as_path())
+                .await;
+            match injection {
+                Ok(Injection::Delayed) => $self.delayed(&Method::$method, &path, start.elapsed()),
+                Ok(Injection::Passed) => {}
+                Err(err) => {
+                    $self.failed(&Method::$method, &path, &err);
+                    return Err(err);
+                }
+            }
         }
     };
 }
 
+// the macros below resolve the path of a request only while injecting, so
+// passing requests through, e.g. before injection is enabled, costs no lock or
+// allocation
 macro_rules! inject_with_ino {
-    ($self:ident, $method:ident, $ino:ident) => {{
-        let inode_map = $self.inode_map.read().await;
-        if let Ok(path) = inode_map.get_path($ino) {
-            let path = path.to_owned();
-            trace!("getting attr from path {}", path.display());
-            drop(inode_map);
-            inject!($self, $method, &path);
+    ($self:ident, $method:ident, $ino:ident) => {
+        if $self.injecting() {
+            let inode_map = $self.inode_map.read().await;
+            if let Ok(path) = inode_map.get_path($ino) {
+                let path = path.to_owned();
+                trace!("getting attr from path {}", path.display());
+                drop(inode_map);
+                inject!($self, $method, &path);
+            }
         }
-    }};
+    };
 }
 
+// the injectors are called with the size of the opened file on the requests
+// with a fh, so it can be matched by the size filters
 macro_rules! inject_with_fh {
-    ($self:ident, $method:ident, $fh:ident) => {{
-        let opened_files = $self.opened_files.read().await;
-        if let Ok(file) = opened_files.get($fh as usize) {
-            let path = file.original_path().to_owned();
-            drop(opened_files);
-            inject!($self, $method, &path);
+    ($self:ident, $method:ident, $fh:ident) => {
+        if $self.injecting() {
+            let opened_files = $self.opened_files.read().await;
+            if let Ok(file) = opened_files.get($fh as usize) {
+                let path = file.original_path().to_owned();
+                let size = file.size()?;
+                drop(opened_files);
+                FileSize(size)
+                    .scope(async {
+                        inject!($self, $method, &path);
+                        Ok::<_, Error>(())
+                    })
+                    .await?;
+            }
         }
-    }};
+    };
 }
 
 macro_rules! inject_write_data {
-    ($self:ident, $fh:ident, $data:ident) => {{
-        let opened_files = $self.opened_files.read().await;
-        if let Ok(file) = opened_files.get($fh as usize) {
-            let path = file.original_path().to_owned();
-            trace!("Write data before inject {:?}", $data);
-            $self
-                .injector
-                .read()
-                .await
-                .inject_write_data($self.rebuild_path(path)?.as_path(), &mut $data)?;
-            trace!("Write data after inject {:?}", $data);
+    ($self:ident, $fh:ident, $data:ident) => {
+        if $self.injecting() {
+            let opened_files = $self.opened_files.read().await;
+            if let Ok(file) = opened_files.get($fh as usize) {
+                let path = file.original_path().to_owned();
+                let size = file.size()?;
+                trace!("Write data before inject {:?}", $data);
+                let injector = $self.injector.load();
+                FileSize(size)
+                    .scope(async {
+                        injector.inject_write_data($self.rebuild_path(path)?.as_path(), &mut $data)
+                    })
+                    .await?;
+                trace!("Write data after inject {:?}", $data);
+            }
+        }
+    };
+}
+
+// inject_append evaluates to whether the appended data should be synced. Only
+// the data written at or past the end of the file is passed to the injectors
+macro_rules! inject_append {
+    ($self:ident, $fh:ident, $offset:ident, $data:ident) => {{
+        let mut sync = false;
+        if $self.injecting() {
+            let opened_files = $self.opened_files.read().await;
+            if let Ok(file) = opened_files.get($fh as usize) {
+                if $offset >= stat::fstat(file.fd)?.st_size {
+                    let path = file.original_path().to_owned();
+                    let size = file.size()?;
+                    drop(opened_files);
+                    let injector = $self.injector.load();
+                    sync = FileSize(size)
+                        .scope(async {
+                            injector.inject_append($self.rebuild_path(path)?.as_path(), &mut $data)
+                        })
+                        .await?;
+                }
+            }
         }
+        sync
     }};
 }
 
+macro_rules! inject_transfer_with_fh {
+    ($self:ident, $method:ident, $fh:ident, $size:expr) => {
+        if $self.injecting() {
+            let opened_files = $self.opened_files.read().await;
+            if let Ok(file) = opened_files.get($fh as usize) {
+                let path = $self.rebuild_path(file.original_path())?;
+                let file_size = file.size()?;
+                // don't block opening and releasing files while delaying
+                drop(opened_files);
+                let start = Instant::now();
+                let injection = FileSize(file_size)
+                    .scope(async {
+                        $self
+                            .injector
+                            .load()
+                            .inject_transfer(&Method::$method, &path, $size)
+                            .await
+                    })
+                    .await;
+                match injection {
+                    Ok(Injection::Delayed) => {
+                        $self.delayed(&Method::$method, &path, start.elapsed())
+                    }
+                    Ok(Injection::Passed) => {}
+                    Err(err) => {
+                        $self.failed(&Method::$method, &path, &err);
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    };
+}
+
 macro_rules! inject_with_dir_fh {
-    ($self:ident, $method:ident, $fh:ident) => {{
-        let opened_dirs = $self.opened_dirs.read().await;
-        if let Ok(dir) = opened_dirs.get($fh as usize) {
-            let path = dir.original_path().to_owned();
-            drop(opened_dirs);
-            inject!($self, $method, &path);
+    ($self:ident, $method:ident, $fh:ident) => {
+        if $self.injecting() {
+            let opened_dirs = $self.opened_dirs.read().await;
+            if let Ok(dir) = opened_dirs.get($fh as usize) {
+                let path = dir.original_path().to_owned();
+                drop(opened_dirs);
+                inject!($self, $method, &path);
+            }
         }
-    }};
+    };
 }
 
 macro_rules! inject_with_parent_and_name {
-    ($self:ident, $method:ident, $parent:ident, $name:expr) => {{
-        let inode_map = $self.inode_map.read().await;
-        if let Ok(parent_path) = inode_map.get_path($parent) {
-            let old_path = parent_path.join($name);
-            trace!("get path: {}", old_path.display());
-            drop(inode_map);
-            inject!($self, $method, old_path.as_path());
+    ($self:ident, $method:ident, $parent:ident, $name:expr) => {
+        if $self.injecting() {
+            let inode_map = $self.inode_map.read().await;
+            if let Ok(parent_path) = inode_map.get_path($parent) {
+                let old_path = parent_path.join($name);
+                trace!("get path: {}", old_path.display());
+                drop(inode_map);
+                inject!($self, $method, old_path.as_path());
+            }
         }
-    }};
+    };
 }
 
 macro_rules! inject_attr {
     ($self:ident, $attr:ident, $path:expr) => {
-        if $self.enable_injection.load(Ordering::SeqCst) {
+        if $self.injecting() {
             $self
                 .injector
-                .read()
-                .await
+                .load()
                 .inject_attr(&mut $attr, $self.rebuild_path($path)?.as_path());
         }
     };
@@ -126,9 +225,9 @@ macro_rules! inject_attr {
 
 macro_rules! inject_reply {
     ($self:ident, $method:ident, $path:expr, $reply:ident, $reply_typ:ident) => {
-        if $self.enable_injection.load(Ordering::SeqCst) {
+        if $self.injecting() {
             trace!("before inject {:?}", $reply);
-            $self.injector.read().await.inject_reply(
+            $self.injector.load().inject_reply(
                 &Method::$method,
                 $self.rebuild_path($path)?.as_path(),
                 &mut Reply::$reply_typ(&mut $reply),
@@ -144,12 +243,29 @@ pub struct HookFs {
     original_path: PathBuf,
 
     enable_injection: AtomicBool,
+    // paused by the RPC: requests are passed through without calling the
+    // injectors, while the mount and the replaced fds are kept
+    paused: AtomicBool,
+    // the time when the injection was enabled last time
+    enabled_at: Mutex<Option<Instant>>,
+    // the wall clock time when the injection was enabled and disabled last
+    // time, reported to correlate the injection with monitoring
+    enabled_time: Mutex<Option<SystemTime>>,
+    disabled_time: Mutex<Option<SystemTime>>,
+
+    // bypass the page cache for all opened files
+    direct_io: bool,
 
     opened_files: RwLock<FhMap<File>>,
 
     opened_dirs: RwLock<FhMap<Dir>>,
 
-    pub injector: RwLock<MultiInjector>,
+    pub injector: LiveInjector,
+
+    pub counters: Counters,
+
+    // where the injected faults are logged, if it's set
+    events: Option<Arc<EventSink>>,
 
     // map from inode to real path
     inode_map: RwLock<InodeMap>,
@@ -272,10 +388,16 @@ impl std::ops::DerefMut for Dir {
     }
 }
 
+// the size of an opened file is unknown until it's needed
+const UNKNOWN_SIZE: u64 = u64::MAX;
+
 #[derive(Debug)]
 pub struct File {
     pub fd: RawFd,
     original_path: PathBuf,
+    size: AtomicU64,
+    // opened with O_APPEND, which is not passed to the backing fd
+    append: bool,
 }
 
 impl File {
@@ -283,11 +405,40 @@ impl File {
         File {
             fd,
             original_path: path.as_ref().to_owned(),
+            size: AtomicU64::new(UNKNOWN_SIZE),
+            append: false,
         }
     }
+
+    fn with_append(mut self, flags: i32) -> File {
+        self.append = flags & libc::O_APPEND != 0;
+        self
+    }
     fn original_path(&self) -> &Path {
         &self.original_path
     }
+
+    // size returns the size of the file. It's looked up once per open, and then
+    // kept up to date by the writes on this file, so truncating the file
+    // elsewhere is not seen
+    fn size(&self) -> Result<u64> {
+        let size = self.size.load(Ordering::SeqCst);
+        if size != UNKNOWN_SIZE {
+            return Ok(size);
+        }
+        let size = stat::fstat(self.fd)?.st_size as u64;
+        // a write may have set the size meanwhile
+        let _ = self
+            .size
+            .compare_exchange(UNKNOWN_SIZE, size, Ordering::SeqCst, Ordering::SeqCst);
+        Ok(self.size.load(Ordering::SeqCst))
+    }
+
+    // extend_to records a write ending at `end`. An unknown size is kept, as
+    // it's the max value
+    fn extend_to(&self, end: u64) {
+        self.size.fetch_max(end, Ordering::SeqCst);
+    }
 }
 
 unsafe impl Send for Dir {}
@@ -309,18 +460,130 @@ impl HookFs {
             original_path: original_path.as_ref().to_owned(),
             opened_files: RwLock::new(FhMap::from(Slab::new())),
             opened_dirs: RwLock::new(FhMap::from(Slab::new())),
-            injector: RwLock::new(injector),
+            injector: LiveInjector::new(injector),
+            counters: Counters::default(),
             inode_map,
             enable_injection: AtomicBool::from(false),
+            paused: AtomicBool::from(false),
+            enabled_at: Mutex::new(None),
+            enabled_time: Mutex::new(None),
+            disabled_time: Mutex::new(None),
+            direct_io: false,
+            events: None,
+        }
+    }
+
+    pub fn with_events(mut self, events: Option<Arc<EventSink>>) -> Self {
+        self.events = events;
+        self
+    }
+
+    // delayed and failed record an injection on `path`, the path on the mount,
+    // in the counters and the event log
+    fn delayed(&self, method: &Method, path: &Path, latency: Duration) {
+        self.counters.delay(method, latency);
+        if let Some(events) = &self.events {
+            events.send(method, path, Action::Delayed { latency });
+        }
+    }
+
+    fn failed(&self, method: &Method, path: &Path, err: &Error) {
+        self.counters.fail(method, err);
+        if let Some(events) = &self.events {
+            events.send(method, path, Action::failed(err));
+        }
+    }
+
+    // corrupted logs the data changed by the injectors from `original`, which is
+    // returned by `original_data`
+    fn corrupted(&self, method: &Method, path: &Path, original: Option<Vec<u8>>, data: &[u8]) {
+        if let (Some(events), Some(original)) = (&self.events, original) {
+            if let Some(action) = Action::corrupted(&original, data) {
+                events.send(method, path, action);
+            }
+        }
+    }
+
+    // original_data copies the data to be compared after injection, if the event
+    // log is set and the injection is running
+    fn original_data(&self, data: &[u8]) -> Option<Vec<u8>> {
+        match &self.events {
+            Some(_) if self.injecting() => Some(data.to_vec()),
+            _ => None,
+        }
+    }
+
+    pub fn with_direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    pub fn direct_io(&self) -> bool {
+        self.direct_io
+    }
+
+    // open_flags returns the FOPEN_* flags replied to open and create
+    fn open_flags(&self) -> i32 {
+        if self.direct_io {
+            consts::FOPEN_DIRECT_IO as i32
+        } else {
+            0
         }
     }
 
     pub fn enable_injection(&self) {
+        let now = Instant::now();
+        *self.enabled_at.lock().unwrap() = Some(now);
+        *self.enabled_time.lock().unwrap() = Some(SystemTime::now());
+        *self.disabled_time.lock().unwrap() = None;
+        // the time windows of injectors start from now
+        self.injector.load().enable(now);
         self.enable_injection.store(true, Ordering::SeqCst);
     }
 
     pub fn disable_injection(&self) {
-        self.enable_injection.store(false, Ordering::SeqCst);
+        if self.enable_injection.swap(false, Ordering::SeqCst) {
+            *self.disabled_time.lock().unwrap() = Some(SystemTime::now());
+        }
+    }
+
+    pub fn pause_injection(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume_injection(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn injection_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    // injecting is checked first by every request
+    fn injecting(&self) -> bool {
+        self.enable_injection.load(Ordering::SeqCst) && !self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn mount_path(&self) -> &Path {
+        &self.mount_path
+    }
+
+    pub fn injection_enabled(&self) -> bool {
+        self.enable_injection.load(Ordering::SeqCst)
+    }
+
+    pub fn enabled_at(&self) -> Option<Instant> {
+        *self.enabled_at.lock().unwrap()
+    }
+
+    pub fn enabled_time(&self) -> Option<SystemTime> {
+        *self.enabled_time.lock().unwrap()
+    }
+
+    // disabled_time returns None if the injection has not been disabled since it
+    // was enabled last time
+    pub fn disabled_time(&self) -> Option<SystemTime> {
+        *self.disabled_time.lock().unwrap()
     }
 
     pub fn rebuild_path<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
@@ -496,7 +759,6 @@ impl AsyncFileSystemImpl for HookFs {
         let mut inode_map = self.inode_map.write().await;
         let parent_path = inode_map.get_path(parent)?;
         let path = parent_path.join(&name);
-        inject!(self, MKNOD, path.as_path());
         let cpath = CString::new(path.as_os_str().as_bytes())?;
 
         trace!("mknod for {:?}", cpath);
@@ -636,6 +898,8 @@ impl AsyncFileSystemImpl for HookFs {
         _flags: u32,
     ) -> Result<()> {
         trace!("rename");
+        // the injectors match the source path. Like other operations, a fault
+        // is injected before anything is renamed
         inject_with_parent_and_name!(self, RENAME, parent, &name);
 
         let mut inode_map = self.inode_map.write().await;
@@ -718,7 +982,8 @@ impl AsyncFileSystemImpl for HookFs {
             debug!("direct io flag is ignored directly")
         }
         // filter out append. The kernel layer will translate the
-        // offsets for us appropriately.
+        // offsets for us appropriately. The writes on the backing fd use pwrite,
+        // whose offset would be ignored with O_APPEND.
         let filtered_flags = flags & (!libc::O_APPEND) & (!libc::O_DIRECT);
         let filtered_flags = OFlag::from_bits_truncate(filtered_flags as i32);
 
@@ -728,13 +993,13 @@ impl AsyncFileSystemImpl for HookFs {
         trace!("open with flags: {:?}", filtered_flags);
 
         let fd = async_open(&path, filtered_flags, stat::Mode::S_IRWXU).await?;
-        let fh = self.opened_files.write().await.insert(File::new(fd, path)) as u64;
+        let file = File::new(fd, path).with_append(flags);
+        let fh = self.opened_files.write().await.insert(file) as u64;
 
         trace!("return with fh: {}, flags: {}", fh, 0);
 
-        let mut reply = Open::new(fh, 0);
+        let mut reply = Open::new(fh, self.open_flags());
         inject_reply!(self, OPEN, path, reply, Open);
-        // TODO: force DIRECT_IO is not a great option
         Ok(reply)
     }
 
@@ -751,12 +1016,21 @@ impl AsyncFileSystemImpl for HookFs {
         trace!("read");
         inject_with_fh!(self, READ, fh);
 
-        let opened_files = self.opened_files.read().await;
-        let file = opened_files.get(fh as usize)?;
-        let buf = async_read(file.fd, size as usize, offset).await?;
+        let (buf, path) = {
+            let opened_files = self.opened_files.read().await;
+            let file = opened_files.get(fh as usize)?;
+            let buf = async_read(file.fd, size as usize, offset).await?;
+            (buf, file.original_path().to_owned())
+        };
+        inject_transfer_with_fh!(self, READ, fh, buf.len());
 
         let mut reply = Data::new(buf);
-        inject_reply!(self, READ, &file.original_path(), reply, Data);
+        let original = self.original_data(&reply.data);
+        inject_reply!(self, READ, &path, reply, Data);
+        if original.is_some() {
+            let path = self.rebuild_path(&path)?;
+            self.corrupted(&Method::READ, &path, original, &reply.data);
+        }
         Ok(reply)
     }
 
@@ -772,12 +1046,44 @@ impl AsyncFileSystemImpl for HookFs {
         _lock_owner: Option<u64>,
     ) -> Result<Write> {
         trace!("write");
+        // faults are injected before anything is written, so a failed write
+        // leaves the file unchanged. Only a torn append writes part of the data
         inject_with_fh!(self, WRITE, fh);
+        // a write on a file opened with O_APPEND goes to the real end of the
+        // file, even if the kernel has been told another size by an attr
+        // override injector
+        let offset = {
+            let opened_files = self.opened_files.read().await;
+            let file = opened_files.get(fh as usize)?;
+            if file.append {
+                stat::fstat(file.fd)?.st_size
+            } else {
+                offset
+            }
+        };
+        let original = self.original_data(&data);
         inject_write_data!(self, fh, data);
+        let len = data.len();
+        let sync = inject_append!(self, fh, offset, data);
+        // a torn append is reported as written in full, as if the rest were
+        // lost in a crash
+        let torn = data.len() < len;
+        inject_transfer_with_fh!(self, WRITE, fh, data.len());
         let opened_files = self.opened_files.read().await;
         let file = opened_files.get(fh as usize)?;
 
-        let size = async_write(file.fd, data, offset).await?;
+        if original.is_some() {
+            let path = self.rebuild_path(file.original_path())?;
+            self.corrupted(&Method::WRITE, &path, original, &data);
+        }
+
+        let fd = file.fd;
+        let size = async_write(fd, data, offset).await?;
+        file.extend_to(offset as u64 + size as u64);
+        if sync {
+            spawn_blocking(move || fsync(fd)).await??;
+        }
+        let size = if torn { len } else { size };
         let mut reply = Write::new(size as u32);
         inject_reply!(self, WRITE, file.original_path(), reply, Write);
         Ok(reply)
@@ -818,9 +1124,15 @@ impl AsyncFileSystemImpl for HookFs {
     }
 
     #[instrument(skip(self))]
-    async fn fsync(&self, _ino: u64, fh: u64, _datasync: bool) -> Result<()> {
+    async fn fsync(&self, _ino: u64, fh: u64, datasync: bool) -> Result<()> {
         trace!("fsync");
-        inject_with_fh!(self, FSYNC, fh);
+        // a fault is injected before the sync is forwarded, so the written data
+        // stays in the page cache. It's visible until a crash, but lost after it
+        if datasync {
+            inject_with_fh!(self, FDATASYNC, fh);
+        } else {
+            inject_with_fh!(self, FSYNC, fh);
+        }
 
         let opened_files = self.opened_files.read().await;
         let fd: RawFd = {
@@ -828,7 +1140,7 @@ impl AsyncFileSystemImpl for HookFs {
             file.fd
         };
 
-        spawn_blocking(move || fsync(fd)).await??;
+        spawn_blocking(move || if datasync { fdatasync(fd) } else { fsync(fd) }).await??;
 
         Ok(())
     }
@@ -881,6 +1193,8 @@ impl AsyncFileSystemImpl for HookFs {
             trace!("empty reply");
             return Ok(());
         }
+        // if any entry fails, the error is replied instead of the entries added
+        // so far, so the caller never sees a partial listing
         for (index, entry) in all_entries.iter().enumerate().skip(offset as usize) {
             let entry = (*entry)?;
 
@@ -905,13 +1219,22 @@ impl AsyncFileSystemImpl for HookFs {
     async fn releasedir(&self, _ino: u64, fh: u64, _flags: i32) -> Result<()> {
         trace!("releasedir");
 
-        self.opened_dirs.write().await.remove(fh as usize);
+        // the kernel never retries a release, so the handle is removed before
+        // injecting, otherwise an injected fault would leak it
+        let path = {
+            let mut opened_dirs = self.opened_dirs.write().await;
+            let path = opened_dirs.get(fh as usize)?.original_path().to_owned();
+            opened_dirs.remove(fh as usize);
+            path
+        };
+        inject!(self, RELEASEDIR, &path);
         Ok(())
     }
 
     #[instrument(skip(self))]
-    async fn fsyncdir(&self, ino: u64, _fh: u64, _datasync: bool) -> Result<()> {
-        // TODO: inject
+    async fn fsyncdir(&self, ino: u64, fh: u64, _datasync: bool) -> Result<()> {
+        trace!("fsyncdir");
+        inject_with_dir_fh!(self, FSYNCDIR, fh);
 
         let inode_map = self.inode_map.read().await;
         let path = inode_map.get_path(ino)?.to_owned();
@@ -982,9 +1305,7 @@ impl AsyncFileSystemImpl for HookFs {
         let cpath = CString::new(path.as_os_str().as_bytes())?;
         let name = CString::new(name.as_bytes())?;
 
-        let mut buf = Vec::new();
-        buf.resize(size as usize, 0u8);
-
+        // with zero size, only the size of the value is queried
         let data = async_getxattr(cpath, name, size as usize).await?;
 
         let mut reply = if size == 0 {
@@ -1008,27 +1329,12 @@ impl AsyncFileSystemImpl for HookFs {
         let path = inode_map.get_path(ino)?.to_owned();
         let cpath = CString::new(path.as_os_str().as_bytes())?;
 
-        let mut buf = Vec::new();
-        buf.resize(size as usize, 0u8);
-
-        let shared_buf = std::sync::Arc::new(buf);
-        let buf_clone = shared_buf.clone();
-
-        let ret = spawn_blocking(move || {
-            let path_ptr = &cpath.as_bytes_with_nul()[0] as *const u8 as *const libc::c_char;
-            let buf_ptr = buf_clone.as_slice() as *const [u8] as *mut [u8] as *mut libc::c_char;
-            unsafe { llistxattr(path_ptr, buf_ptr, size as usize) }
-        })
-        .await?;
-
-        if ret == -1 {
-            return Err(Error::last());
-        }
+        let names = async_listxattr(cpath, size as usize).await?;
 
         let mut reply = if size == 0 {
-            Xattr::size(ret as u32)
+            Xattr::size(names.len() as u32)
         } else {
-            Xattr::data(shared_buf.as_slice().to_owned())
+            Xattr::data(names)
         };
         inject_reply!(self, LISTXATTR, path, reply, Xattr);
 
@@ -1045,17 +1351,7 @@ impl AsyncFileSystemImpl for HookFs {
         let path = CString::new(path.as_os_str().as_bytes())?;
         let name = CString::new(name.as_bytes())?;
 
-        let ret = spawn_blocking(move || {
-            let path_ptr = &path.as_bytes_with_nul()[0] as *const u8 as *const libc::c_char;
-            let name_ptr = &name.as_bytes_with_nul()[0] as *const u8 as *const libc::c_char;
-            unsafe { lremovexattr(path_ptr, name_ptr) }
-        })
-        .await?;
-
-        if ret == -1 {
-            return Err(Error::last());
-        }
-        Ok(())
+        async_removexattr(path, name).await
     }
 
     #[instrument(skip(self))]
@@ -1103,14 +1399,16 @@ impl AsyncFileSystemImpl for HookFs {
         async_lchown(&path, Some(uid), Some(gid)).await?;
 
         let stat = self.get_file_attr(&path).await?;
-        let fh = self.opened_files.write().await.insert(File::new(fd, &path));
+        let file = File::new(fd, &path).with_append(flags);
+        let fh = self.opened_files.write().await.insert(file);
 
         // TODO: support generation number
         // this can be implemented with ioctl FS_IOC_GETVERSION
         trace!("return with stat: {:?} fh: {}", stat, fh);
         inode_map.insert_path(stat.ino, path.clone());
         inode_map.increase_ref(stat.ino);
-        let mut reply = Create::new(stat, 0, fh as u64, flags);
+        // the flags in the reply are FOPEN_* flags, not the flags of open(2)
+        let mut reply = Create::new(stat, 0, fh as u64, self.open_flags());
         inject_reply!(self, CREATE, path.as_path(), reply, Create);
         Ok(reply)
     }
@@ -1158,7 +1456,8 @@ async fn async_setxattr(path: CString, name: CString, data: Vec<u8>, flags: i32)
     spawn_blocking(move || {
         let path_ptr = &path.as_bytes_with_nul()[0] as *const u8 as *const libc::c_char;
         let name_ptr = &name.as_bytes_with_nul()[0] as *const u8 as *const libc::c_char;
-        let data_ptr = &data[0] as *const u8 as *const libc::c_void;
+        // an empty value is valid, so the pointer is never taken from data[0]
+        let data_ptr = data.as_ptr() as *const libc::c_void;
         let ret = unsafe { lsetxattr(path_ptr, name_ptr, data_ptr, data.len(), flags) };
 
         if ret == -1 {
@@ -1190,6 +1489,37 @@ async fn async_getxattr(path: CString, name: CString, size: usize) -> Result<Vec
     .await?
 }
 
+async fn async_listxattr(path: CString, size: usize) -> Result<Vec<u8>> {
+    spawn_blocking(move || {
+        let mut buf = vec![0u8; size];
+
+        let path_ptr = path.as_ptr();
+        let buf_ptr = buf.as_mut_ptr() as *mut libc::c_char;
+
+        let ret = unsafe { llistxattr(path_ptr, buf_ptr, size) };
+        if ret == -1 {
+            Err(Error::last())
+        } else {
+            // only the names returned are replied, instead of the whole buffer
+            buf.resize(ret as usize, 0);
+            Ok(buf)
+        }
+    })
+    .await?
+}
+
+async fn async_removexattr(path: CString, name: CString) -> Result<()> {
+    spawn_blocking(move || {
+        let ret = unsafe { lremovexattr(path.as_ptr(), name.as_ptr()) };
+        if ret == -1 {
+            Err(Error::last())
+        } else {
+            Ok(())
+        }
+    })
+    .await?
+}
+
 async fn async_read(fd: RawFd, count: usize, offset: i64) -> Result<Vec<u8>> {
     spawn_blocking(move || unsafe {
         let mut buf = Vec::new();
@@ -1,33 +1,122 @@
 use std::future::Future;
-use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 
+use anyhow::{anyhow, Result};
 use once_cell::sync::Lazy;
 use tokio::runtime::Runtime;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio::task::JoinHandle;
 use tracing::trace;
 
-pub static RUNTIME: Lazy<RwLock<Option<Runtime>>> = Lazy::new(|| {
-    trace!("build tokio runtime");
+// the max count of threads running the blocking file operations, besides the
+// workers. It's the max count of all threads by default in tokio.
+const MAX_BLOCKING_THREADS: usize = 512;
+
+// the count of worker threads. Zero means the count of CPUs
+static WORKER_THREADS: AtomicUsize = AtomicUsize::new(0);
 
-    RwLock::new(Some(
-        tokio::runtime::Builder::new()
-            .threaded_scheduler()
-            .thread_name("toda")
-            .enable_all()
-            .build()
-            .unwrap(),
-    ))
+// the max count of requests being handled at once. Zero means unlimited
+static MAX_PENDING_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+static PENDING_REQUESTS: Lazy<Option<Arc<Semaphore>>> = Lazy::new(|| {
+    let max = MAX_PENDING_REQUESTS.load(Ordering::SeqCst);
+    if max == 0 {
+        return None;
+    }
+    Some(Arc::new(Semaphore::new(max)))
 });
 
+// the runtime shared by all mounts. It's dropped by `shutdown` once the last
+// mount has gone, and built again by the next request.
+static RUNTIME: Lazy<RwLock<Option<Runtime>>> = Lazy::new(|| RwLock::new(None));
+
+fn build_runtime() -> Runtime {
+    trace!("build tokio runtime");
+
+    let mut builder = tokio::runtime::Builder::new();
+    builder
+        .threaded_scheduler()
+        .thread_name("toda")
+        .enable_all();
+    let worker_threads = WORKER_THREADS.load(Ordering::SeqCst);
+    if worker_threads > 0 {
+        builder
+            .core_threads(worker_threads)
+            .max_threads(worker_threads + MAX_BLOCKING_THREADS);
+    }
+
+    builder.build().unwrap()
+}
+
+// with_runtime calls `f` with the runtime, which is built if there is none
+fn with_runtime<F: FnOnce(&Runtime) -> R, R>(f: F) -> R {
+    if let Some(runtime) = &*RUNTIME.read().unwrap() {
+        return f(runtime);
+    }
+    let mut runtime = RUNTIME.write().unwrap();
+    f(runtime.get_or_insert_with(build_runtime))
+}
+
+// shutdown drops the runtime unless `in_use` tells it's still used. It's
+// checked under the lock, as a new mount may have come up meanwhile, which
+// won't be served until the lock is released and a new runtime is built.
+pub fn shutdown<F: FnOnce() -> bool>(in_use: F) {
+    let runtime = {
+        let mut runtime = RUNTIME.write().unwrap();
+        if in_use() {
+            return;
+        }
+        runtime.take()
+    };
+    // dropping the runtime waits for the blocking threads, which may spawn
+    // again, so the lock is released first
+    drop(runtime);
+}
+
+// set_worker_threads sets the count of threads serving the FUSE requests of all
+// mounts. It only takes effect the next time the runtime is built, i.e. on the
+// first request while no mount is served.
+pub fn set_worker_threads(threads: usize) -> Result<()> {
+    if threads == 0 {
+        return Err(anyhow!("count of worker threads must be positive"));
+    }
+
+    WORKER_THREADS.store(threads, Ordering::SeqCst);
+    Ok(())
+}
+
+// set_max_pending_requests bounds the requests of all mounts being handled at
+// once, including the ones delayed by injection. It only takes effect before the
+// first request.
+pub fn set_max_pending_requests(max: usize) -> Result<()> {
+    if max == 0 {
+        return Err(anyhow!("max count of pending requests must be positive"));
+    }
+
+    MAX_PENDING_REQUESTS.store(max, Ordering::SeqCst);
+    Ok(())
+}
+
+// acquire_request blocks the calling FUSE thread until another request can be
+// handled, and returns the permit to hold while handling it. Then no more
+// request is read from the kernel, which queues them instead of toda.
+pub fn acquire_request() -> Option<OwnedSemaphorePermit> {
+    let semaphore = PENDING_REQUESTS.as_ref()?;
+    if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+        return Some(permit);
+    }
+    trace!("too many pending requests, wait for one to finish");
+    let permit = semaphore.clone().acquire_owned();
+    Some(futures::executor::block_on(permit))
+}
+
 pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
 where
     F: Future + Send + 'static,
     F::Output: Send + 'static,
 {
-    if let Some(runtime) = &*RUNTIME.read().unwrap() {
-        return runtime.spawn(future);
-    }
-    unreachable!()
+    with_runtime(|runtime| runtime.spawn(future))
 }
 
 pub fn spawn_blocking<F, R>(func: F) -> JoinHandle<R>
@@ -35,8 +124,5 @@ where
     R: Send + 'static,
     F: FnOnce() -> R + Send + 'static,
 {
-    if let Some(runtime) = &*RUNTIME.read().unwrap() {
-        return runtime.handle().spawn_blocking(func);
-    }
-    unreachable!()
+    with_runtime(|runtime| runtime.handle().spawn_blocking(func))
 }
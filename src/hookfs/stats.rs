@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::errors::HookFsError;
+use crate::injector::{Method, METHOD_COUNT};
+
+#[derive(Debug, Default)]
+struct Counter {
+    intercepted: AtomicU64,
+    delayed: AtomicU64,
+    failed: AtomicU64,
+    // the total time spent in delays, in nanoseconds
+    latency: AtomicU64,
+    // the count of injected faults, keyed by errno
+    errnos: Mutex<BTreeMap<i32, u64>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct CounterSnapshot {
+    pub intercepted: u64,
+    pub delayed: u64,
+    pub failed: u64,
+    #[serde(with = "humantime_serde")]
+    pub latency: Duration,
+    pub errnos: BTreeMap<i32, u64>,
+}
+
+// Counters records how many operations of every method have been intercepted,
+// delayed or failed by the injectors.
+#[derive(Debug)]
+pub struct Counters {
+    // indexed by the bit position of the method
+    counters: Vec<Counter>,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Counters {
+            counters: (0..METHOD_COUNT).map(|_| Counter::default()).collect(),
+        }
+    }
+}
+
+impl Counters {
+    fn get(&self, method: &Method) -> &Counter {
+        &self.counters[method.bits().trailing_zeros() as usize]
+    }
+
+    pub fn intercept(&self, method: &Method) {
+        self.get(method).intercepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn delay(&self, method: &Method, latency: Duration) {
+        let counter = self.get(method);
+        counter.delayed.fetch_add(1, Ordering::Relaxed);
+        counter
+            .latency
+            .fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn fail(&self, method: &Method, err: &HookFsError) {
+        let counter = self.get(method);
+        counter.failed.fetch_add(1, Ordering::Relaxed);
+        if let HookFsError::Sys(errno) = err {
+            *counter
+                .errnos
+                .lock()
+                .unwrap()
+                .entry(*errno as i32)
+                .or_default() += 1;
+        }
+    }
+
+    // snapshot returns the counters of methods which have been intercepted
+    // at least once, keyed by the method name
+    pub fn snapshot(&self) -> BTreeMap<String, CounterSnapshot> {
+        self.counters
+            .iter()
+            .enumerate()
+            .filter_map(|(index, counter)| {
+                let intercepted = counter.intercepted.load(Ordering::Relaxed);
+                if intercepted == 0 {
+                    return None;
+                }
+                let name = Method::from_bits(1 << index)?.name()?;
+
+                Some((
+                    name.to_owned(),
+                    CounterSnapshot {
+                        intercepted,
+                        delayed: counter.delayed.load(Ordering::Relaxed),
+                        failed: counter.failed.load(Ordering::Relaxed),
+                        latency: Duration::from_nanos(counter.latency.load(Ordering::Relaxed)),
+                        errnos: counter.errnos.lock().unwrap().clone(),
+                    },
+                ))
+            })
+            .collect()
+    }
+}
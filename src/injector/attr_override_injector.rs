@@ -1,18 +1,18 @@
 use std::path::Path;
+use std::time::Instant;
 
 use async_trait::async_trait;
 use fuser::{FileAttr, FileType};
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
 use super::injector_config::{AttrOverrideConfig, FileType as ConfigFileType, FilterConfig};
-use super::{filter, Injector};
+use super::{filter, Injection, Injector};
 use crate::hookfs::Result;
 
 #[derive(Debug)]
 pub struct AttrOverrideInjector {
     filter: filter::Filter,
 
-    ino: Option<u64>,
     size: Option<u64>,
     blocks: Option<u64>,
     atime: Option<std::time::SystemTime>,
@@ -28,8 +28,12 @@ pub struct AttrOverrideInjector {
 
 #[async_trait]
 impl Injector for AttrOverrideInjector {
-    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
-        Ok(())
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<Injection> {
+        Ok(Injection::Passed)
+    }
+
+    fn enable(&self, enabled_at: Instant) {
+        self.filter.enable(enabled_at)
     }
 
     fn inject_attr(&self, attr: &mut FileAttr, path: &Path) {
@@ -38,10 +42,6 @@ impl Injector for AttrOverrideInjector {
             return;
         }
 
-        if let Some(ino) = self.ino {
-            trace!("overriding ino");
-            attr.ino = ino
-        }
         if let Some(size) = self.size {
             trace!("overriding size");
             attr.size = size
@@ -90,14 +90,35 @@ impl Injector for AttrOverrideInjector {
 }
 
 impl AttrOverrideInjector {
-    pub fn build(conf: AttrOverrideConfig) -> anyhow::Result<Self> {
+    pub fn build(conf: AttrOverrideConfig, root: &Path) -> anyhow::Result<Self> {
         debug!("build attr override injector");
 
-        let filter = filter::Filter::build(FilterConfig {
-            path: Some(conf.path),
-            methods: None,
-            percent: conf.percent,
-        })?;
+        let filter = filter::Filter::build(
+            FilterConfig {
+                id: None,
+                path: Some(conf.path),
+                include: Vec::new(),
+                exclude: Vec::new(),
+                methods: None,
+                uid: None,
+                gid: None,
+                min_size: None,
+                max_size: None,
+                percent: conf.percent,
+                seed: conf.seed,
+                start_delay: conf.start_delay,
+                duration: conf.duration,
+                every_nth: None,
+                at_occurrence: None,
+            },
+            root,
+        )?;
+
+        // the inode number is used as the node id by the kernel, so replying
+        // with another one would break the following requests on the file
+        if conf.ino.is_some() {
+            warn!("overriding ino is not supported, it will be ignored");
+        }
 
         let atime = conf.atime;
         let mtime = conf.mtime;
@@ -116,7 +137,6 @@ impl AttrOverrideInjector {
         Ok(Self {
             filter,
 
-            ino: conf.ino,
             size: conf.size,
             blocks: conf.blocks,
             atime,
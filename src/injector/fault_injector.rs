@@ -1,4 +1,5 @@
 use std::path::Path;
+use std::time::Instant;
 
 use async_trait::async_trait;
 use nix::errno::Errno;
@@ -6,7 +7,7 @@ use rand::Rng;
 use tracing::{debug, trace};
 
 use super::injector_config::FaultsConfig;
-use super::{filter, Injector};
+use super::{filter, Injection, Injector};
 use crate::hookfs::{Error, Result};
 
 #[derive(Debug)]
@@ -20,7 +21,7 @@ pub struct FaultInjector {
 
 #[async_trait]
 impl Injector for FaultInjector {
-    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<Injection> {
         debug!("test filter");
         if self.filter.filter(method, path) {
             debug!("inject io fault");
@@ -38,23 +39,30 @@ impl Injector for FaultInjector {
             }
         }
 
-        Ok(())
+        Ok(Injection::Passed)
+    }
+
+    fn enable(&self, enabled_at: Instant) {
+        self.filter.enable(enabled_at)
     }
 }
 
 impl FaultInjector {
-    pub fn build(conf: FaultsConfig) -> anyhow::Result<Self> {
+    pub fn build(conf: FaultsConfig, root: &Path) -> anyhow::Result<Self> {
         trace!("build fault injector");
 
-        let errnos: Vec<_> = conf
+        let mut errnos: Vec<_> = conf
             .faults
             .iter()
             .map(|item| (Errno::from_i32(item.errno), item.weight))
             .collect();
+        if let Some(errno) = conf.errno {
+            errnos.push((Errno::from_i32(errno), 1));
+        }
 
         let sum = errnos.iter().fold(0, |acc, w| acc + w.1);
         Ok(Self {
-            filter: filter::Filter::build(conf.filter)?,
+            filter: filter::Filter::build(conf.filter, root)?,
             errnos,
             sum,
         })
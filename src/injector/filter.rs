@@ -1,16 +1,21 @@
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Error, Result};
+use anyhow::{Context, Error, Result};
 use bitflags::bitflags;
 use glob::{MatchOptions, Pattern};
-use rand::Rng;
 use tracing::{info, trace};
 
-use super::injector_config::FilterConfig;
+use super::injector_config::{ConfigError, FilterConfig};
+use super::probability::Probability;
+use crate::hookfs::{Caller, FileSize};
 
 bitflags! {
-    pub struct Method: u32 {
+    pub struct Method: u64 {
         const LOOKUP = 1;
         const FORGET = 1<<1;
         const GETATTR = 1<<2;
@@ -43,109 +48,360 @@ bitflags! {
         const GETLK = 1<<29;
         const SETLK = 1<<30;
         const BMAP = 1<<31;
+        // fsync with the datasync flag
+        const FDATASYNC = 1<<32;
     }
 }
 
+pub const METHOD_COUNT: usize = 33;
+
+const METHOD_NAMES: [(Method, &str); METHOD_COUNT] = [
+    (Method::LOOKUP, "lookup"),
+    (Method::FORGET, "forget"),
+    (Method::GETATTR, "getattr"),
+    (Method::SETATTR, "setattr"),
+    (Method::READLINK, "readlink"),
+    (Method::MKNOD, "mknod"),
+    (Method::MKDIR, "mkdir"),
+    (Method::UNLINK, "unlink"),
+    (Method::RMDIR, "rmdir"),
+    (Method::SYMLINK, "symlink"),
+    (Method::RENAME, "rename"),
+    (Method::LINK, "link"),
+    (Method::OPEN, "open"),
+    (Method::READ, "read"),
+    (Method::WRITE, "write"),
+    (Method::FLUSH, "flush"),
+    (Method::RELEASE, "release"),
+    (Method::FSYNC, "fsync"),
+    (Method::OPENDIR, "opendir"),
+    (Method::READDIR, "readdir"),
+    (Method::RELEASEDIR, "releasedir"),
+    (Method::FSYNCDIR, "fsyncdir"),
+    (Method::STATFS, "statfs"),
+    (Method::SETXATTR, "setxattr"),
+    (Method::GETXATTR, "getxattr"),
+    (Method::LISTXATTR, "listxattr"),
+    (Method::REMOVEXATTR, "removexattr"),
+    (Method::ACCESS, "access"),
+    (Method::CREATE, "create"),
+    (Method::GETLK, "getlk"),
+    (Method::SETLK, "setlk"),
+    (Method::BMAP, "bmap"),
+    (Method::FDATASYNC, "fdatasync"),
+];
+
+impl Method {
+    // name returns the lowercase name of a single method, or None if `self`
+    // contains zero or multiple methods
+    pub fn name(&self) -> Option<&'static str> {
+        METHOD_NAMES
+            .iter()
+            .find(|(method, _)| method == self)
+            .map(|(_, name)| *name)
+    }
+}
+
+// method_names returns the names of all methods, joined with commas
+pub(super) fn method_names() -> String {
+    let names: Vec<_> = METHOD_NAMES.iter().map(|(_, name)| *name).collect();
+    names.join(", ")
+}
+
 impl TryFrom<&str> for Method {
-    fn try_from(s: &str) -> Result<Method> {
-        match s.to_lowercase().as_str() {
-            "lookup" => Ok(Method::LOOKUP),
-            "forget" => Ok(Method::FORGET),
-            "getattr" => Ok(Method::GETATTR),
-            "setattr" => Ok(Method::SETATTR),
-            "readlink" => Ok(Method::READLINK),
-            "mknod" => Ok(Method::MKNOD),
-            "mkdir" => Ok(Method::MKDIR),
-            "unlink" => Ok(Method::UNLINK),
-            "rmdir" => Ok(Method::RMDIR),
-            "symlink" => Ok(Method::SYMLINK),
-            "rename" => Ok(Method::RENAME),
-            "link" => Ok(Method::LINK),
-            "open" => Ok(Method::OPEN),
-            "read" => Ok(Method::READ),
-            "write" => Ok(Method::WRITE),
-            "flush" => Ok(Method::FLUSH),
-            "release" => Ok(Method::RELEASE),
-            "fsync" => Ok(Method::FSYNC),
-            "opendir" => Ok(Method::OPENDIR),
-            "readdir" => Ok(Method::READDIR),
-            "releasedir" => Ok(Method::RELEASEDIR),
-            "fsyncdir" => Ok(Method::FSYNCDIR),
-            "statfs" => Ok(Method::STATFS),
-            "setxattr" => Ok(Method::SETXATTR),
-            "getxattr" => Ok(Method::GETXATTR),
-            "listxattr" => Ok(Method::LISTXATTR),
-            "removexattr" => Ok(Method::REMOVEXATTR),
-            "access" => Ok(Method::ACCESS),
-            "create" => Ok(Method::CREATE),
-            "getlk" => Ok(Method::GETLK),
-            "setlk" => Ok(Method::SETLK),
-            "bmap" => Ok(Method::BMAP),
-            _ => Err(anyhow!("")),
-        }
-    }
-    type Error = Error;
+    fn try_from(s: &str) -> Result<Method, ConfigError> {
+        let s = s.to_lowercase();
+        METHOD_NAMES
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(method, _)| *method)
+            .ok_or(ConfigError::UnknownMethod { method: s })
+    }
+    type Error = ConfigError;
+}
+
+// TimeWindow limits the injection to a period relative to the time when the
+// injection is enabled
+#[derive(Debug)]
+struct TimeWindow {
+    start_delay: Duration,
+    // None means the window never ends
+    duration: Option<Duration>,
+    enabled_at: Mutex<Instant>,
+}
+
+impl TimeWindow {
+    fn contains(&self, now: Instant) -> bool {
+        let enabled_at = *self.enabled_at.lock().unwrap();
+        let elapsed = now.saturating_duration_since(enabled_at);
+        if elapsed < self.start_delay {
+            return false;
+        }
+        match self.duration {
+            Some(duration) => elapsed - self.start_delay < duration,
+            None => true,
+        }
+    }
+}
+
+// Occurrence only lets through the requests with some indices. The matched
+// requests are counted from 1 for each method, since injection is enabled
+#[derive(Debug)]
+struct Occurrence {
+    every_nth: Option<u64>,
+    at: Option<u64>,
+    counters: Vec<AtomicU64>,
+}
+
+impl Occurrence {
+    fn build(every_nth: Option<u64>, at: Option<u64>) -> Option<Self> {
+        if every_nth.is_none() && at.is_none() {
+            return None;
+        }
+        Some(Occurrence {
+            every_nth,
+            at,
+            counters: (0..METHOD_COUNT).map(|_| AtomicU64::new(0)).collect(),
+        })
+    }
+
+    fn reset(&self) {
+        for counter in self.counters.iter() {
+            counter.store(0, Ordering::SeqCst);
+        }
+    }
+
+    // next counts a matched request of the method, and returns whether it's
+    // one of the occurrences to inject
+    fn next(&self, method: &Method) -> bool {
+        let counter = match self.counters.get(method.bits().trailing_zeros() as usize) {
+            Some(counter) => counter,
+            None => return false,
+        };
+        let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        self.at == Some(count) || self.every_nth.map_or(false, |n| count % n == 0)
+    }
 }
 
 #[derive(Debug)]
 pub struct Filter {
-    path_filter: Option<Pattern>,
+    // a path is matched if it matches any of `include` (or `include` is empty),
+    // and none of `exclude`
+    include: PathMatcher,
+    exclude: PathMatcher,
     methods: Method,
-    probability: f64,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    probability: Probability,
+    window: Option<TimeWindow>,
+    occurrence: Option<Occurrence>,
 }
 
 impl Filter {
-    pub fn build(conf: FilterConfig) -> Result<Self> {
+    pub fn build(conf: FilterConfig, root: &Path) -> Result<Self> {
         info!("build filter");
-        let methods = conf
-            .methods
-            .filter(|methods| !methods.is_empty())
-            .map(|methods| {
-                methods
-                    .iter()
-                    .filter_map(|method| Method::try_from(method.as_str()).ok())
-                    .fold(Method::empty(), |methods, method| methods | method)
-            })
-            .unwrap_or(Method::all());
-
-        let path_filter = conf
-            .path
-            .map(|path| -> Option<Pattern> {
-                if !path.is_empty() {
-                    Pattern::new(&path).ok()
-                } else {
-                    None
-                }
+        let methods = match conf.methods.filter(|methods| !methods.is_empty()) {
+            Some(methods) => methods
+                .iter()
+                .try_fold(Method::empty(), |methods, method| {
+                    Ok::<_, Error>(methods | Method::try_from(method.as_str())?)
+                })?,
+            None => Method::all(),
+        };
+
+        // `path` is a shorthand for a single included pattern
+        let include = PathMatcher::build(conf.path.iter().chain(conf.include.iter()), root)?;
+        let exclude = PathMatcher::build(conf.exclude.iter(), root)?;
+
+        let duration = conf
+            .duration
+            .filter(|duration| *duration != Duration::from_secs(0));
+        let window = if conf.start_delay.is_some() || duration.is_some() {
+            Some(TimeWindow {
+                start_delay: conf.start_delay.unwrap_or_default(),
+                duration,
+                enabled_at: Mutex::new(Instant::now()),
             })
-            .flatten();
+        } else {
+            None
+        };
+
         Ok(Self {
-            path_filter,
+            include,
+            exclude,
             methods,
-            probability: conf.percent as f64 / 100f64,
+            uid: conf.uid,
+            gid: conf.gid,
+            min_size: conf.min_size,
+            max_size: conf.max_size,
+            probability: Probability::from_percent(conf.percent, conf.seed)?,
+            window,
+            occurrence: Occurrence::build(conf.every_nth, conf.at_occurrence),
         })
     }
 
+    // enable sets the time from which the time window is counted, and counts
+    // the occurrences from zero again. Until it is called, the window is counted
+    // from the time the filter is built.
+    pub fn enable(&self, enabled_at: Instant) {
+        if let Some(window) = &self.window {
+            *window.enabled_at.lock().unwrap() = enabled_at;
+        }
+        if let Some(occurrence) = &self.occurrence {
+            occurrence.reset();
+        }
+    }
+
     pub fn filter(&self, method: &Method, path: &Path) -> bool {
-        let mut rng = rand::thread_rng();
-        let p: f64 = rng.gen();
-
-        let match_path = match &self.path_filter {
-            Some(filter) => filter.matches_path_with(
-                path,
-                MatchOptions {
-                    case_sensitive: true,
-                    require_literal_separator: true,
-                    require_literal_leading_dot: false,
-                },
-            ),
-            None => true,
-        };
+        let match_path =
+            (self.include.is_empty() || self.include.matches(path)) && !self.exclude.matches(path);
         let match_method = !(self.methods & *method).is_empty();
-        let match_probability = p < self.probability;
         trace!("path filter: {}", match_path);
         trace!("method filter: {}", match_method);
+        if !(match_path && match_method) {
+            return false;
+        }
+
+        if !self.match_caller() {
+            trace!("caller filter: false");
+            return false;
+        }
+
+        if !self.match_size() {
+            trace!("size filter: false");
+            return false;
+        }
+
+        if let Some(window) = &self.window {
+            let in_window = window.contains(Instant::now());
+            trace!("time window: {}", in_window);
+            if !in_window {
+                return false;
+            }
+        }
+
+        if let Some(occurrence) = &self.occurrence {
+            let match_occurrence = occurrence.next(method);
+            trace!("occurrence: {}", match_occurrence);
+            if !match_occurrence {
+                return false;
+            }
+        }
+
+        // only roll the dice for matched requests, so a seeded experiment is
+        // not affected by unrelated operations
+        let match_probability = self.probability.should_apply();
         trace!("probability: {}", match_probability);
 
-        match_path && match_method && match_probability
+        match_probability
+    }
+
+    // match_caller returns whether the request is sent by a process with the uid
+    // and gid of the filter. A request without a caller is never matched, unless
+    // neither is set.
+    fn match_caller(&self) -> bool {
+        if self.uid.is_none() && self.gid.is_none() {
+            return true;
+        }
+
+        match Caller::current() {
+            Some(caller) => {
+                self.uid.map_or(true, |uid| uid == caller.uid)
+                    && self.gid.map_or(true, |gid| gid == caller.gid)
+            }
+            None => false,
+        }
     }
+
+    // match_size returns whether the request is on an opened file with the size
+    // in the range of the filter. A request not on an opened file is never
+    // matched, unless neither bound is set.
+    fn match_size(&self) -> bool {
+        if self.min_size.is_none() && self.max_size.is_none() {
+            return true;
+        }
+
+        match FileSize::current() {
+            Some(FileSize(size)) => {
+                self.min_size.map_or(true, |min_size| size >= min_size)
+                    && self.max_size.map_or(true, |max_size| size <= max_size)
+            }
+            None => false,
+        }
+    }
+}
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+// PathMatcher matches paths against a list of patterns. The patterns without
+// any wildcard are looked up in a set, so injecting into a few known files
+// doesn't cost a glob evaluation on every request.
+#[derive(Debug, Default)]
+struct PathMatcher {
+    exact: HashSet<String>,
+    patterns: Vec<Pattern>,
+}
+
+impl PathMatcher {
+    fn build<'a, I: Iterator<Item = &'a String>>(paths: I, root: &Path) -> Result<Self> {
+        let mut matcher = PathMatcher::default();
+        for path in paths.filter(|path| !path.is_empty()) {
+            if is_literal(path) {
+                matcher.exact.insert(resolve_literal(path, root));
+            } else {
+                matcher.patterns.push(build_pattern(path, root)?);
+            }
+        }
+        Ok(matcher)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.patterns.is_empty()
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        // a pattern never matches a path which is not valid UTF-8
+        let path_str = match path.to_str() {
+            Some(path_str) => path_str,
+            None => return false,
+        };
+        self.exact.contains(path_str)
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| pattern.matches_with(path_str, MATCH_OPTIONS))
+    }
+}
+
+// is_literal returns whether the pattern matches only the path it spells
+fn is_literal(path: &str) -> bool {
+    !path.contains(|c: char| matches!(c, '*' | '?' | '['))
+}
+
+// resolve_literal is `build_pattern` for a pattern without wildcards
+fn resolve_literal(path: &str, root: &Path) -> String {
+    if Path::new(path).is_absolute() {
+        path.to_owned()
+    } else {
+        let root = root.to_string_lossy();
+        format!("{}/{}", root.trim_end_matches('/'), path)
+    }
+}
+
+// build_pattern builds a path pattern. A relative pattern is matched against the
+// path relative to the root.
+fn build_pattern(path: &str, root: &Path) -> Result<Pattern> {
+    let pattern = if Path::new(path).is_absolute() {
+        path.to_owned()
+    } else {
+        let root = root.to_string_lossy();
+        format!("{}/{}", Pattern::escape(root.trim_end_matches('/')), path)
+    };
+    Pattern::new(&pattern).with_context(|| format!("invalid path pattern `{}`", path))
 }
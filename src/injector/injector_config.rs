@@ -1,6 +1,58 @@
+use std::convert::TryFrom;
 use std::time::Duration;
 
-use serde::{Deserialize, Serialize};
+use glob::{Pattern, PatternError};
+use nix::errno::Errno;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use thiserror::Error;
+
+use super::filter::{self, Method};
+
+// ConfigError pinpoints the field which makes an injector config invalid. The
+// fields are named as in the JSON config
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error(
+        "unknown method `{method}`, valid methods are: {}",
+        filter::method_names()
+    )]
+    UnknownMethod { method: String },
+
+    #[error("`methods` is empty, while no path is set")]
+    EmptyMethods,
+
+    #[error("invalid pattern `{pattern}` in `{field}`: {source}")]
+    InvalidPattern {
+        field: &'static str,
+        pattern: String,
+        source: PatternError,
+    },
+
+    #[error("`percent` must be in [0, 100], got {percent}")]
+    Percent { percent: i32 },
+
+    #[error("`{field}` must be positive")]
+    NotPositive { field: String },
+
+    #[error("only one of `{0}` and `{1}` can be set")]
+    Conflict(&'static str, &'static str),
+
+    #[error("either `{0}` or `{1}` is required")]
+    Missing(&'static str, &'static str),
+
+    #[error("`{field}` is invalid: {reason}")]
+    Invalid { field: String, reason: String },
+}
+
+// errnos on Linux are less than 4096, as the syscalls return them negated in
+// [-4095, -1]
+const MAX_ERRNO: i32 = 4096;
+
+fn not_positive<S: Into<String>>(field: S) -> ConfigError {
+    ConfigError::NotPositive {
+        field: field.into(),
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "type")]
@@ -10,6 +62,59 @@ pub enum InjectorConfig {
     Fault(FaultsConfig),
     AttrOverride(AttrOverrideConfig),
     Mistake(MistakesConfig),
+    SpaceLimit(SpaceLimitConfig),
+    Throttle(ThrottleConfig),
+    ShortIO(ShortIOConfig),
+    Warmup(WarmupConfig),
+    TornAppend(TornAppendConfig),
+}
+
+impl InjectorConfig {
+    // validate checks the config without building the injector, so that an
+    // invalid config can be reported before anything is changed. Durations
+    // are not checked, as negative ones are rejected by the deserializer
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match self {
+            InjectorConfig::Latency(conf) => conf.validate(),
+            InjectorConfig::Fault(conf) => conf.validate(),
+            InjectorConfig::AttrOverride(conf) => conf.validate(),
+            InjectorConfig::Mistake(conf) => conf.validate(),
+            InjectorConfig::SpaceLimit(conf) => conf.filter.validate(),
+            InjectorConfig::Throttle(conf) => conf.validate(),
+            InjectorConfig::ShortIO(conf) => conf.validate(),
+            InjectorConfig::Warmup(conf) => conf.validate(),
+            InjectorConfig::TornAppend(conf) => conf.filter.validate(),
+        }
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        let id = match self {
+            InjectorConfig::Latency(conf) => &conf.filter.id,
+            InjectorConfig::Fault(conf) => &conf.filter.id,
+            InjectorConfig::AttrOverride(conf) => &conf.id,
+            InjectorConfig::Mistake(conf) => &conf.filter.id,
+            InjectorConfig::SpaceLimit(conf) => &conf.filter.id,
+            InjectorConfig::Throttle(conf) => &conf.filter.id,
+            InjectorConfig::ShortIO(conf) => &conf.filter.id,
+            InjectorConfig::Warmup(conf) => &conf.filter.id,
+            InjectorConfig::TornAppend(conf) => &conf.filter.id,
+        };
+        id.as_deref()
+    }
+
+    pub(super) fn id_mut(&mut self) -> &mut Option<String> {
+        match self {
+            InjectorConfig::Latency(conf) => &mut conf.filter.id,
+            InjectorConfig::Fault(conf) => &mut conf.filter.id,
+            InjectorConfig::AttrOverride(conf) => &mut conf.id,
+            InjectorConfig::Mistake(conf) => &mut conf.filter.id,
+            InjectorConfig::SpaceLimit(conf) => &mut conf.filter.id,
+            InjectorConfig::Throttle(conf) => &mut conf.filter.id,
+            InjectorConfig::ShortIO(conf) => &mut conf.filter.id,
+            InjectorConfig::Warmup(conf) => &mut conf.filter.id,
+            InjectorConfig::TornAppend(conf) => &mut conf.filter.id,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -17,39 +122,299 @@ pub enum InjectorConfig {
 pub struct LatencyConfig {
     #[serde(flatten)]
     pub filter: FilterConfig,
-    #[serde(with = "humantime_serde")]
-    pub latency: Duration,
+    // shorthand for a fixed distribution
+    #[serde(default, with = "humantime_serde")]
+    pub latency: Option<Duration>,
+    pub distribution: Option<LatencyDistribution>,
+    // the time to transfer a byte, modeling a slow device. With it, only reads
+    // and writes are delayed, by `latency + size * nanos_per_byte`, where the
+    // base `latency` (or the one sampled from `distribution`) is optional
+    pub nanos_per_byte: Option<f64>,
+    // the max count of requests delayed at the same time. Requests over the
+    // limit are passed without delay
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: usize,
 }
 
+fn default_max_concurrent() -> usize {
+    1024
+}
+
+impl LatencyConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.filter.validate()?;
+        match (&self.latency, &self.distribution) {
+            (Some(_), Some(_)) => return Err(ConfigError::Conflict("latency", "distribution")),
+            (None, None) if self.nanos_per_byte.is_none() => {
+                return Err(ConfigError::Missing("latency", "distribution"))
+            }
+            (None, Some(distribution)) => distribution.validate()?,
+            _ => {}
+        }
+        if let Some(nanos_per_byte) = self.nanos_per_byte {
+            if !(nanos_per_byte.is_finite() && nanos_per_byte >= 0.0) {
+                return Err(ConfigError::Invalid {
+                    field: "nanosPerByte".to_owned(),
+                    reason: format!("must be non-negative, got {}", nanos_per_byte),
+                });
+            }
+        }
+        if self.max_concurrent == 0 {
+            return Err(not_positive("maxConcurrent"));
+        }
+        Ok(())
+    }
+}
+
+// LatencyDistribution is the distribution which the latency of every request is
+// sampled from
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "camelCase")]
+pub enum LatencyDistribution {
+    Fixed {
+        #[serde(with = "humantime_serde")]
+        latency: Duration,
+    },
+    Uniform {
+        #[serde(with = "humantime_serde")]
+        min: Duration,
+        #[serde(with = "humantime_serde")]
+        max: Duration,
+    },
+    // samples below zero are taken as zero
+    Normal {
+        #[serde(with = "humantime_serde")]
+        mean: Duration,
+        #[serde(with = "humantime_serde")]
+        stddev: Duration,
+    },
+    // heavy tailed, the latency is never less than `scale`
+    Pareto {
+        #[serde(with = "humantime_serde")]
+        scale: Duration,
+        shape: f64,
+    },
+}
+
+impl LatencyDistribution {
+    fn validate(&self) -> Result<(), ConfigError> {
+        match self {
+            LatencyDistribution::Uniform { min, max } if min > max => Err(ConfigError::Invalid {
+                field: "distribution.min".to_owned(),
+                reason: format!("{:?} is greater than max ({:?})", min, max),
+            }),
+            LatencyDistribution::Pareto { scale, .. } if *scale == Duration::from_secs(0) => {
+                Err(not_positive("distribution.scale"))
+            }
+            LatencyDistribution::Pareto { shape, .. } if !(shape.is_finite() && *shape > 0.0) => {
+                Err(not_positive("distribution.shape"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// FaultsConfig fails the requests with an errno. A failed write never writes
+// any data, see `TornAppendConfig` for a write which is only partly written
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct FaultsConfig {
     #[serde(flatten)]
     pub filter: FilterConfig,
 
+    #[serde(default)]
     pub faults: Vec<FaultConfig>,
+    // shorthand for a single fault with weight 1
+    #[serde(default, deserialize_with = "deserialize_optional_errno")]
+    pub errno: Option<i32>,
+}
+
+impl FaultsConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.filter.validate()?;
+        if self.faults.is_empty() && self.errno.is_none() {
+            return Err(ConfigError::Missing("faults", "errno"));
+        }
+        if let Some(errno) = self.errno {
+            validate_errno("errno".to_owned(), errno)?;
+        }
+        for (index, fault) in self.faults.iter().enumerate() {
+            validate_errno(format!("faults[{}].errno", index), fault.errno)?;
+            if fault.weight <= 0 {
+                return Err(not_positive(format!("faults[{}].weight", index)));
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct FilterConfig {
+    // the id to enable or disable the injector with, the index of the injector
+    // in the config by default
+    pub id: Option<String>,
     pub path: Option<String>,
+    // the patterns of paths to inject, and the ones never injected. An exclude
+    // pattern wins over an include one
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
     pub methods: Option<Vec<String>>,
+    // only inject the requests sent by processes with the uid and gid
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    // only inject the requests on opened files with the size in the range
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    #[serde(default = "default_percent")]
     pub percent: i32,
+    pub seed: Option<u64>,
+    // the injection is active from `start_delay` after injection is enabled,
+    // and lasts for `duration`. Unset or zero `duration` means until resume
+    #[serde(default, with = "humantime_serde")]
+    pub start_delay: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub duration: Option<Duration>,
+    // only inject every nth matched request, or the one matched request at the
+    // index, counted from 1 for each method. If both are set, the requests
+    // picked by either are injected
+    pub every_nth: Option<u64>,
+    pub at_occurrence: Option<u64>,
+}
+
+fn default_percent() -> i32 {
+    100
+}
+
+impl FilterConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(methods) = &self.methods {
+            if methods.is_empty() && self.path.is_none() && self.include.is_empty() {
+                return Err(ConfigError::EmptyMethods);
+            }
+            for method in methods {
+                Method::try_from(method.as_str())?;
+            }
+        }
+        let patterns = self
+            .path
+            .iter()
+            .map(|path| ("path", path))
+            .chain(self.include.iter().map(|path| ("include", path)))
+            .chain(self.exclude.iter().map(|path| ("exclude", path)));
+        for (field, pattern) in patterns {
+            validate_pattern(field, pattern)?;
+        }
+        if let (Some(min_size), Some(max_size)) = (self.min_size, self.max_size) {
+            if min_size > max_size {
+                return Err(ConfigError::Invalid {
+                    field: "minSize".to_owned(),
+                    reason: format!("{} is greater than `maxSize` {}", min_size, max_size),
+                });
+            }
+        }
+        if self.every_nth == Some(0) {
+            return Err(not_positive("everyNth"));
+        }
+        if self.at_occurrence == Some(0) {
+            return Err(not_positive("atOccurrence"));
+        }
+        validate_percent(self.percent)
+    }
+}
+
+fn validate_pattern(field: &'static str, pattern: &str) -> Result<(), ConfigError> {
+    Pattern::new(pattern)
+        .map(|_| ())
+        .map_err(|source| ConfigError::InvalidPattern {
+            field,
+            pattern: pattern.to_owned(),
+            source,
+        })
+}
+
+fn validate_percent(percent: i32) -> Result<(), ConfigError> {
+    if !(0..=100).contains(&percent) {
+        return Err(ConfigError::Percent { percent });
+    }
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct FaultConfig {
+    #[serde(deserialize_with = "deserialize_errno")]
     pub errno: i32,
     pub weight: i32,
 }
 
+// an errno is set either as a number, or as a name such as `EDQUOT`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ErrnoValue {
+    Code(i32),
+    Name(String),
+}
+
+impl ErrnoValue {
+    fn code<E: de::Error>(self) -> Result<i32, E> {
+        match self {
+            ErrnoValue::Code(code) => Ok(code),
+            ErrnoValue::Name(name) => {
+                errno_from_name(&name).ok_or_else(|| E::custom(format!("unknown errno `{}`", name)))
+            }
+        }
+    }
+}
+
+// errno_from_name finds the errno whose name is `name`, by formatting every
+// errno known to nix
+fn errno_from_name(name: &str) -> Option<i32> {
+    let name = name.to_uppercase();
+    (1..MAX_ERRNO).find(|code| format!("{:?}", Errno::from_i32(*code)) == name)
+}
+
+fn deserialize_errno<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+    ErrnoValue::deserialize(deserializer)?.code()
+}
+
+fn deserialize_optional_errno<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<i32>, D::Error> {
+    Option::<ErrnoValue>::deserialize(deserializer)?
+        .map(ErrnoValue::code)
+        .transpose()
+}
+
+// validate_errno checks that the errno is known, so exactly the same code is
+// returned to the application
+fn validate_errno(field: String, errno: i32) -> Result<(), ConfigError> {
+    if errno <= 0 {
+        return Err(not_positive(field));
+    }
+    if Errno::from_i32(errno) == Errno::UnknownErrno {
+        return Err(ConfigError::Invalid {
+            field,
+            reason: format!("unknown errno {}", errno),
+        });
+    }
+    Ok(())
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct AttrOverrideConfig {
+    pub id: Option<String>,
     pub path: String,
     pub percent: i32,
+    pub seed: Option<u64>,
+    #[serde(default, with = "humantime_serde")]
+    pub start_delay: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub duration: Option<Duration>,
 
     pub ino: Option<u64>,
     pub size: Option<u64>,
@@ -65,6 +430,13 @@ pub struct AttrOverrideConfig {
     pub rdev: Option<u32>,
 }
 
+impl AttrOverrideConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        validate_pattern("path", &self.path)?;
+        validate_percent(self.percent)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum FileType {
@@ -89,6 +461,8 @@ pub struct Timespec {
 pub enum MistakeType {
     Zero,
     Random,
+    // fill with a specific byte, e.g. `{"byte": 255}`
+    Byte(u8),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -106,3 +480,109 @@ pub struct MistakesConfig {
     #[serde(flatten)]
     pub filter: FilterConfig,
 }
+
+impl MistakesConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.filter.validate()?;
+        if self.mistake.max_length == 0 {
+            return Err(not_positive("mistake.maxLength"));
+        }
+        if self.mistake.max_occurrences == 0 {
+            return Err(not_positive("mistake.maxOccurrences"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SpaceLimitConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+    // the count of bytes which can be written before writes fail with ENOSPC.
+    // It's counted again from zero when the injectors are updated
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ThrottleConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+    // bytes per second
+    pub rate: u64,
+    // the bytes which can be transferred at once without delay. It's the
+    // bytes of one second by default
+    pub burst: Option<u64>,
+}
+
+impl ThrottleConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.filter.validate()?;
+        if self.rate == 0 {
+            return Err(not_positive("rate"));
+        }
+        if self.burst == Some(0) {
+            return Err(not_positive("burst"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ShortIOConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+    // the max count of bytes transferred by a single read or write
+    pub max_bytes: usize,
+    // transfer a random count of bytes in [1, max_bytes] instead
+    #[serde(default)]
+    pub random: bool,
+}
+
+impl ShortIOConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.filter.validate()?;
+        // a transfer of zero bytes means the end of the file for reads, and
+        // makes the writes retried forever
+        if self.max_bytes == 0 {
+            return Err(not_positive("maxBytes"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WarmupConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+    // the count of requests delayed for every method after injection is enabled
+    pub count: u64,
+    #[serde(with = "humantime_serde")]
+    pub delay: Duration,
+}
+
+impl WarmupConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.filter.validate()?;
+        if self.count == 0 {
+            return Err(not_positive("count"));
+        }
+        if self.delay == Duration::from_secs(0) {
+            return Err(not_positive("delay"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TornAppendConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+    // sync the part of the append which is kept, so that only the rest is lost
+    #[serde(default)]
+    pub sync: bool,
+}
@@ -1,41 +1,149 @@
 use std::path::Path;
-use std::time::Duration;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::sync::Semaphore;
 use tokio::time::delay_for;
 use tracing::{debug, trace};
 
-use super::injector_config::LatencyConfig;
-use super::{filter, Injector};
+use super::injector_config::{LatencyConfig, LatencyDistribution};
+use super::{filter, Injection, Injector};
 use crate::hookfs::Result;
 
 #[derive(Debug)]
 pub struct LatencyInjector {
-    latency: Duration,
+    // the base latency, which is zero if it's None
+    distribution: Option<LatencyDistribution>,
+    nanos_per_byte: Option<f64>,
+    rng: Mutex<StdRng>,
     filter: filter::Filter,
+    // the delays are async and don't block the worker threads, but every
+    // delayed request still holds a FUSE request of the kernel, so the count
+    // of concurrent delays is bounded
+    permits: Semaphore,
 }
 
 #[async_trait]
 impl Injector for LatencyInjector {
-    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
-        trace!("test for filter");
-        if self.filter.filter(method, path) {
-            debug!("inject io delay {:?}", self.latency);
-            delay_for(self.latency).await;
-            debug!("latency finished");
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<Injection> {
+        // the latency per byte is delayed once the size of the transfer is known
+        if self.nanos_per_byte.is_some() {
+            return Ok(Injection::Passed);
+        }
+        self.delay(method, path, 0).await
+    }
+
+    async fn inject_transfer(
+        &self,
+        method: &filter::Method,
+        path: &Path,
+        size: usize,
+    ) -> Result<Injection> {
+        if self.nanos_per_byte.is_none() {
+            return Ok(Injection::Passed);
         }
+        self.delay(method, path, size).await
+    }
 
-        Ok(())
+    fn enable(&self, enabled_at: Instant) {
+        self.filter.enable(enabled_at)
     }
 }
 
 impl LatencyInjector {
-    pub fn build(conf: LatencyConfig) -> anyhow::Result<Self> {
+    pub fn build(conf: LatencyConfig, root: &Path) -> anyhow::Result<Self> {
         trace!("build latency injector");
 
+        // the config has been validated, so at most one of them is set
+        let distribution = match conf.distribution {
+            Some(distribution) => Some(distribution),
+            None => conf
+                .latency
+                .map(|latency| LatencyDistribution::Fixed { latency }),
+        };
+
+        // the seed of the filter also makes the sampled latencies reproducible
+        let rng = match conf.filter.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
         Ok(Self {
-            latency: conf.latency,
-            filter: filter::Filter::build(conf.filter)?,
+            distribution,
+            nanos_per_byte: conf.nanos_per_byte,
+            rng: Mutex::new(rng),
+            filter: filter::Filter::build(conf.filter, root)?,
+            permits: Semaphore::new(conf.max_concurrent),
         })
     }
 }
+
+impl LatencyInjector {
+    // delay waits for the latency of a request transferring `size` bytes, if
+    // it's matched by the filter
+    async fn delay(&self, method: &filter::Method, path: &Path, size: usize) -> Result<Injection> {
+        trace!("test for filter");
+        if !self.filter.filter(method, path) {
+            return Ok(Injection::Passed);
+        }
+
+        let _permit = match self.permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                debug!("too many delayed requests, pass without delay");
+                return Ok(Injection::Passed);
+            }
+        };
+        let latency = self.sample() + self.transfer_time(size);
+        debug!("inject io delay {:?}", latency);
+        delay_for(latency).await;
+        debug!("latency finished");
+
+        Ok(Injection::Delayed)
+    }
+
+    fn transfer_time(&self, size: usize) -> Duration {
+        match self.nanos_per_byte {
+            Some(nanos_per_byte) => from_secs(size as f64 * nanos_per_byte / 1e9),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    fn sample(&self) -> Duration {
+        let distribution = match &self.distribution {
+            Some(distribution) => distribution,
+            None => return Duration::from_secs(0),
+        };
+        let mut rng = self.rng.lock().unwrap();
+        match distribution {
+            LatencyDistribution::Fixed { latency } => *latency,
+            LatencyDistribution::Uniform { min, max } if min == max => *min,
+            LatencyDistribution::Uniform { min, max } => {
+                from_secs(rng.gen_range(min.as_secs_f64(), max.as_secs_f64()))
+            }
+            LatencyDistribution::Normal { mean, stddev } => {
+                // Box-Muller transform. `1 - gen()` is in (0, 1], so ln never
+                // gets zero
+                let u1: f64 = 1.0 - rng.gen::<f64>();
+                let u2: f64 = rng.gen();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                let latency = mean.as_secs_f64() + z * stddev.as_secs_f64();
+                from_secs(latency)
+            }
+            LatencyDistribution::Pareto { scale, shape } => {
+                // inverse transform sampling
+                let u: f64 = 1.0 - rng.gen::<f64>();
+                from_secs(scale.as_secs_f64() / u.powf(1.0 / shape))
+            }
+        }
+    }
+}
+
+// from_secs converts a sampled latency to a duration. It's clamped, as
+// `Duration::from_secs_f64` panics on negative or overflowed values.
+fn from_secs(secs: f64) -> Duration {
+    Duration::from_secs_f64(secs.max(0.0).min(u32::MAX as f64))
+}
@@ -0,0 +1,30 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use super::MultiInjector;
+
+// LiveInjector holds the injectors of a mount, which are replaced as a whole on
+// update. A request loads the current ones without a lock and keeps them until
+// it's done. An update swaps in the new injectors at once, while the requests
+// in flight finish with the old ones.
+#[derive(Debug)]
+pub struct LiveInjector {
+    current: ArcSwap<MultiInjector>,
+}
+
+impl LiveInjector {
+    pub fn new(injector: MultiInjector) -> LiveInjector {
+        LiveInjector {
+            current: ArcSwap::from_pointee(injector),
+        }
+    }
+
+    pub fn load(&self) -> Arc<MultiInjector> {
+        self.current.load_full()
+    }
+
+    pub fn store(&self, injector: MultiInjector) {
+        self.current.store(Arc::new(injector));
+    }
+}
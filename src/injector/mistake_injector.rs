@@ -1,25 +1,36 @@
 use std::cmp::{max, min};
 use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
 
 use async_trait::async_trait;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use tracing::{debug, trace};
 
 use super::injector_config::{MistakeConfig, MistakeType, MistakesConfig};
-use super::{filter, Injector};
+use super::{filter, Injection, Injector};
 use crate::hookfs::{Reply, Result};
 
+// mixed into the seed of the filter to seed the mistakes, "mistake" in ascii
+const MISTAKE_SEED: u64 = 0x6d69_7374_616b_6500;
+
 #[derive(Debug)]
 pub struct MistakeInjector {
     mistake: MistakeConfig,
     filter: filter::Filter,
+    rng: Mutex<StdRng>,
 }
 
 #[async_trait]
 impl Injector for MistakeInjector {
-    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<Injection> {
         debug!("MI:Injecting");
-        Ok(())
+        Ok(Injection::Passed)
+    }
+
+    fn enable(&self, enabled_at: Instant) {
+        self.filter.enable(enabled_at)
     }
 
     fn inject_reply(&self, method: &super::Method, path: &Path, reply: &mut Reply) -> Result<()> {
@@ -43,16 +54,24 @@ impl Injector for MistakeInjector {
 }
 
 impl MistakeInjector {
-    pub fn build(conf: MistakesConfig) -> anyhow::Result<Self> {
+    pub fn build(conf: MistakesConfig, root: &Path) -> anyhow::Result<Self> {
         trace!("build mistake injector");
+        // the seed of the filter also makes the corrupted bytes reproducible. It's
+        // mixed with a constant, or the bytes would be drawn from the same stream
+        // as the probability of the filter, and correlate with it
+        let rng = match conf.filter.seed {
+            Some(seed) => StdRng::seed_from_u64(seed ^ MISTAKE_SEED),
+            None => StdRng::from_entropy(),
+        };
         Ok(Self {
             mistake: conf.mistake,
-            filter: filter::Filter::build(conf.filter)?,
+            filter: filter::Filter::build(conf.filter, root)?,
+            rng: Mutex::new(rng),
         })
     }
     pub fn handle(&self, data: &mut Vec<u8>) -> Result<()> {
         trace!("sabotage data");
-        let mut rng = rand::thread_rng();
+        let mut rng = self.rng.lock().unwrap();
         let data_length = data.len();
         let mistake = &self.mistake;
         let occurrence = match mistake.max_occurrences {
@@ -73,11 +92,16 @@ impl MistakeInjector {
             );
             match mistake.filling {
                 MistakeType::Zero => {
-                    for i in pos..pos + length {
-                        data[i] = 0;
+                    for byte in data[pos..pos + length].iter_mut() {
+                        *byte = 0;
                     }
                 }
                 MistakeType::Random => rng.fill(&mut data[pos..pos + length]),
+                MistakeType::Byte(value) => {
+                    for byte in data[pos..pos + length].iter_mut() {
+                        *byte = value;
+                    }
+                }
             }
         }
         Ok(())
@@ -3,22 +3,64 @@ mod fault_injector;
 mod filter;
 mod injector_config;
 mod latency_injector;
+mod live_injector;
 mod mistake_injector;
 mod multi_injector;
+mod probability;
+mod short_io_injector;
+mod space_limit_injector;
+mod throttle_injector;
+mod torn_append_injector;
+mod warmup_injector;
 
 use std::path::Path;
+use std::time::Instant;
 
 use async_trait::async_trait;
-pub use filter::Method;
+pub use filter::{Method, METHOD_COUNT};
 use fuser::FileAttr;
-pub use injector_config::InjectorConfig;
+pub use injector_config::{ConfigError, InjectorConfig};
+pub use live_injector::LiveInjector;
 pub use multi_injector::MultiInjector;
+pub use probability::Probability;
 
 use crate::hookfs::{Reply, Result};
 
+// Injection describes what an injector has done to a request which is
+// allowed to continue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Injection {
+    Passed,
+    Delayed,
+}
+
+impl Injection {
+    pub fn merge(self, other: Injection) -> Injection {
+        match (self, other) {
+            (Injection::Passed, Injection::Passed) => Injection::Passed,
+            _ => Injection::Delayed,
+        }
+    }
+}
+
 #[async_trait]
 pub trait Injector: Send + Sync + std::fmt::Debug {
-    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()>;
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<Injection>;
+
+    // inject_transfer is called with the count of bytes read or written, after
+    // the data is read and before it's written
+    async fn inject_transfer(
+        &self,
+        _method: &filter::Method,
+        _path: &Path,
+        _size: usize,
+    ) -> Result<Injection> {
+        Ok(Injection::Passed)
+    }
+
+    // enable is called when the injection is enabled, with the time from which
+    // the time windows of filters are counted
+    fn enable(&self, _enabled_at: Instant) {}
 
     fn inject_reply(
         &self,
@@ -32,5 +74,12 @@ pub trait Injector: Send + Sync + std::fmt::Debug {
         Ok(())
     }
 
+    // inject_append is called with the data written at or past the end of the
+    // file, after `inject_write_data`. It returns whether the data should be
+    // synced once it's written
+    fn inject_append(&self, _path: &Path, _data: &mut Vec<u8>) -> Result<bool> {
+        Ok(false)
+    }
+
     fn inject_attr(&self, _attr: &mut FileAttr, _path: &Path) {}
 }
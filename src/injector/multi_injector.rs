@@ -1,4 +1,7 @@
+use std::collections::HashSet;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
 use async_trait::async_trait;
 use fuser::FileAttr;
@@ -6,56 +9,178 @@ use tracing::trace;
 
 use super::attr_override_injector::AttrOverrideInjector;
 use super::fault_injector::FaultInjector;
-use super::injector_config::InjectorConfig;
+use super::injector_config::{ConfigError, InjectorConfig};
 use super::latency_injector::LatencyInjector;
 use super::mistake_injector::MistakeInjector;
-use super::{filter, Injector};
+use super::short_io_injector::ShortIOInjector;
+use super::space_limit_injector::SpaceLimitInjector;
+use super::throttle_injector::ThrottleInjector;
+use super::torn_append_injector::TornAppendInjector;
+use super::warmup_injector::WarmupInjector;
+use super::{filter, Injection, Injector};
 use crate::hookfs::{Reply, Result};
 
 #[derive(Debug)]
 pub struct MultiInjector {
     injectors: Vec<Box<dyn Injector>>,
+    config: Vec<InjectorConfig>,
+    // whether each injector is enabled, in the same order as `injectors`. A
+    // disabled injector is skipped until it's enabled again
+    enabled: Vec<AtomicBool>,
 }
 
 impl MultiInjector {
     pub fn build(conf: Vec<InjectorConfig>) -> anyhow::Result<Self> {
+        Self::build_with_root(conf, Path::new("/"))
+    }
+
+    // build_with_root builds the injectors for a mount on `root`. Relative path
+    // patterns in the config are matched against the path relative to `root`.
+    pub fn build_with_root(conf: Vec<InjectorConfig>, root: &Path) -> anyhow::Result<Self> {
+        Self::build_for_mount(conf, root, false)
+    }
+
+    // build_for_mount builds the injectors for a mount on `root`, which serves
+    // the reads with direct io if `direct_io` is set
+    pub fn build_for_mount(
+        conf: Vec<InjectorConfig>,
+        root: &Path,
+        direct_io: bool,
+    ) -> anyhow::Result<Self> {
         trace!("build multiinjectors");
+        // the configs are kept with the ids, so they are shown in the status
+        let mut config = conf.clone();
+        let mut ids = HashSet::new();
+        for (index, conf) in config.iter_mut().enumerate() {
+            // the builders of the injectors take the config as valid
+            conf.validate()?;
+            let id = conf.id_mut().get_or_insert_with(|| index.to_string());
+            if !ids.insert(id.clone()) {
+                return Err(ConfigError::Invalid {
+                    field: "id".to_owned(),
+                    reason: format!("`{}` is used by more than one injector", id),
+                }
+                .into());
+            }
+        }
         let mut injectors = Vec::new();
 
         for injector in conf.into_iter() {
             let injector = match injector {
                 InjectorConfig::Fault(faults) => {
-                    (box FaultInjector::build(faults)?) as Box<dyn Injector>
+                    (box FaultInjector::build(faults, root)?) as Box<dyn Injector>
                 }
                 InjectorConfig::Latency(latency) => {
-                    (box LatencyInjector::build(latency)?) as Box<dyn Injector>
+                    (box LatencyInjector::build(latency, root)?) as Box<dyn Injector>
                 }
                 InjectorConfig::AttrOverride(attr_override) => {
-                    (box AttrOverrideInjector::build(attr_override)?) as Box<dyn Injector>
+                    (box AttrOverrideInjector::build(attr_override, root)?) as Box<dyn Injector>
                 }
                 InjectorConfig::Mistake(mistakes) => {
-                    (box MistakeInjector::build(mistakes)?) as Box<dyn Injector>
+                    (box MistakeInjector::build(mistakes, root)?) as Box<dyn Injector>
+                }
+                InjectorConfig::SpaceLimit(space_limit) => {
+                    (box SpaceLimitInjector::build(space_limit, root)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Throttle(throttle) => {
+                    (box ThrottleInjector::build(throttle, root)?) as Box<dyn Injector>
+                }
+                InjectorConfig::ShortIO(short_io) => {
+                    (box ShortIOInjector::build(short_io, root, direct_io)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Warmup(warmup) => {
+                    (box WarmupInjector::build(warmup, root)?) as Box<dyn Injector>
+                }
+                InjectorConfig::TornAppend(torn_append) => {
+                    (box TornAppendInjector::build(torn_append, root)?) as Box<dyn Injector>
                 }
             };
             injectors.push(injector)
         }
 
-        Ok(Self { injectors })
+        let enabled = injectors.iter().map(|_| AtomicBool::new(true)).collect();
+        Ok(Self {
+            injectors,
+            config,
+            enabled,
+        })
+    }
+
+    pub fn config(&self) -> &[InjectorConfig] {
+        &self.config
+    }
+
+    pub fn is_enabled(&self, index: usize) -> bool {
+        self.enabled[index].load(Ordering::SeqCst)
+    }
+
+    // set_enabled enables or disables the injector with `id`, and returns false
+    // if there is no such injector
+    pub fn set_enabled(&self, id: &str, enabled: bool) -> bool {
+        match self.config.iter().position(|conf| conf.id() == Some(id)) {
+            Some(index) => {
+                self.enabled[index].store(enabled, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn active(&self) -> impl Iterator<Item = &dyn Injector> {
+        self.injectors
+            .iter()
+            .zip(self.enabled.iter())
+            .filter(|(_, enabled)| enabled.load(Ordering::SeqCst))
+            .map(|(injector, _)| injector.as_ref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.injectors.len()
+    }
+
+    // enabled_len counts the injectors which are not disabled
+    pub fn enabled_len(&self) -> usize {
+        self.active().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.injectors.is_empty()
     }
 }
 
 #[async_trait]
 impl Injector for MultiInjector {
-    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
-        for injector in self.injectors.iter() {
-            injector.inject(method, path).await?
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<Injection> {
+        let mut injection = Injection::Passed;
+        for injector in self.active() {
+            injection = injection.merge(injector.inject(method, path).await?);
         }
 
-        Ok(())
+        Ok(injection)
     }
 
-    fn inject_reply(&self, method: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
+    async fn inject_transfer(
+        &self,
+        method: &filter::Method,
+        path: &Path,
+        size: usize,
+    ) -> Result<Injection> {
+        let mut injection = Injection::Passed;
+        for injector in self.active() {
+            injection = injection.merge(injector.inject_transfer(method, path, size).await?);
+        }
+
+        Ok(injection)
+    }
+
+    fn enable(&self, enabled_at: Instant) {
         for injector in self.injectors.iter() {
+            injector.enable(enabled_at)
+        }
+    }
+
+    fn inject_reply(&self, method: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
+        for injector in self.active() {
             injector.inject_reply(method, path, reply)?
         }
 
@@ -63,15 +188,23 @@ impl Injector for MultiInjector {
     }
 
     fn inject_attr(&self, attr: &mut FileAttr, path: &Path) {
-        for injector in self.injectors.iter() {
+        for injector in self.active() {
             injector.inject_attr(attr, path)
         }
     }
 
     fn inject_write_data(&self, path: &Path, data: &mut Vec<u8>) -> Result<()> {
-        for injector in self.injectors.iter() {
+        for injector in self.active() {
             injector.inject_write_data(path, data)?;
         }
         Ok(())
     }
+
+    fn inject_append(&self, path: &Path, data: &mut Vec<u8>) -> Result<bool> {
+        let mut sync = false;
+        for injector in self.active() {
+            sync |= injector.inject_append(path, data)?;
+        }
+        Ok(sync)
+    }
 }
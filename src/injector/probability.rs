@@ -0,0 +1,44 @@
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// Probability decides whether an injection should be applied. The RNG can be
+// seeded so that an experiment can be reproduced exactly.
+#[derive(Debug)]
+pub struct Probability {
+    probability: f64,
+    rng: Mutex<StdRng>,
+}
+
+impl Probability {
+    pub fn new(probability: f64, seed: Option<u64>) -> Result<Self> {
+        if !(0.0..=1.0).contains(&probability) {
+            return Err(anyhow!(
+                "probability {} is out of range [0, 1]",
+                probability
+            ));
+        }
+
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        Ok(Self {
+            probability,
+            rng: Mutex::new(rng),
+        })
+    }
+
+    pub fn from_percent(percent: i32, seed: Option<u64>) -> Result<Self> {
+        Self::new(percent as f64 / 100f64, seed)
+    }
+
+    pub fn should_apply(&self) -> bool {
+        let p: f64 = self.rng.lock().unwrap().gen();
+
+        p < self.probability
+    }
+}
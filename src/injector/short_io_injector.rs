@@ -0,0 +1,101 @@
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tracing::{debug, trace};
+
+use super::injector_config::{ConfigError, ShortIOConfig};
+use super::{filter, Injection, Injector};
+use crate::hookfs::{Reply, Result};
+
+// ShortIOInjector makes reads and writes transfer fewer bytes than requested,
+// while still succeeding. Reads are only shortened with direct io, as the
+// kernel takes a short read into the page cache as the end of the file, and
+// shrinks the cached size of the file.
+#[derive(Debug)]
+pub struct ShortIOInjector {
+    filter: filter::Filter,
+    max_bytes: usize,
+    random: bool,
+    rng: Mutex<StdRng>,
+    direct_io: bool,
+}
+
+#[async_trait]
+impl Injector for ShortIOInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<Injection> {
+        Ok(Injection::Passed)
+    }
+
+    fn enable(&self, enabled_at: Instant) {
+        self.filter.enable(enabled_at)
+    }
+
+    fn inject_reply(&self, method: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
+        // the data of readlink is never shortened
+        if *method != filter::Method::READ || !self.direct_io || !self.filter.filter(method, path) {
+            return Ok(());
+        }
+        if let Reply::Data(data) = reply {
+            self.shorten(&mut data.data);
+        }
+        Ok(())
+    }
+
+    fn inject_write_data(&self, path: &Path, data: &mut Vec<u8>) -> Result<()> {
+        if self.filter.filter(&filter::Method::WRITE, path) {
+            self.shorten(data);
+        }
+        Ok(())
+    }
+}
+
+impl ShortIOInjector {
+    pub fn build(conf: ShortIOConfig, root: &Path, direct_io: bool) -> anyhow::Result<Self> {
+        trace!("build short io injector");
+
+        // without direct io, reads are left untouched unless they are asked
+        // for explicitly, which is an error
+        let read = conf.filter.methods.iter().flatten().any(|method| {
+            filter::Method::try_from(method.as_str())
+                .map_or(false, |method| method == filter::Method::READ)
+        });
+        if read && !direct_io {
+            return Err(ConfigError::Invalid {
+                field: "methods".to_owned(),
+                reason: "`read` can only be shortened on a mount with --direct-io, as a short \
+                         read through the page cache truncates the file"
+                    .to_owned(),
+            }
+            .into());
+        }
+
+        let rng = match conf.filter.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter, root)?,
+            max_bytes: conf.max_bytes,
+            random: conf.random,
+            rng: Mutex::new(rng),
+            direct_io,
+        })
+    }
+
+    fn shorten(&self, data: &mut Vec<u8>) {
+        let mut len = std::cmp::min(data.len(), self.max_bytes);
+        if self.random && len > 1 {
+            len = self.rng.lock().unwrap().gen_range(1, len + 1);
+        }
+        if len < data.len() {
+            debug!("shorten transfer from {} to {} bytes", data.len(), len);
+            data.truncate(len);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use async_trait::async_trait;
+use nix::errno::Errno;
+use tracing::{debug, trace};
+
+use super::injector_config::SpaceLimitConfig;
+use super::{filter, Injection, Injector};
+use crate::hookfs::{Error, Result};
+
+// SpaceLimitInjector lets the writes to the matched paths succeed until `limit`
+// bytes have been written, and fails them with ENOSPC after that
+#[derive(Debug)]
+pub struct SpaceLimitInjector {
+    filter: filter::Filter,
+    limit: u64,
+    written: AtomicU64,
+}
+
+#[async_trait]
+impl Injector for SpaceLimitInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<Injection> {
+        Ok(Injection::Passed)
+    }
+
+    fn enable(&self, enabled_at: Instant) {
+        self.filter.enable(enabled_at)
+    }
+
+    fn inject_write_data(&self, path: &Path, data: &mut Vec<u8>) -> Result<()> {
+        if !self.filter.filter(&filter::Method::WRITE, path) {
+            return Ok(());
+        }
+
+        let len = data.len() as u64;
+        let mut written = self.written.load(Ordering::SeqCst);
+        loop {
+            let available = self.limit.saturating_sub(written);
+            if available == 0 && len > 0 {
+                debug!("space limit {} is exhausted", self.limit);
+                return Err(Error::Sys(Errno::ENOSPC));
+            }
+            let accepted = std::cmp::min(len, available);
+            match self.written.compare_exchange(
+                written,
+                written + accepted,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    // write what fits, the caller gets ENOSPC on the next write
+                    if accepted < len {
+                        debug!("truncate write from {} to {} bytes", len, accepted);
+                        data.truncate(accepted as usize);
+                    }
+                    return Ok(());
+                }
+                Err(current) => written = current,
+            }
+        }
+    }
+}
+
+impl SpaceLimitInjector {
+    pub fn build(conf: SpaceLimitConfig, root: &Path) -> anyhow::Result<Self> {
+        trace!("build space limit injector");
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter, root)?,
+            limit: conf.bytes,
+            written: AtomicU64::new(0),
+        })
+    }
+}
@@ -0,0 +1,95 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::time::delay_for;
+use tracing::{debug, trace};
+
+use super::injector_config::ThrottleConfig;
+use super::{filter, Injection, Injector};
+use crate::hookfs::Result;
+
+// TokenBucket is refilled with `rate` tokens per second, up to `burst` tokens.
+// Every byte transferred takes a token. The tokens can go negative, so the
+// concurrent requests queue up behind each other.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    // take takes `size` tokens, and returns how long to wait before they are
+    // available
+    fn take(&self, size: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = *state;
+        let now = Instant::now();
+        let tokens = (tokens + now.duration_since(last).as_secs_f64() * self.rate).min(self.burst);
+        let tokens = tokens - size as f64;
+        *state = (tokens, now);
+
+        if tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-tokens / self.rate)
+        }
+    }
+}
+
+// ThrottleInjector limits the bandwidth of reads and writes
+#[derive(Debug)]
+pub struct ThrottleInjector {
+    filter: filter::Filter,
+    bucket: TokenBucket,
+}
+
+#[async_trait]
+impl Injector for ThrottleInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<Injection> {
+        Ok(Injection::Passed)
+    }
+
+    async fn inject_transfer(
+        &self,
+        method: &filter::Method,
+        path: &Path,
+        size: usize,
+    ) -> Result<Injection> {
+        if !self.filter.filter(method, path) {
+            return Ok(Injection::Passed);
+        }
+
+        let delay = self.bucket.take(size);
+        if delay == Duration::from_secs(0) {
+            return Ok(Injection::Passed);
+        }
+        debug!("throttle {} bytes for {:?}", size, delay);
+        delay_for(delay).await;
+
+        Ok(Injection::Delayed)
+    }
+
+    fn enable(&self, enabled_at: Instant) {
+        self.filter.enable(enabled_at)
+    }
+}
+
+impl ThrottleInjector {
+    pub fn build(conf: ThrottleConfig, root: &Path) -> anyhow::Result<Self> {
+        trace!("build throttle injector");
+
+        let burst = conf.burst.unwrap_or(conf.rate);
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter, root)?,
+            bucket: TokenBucket {
+                rate: conf.rate as f64,
+                burst: burst as f64,
+                state: Mutex::new((burst as f64, Instant::now())),
+            },
+        })
+    }
+}
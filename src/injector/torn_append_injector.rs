@@ -0,0 +1,62 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tracing::{debug, trace};
+
+use super::injector_config::TornAppendConfig;
+use super::{filter, Injection, Injector};
+use crate::hookfs::Result;
+
+// TornAppendInjector models a crash in the middle of flushing an append. Only a
+// random prefix of the data appended to a file is written, while the write
+// still succeeds with the full length.
+#[derive(Debug)]
+pub struct TornAppendInjector {
+    filter: filter::Filter,
+    sync: bool,
+    rng: Mutex<StdRng>,
+}
+
+#[async_trait]
+impl Injector for TornAppendInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<Injection> {
+        Ok(Injection::Passed)
+    }
+
+    fn enable(&self, enabled_at: Instant) {
+        self.filter.enable(enabled_at)
+    }
+
+    fn inject_append(&self, path: &Path, data: &mut Vec<u8>) -> Result<bool> {
+        if data.is_empty() || !self.filter.filter(&filter::Method::WRITE, path) {
+            return Ok(false);
+        }
+
+        let len = self.rng.lock().unwrap().gen_range(0, data.len());
+        debug!("tear append from {} to {} bytes", data.len(), len);
+        data.truncate(len);
+        Ok(self.sync)
+    }
+}
+
+impl TornAppendInjector {
+    pub fn build(conf: TornAppendConfig, root: &Path) -> anyhow::Result<Self> {
+        trace!("build torn append injector");
+
+        // the seed of the filter also makes the torn lengths reproducible
+        let rng = match conf.filter.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter, root)?,
+            sync: conf.sync,
+            rng: Mutex::new(rng),
+        })
+    }
+}
@@ -0,0 +1,69 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::time::delay_for;
+use tracing::{debug, trace};
+
+use super::injector_config::WarmupConfig;
+use super::{filter, Injection, Injector, METHOD_COUNT};
+use crate::hookfs::Result;
+
+// WarmupInjector delays the first `count` requests of every method after the
+// injection is enabled, like a cold cache. Once a method has run out of its
+// count, its requests are passed without being filtered.
+#[derive(Debug)]
+pub struct WarmupInjector {
+    filter: filter::Filter,
+    count: u64,
+    delay: Duration,
+    // the count of requests left to delay, indexed by the bit of the method
+    remaining: Vec<AtomicU64>,
+}
+
+#[async_trait]
+impl Injector for WarmupInjector {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<Injection> {
+        let remaining = match self.remaining.get(method.bits().trailing_zeros() as usize) {
+            Some(remaining) => remaining,
+            None => return Ok(Injection::Passed),
+        };
+        if remaining.load(Ordering::Relaxed) == 0 || !self.filter.filter(method, path) {
+            return Ok(Injection::Passed);
+        }
+        // the requests racing for the last count are passed
+        if remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_err()
+        {
+            return Ok(Injection::Passed);
+        }
+
+        debug!("warm up with delay {:?}", self.delay);
+        delay_for(self.delay).await;
+        Ok(Injection::Delayed)
+    }
+
+    fn enable(&self, enabled_at: Instant) {
+        self.filter.enable(enabled_at);
+        for remaining in self.remaining.iter() {
+            remaining.store(self.count, Ordering::SeqCst);
+        }
+    }
+}
+
+impl WarmupInjector {
+    pub fn build(conf: WarmupConfig, root: &Path) -> anyhow::Result<Self> {
+        trace!("build warmup injector");
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter, root)?,
+            count: conf.count,
+            delay: conf.delay,
+            remaining: (0..METHOD_COUNT)
+                .map(|_| AtomicU64::new(conf.count))
+                .collect(),
+        })
+    }
+}
@@ -1,23 +1,173 @@
-use std::sync::{mpsc, Arc, Mutex};
+use std::collections::BTreeMap;
+use std::fs::{DirBuilder, Permissions};
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, SystemTime};
 
+use anyhow::Context;
 use jsonrpc_derive::rpc;
 use jsonrpc_stdio_server::jsonrpc_core::*;
 use jsonrpc_stdio_server::ServerBuilder;
-use tracing::{info, trace};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixListener};
+use tracing::{debug, error, info, trace};
 
-use crate::hookfs::HookFs;
-use crate::injector::{InjectorConfig, MultiInjector};
+use crate::fuse_device::FuseDeviceError;
+use crate::hookfs::{CounterSnapshot, HookFs};
+use crate::injector::{ConfigError, Injector, InjectorConfig, MultiInjector};
+use crate::mount::MountError;
+use crate::mount_injector::MountMode;
+use crate::ptrace::PtraceError;
+use crate::replacer::{FdReport, ProcessReport, ReplacerError};
+
+// the codes of the errors returned by the RPC methods. They are in the range of
+// server errors defined by JSON-RPC, and never change, so controllers can tell
+// the errors apart without parsing the messages. Other errors are returned as
+// the internal error -32603.
+pub const MOUNT_FAILED: i64 = -32001;
+pub const FUSE_UNAVAILABLE: i64 = -32002;
+pub const PTRACE_DENIED: i64 = -32003;
+pub const CONFIG_INVALID: i64 = -32004;
+pub const NOT_MOUNTED: i64 = -32005;
+pub const INJECTOR_NOT_FOUND: i64 = -32006;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Comm {
     Shutdown = 0,
 }
 
-pub async fn start_server(config: RpcImpl) {
-    info!("Starting jsonrpc server");
-    let server = new_server(config);
-    let server = server.build();
-    server.await;
+// ListenAddr is where the jsonrpc server is served. Requests and responses
+// are one JSON per line on a socket, like on the stdio.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Stdio,
+    TcpAddr(SocketAddr),
+    UnixPath(PathBuf),
+}
+
+impl Default for ListenAddr {
+    fn default() -> Self {
+        ListenAddr::Stdio
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    // parses `stdio`, `unix:<path>` or a tcp address `<host>:<port>`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s == "stdio" {
+            return Ok(ListenAddr::Stdio);
+        }
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                anyhow::bail!("empty unix socket path");
+            }
+            return Ok(ListenAddr::UnixPath(PathBuf::from(path)));
+        }
+        let addr = s.strip_prefix("tcp:").unwrap_or(s);
+        let addr = addr
+            .parse()
+            .with_context(|| format!("invalid listen address {}", s))?;
+        Ok(ListenAddr::TcpAddr(addr))
+    }
+}
+
+pub async fn start_server(config: RpcImpl, addr: ListenAddr) -> anyhow::Result<()> {
+    info!("Starting jsonrpc server on {:?}", addr);
+    match addr {
+        ListenAddr::Stdio => {
+            let server = new_server(config);
+            let server = server.build();
+            server.await;
+        }
+        ListenAddr::TcpAddr(addr) => {
+            let io = Arc::new(new_handler(config));
+            let mut listener = TcpListener::bind(addr).await?;
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                debug!("jsonrpc connection from {}", peer);
+                tokio::spawn(serve_connection(stream, io.clone()));
+            }
+        }
+        ListenAddr::UnixPath(path) => {
+            let io = Arc::new(new_handler(config));
+            let mut listener = bind_private(&path)?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                debug!("jsonrpc connection on {}", path.display());
+                tokio::spawn(serve_connection(stream, io.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+// bind_private binds a unix socket which only the owner, usually root, can
+// connect to. The socket is bound in a new private directory beside the path,
+// and moved onto the path once its mode is set, so nobody can connect in
+// between. A umask would do the same, but it would also change the mode of the
+// files created by the other threads meanwhile. The rename replaces a socket
+// left by a previous run.
+fn bind_private(path: &Path) -> anyhow::Result<UnixListener> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let dir = parent.join(format!(".toda-rpc-{}", std::process::id()));
+    // it fails if the directory exists, so it can't be made by someone else
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .context(format!("create {}", dir.display()))?;
+
+    let socket = dir.join("socket");
+    let bound = UnixListener::bind(&socket)
+        .and_then(|listener| {
+            std::fs::set_permissions(&socket, Permissions::from_mode(0o600))?;
+            std::fs::rename(&socket, path)?;
+            Ok(listener)
+        })
+        .context(format!("bind {}", path.display()));
+    if let Err(err) = std::fs::remove_file(&socket) {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            error!("fail to remove {}: {}", socket.display(), err);
+        }
+    }
+    if let Err(err) = std::fs::remove_dir(&dir) {
+        error!("fail to remove {}: {}", dir.display(), err);
+    }
+    bound
+}
+
+// serve_connection answers the requests on a connection until it's closed
+async fn serve_connection<S>(stream: S, io: Arc<IoHandler>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => {
+                error!("fail to read jsonrpc request: {}", err);
+                break;
+            }
+        };
+        if let Some(mut response) = io.handle_request(&line).await {
+            response.push('\n');
+            if let Err(err) = writer.write_all(response.as_bytes()).await {
+                error!("fail to write jsonrpc response: {}", err);
+                break;
+            }
+        }
+    }
 }
 
 pub fn new_server(config: RpcImpl) -> ServerBuilder {
@@ -33,27 +183,329 @@ pub fn new_handler(config: RpcImpl) -> IoHandler {
     io
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InjectionStatus {
+    pub mounted: bool,
+    pub error: Option<String>,
+    pub mount_mode: MountMode,
+    // the injection is paused by the `pause` method
+    pub paused: bool,
+    pub mounts: Vec<MountStatus>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MountStatus {
+    pub path: PathBuf,
+    pub injection_enabled: bool,
+    // RFC 3339 timestamps of the last time the injection was enabled and
+    // disabled. `active_duration` is only set while it's enabled
+    pub enabled_at: Option<String>,
+    pub disabled_at: Option<String>,
+    #[serde(default, with = "humantime_serde")]
+    pub active_duration: Option<Duration>,
+    pub injectors: Vec<InjectorStatus>,
+    pub counters: BTreeMap<String, CounterSnapshot>,
+    // the processes whose fds, cwd and mmaps are moved onto the mount
+    pub replaced: Vec<ProcessReport>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InjectorStatus {
+    #[serde(flatten)]
+    pub config: InjectorConfig,
+    pub enabled: bool,
+}
+
+// Replacement is an fd of a process, which is reopened onto the mount
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Replacement {
+    pub pid: i32,
+    #[serde(flatten)]
+    pub fd: FdReport,
+}
+
+// Health is the lifecycle state of toda. The error is attached when it's failed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "state", content = "error")]
+pub enum Health {
+    Mounting,
+    Ready,
+    Recovering,
+    Failed(String),
+}
+
 #[rpc]
 pub trait Rpc {
     #[rpc(name = "get_status")]
     fn get_status(&self, inst: String) -> Result<String>;
     #[rpc(name = "update")]
-    fn update(&self, config: Vec<InjectorConfig>) -> Result<String>;
+    fn update(&self, config: Vec<InjectorConfig>) -> Result<usize>;
+    #[rpc(name = "get_injection_status")]
+    fn get_injection_status(&self) -> Result<InjectionStatus>;
+    #[rpc(name = "health")]
+    fn get_health(&self) -> Result<Health>;
+    #[rpc(name = "list_replacements")]
+    fn list_replacements(&self) -> Result<Vec<Replacement>>;
+    #[rpc(name = "disable_injector")]
+    fn disable_injector(&self, id: String) -> Result<()>;
+    #[rpc(name = "enable_injector")]
+    fn enable_injector(&self, id: String) -> Result<()>;
+    #[rpc(name = "pause")]
+    fn pause(&self) -> Result<()>;
+    #[rpc(name = "resume_injection")]
+    fn resume_injection(&self) -> Result<()>;
+}
+
+// MountState is the outcome of the injection served by the RPC. It's shared
+// with the caller like the health, so that the server can be started before
+// the injection returns. Until then, nothing is mounted.
+pub struct MountState {
+    pub status: anyhow::Result<()>,
+    pub hookfs: Vec<Arc<HookFs>>,
+    // the processes replaced on each mount, in the same order as `hookfs`
+    pub replaced: Vec<Vec<ProcessReport>>,
+}
+
+impl Default for MountState {
+    fn default() -> Self {
+        MountState {
+            status: Ok(()),
+            hookfs: Vec::new(),
+            replaced: Vec::new(),
+        }
+    }
 }
 
 pub struct RpcImpl {
-    status: Mutex<anyhow::Result<()>>,
+    state: Arc<RwLock<MountState>>,
     tx: Mutex<mpsc::Sender<Comm>>,
-    hookfs: Option<Arc<HookFs>>,
+    health: Arc<Mutex<Health>>,
+    mount_mode: MountMode,
 }
 
 impl RpcImpl {
     pub fn new(
         status: Mutex<anyhow::Result<()>>,
         tx: Mutex<mpsc::Sender<Comm>>,
-        hookfs: Option<Arc<HookFs>>,
+        hookfs: Vec<Arc<HookFs>>,
     ) -> Self {
-        Self { status, tx, hookfs }
+        let status = status.into_inner().unwrap();
+        let health = match &status {
+            Ok(_) => Health::Ready,
+            Err(e) => Health::Failed(e.to_string()),
+        };
+        Self {
+            state: Arc::new(RwLock::new(MountState {
+                status,
+                hookfs,
+                replaced: Vec::new(),
+            })),
+            tx,
+            health: Arc::new(Mutex::new(health)),
+            mount_mode: MountMode::default(),
+        }
+    }
+
+    // with_health shares the health state with the caller, so that it can be
+    // changed through the lifecycle of toda
+    pub fn with_health(mut self, health: Arc<Mutex<Health>>) -> Self {
+        self.health = health;
+        self
+    }
+
+    // with_mount_state shares the outcome of the injection with the caller,
+    // which sets it once the injection returns
+    pub fn with_mount_state(mut self, state: Arc<RwLock<MountState>>) -> Self {
+        self.state = state;
+        self
+    }
+
+    pub fn with_mount_mode(mut self, mount_mode: MountMode) -> Self {
+        self.mount_mode = mount_mode;
+        self
+    }
+
+    // set_injector_enabled enables or disables the injector with `id` on all
+    // mounts, while the other injectors keep running
+    fn set_injector_enabled(&self, id: &str, enabled: bool) -> Result<()> {
+        let state = self.mounted()?;
+        let mut found = false;
+        for hookfs in state.hookfs.iter() {
+            found |= hookfs.injector.load().set_enabled(id, enabled);
+        }
+        if !found {
+            return Err(server_error(
+                INJECTOR_NOT_FOUND,
+                format!("injector `{}` is not found", id),
+            ));
+        }
+        info!("injector {} is enabled: {}", id, enabled);
+        Ok(())
+    }
+
+    // set_paused pauses or resumes the injection on all mounts. Unlike resuming
+    // toda, the mounts and the replaced fds are kept, so it can be undone at once
+    fn set_paused(&self, paused: bool) -> Result<()> {
+        let state = self.mounted()?;
+        for hookfs in state.hookfs.iter() {
+            if paused {
+                hookfs.pause_injection();
+            } else {
+                hookfs.resume_injection();
+            }
+        }
+        info!("injection is paused: {}", paused);
+        Ok(())
+    }
+
+    // with_replaced sets the processes replaced on each mount, in the same
+    // order as `hookfs`
+    pub fn with_replaced(self, replaced: Vec<Vec<ProcessReport>>) -> Self {
+        self.state.write().unwrap().replaced = replaced;
+        self
+    }
+
+    // mounted returns the mount state if the injection has succeeded, or the
+    // error to reply otherwise
+    fn mounted(&self) -> Result<RwLockReadGuard<'_, MountState>> {
+        let state = self.state.read().unwrap();
+        if let Err(e) = &state.status {
+            return Err(rpc_error(e));
+        }
+        if state.hookfs.is_empty() {
+            return Err(server_error(NOT_MOUNTED, "hookfs is not mounted"));
+        }
+        Ok(state)
+    }
+}
+
+// update_injectors replaces the injectors of all hookfs with the ones built from
+// `config`, and returns the count of injectors. All injectors are built before
+// taking the lock, so an invalid config leaves the current injectors untouched.
+pub fn update_injectors(
+    hookfs: &[Arc<HookFs>],
+    config: Vec<InjectorConfig>,
+) -> anyhow::Result<usize> {
+    let injectors = hookfs
+        .iter()
+        .map(|hookfs| {
+            MultiInjector::build_for_mount(config.clone(), hookfs.mount_path(), hookfs.direct_io())
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for (hookfs, injectors) in hookfs.iter().zip(injectors) {
+        // the time windows of new injectors are counted from the time
+        // when the injection was enabled, not from the update
+        if let Some(enabled_at) = hookfs.enabled_at() {
+            injectors.enable(enabled_at);
+        }
+        // requests in flight keep the old injectors, and the new ones apply
+        // to the requests after the swap
+        hookfs.injector.store(injectors);
+    }
+
+    Ok(config.len())
+}
+
+// validate_configs validates every injector config, and reports the index of
+// the first invalid one
+pub fn validate_configs(config: &[InjectorConfig]) -> anyhow::Result<()> {
+    for (index, config) in config.iter().enumerate() {
+        config
+            .validate()
+            .with_context(|| format!("invalid injector config at index {}", index))?;
+    }
+    Ok(())
+}
+
+// injection_status describes the mounts of `hookfs`, with the processes
+// replaced on each of them. `error` is the error of the injection, if it failed
+pub fn injection_status(
+    hookfs: &[Arc<HookFs>],
+    replaced: &[Vec<ProcessReport>],
+    mount_mode: MountMode,
+    error: Option<String>,
+) -> InjectionStatus {
+    let mounts = hookfs
+        .iter()
+        .enumerate()
+        .map(|(index, hookfs)| MountStatus {
+            path: hookfs.mount_path().to_owned(),
+            injection_enabled: hookfs.injection_enabled(),
+            enabled_at: hookfs.enabled_time().map(format_time),
+            disabled_at: hookfs.disabled_time().map(format_time),
+            active_duration: hookfs
+                .enabled_time()
+                .filter(|_| hookfs.injection_enabled())
+                .and_then(|enabled_time| enabled_time.elapsed().ok()),
+            injectors: {
+                let injector = hookfs.injector.load();
+                injector
+                    .config()
+                    .iter()
+                    .enumerate()
+                    .map(|(index, config)| InjectorStatus {
+                        config: config.clone(),
+                        enabled: injector.is_enabled(index),
+                    })
+                    .collect()
+            },
+            counters: hookfs.counters.snapshot(),
+            replaced: replaced.get(index).cloned().unwrap_or_default(),
+        })
+        .collect::<Vec<_>>();
+    InjectionStatus {
+        mounted: error.is_none() && !mounts.is_empty(),
+        error,
+        mount_mode,
+        paused: hookfs.iter().any(|hookfs| hookfs.injection_paused()),
+        mounts,
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_millis(time).to_string()
+}
+
+// error_code finds the category of `err` from the errors in its chain
+pub fn error_code(err: &anyhow::Error) -> ErrorCode {
+    for cause in err.chain() {
+        if cause.is::<FuseDeviceError>() {
+            return ErrorCode::ServerError(FUSE_UNAVAILABLE);
+        }
+        if cause.is::<MountError>() {
+            return ErrorCode::ServerError(MOUNT_FAILED);
+        }
+        if cause.is::<ConfigError>() {
+            return ErrorCode::ServerError(CONFIG_INVALID);
+        }
+        if let Some(PtraceError::Denied { .. }) = cause.downcast_ref::<PtraceError>() {
+            return ErrorCode::ServerError(PTRACE_DENIED);
+        }
+        match cause.downcast_ref::<ReplacerError>() {
+            Some(err) if err.is_ptrace_denied() => return ErrorCode::ServerError(PTRACE_DENIED),
+            _ => {}
+        }
+    }
+    ErrorCode::InternalError
+}
+
+fn rpc_error(err: &anyhow::Error) -> Error {
+    Error {
+        code: error_code(err),
+        message: format!("{:#}", err),
+        data: None,
+    }
+}
+
+fn server_error<E: ToString>(code: i64, err: E) -> Error {
+    Error {
+        code: ErrorCode::ServerError(code),
+        message: err.to_string(),
+        data: None,
     }
 }
 
@@ -66,7 +518,8 @@ impl Drop for RpcImpl {
 impl Rpc for RpcImpl {
     fn get_status(&self, _inst: String) -> Result<String> {
         info!("rpc get_status called");
-        match &*self.status.lock().unwrap() {
+        match &self.state.read().unwrap().status {
+            Ok(_) if *self.health.lock().unwrap() == Health::Mounting => Ok("mounting".to_string()),
             Ok(_) => Ok("ok".to_string()),
             Err(e) => {
                 let tx = &self.tx.lock().unwrap();
@@ -76,20 +529,65 @@ impl Rpc for RpcImpl {
             }
         }
     }
-    fn update(&self, config: Vec<InjectorConfig>) -> Result<String> {
+    fn update(&self, config: Vec<InjectorConfig>) -> Result<usize> {
         info!("rpc update called");
-        if let Err(e) = &*self.status.lock().unwrap() {
-            return Ok(e.to_string());
-        }
-        let injectors = MultiInjector::build(config);
-        if let Err(e) = &injectors {
-            return Ok(e.to_string());
-        }
-        futures::executor::block_on((async || {
-            let hookfs = self.hookfs.as_ref().unwrap();
-            let mut current_injectors = hookfs.injector.write().await;
-            *current_injectors = injectors.unwrap();
-        })());
-        Ok("ok".to_string())
+        let state = self.mounted()?;
+        validate_configs(&config).map_err(|e| server_error(CONFIG_INVALID, format!("{:#}", e)))?;
+        let count =
+            update_injectors(&state.hookfs, config).map_err(|e| server_error(CONFIG_INVALID, e))?;
+        info!("{} injectors are active", count);
+        Ok(count)
+    }
+    fn get_health(&self) -> Result<Health> {
+        trace!("rpc health called");
+        Ok(self.health.lock().unwrap().clone())
+    }
+    fn list_replacements(&self) -> Result<Vec<Replacement>> {
+        info!("rpc list_replacements called");
+        let replacements = self
+            .state
+            .read()
+            .unwrap()
+            .replaced
+            .iter()
+            .flatten()
+            .flat_map(|process| {
+                process.fds.iter().map(move |fd| Replacement {
+                    pid: process.pid,
+                    fd: fd.clone(),
+                })
+            })
+            .collect();
+        Ok(replacements)
+    }
+    fn disable_injector(&self, id: String) -> Result<()> {
+        info!("rpc disable_injector called");
+        self.set_injector_enabled(&id, false)
+    }
+    fn enable_injector(&self, id: String) -> Result<()> {
+        info!("rpc enable_injector called");
+        self.set_injector_enabled(&id, true)
+    }
+    fn pause(&self) -> Result<()> {
+        info!("rpc pause called");
+        self.set_paused(true)
+    }
+    fn resume_injection(&self) -> Result<()> {
+        info!("rpc resume_injection called");
+        self.set_paused(false)
+    }
+    fn get_injection_status(&self) -> Result<InjectionStatus> {
+        info!("rpc get_injection_status called");
+        let state = self.state.read().unwrap();
+        let error = match &state.status {
+            Ok(_) => None,
+            Err(e) => Some(e.to_string()),
+        };
+        Ok(injection_status(
+            &state.hookfs,
+            &state.replaced,
+            self.mount_mode,
+            error,
+        ))
     }
 }
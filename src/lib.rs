@@ -12,7 +12,6 @@
 // limitations under the License.
 
 #![feature(box_syntax)]
-#![feature(async_closure)]
 #![feature(vec_into_raw_parts)]
 #![feature(atomic_mut_ptr)]
 #![feature(drain_filter)]
@@ -23,9 +22,13 @@ pub mod fuse_device;
 pub mod hookfs;
 pub mod injector;
 pub mod jsonrpc;
+pub mod metrics;
 pub mod mount;
 pub mod mount_injector;
 pub mod ptrace;
 pub mod replacer;
 pub mod stop;
+pub mod toda;
 pub mod utils;
+
+pub use crate::toda::{Config, Injection, PtraceDenied, ReplaceCounts, Summary, Toda};
@@ -11,202 +11,543 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#![feature(box_syntax)]
-#![feature(async_closure)]
-#![feature(vec_into_raw_parts)]
-#![feature(atomic_mut_ptr)]
-#![feature(drain_filter)]
-#![allow(clippy::or_fun_call)]
-#![allow(clippy::too_many_arguments)]
-
-extern crate derive_more;
-
-mod fuse_device;
-mod hookfs;
-mod injector;
-mod jsonrpc;
-mod mount;
-mod mount_injector;
-mod ptrace;
-mod replacer;
-mod stop;
-mod utils;
-
 use std::convert::TryFrom;
+use std::io::Read;
+use std::net::SocketAddr;
 use std::os::unix::io::RawFd;
-use std::path::PathBuf;
-use std::sync::{mpsc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::Duration;
 use std::{io, thread};
 
-use anyhow::Result;
-use injector::InjectorConfig;
-use jsonrpc::start_server;
-use mount_injector::{MountInjectionGuard, MountInjector};
+use anyhow::{Context, Result};
+use nix::errno::Errno;
 use nix::sys::signal::{signal, SigHandler, Signal};
 use nix::unistd::{pipe, read, write};
-use nix::mount::{mount, MsFlags};
-use replacer::{Replacer, UnionReplacer};
+use structopt::clap::{self, ErrorKind};
 use structopt::StructOpt;
+use toda::hookfs::{EventSink, HookFs};
+use toda::injector::InjectorConfig;
+use toda::jsonrpc::{
+    self, start_server, update_injectors, validate_configs, Health, InjectionStatus, ListenAddr,
+    MountState,
+};
+use toda::mount::RetryPolicy;
+use toda::mount_injector::{FuseOptions, MountMode};
+use toda::replacer::{ProcessFilter, ReplacerKind};
+use toda::{metrics, ptrace, Config, PtraceDenied, Summary, Toda};
 use tokio::runtime::Runtime;
-use tracing::{info, instrument};
+use tracing::{error, info, warn};
+use tracing_subscriber::filter::{Directive, LevelFilter};
 use tracing_subscriber::EnvFilter;
-use utils::encode_path;
 
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(name = "basic")]
 struct Options {
-    #[structopt(long)]
-    path: PathBuf,
+    #[structopt(long, required = true)]
+    path: Vec<PathBuf>,
 
     #[structopt(long = "mount-only")]
     mount_only: bool,
 
+    /// The replacers moving the fds, cwd and mmaps opened on the path onto the
+    /// mount, separated by commas. All of them trace the processes with ptrace,
+    /// so pass an empty list where ptrace is blocked. Without `fd`, the files
+    /// opened before injection are not injected
+    #[structopt(long = "replacers", default_value = "fd,cwd,mmap")]
+    replacers: ReplacerKind,
+
+    /// Only replace the processes whose comm or cmdline matches this regex,
+    /// e.g. `tikv`. The other processes are never traced
+    #[structopt(long = "target-process")]
+    target_process: Option<ProcessFilter>,
+
+    /// Count of threads tracing and replacing the processes at the same time,
+    /// which shortens the replacement on a host with many processes. Each
+    /// thread replaces the processes whose pid modulo the count is its index
+    #[structopt(long = "replace-concurrency", default_value = "1")]
+    replace_concurrency: usize,
+
+    /// How the FUSE mount is set up, `move` or `direct`. The `direct` mode works
+    /// on filesystems which can't be moved, but the fds opened before injection
+    /// are not replaced
+    #[structopt(long = "mount-mode", default_value = "move")]
+    mount_mode: MountMode,
+
+    /// Don't mount with `allow_other`, so only root can access the path
+    #[structopt(long = "no-allow-other")]
+    no_allow_other: bool,
+
+    /// The `max_read` option of the FUSE mount
+    #[structopt(long = "max-read")]
+    max_read: Option<u32>,
+
+    /// Open all files with direct io, so reads and writes bypass the page
+    /// cache. Corrupted data injected on reads is then seen by every read,
+    /// instead of being cached. Without it, the fds opened with O_DIRECT are
+    /// not replaced, as the mount would serve them through the page cache
+    #[structopt(long = "direct-io")]
+    direct_io: bool,
+
+    /// Keep the original mounts under this directory during injection, instead
+    /// of beside the injected paths
+    #[structopt(long = "mount-base-dir")]
+    mount_base_dir: Option<PathBuf>,
+
+    /// Count of threads serving the FUSE requests, the count of CPUs by default.
+    /// Injected latency is waited without holding a thread, so more threads
+    /// don't let more requests be delayed at once: see `maxConcurrent` of the
+    /// latency injector for that. The threads only bound the requests being
+    /// forwarded to the original files at the same time.
+    #[structopt(long = "worker-threads")]
+    worker_threads: Option<usize>,
+
+    /// Max count of requests handled at once, including the delayed ones,
+    /// unlimited by default. Past it, the requests wait in the kernel, which
+    /// bounds the memory of toda under many concurrent operations, e.g. with
+    /// io_uring or AIO.
+    #[structopt(long = "max-pending-requests")]
+    max_pending_requests: Option<usize>,
+
+    /// Restore the original mount if the FUSE server exits while injection is
+    /// enabled. Unless `--mount-only` is set, the fds opened on the mount are
+    /// also moved back to the original files.
+    #[structopt(long = "recover-on-crash")]
+    recover_on_crash: bool,
+
+    /// Remove the mounts left on the paths by a run which was killed without
+    /// resuming, e.g. by SIGKILL, before injection. The killed run must have
+    /// used the same `--mount-mode` and `--mount-base-dir`
+    #[structopt(long = "force-cleanup")]
+    force_cleanup: bool,
+
+    /// Create the paths which don't exist, and bind mount a path which is not a
+    /// mount point on itself, so any directory can be injected. The bind mounts
+    /// are removed on exit, but the directories created are kept
+    #[structopt(long = "create")]
+    create: bool,
+
+    /// Interval in milliseconds between the retries of a failed mount
+    /// operation, e.g. the umount of a busy mount, or the wait for the FUSE
+    /// mount to be ready
+    #[structopt(
+        long = "mount-retry-interval",
+        alias = "umount-retry-interval",
+        default_value = "500"
+    )]
+    mount_retry_interval: u64,
+
+    /// Times to retry a failed mount operation before giving up
+    #[structopt(
+        long = "mount-retry-times",
+        alias = "umount-retry-times",
+        default_value = "20"
+    )]
+    mount_retry_times: usize,
+
+    /// Recover and exit after injecting for this long, e.g. `30m`, in case the
+    /// controller which should stop toda has gone
+    #[structopt(long = "max-duration", parse(try_from_str = humantime::parse_duration))]
+    max_duration: Option<Duration>,
+
+    /// Dump the codes injected into the traced processes to this directory,
+    /// for debugging
+    #[structopt(long = "dump-codes-dir")]
+    dump_codes_dir: Option<PathBuf>,
+
+    /// Serve Prometheus metrics of the injected operations on this address,
+    /// e.g. `0.0.0.0:9090`
+    #[structopt(long = "metrics-addr")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Where the JSON-RPC server listens: `stdio` by default, `unix:<path>` for
+    /// a Unix socket, or `<host>:<port>` for TCP. The Unix socket is created
+    /// with mode 0600. The TCP server has no authentication, so anyone who can
+    /// reach it can change or stop the injection: bind it to `127.0.0.1` rather
+    /// than a public interface
+    #[structopt(long = "rpc-addr", env = "TODA_RPC_ADDR", default_value = "stdio")]
+    rpc_addr: ListenAddr,
+
+    /// File of the injector config in JSON, or `-` to read it from stdin. It's
+    /// validated and applied on start, and reloaded on SIGHUP unless it's read
+    /// from stdin. Then stdin is not left for the RPC, so it's rejected unless
+    /// another `--rpc-addr` is set
+    #[structopt(long = "config")]
+    config: Option<PathBuf>,
+
+    /// File to dump the injector config, the mounts and the replaced processes
+    /// to in JSON on SIGUSR1
+    #[structopt(
+        long = "dump-file",
+        env = "TODA_DUMP_FILE",
+        default_value = "/tmp/toda-state.json"
+    )]
+    dump_file: PathBuf,
+
+    /// What to do if ptrace is denied, e.g. by Yama or without CAP_SYS_PTRACE:
+    /// `fail` before anything is mounted, or inject with `mount-only`, so the
+    /// files opened before injection are not injected
+    #[structopt(long = "on-ptrace-denied", default_value = "fail")]
+    on_ptrace_denied: PtraceDenied,
+
+    /// Append a JSON line to this file for every injected fault, with the time,
+    /// the pid of the caller, the method, the path and what was injected.
+    /// Events are buffered, and dropped when the buffer is full rather than
+    /// delaying the requests
+    #[structopt(long = "event-log")]
+    event_log: Option<PathBuf>,
+
+    /// Print the mount operations and the fds which would be replaced, then exit
+    /// without mounting or replacing anything
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+
+    /// Print a JSON summary to stdout on exit: the processes and fds replaced or
+    /// skipped, and whether the recovery has succeeded. The exit code is
+    /// non-zero if the injection isn't applied to anything, e.g. no process is
+    /// replaced, or the recovery fails
+    #[structopt(long = "report")]
+    report: bool,
+
+    /// The level of logs, or comma separated directives with a level per module
+    /// as in `RUST_LOG`, e.g. `toda::ptrace=trace,toda::replacer=trace,info`.
+    /// The logs of every FUSE request are kept at most at `debug`, unless a
+    /// directive is set on `toda::hookfs` or `fuser`. `RUST_LOG` overrides it
     #[structopt(short = "v", long = "verbose", default_value = "trace")]
     verbose: String,
 }
 
-#[instrument(skip(option))]
-fn inject(option: Options, injector_config: Vec<InjectorConfig>) -> Result<MountInjectionGuard> {
-    info!("inject with config {:?}", injector_config);
-
-    let path = option.path.clone();
-
-    info!("canonicalizing path {}", path.display());
-    let path = path.canonicalize()?;
-
-    // 1. Set mount properties.
-    // 2. Mirror mount.
-    const NONE: Option<&'static [u8]> = None;
-    mount(NONE, path.as_path(), NONE, MsFlags::MS_PRIVATE, NONE).unwrap_or_else(|e| panic!("make-private failed: {}", e));
-    mount(Some(path.as_path()), path.as_path(), NONE, MsFlags::MS_BIND, NONE).unwrap_or_else(|e| panic!("mount bind failed: {}", e));
-
-    let replacer = if !option.mount_only {
-        let mut replacer = UnionReplacer::new();
-        replacer.prepare(&path, &path)?;
-
-        Some(replacer)
-    } else {
-        None
-    };
-
-    if let Err(err) = fuse_device::mkfuse_node() {
-        info!("fail to make /dev/fuse node: {}", err)
+impl Options {
+    // validate rejects the combinations of options which clap can't tell, as
+    // they depend on the values
+    fn validate(&self) -> std::result::Result<(), clap::Error> {
+        if self.config.as_deref() == Some(Path::new(STDIN_CONFIG))
+            && self.rpc_addr == ListenAddr::Stdio
+        {
+            return Err(clap::Error::with_description(
+                "`--config -` reads stdin, which is used by `--rpc-addr stdio`, \
+                 set another `--rpc-addr`",
+                ErrorKind::ArgumentConflict,
+            ));
+        }
+        Ok(())
     }
 
-    let mut injection = MountInjector::create_injection(&option.path, injector_config)?;
-    let mount_guard = injection.mount()?;
-    info!("mount successfully");
-
-    if let Some(mut replacer) = replacer {
-        // At this time, `mount --move` has already been executed.
-        // Our FUSE are mounted on the "path", so we
-        replacer.run()?;
-        drop(replacer);
-        info!("replacer detached");
+    fn config(&self, injectors: Vec<InjectorConfig>) -> Config {
+        Config {
+            paths: self.path.clone(),
+            mount_only: self.mount_only,
+            replacers: self.replacers,
+            target_process: self.target_process.clone(),
+            replace_concurrency: self.replace_concurrency,
+            mount_mode: self.mount_mode,
+            fuse_options: FuseOptions {
+                allow_other: !self.no_allow_other,
+                max_read: self.max_read,
+                direct_io: self.direct_io,
+                worker_threads: self.worker_threads,
+                max_pending_requests: self.max_pending_requests,
+            },
+            base_dir: self.mount_base_dir.clone(),
+            recover_on_crash: self.recover_on_crash,
+            force_cleanup: self.force_cleanup,
+            create: self.create,
+            retry_policy: RetryPolicy {
+                interval_ms: self.mount_retry_interval,
+                times: self.mount_retry_times,
+            },
+            on_ptrace_denied: self.on_ptrace_denied,
+            injectors,
+            events: None,
+            health: None,
+        }
     }
+}
 
-    info!("enable injection");
-    mount_guard.enable_injection();
-
-    Ok(mount_guard)
+const DEFAULT_VERBOSE: &str = "trace";
+
+// the modules logging every FUSE request, and the most verbose level they are
+// logged at unless they are set explicitly
+const NOISY_TARGETS: [(&str, LevelFilter); 2] = [
+    ("fuser", LevelFilter::INFO),
+    ("toda::hookfs", LevelFilter::DEBUG),
+];
+
+fn parse_level(level: &str) -> Option<LevelFilter> {
+    match level.trim().to_lowercase().as_str() {
+        "off" => Some(LevelFilter::OFF),
+        "error" => Some(LevelFilter::ERROR),
+        "warn" => Some(LevelFilter::WARN),
+        "info" => Some(LevelFilter::INFO),
+        "debug" => Some(LevelFilter::DEBUG),
+        "trace" => Some(LevelFilter::TRACE),
+        _ => None,
+    }
 }
 
-#[instrument(skip(option, mount_guard))]
-fn resume(option: Options, mount_guard: MountInjectionGuard) -> Result<()> {
-    info!("disable injection");
-    mount_guard.disable_injection();
+// build_env_filter parses the directives of `--verbose`, and caps the level of
+// the noisy modules which are not set by them
+fn build_env_filter(verbose: &str) -> Result<EnvFilter> {
+    let mut filter = EnvFilter::try_new(verbose)?;
+    let directives = verbose.split(',').map(str::trim).collect::<Vec<_>>();
+    let global = directives
+        .iter()
+        .filter_map(|directive| parse_level(directive))
+        .last()
+        .unwrap_or(LevelFilter::ERROR);
+    for (target, level) in NOISY_TARGETS.iter() {
+        let explicit = directives
+            .iter()
+            .any(|directive| directive.starts_with(target));
+        if !explicit && global > *level {
+            filter = filter.add_directive(format!("{}={}", target, level).parse::<Directive>()?);
+        }
+    }
+    Ok(filter)
+}
 
-    let path = option.path.clone();
+static SIGNAL_PIPE_WRITER: AtomicI32 = AtomicI32::new(-1);
 
-    info!("canonicalizing path {}", path.display());
-    let path = path.canonicalize()?;
-    let (_, new_path) = encode_path(&path)?;
+// every signal is sent through the pipe as a single byte, so a message can
+// never be read partially
+const EXIT_MSG: u8 = b'E';
+const RELOAD_MSG: u8 = b'R';
+const DUMP_MSG: u8 = b'D';
 
-    let replacer = if !option.mount_only {
-        let mut replacer = UnionReplacer::new();
-        replacer.prepare(&path, &new_path)?;
-        info!("running replacer");
-        let result = replacer.run();
-        info!("replace result: {:?}", result);
+#[derive(Debug, PartialEq, Eq)]
+enum SignalMsg {
+    Exit,
+    Reload,
+    Dump,
+}
 
-        Some(replacer)
-    } else {
-        None
+extern "C" fn signal_handler(sig: libc::c_int) {
+    let msg = match sig {
+        libc::SIGHUP => RELOAD_MSG,
+        libc::SIGUSR1 => DUMP_MSG,
+        _ => EXIT_MSG,
     };
+    // only async-signal-safe calls are allowed here. If the write fails, there
+    // is nothing to do in a signal handler
+    let writer = SIGNAL_PIPE_WRITER.load(Ordering::SeqCst);
+    let _ = write(writer, &[msg]);
+}
 
-    info!("recovering mount");
-    mount_guard.recover_mount()?;
-
-    info!("replacers detached");
-    info!("recover successfully");
+// wait_for_signal blocks until a message is read from the signal pipe. If the
+// pipe is closed, it returns `Exit`, so that the recovery still runs.
+fn wait_for_signal(chan: RawFd) -> Result<SignalMsg> {
+    let mut buf = [0u8; 1];
+    loop {
+        match read(chan, &mut buf) {
+            Ok(0) => {
+                warn!("signal pipe is closed");
+                return Ok(SignalMsg::Exit);
+            }
+            Ok(_) if buf[0] == RELOAD_MSG => return Ok(SignalMsg::Reload),
+            Ok(_) if buf[0] == DUMP_MSG => return Ok(SignalMsg::Dump),
+            Ok(_) => return Ok(SignalMsg::Exit),
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
 
-    drop(replacer);
-    Ok(())
+// exit_after sends `Exit` through the signal pipe after `duration`, so toda
+// recovers in the same way as on SIGTERM
+fn exit_after(duration: Duration) {
+    thread::spawn(move || {
+        thread::sleep(duration);
+        info!("max duration {:?} has passed", duration);
+        let writer = SIGNAL_PIPE_WRITER.load(Ordering::SeqCst);
+        if let Err(err) = write(writer, &[EXIT_MSG]) {
+            error!("fail to send exit message: {:?}", err);
+        }
+    });
 }
 
-static mut SIGNAL_PIPE_WRITER: RawFd = 0;
+// the config path reading the config from stdin
+const STDIN_CONFIG: &str = "-";
 
-const SIGNAL_MSG: [u8; 6] = *b"SIGNAL";
+fn read_config(path: &Path) -> Result<Vec<InjectorConfig>> {
+    let config = if path == Path::new(STDIN_CONFIG) {
+        let mut config = Vec::new();
+        io::stdin()
+            .read_to_end(&mut config)
+            .context("read config from stdin")?;
+        config
+    } else {
+        std::fs::read(path).context(format!("read config {}", path.display()))?
+    };
+    let config: Vec<InjectorConfig> =
+        serde_json::from_slice(&config).context(format!("parse config {}", path.display()))?;
+    validate_configs(&config).context(format!("validate config {}", path.display()))?;
+    Ok(config)
+}
 
-extern "C" fn signal_handler(_: libc::c_int) {
-    unsafe {
-        write(SIGNAL_PIPE_WRITER, &SIGNAL_MSG).unwrap();
-    }
+// reload_config reads the injector config from `path` and applies it to all
+// hookfs, in the same way as the `update` RPC
+fn reload_config(path: &Path, hookfs: &[Arc<HookFs>]) -> Result<usize> {
+    update_injectors(hookfs, read_config(path)?)
 }
 
-fn wait_for_signal(chan: RawFd) -> Result<()> {
-    let mut buf = vec![0u8; 6];
-    read(chan, buf.as_mut_slice())?;
+// dump_status writes the status of the injection to `path` in JSON. It's
+// written to a temporary file first, so a reader never sees a partial dump
+fn dump_status(path: &Path, status: &InjectionStatus) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, serde_json::to_vec_pretty(status)?)
+        .context(format!("write {}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, path).context(format!("rename to {}", path.display()))?;
     Ok(())
 }
 
 fn main() -> Result<()> {
     let (reader, writer) = pipe()?;
-    unsafe {
-        SIGNAL_PIPE_WRITER = writer;
-    }
+    SIGNAL_PIPE_WRITER.store(writer, Ordering::SeqCst);
 
     unsafe { signal(Signal::SIGINT, SigHandler::Handler(signal_handler))? };
     unsafe { signal(Signal::SIGTERM, SigHandler::Handler(signal_handler))? };
+    unsafe { signal(Signal::SIGHUP, SigHandler::Handler(signal_handler))? };
+    unsafe { signal(Signal::SIGUSR1, SigHandler::Handler(signal_handler))? };
 
     let option = Options::from_args();
-    let env_filter = EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_from(&option.verbose))
-        .or_else(|_| EnvFilter::try_new("trace"))
-        .unwrap();
+    if let Err(err) = option.validate() {
+        err.exit();
+    }
+    let (env_filter, invalid_verbose) = match EnvFilter::try_from_default_env() {
+        Ok(env_filter) => (env_filter, None),
+        Err(_) => match build_env_filter(&option.verbose) {
+            Ok(env_filter) => (env_filter, None),
+            Err(err) => (build_env_filter(DEFAULT_VERBOSE)?, Some(err)),
+        },
+    };
     tracing_subscriber::fmt()
         .with_writer(io::stderr)
         .with_env_filter(env_filter)
         .init();
+    if let Some(err) = invalid_verbose {
+        warn!(
+            "invalid --verbose `{}`: {}, use `{}` instead",
+            option.verbose, err, DEFAULT_VERBOSE
+        );
+    }
     info!("start with option: {:?}", option);
-    let mount_injector = inject(option.clone(), vec![]);
-
-    let status = match &mount_injector {
-        Ok(_) => Ok(()),
-        Err(e) => Err(anyhow::Error::msg(e.to_string())),
+    if let Some(dir) = &option.dump_codes_dir {
+        ptrace::set_code_dump_dir(dir)?;
+    }
+    let injector_config = match &option.config {
+        Some(path) => read_config(path)?,
+        None => Vec::new(),
     };
+    let mut config = option.config(injector_config);
+    if let (Some(path), false) = (&option.event_log, option.dry_run) {
+        config.events = Some(Arc::new(EventSink::to_file(path)?));
+    }
+    let health = Arc::new(Mutex::new(Health::Mounting));
+    config.health = Some(health.clone());
+    let toda = Toda::new(config);
+    if option.dry_run {
+        for line in toda.plan()? {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
 
+    // the RPC server is started before the injection, so the health can be
+    // watched while mounting. The other methods fail until the injection returns
+    let mount_state = Arc::new(RwLock::new(MountState::default()));
     let (tx, _rx) = mpsc::channel();
     {
-        let hookfs = match &mount_injector {
-            Ok(e) => Some(e.hookfs.clone().into()),
-            Err(_) => None,
-        };
-        thread::spawn(|| {
-            Runtime::new()
-                .expect("Failed to create Tokio runtime")
-                .block_on(start_server(jsonrpc::RpcImpl::new(
-                    Mutex::new(status),
-                    Mutex::new(tx),
-                    hookfs,
-                )));
+        let health = health.clone();
+        let mount_state = mount_state.clone();
+        let mount_mode = option.mount_mode;
+        let rpc_addr = option.rpc_addr.clone();
+        thread::spawn(move || {
+            let mut runtime = Runtime::new().expect("Failed to create Tokio runtime");
+            let rpc = jsonrpc::RpcImpl::new(Mutex::new(Ok(())), Mutex::new(tx), Vec::new())
+                .with_health(health)
+                .with_mount_state(mount_state)
+                .with_mount_mode(mount_mode);
+            if let Err(err) = runtime.block_on(start_server(rpc, rpc_addr)) {
+                error!("jsonrpc server exited: {:?}", err);
+            }
+        });
+    }
+    let mount_injector = toda.inject();
+
+    if let (Ok(_), Some(duration)) = (&mount_injector, option.max_duration) {
+        exit_after(duration);
+    }
+
+    // the error is kept as it is, so the RPC can tell its category
+    let (injection, status) = match mount_injector {
+        Ok(injection) => (Some(injection), Ok(())),
+        Err(e) => (None, Err(e)),
+    };
+
+    let mut summary = match (&injection, &status) {
+        (Some(injection), _) => injection.summary(),
+        (None, Err(err)) => Summary::failed(err),
+        (None, Ok(())) => Summary::default(),
+    };
+    let error = status.as_ref().err().map(|e| e.to_string());
+    let (hookfs, replaced) = match &injection {
+        Some(injection) => (injection.hookfs(), injection.replaced().to_vec()),
+        None => (Vec::new(), Vec::new()),
+    };
+    *mount_state.write().unwrap() = MountState {
+        status,
+        hookfs: hookfs.clone(),
+        replaced: replaced.clone(),
+    };
+    // the metrics are served on their own runtime, which lives as long as toda,
+    // even if the RPC server has exited
+    if let Some(addr) = option.metrics_addr {
+        let hookfs = hookfs.clone();
+        thread::spawn(move || {
+            let mut runtime = Runtime::new().expect("Failed to create Tokio runtime");
+            if let Err(err) = runtime.block_on(metrics::start_server(addr, hookfs)) {
+                error!("metrics server exited: {:?}", err);
+            }
         });
     }
     info!("waiting for signal to exit");
-    wait_for_signal(reader)?;
+    loop {
+        match wait_for_signal(reader)? {
+            SignalMsg::Exit => break,
+            SignalMsg::Reload => match &option.config {
+                Some(path) if path == Path::new(STDIN_CONFIG) => {
+                    warn!("config is read from stdin, ignore reloading")
+                }
+                Some(path) if injection.is_some() => match reload_config(path, &hookfs) {
+                    Ok(count) => info!("config reloaded, {} injectors are active", count),
+                    Err(err) => error!("fail to reload config: {:?}", err),
+                },
+                Some(_) => warn!("injection has failed, ignore reloading"),
+                None => warn!("no config file is specified, ignore reloading"),
+            },
+            SignalMsg::Dump => {
+                let status =
+                    jsonrpc::injection_status(&hookfs, &replaced, option.mount_mode, error.clone());
+                match dump_status(&option.dump_file, &status) {
+                    Ok(()) => info!("status dumped to {}", option.dump_file.display()),
+                    Err(err) => error!("fail to dump status: {:?}", err),
+                }
+            }
+        }
+    }
     info!("start to recover and exit");
-    if let Ok(v) = mount_injector {
-        resume(option, v)?;
+    let result = match injection {
+        Some(injection) => injection.resume(),
+        None => Ok(()),
+    };
+    if option.report {
+        summary.set_recovery(&result);
+        println!("{}", serde_json::to_string(&summary)?);
+        if result.is_ok() && !summary.succeeded() {
+            error!("injection isn't applied to anything");
+            std::process::exit(1);
+        }
     }
-    Ok(())
+    result
 }
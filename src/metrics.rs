@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info};
+
+use crate::hookfs::{CounterSnapshot, HookFs};
+
+// the longest request line read, which is plenty for `GET /metrics HTTP/1.1`
+const MAX_REQUEST_LINE: u64 = 1024;
+
+// start_server serves the counters of all hookfs in the Prometheus text format
+// on `addr`. It's a minimal HTTP server which answers `GET /metrics` only.
+pub async fn start_server(addr: SocketAddr, hookfs: Vec<Arc<HookFs>>) -> Result<()> {
+    let mut listener = TcpListener::bind(addr).await?;
+    info!("metrics server listening on {}", addr);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        debug!("metrics request from {}", peer);
+        let hookfs = hookfs.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve(stream, &hookfs).await {
+                error!("fail to serve metrics request: {:?}", err);
+            }
+        });
+    }
+}
+
+async fn serve(mut stream: TcpStream, hookfs: &[Arc<HookFs>]) -> Result<()> {
+    // only the request line is needed, the rest of the request is ignored. It
+    // may arrive in several segments, so it's read until the line ends
+    let mut buf = Vec::new();
+    BufReader::new((&mut stream).take(MAX_REQUEST_LINE))
+        .read_until(b'\n', &mut buf)
+        .await?;
+    let request_line = String::from_utf8_lossy(&buf);
+
+    let response = if request_line.starts_with("GET /metrics ") {
+        let body = render(hookfs);
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    Ok(())
+}
+
+// render sums up the counters of all mounts, keyed by method name
+fn render(hookfs: &[Arc<HookFs>]) -> String {
+    let mut counters: BTreeMap<String, CounterSnapshot> = BTreeMap::new();
+    let mut active_injectors = 0;
+    for hookfs in hookfs.iter() {
+        for (method, snapshot) in hookfs.counters.snapshot() {
+            let counter = counters.entry(method).or_default();
+            counter.intercepted += snapshot.intercepted;
+            counter.delayed += snapshot.delayed;
+            counter.failed += snapshot.failed;
+            counter.latency += snapshot.latency;
+            for (errno, count) in snapshot.errnos {
+                *counter.errnos.entry(errno).or_default() += count;
+            }
+        }
+        active_injectors += hookfs.injector.load().enabled_len();
+    }
+
+    let mut out = String::new();
+    // writing to a String never fails
+    let _ = write_metrics(&mut out, &counters, active_injectors);
+    out
+}
+
+fn write_metrics(
+    out: &mut String,
+    counters: &BTreeMap<String, CounterSnapshot>,
+    active_injectors: usize,
+) -> std::fmt::Result {
+    writeln!(
+        out,
+        "# HELP toda_operations_total Operations intercepted while injection is enabled."
+    )?;
+    writeln!(out, "# TYPE toda_operations_total counter")?;
+    for (method, counter) in counters.iter() {
+        writeln!(
+            out,
+            "toda_operations_total{{method=\"{}\"}} {}",
+            method, counter.intercepted
+        )?;
+    }
+
+    writeln!(
+        out,
+        "# HELP toda_faults_injected_total Faults injected into operations."
+    )?;
+    writeln!(out, "# TYPE toda_faults_injected_total counter")?;
+    for (method, counter) in counters.iter() {
+        for (errno, count) in counter.errnos.iter() {
+            writeln!(
+                out,
+                "toda_faults_injected_total{{method=\"{}\",errno=\"{}\"}} {}",
+                method, errno, count
+            )?;
+        }
+    }
+
+    writeln!(
+        out,
+        "# HELP toda_latency_injected_seconds Total latency injected into operations."
+    )?;
+    writeln!(out, "# TYPE toda_latency_injected_seconds counter")?;
+    let latency: Duration = counters.values().map(|counter| counter.latency).sum();
+    writeln!(
+        out,
+        "toda_latency_injected_seconds {}",
+        latency.as_secs_f64()
+    )?;
+
+    writeln!(
+        out,
+        "# HELP toda_active_injectors Injectors currently enabled."
+    )?;
+    writeln!(out, "# TYPE toda_active_injectors gauge")?;
+    writeln!(out, "toda_active_injectors {}", active_injectors)?;
+
+    Ok(())
+}
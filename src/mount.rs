@@ -1,13 +1,182 @@
 use std::fs::create_dir_all;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use tracing::info;
+use anyhow::{anyhow, Context, Result};
+use nix::mount::{mount, umount, MsFlags};
+use procfs::process::{self, MountOptFields, Process};
 use retry::delay::Fixed;
-use anyhow::{Context, Result};
 use retry::{retry, OperationResult};
-use nix::mount::{mount, MsFlags, umount};
-use procfs::process::{self, Process};
+use thiserror::Error;
+use tracing::{info, warn};
 
+#[derive(Error, Debug)]
+pub enum MountError {
+    #[error(
+        "cannot create {}, as it's on the read-only mount {}",
+        .target.display(),
+        .mount_point.display()
+    )]
+    ReadOnly {
+        target: PathBuf,
+        mount_point: PathBuf,
+    },
+
+    #[error(
+        "{} has been injected by toda, maybe by a run which was killed. The stale mounts can be removed by force cleanup",
+        .path.display()
+    )]
+    AlreadyInjected { path: PathBuf },
+
+    #[error(
+        "{} is not a mount point. Only a whole mount, e.g. a volume, can be injected",
+        .path.display()
+    )]
+    NotMountPoint { path: PathBuf },
+
+    #[error("{} doesn't exist. Create it first, or inject with `--create`", .path.display())]
+    NotFound { path: PathBuf },
+
+    #[error("cannot access {}: {source}", .path.display())]
+    Inaccessible {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error(
+        "{} overlaps {}. Nested paths can't be injected together, inject the outer one instead",
+        .path.display(),
+        .other.display()
+    )]
+    Overlapping { path: PathBuf, other: PathBuf },
+
+    #[error("the FUSE mount on {} doesn't respond: {source}", .path.display())]
+    FuseNotServing {
+        path: PathBuf,
+        source: anyhow::Error,
+    },
+}
+
+// check_overlapping rejects a path which is the same as, or nested in, another
+// one. The FUSE mount of the outer path would be moved away with its original
+// mount, or hide the inner one.
+pub fn check_overlapping(paths: &[PathBuf]) -> std::result::Result<(), MountError> {
+    for (index, path) in paths.iter().enumerate() {
+        for other in paths[..index].iter() {
+            if path.starts_with(other) || other.starts_with(path) {
+                return Err(MountError::Overlapping {
+                    path: path.clone(),
+                    other: other.clone(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// RetryPolicy controls how many times, and how often, a failed mount or umount
+// is retried
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub interval_ms: u64,
+    pub times: usize,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            interval_ms: 500,
+            times: 20,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn delays(&self) -> impl Iterator<Item = std::time::Duration> {
+        Fixed::from_millis(self.interval_ms).take(self.times)
+    }
+}
+
+// Propagation is the propagation type of a mount. A mount is private if none of
+// the fields is set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Propagation {
+    pub shared: bool,
+    pub slave: bool,
+    pub unbindable: bool,
+}
+
+impl Propagation {
+    // restore applies the propagation type to the mount on `path`, which has
+    // been made private. A shared mount joins a new peer group, as the old one
+    // can't be joined again. For the same reason, a slave mount can't be
+    // restored.
+    pub fn restore<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if self.slave {
+            warn!(
+                "{} was a slave mount, which can't be restored",
+                path.display()
+            );
+        }
+
+        let flags = if self.unbindable {
+            MsFlags::MS_UNBINDABLE
+        } else if self.shared {
+            MsFlags::MS_SHARED
+        } else {
+            return Ok(());
+        };
+        const NONE: Option<&'static [u8]> = None;
+        mount(NONE, path, NONE, flags, NONE)
+            .context(format!("restore propagation of {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+// MountSource is what a mount was made from, so the filesystem can be mounted
+// again if the mount itself is lost
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountSource {
+    pub source: String,
+    pub fs_type: String,
+    // the directory of the filesystem mounted, which is not `/` for a bind mount
+    pub root: String,
+    pub flags: MsFlags,
+    pub options: String,
+}
+
+impl MountSource {
+    // remount mounts the filesystem on `target` again. A bind mount of a
+    // directory can't be made again without the mount it was bound from
+    pub fn remount<P: AsRef<Path>>(&self, target: P) -> Result<()> {
+        let target = target.as_ref();
+        if self.root != "/" {
+            return Err(anyhow!(
+                "{} is a bind mount of {} on {}, which can't be mounted again",
+                target.display(),
+                self.root,
+                self.source
+            ));
+        }
+        create_dir_all(target)?;
+        mount(
+            Some(self.source.as_str()),
+            target,
+            Some(self.fs_type.as_str()),
+            self.flags,
+            Some(self.options.as_str()),
+        )
+        .context(format!(
+            "mount {} of type {} on {}",
+            self.source,
+            self.fs_type,
+            target.display()
+        ))?;
+
+        Ok(())
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct MountsInfo {
@@ -34,22 +203,199 @@ impl MountsInfo {
         Ok(false)
     }
 
+    // propagation returns the propagation type of the mount on `path`
+    pub fn propagation<P: AsRef<Path>>(&self, path: P) -> Propagation {
+        self.mounts
+            .iter()
+            .rev()
+            .find(|item| item.mount_point == path.as_ref())
+            .map(|item| {
+                let mut propagation = Propagation::default();
+                for field in item.opt_fields.iter() {
+                    match field {
+                        MountOptFields::Shared(_) => propagation.shared = true,
+                        MountOptFields::Master(_) => propagation.slave = true,
+                        MountOptFields::Unbindable => propagation.unbindable = true,
+                        _ => {}
+                    }
+                }
+                propagation
+            })
+            .unwrap_or_default()
+    }
+
+    // source returns what the mount on `path` is made from
+    pub fn source<P: AsRef<Path>>(&self, path: P) -> Option<MountSource> {
+        let item = self
+            .mounts
+            .iter()
+            .rev()
+            .find(|item| item.mount_point == path.as_ref())?;
+
+        let mut flags = MsFlags::empty();
+        for (option, flag) in [
+            ("ro", MsFlags::MS_RDONLY),
+            ("nosuid", MsFlags::MS_NOSUID),
+            ("nodev", MsFlags::MS_NODEV),
+            ("noexec", MsFlags::MS_NOEXEC),
+        ]
+        .iter()
+        {
+            if item.mount_options.contains_key(*option) {
+                flags |= *flag;
+            }
+        }
+        // `ro` and `rw` are also listed in the superblock options, but they are
+        // set by the flags
+        let options = item
+            .super_options
+            .iter()
+            .filter(|(key, _)| key.as_str() != "ro" && key.as_str() != "rw")
+            .map(|(key, value)| match value {
+                Some(value) => format!("{}={}", key, value),
+                None => key.to_owned(),
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        Some(MountSource {
+            source: item
+                .mount_source
+                .clone()
+                .unwrap_or_else(|| "none".to_owned()),
+            fs_type: item.fs_type.clone(),
+            root: item.root.clone(),
+            flags,
+            options,
+        })
+    }
+
+    // mount_id returns the id of the mount visible on exactly `path`
+    pub fn mount_id<P: AsRef<Path>>(&self, path: P) -> Option<i32> {
+        self.mounts
+            .iter()
+            .rev()
+            .find(|item| item.mount_point == path.as_ref())
+            .map(|item| item.mnt_id)
+    }
+
+    // mount_point returns where the mount with `id` is now. The id is only
+    // reused after the mount is gone
+    pub fn mount_point(&self, id: i32) -> Option<&Path> {
+        self.mounts
+            .iter()
+            .find(|item| item.mnt_id == id)
+            .map(|item| item.mount_point.as_path())
+    }
+
+    // mount_of returns the mount which `path` is on
+    fn mount_of<P: AsRef<Path>>(&self, path: P) -> Option<&process::MountInfo> {
+        // the mount point with the most components is the innermost one. If
+        // several mounts are on the same point, the last one is visible
+        self.mounts
+            .iter()
+            .filter(|item| path.as_ref().starts_with(&item.mount_point))
+            .max_by_key(|item| item.mount_point.components().count())
+    }
+
+    // is_fuse returns whether the mount visible on `path` is a FUSE mount named
+    // `fsname`
+    pub fn is_fuse<P: AsRef<Path>>(&self, path: P, fsname: &str) -> bool {
+        self.mounts
+            .iter()
+            .rev()
+            .find(|item| item.mount_point == path.as_ref())
+            .map(|item| {
+                item.fs_type.starts_with("fuse") && item.mount_source.as_deref() == Some(fsname)
+            })
+            .unwrap_or(false)
+    }
+
+    // is_mount_point returns whether a mount is on exactly `path`, rather than on
+    // one of its ancestors like `non_root`
+    pub fn is_mount_point<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.mounts
+            .iter()
+            .any(|item| item.mount_point == path.as_ref())
+    }
+
+    // is_readonly returns whether `path` is on a read-only mount
+    pub fn is_readonly<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.mount_of(path)
+            .map(|item| item.mount_options.contains_key("ro"))
+            .unwrap_or(false)
+    }
+
+    // create_target creates the directory `target`, and fails with a clear error
+    // if it's on a read-only mount
+    fn create_target(&self, target: &Path) -> Result<()> {
+        if !target.exists() && self.is_readonly(target) {
+            let mount_point = self
+                .mount_of(target)
+                .map(|item| item.mount_point.clone())
+                .unwrap_or_default();
+            return Err(MountError::ReadOnly {
+                target: target.to_owned(),
+                mount_point,
+            }
+            .into());
+        }
+
+        create_dir_all(target)?;
+        Ok(())
+    }
+
     pub fn move_mount<P1: AsRef<Path>, P2: AsRef<Path>>(
         &self,
         original_path: P1,
         target_path: P2,
+        retry_policy: RetryPolicy,
+    ) -> Result<()> {
+        self.create_target(target_path.as_ref())?;
+
+        retry(retry_policy.delays(), || {
+            match mount::<_, _, str, str>(
+                Some(original_path.as_ref()),
+                target_path.as_ref(),
+                None,
+                MsFlags::MS_MOVE,
+                None,
+            ) {
+                Err(err) => {
+                    info!("move mount returns error: {:?}", err);
+                    OperationResult::Retry(err)
+                }
+                Ok(()) => OperationResult::Ok(()),
+            }
+        })
+        .context(format!(
+            "source: {}, target: {}",
+            original_path.as_ref().display(),
+            target_path.as_ref().display()
+        ))?;
+
+        Ok(())
+    }
+
+    // mirror_mount bind mounts `original_path` on `target_path`, and leaves the
+    // original mount as it is
+    pub fn mirror_mount<P1: AsRef<Path>, P2: AsRef<Path>>(
+        &self,
+        original_path: P1,
+        target_path: P2,
     ) -> Result<()> {
-        create_dir_all(target_path.as_ref())?;
+        self.create_target(target_path.as_ref())?;
 
-        mount::<_, _, str, str>(
+        const NONE: Option<&'static [u8]> = None;
+        mount(
             Some(original_path.as_ref()),
             target_path.as_ref(),
-            None,
-            MsFlags::MS_MOVE,
-            None,
+            NONE,
+            MsFlags::MS_BIND,
+            NONE,
         )
         .context(format!(
-            "source: {}, target: {}",
+            "bind mount source: {}, target: {}",
             original_path.as_ref().display(),
             target_path.as_ref().display()
         ))?;
@@ -61,18 +407,31 @@ impl MountsInfo {
         &self,
         original_path: P1,
         target_path: P2,
+        retry_policy: RetryPolicy,
     ) -> Result<()> {
         const NONE: Option<&'static [u8]> = None;
-        mount(Some(original_path.as_ref()), target_path.as_ref(), NONE, MsFlags::MS_BIND, NONE).unwrap_or_else(|e| panic!("mount bind failed: {}", e));
-        retry(Fixed::from_millis(500).take(20), || {
+        mount(
+            Some(original_path.as_ref()),
+            target_path.as_ref(),
+            NONE,
+            MsFlags::MS_BIND,
+            NONE,
+        )
+        .context(format!(
+            "bind mount source: {}, target: {}",
+            original_path.as_ref().display(),
+            target_path.as_ref().display()
+        ))?;
+        retry(retry_policy.delays(), || {
             if let Err(err) = umount(original_path.as_ref()) {
                 info!("umount returns error: {:?}", err);
                 OperationResult::Retry(err)
             } else {
                 OperationResult::Ok(())
             }
-        })?;
-        
+        })
+        .context(format!("umount {}", original_path.as_ref().display()))?;
+
         Ok(())
     }
 }
@@ -1,32 +1,186 @@
 use std::ffi::OsStr;
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
 
-use anyhow::{anyhow, Result};
-use nix::mount::umount;
-use retry::delay::Fixed;
+use anyhow::{anyhow, Context, Result};
+use nix::mount::{umount, umount2, MntFlags};
+use nix::sys::stat;
+use nix::unistd::close;
 use retry::{retry, OperationResult};
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
 
+use crate::hookfs::EventSink;
 use crate::injector::{InjectorConfig, MultiInjector};
-use crate::{hookfs, mount, stop};
+use crate::mount::{MountSource, Propagation, RetryPolicy};
+use crate::utils::encode_path;
+use crate::{fuse_device, hookfs, mount, stop};
+
+static ACTIVE_MOUNTS: AtomicUsize = AtomicUsize::new(0);
+
+// ActiveMount counts a mount from before its FUSE thread is spawned until the
+// thread exits. The runtime is shared by all mounts, so only the last one can
+// drop it, and it's built again for the next mount.
+struct ActiveMount;
+
+impl ActiveMount {
+    fn new() -> ActiveMount {
+        ACTIVE_MOUNTS.fetch_add(1, Ordering::SeqCst);
+        ActiveMount
+    }
+}
+
+impl Drop for ActiveMount {
+    fn drop(&mut self) {
+        if ACTIVE_MOUNTS.fetch_sub(1, Ordering::SeqCst) == 1 {
+            hookfs::runtime::shutdown(|| ACTIVE_MOUNTS.load(Ordering::SeqCst) > 0);
+        }
+    }
+}
+
+const FSNAME: &str = "toda";
+
+// MountMode is how the original files are kept accessible for the FUSE server
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum MountMode {
+    // the original mount is moved away, and the FUSE mount takes its place
+    Move,
+    // the original mount is bind mounted to another path, and the FUSE mount
+    // is mounted over it. It works on filesystems which can't be moved, but
+    // the fds opened before injection are not affected
+    Direct,
+}
+
+impl Default for MountMode {
+    fn default() -> Self {
+        MountMode::Move
+    }
+}
+
+impl FromStr for MountMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "move" => Ok(MountMode::Move),
+            "direct" => Ok(MountMode::Direct),
+            _ => Err(anyhow!(
+                "unknown mount mode `{}`, valid modes are: move, direct",
+                s
+            )),
+        }
+    }
+}
+
+// FuseOptions are the options of the FUSE mount
+#[derive(Debug, Clone, Copy)]
+pub struct FuseOptions {
+    // allow the users other than root to access the mount
+    pub allow_other: bool,
+    pub max_read: Option<u32>,
+    // with direct io, every read and write is served by toda instead of the
+    // page cache. Then a corrupted read is seen by every reader, while without
+    // it the corrupted data may be cached and seen by later reads, or a clean
+    // cached copy may be returned instead
+    pub direct_io: bool,
+    // the count of threads serving the FUSE requests, or the count of CPUs if
+    // it's None. The latency injected doesn't hold a thread, as it's waited
+    // asynchronously, so the threads only limit the requests being forwarded
+    // to the original files at the same time.
+    pub worker_threads: Option<usize>,
+    // the max count of requests being handled at once, including the delayed
+    // ones, or unlimited if it's None. Past it, the requests are queued by the
+    // kernel, which bounds the memory of toda under heavy concurrent io, e.g.
+    // io_uring
+    pub max_pending_requests: Option<usize>,
+}
+
+impl Default for FuseOptions {
+    fn default() -> Self {
+        FuseOptions {
+            allow_other: true,
+            max_read: None,
+            direct_io: false,
+            worker_threads: None,
+            max_pending_requests: None,
+        }
+    }
+}
+
+impl FuseOptions {
+    fn mount_options(&self) -> Vec<String> {
+        let mut options = vec![format!("fsname={}", FSNAME)];
+        if self.allow_other {
+            options.push("allow_other".to_owned());
+        }
+        options.push("default_permissions".to_owned());
+        if let Some(max_read) = self.max_read {
+            options.push(format!("max_read={}", max_read));
+        }
+        options
+    }
+}
 
 #[derive(Debug)]
 pub struct MountInjector {
     original_path: PathBuf,
     new_path: PathBuf,
     injector_config: Vec<InjectorConfig>,
+    retry_policy: RetryPolicy,
+    mode: MountMode,
+    fuse_options: FuseOptions,
+    propagation: Propagation,
+    events: Option<Arc<EventSink>>,
 }
 
 pub struct MountInjectionGuard {
     original_path: PathBuf,
     new_path: PathBuf,
+    // the id of the original mount on `new_path`, recorded right after it's
+    // moved there, to find it again on recovery
+    moved_mount: Option<i32>,
+    // whether `new_path` is created by the injection, so it's removed after
+    // recovery
+    created_new_path: bool,
     pub hookfs: Arc<hookfs::HookFs>,
     handler: Option<JoinHandle<Result<()>>>,
+    retry_policy: RetryPolicy,
+    mode: MountMode,
+    // the propagation type of the original mount, restored after recovery
+    propagation: Propagation,
+    // what the original mount is made from, to mount it again if the moved
+    // mount is lost
+    source: Option<MountSource>,
+    // set by whoever restores the original mount first, either `recover_mount`
+    // or the supervisor started by `supervise`
+    recovering: Arc<AtomicBool>,
 }
 
 impl MountInjectionGuard {
+    pub fn original_path(&self) -> &Path {
+        &self.original_path
+    }
+
+    // new_path returns where the original mount is kept during injection
+    pub fn new_path(&self) -> &Path {
+        &self.new_path
+    }
+
+    // moved_path returns where the original mount is now, read from the mount
+    // table, which is `new_path` unless it has been moved since injection
+    pub fn moved_path(&self) -> Result<PathBuf> {
+        moved_path(&self.new_path, self.moved_mount)
+    }
+
+    pub fn mode(&self) -> MountMode {
+        self.mode
+    }
+
     pub fn enable_injection(&self) {
         self.hookfs.enable_injection();
     }
@@ -35,10 +189,79 @@ impl MountInjectionGuard {
         self.hookfs.disable_injection();
     }
 
+    // supervise watches the FUSE thread, and restores the original mount if the
+    // thread exits while injection is still enabled. Otherwise every operation on
+    // the path would hang forever. `before_recover` is called with the original
+    // path and the new path before the FUSE mount is removed, and
+    // `after_recover` with the result of the recovery.
+    pub fn supervise<F, G>(&mut self, before_recover: F, after_recover: G) -> Result<()>
+    where
+        F: FnOnce(&Path, &Path) + Send + 'static,
+        G: FnOnce(&Result<()>) + Send + 'static,
+    {
+        let fuse_handler = self.handler.take().ok_or(anyhow!("handler is empty"))?;
+
+        let hookfs = self.hookfs.clone();
+        let recovering = self.recovering.clone();
+        let original_path = self.original_path.clone();
+        let new_path = self.new_path.clone();
+        let moved_mount = self.moved_mount;
+        let created_new_path = self.created_new_path;
+        let retry_policy = self.retry_policy;
+        let mode = self.mode;
+        let propagation = self.propagation;
+        let source = self.source.clone();
+        self.handler = Some(std::thread::spawn(box move || {
+            let result = match fuse_handler.join() {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!("FUSE thread panicked")),
+            };
+
+            if !hookfs.injection_enabled() || recovering.swap(true, Ordering::SeqCst) {
+                return result;
+            }
+            error!("FUSE thread exited unexpectedly: {:?}", result);
+
+            let recovered = (|| {
+                let moved_to = moved_path(&new_path, moved_mount)?;
+                before_recover(&original_path, &moved_to);
+
+                // nobody serves the FUSE mount any more, so it's detached lazily
+                if let Err(err) = umount2(original_path.as_path(), MntFlags::MNT_DETACH) {
+                    info!("umount returns error: {:?}", err);
+                }
+                restore_mount(
+                    &original_path,
+                    &moved_to,
+                    retry_policy,
+                    mode,
+                    source.as_ref(),
+                )?;
+                propagation.restore(&original_path)?;
+                remove_new_path(&new_path, created_new_path);
+                info!("mount recovered after FUSE thread exited");
+
+                Ok(())
+            })();
+            after_recover(&recovered);
+
+            recovered
+        }));
+
+        Ok(())
+    }
+
     pub fn recover_mount(mut self) -> Result<()> {
+        let handler = self.handler.take().ok_or(anyhow!("handler is empty"))?;
+
+        if self.recovering.swap(true, Ordering::SeqCst) {
+            info!("mount has already been recovered by the supervisor");
+            return handler.join().unwrap();
+        }
+
         let mount_point = self.original_path.clone();
 
-        retry(Fixed::from_millis(500).take(20), || {
+        retry(self.retry_policy.delays(), || {
             if let Err(err) = umount(mount_point.as_path()) {
                 info!("umount returns error: {:?}", err);
                 OperationResult::Retry(err)
@@ -48,91 +271,430 @@ impl MountInjectionGuard {
         })?;
 
         info!("unmount successfully!");
-        self.handler
-            .take()
-            .ok_or(anyhow!("handler is empty"))?
-            .join()
-            .unwrap()?;
-
-        let new_path = self.new_path.clone();
-        let original_path = self.original_path;
+        handler.join().unwrap()?;
 
-        let mounts = mount::MountsInfo::parse_mounts()?;
+        restore_mount(
+            &self.original_path,
+            &self.moved_path()?,
+            self.retry_policy,
+            self.mode,
+            self.source.as_ref(),
+        )?;
+        self.propagation.restore(&self.original_path)?;
+        remove_new_path(&self.new_path, self.created_new_path);
+        Ok(())
+    }
+}
 
-        if mounts.non_root(&original_path)? {
-            // TODO: make the parent mount points private before move mount points
-            mounts.bind_mount(new_path, original_path)?;
-            // mounts.move_mount(new_path, original_path)?;
-        } else {
-            return Err(anyhow!("inject on a root mount"));
+impl Drop for DetachedMount {
+    fn drop(&mut self) {
+        if self.recovered {
+            return;
         }
+        if let Err(err) = self.recover() {
+            error!(
+                "fail to recover detached mount on {}: {:?}",
+                self.original_path.display(),
+                err
+            );
+        }
+    }
+}
+
+// DetachedMount is a FUSE mount made by `mount_detached`, which is served
+// through `fd` outside of toda. It's recovered on drop unless `recover_mount`
+// has been called, so an error before the fd is passed on doesn't leave the
+// original mount hidden under a FUSE mount which nobody serves.
+#[derive(Debug)]
+pub struct DetachedMount {
+    fd: RawFd,
+    original_path: PathBuf,
+    new_path: PathBuf,
+    moved_mount: Option<i32>,
+    created_new_path: bool,
+    retry_policy: RetryPolicy,
+    mode: MountMode,
+    propagation: Propagation,
+    source: Option<MountSource>,
+    recovered: bool,
+}
+
+impl DetachedMount {
+    // fd returns the fd of the fuse device to serve the mount with. It's kept
+    // open until `recover_mount`, so it can be passed to a forked child or sent
+    // over a unix socket in the meantime
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    pub fn original_path(&self) -> &Path {
+        &self.original_path
+    }
+
+    pub fn new_path(&self) -> &Path {
+        &self.new_path
+    }
 
+    // recover_mount removes the FUSE mount and restores the original one. The
+    // mount is detached lazily, as the server may have gone
+    pub fn recover_mount(mut self) -> Result<()> {
+        self.recover()
+    }
+
+    fn recover(&mut self) -> Result<()> {
+        self.recovered = true;
+        let umounted = umount2(self.original_path.as_path(), MntFlags::MNT_DETACH)
+            .context(format!("umount {}", self.original_path.display()));
+        // the fd is closed even if the umount fails, so it's never leaked
+        close(self.fd).ok();
+        umounted?;
+        restore_mount(
+            &self.original_path,
+            &moved_path(&self.new_path, self.moved_mount)?,
+            self.retry_policy,
+            self.mode,
+            self.source.as_ref(),
+        )?;
+        self.propagation.restore(&self.original_path)?;
+        remove_new_path(&self.new_path, self.created_new_path);
         Ok(())
     }
 }
 
+// moved_path finds where the mount with `id` is now. It's `new_path` if the id
+// is unknown, or the mount is gone, so `restore_mount` falls back to mounting
+// the source again.
+fn moved_path(new_path: &Path, id: Option<i32>) -> Result<PathBuf> {
+    let id = match id {
+        Some(id) => id,
+        None => return Ok(new_path.to_owned()),
+    };
+    let mounts = mount::MountsInfo::parse_mounts()?;
+    match mounts.mount_point(id) {
+        Some(path) if path != new_path => {
+            warn!(
+                "the original mount has been moved from {} to {}",
+                new_path.display(),
+                path.display()
+            );
+            Ok(path.to_owned())
+        }
+        Some(_) => Ok(new_path.to_owned()),
+        None => {
+            warn!("the original mount {} is gone", id);
+            Ok(new_path.to_owned())
+        }
+    }
+}
+
+// remove_new_path removes the directory the original mount was kept in after
+// recovery, if it's created by the injection. A directory which is not empty
+// may still keep something, e.g. after an incomplete recovery, so it's kept.
+fn remove_new_path(new_path: &Path, created: bool) {
+    if !created {
+        return;
+    }
+    match std::fs::remove_dir(new_path) {
+        Ok(()) => info!("removed {}", new_path.display()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) if err.raw_os_error() == Some(libc::ENOTEMPTY) => warn!(
+            "{} is not empty after recovery, the recovery may be incomplete, so it's kept",
+            new_path.display()
+        ),
+        Err(err) => warn!("fail to remove {}: {}", new_path.display(), err),
+    }
+}
+
+// restore_mount puts the original mount back on `original_path`. If the moved
+// mount is lost, e.g. its directory was deleted, the filesystem is mounted again
+// from `source` instead.
+fn restore_mount(
+    original_path: &Path,
+    new_path: &Path,
+    retry_policy: RetryPolicy,
+    mode: MountMode,
+    source: Option<&MountSource>,
+) -> Result<()> {
+    if mode == MountMode::Direct {
+        // the original mount has never been moved. Only the mirror on the new
+        // path and the one made on the original path before injection are
+        // removed
+        for path in [new_path, original_path].iter() {
+            retry(retry_policy.delays(), || {
+                if let Err(err) = umount(*path) {
+                    info!("umount returns error: {:?}", err);
+                    OperationResult::Retry(err)
+                } else {
+                    OperationResult::Ok(())
+                }
+            })
+            .context(format!("umount {}", path.display()))?;
+        }
+        return Ok(());
+    }
+
+    let mounts = mount::MountsInfo::parse_mounts()?;
+
+    if !mounts.non_root(original_path)? {
+        return Err(anyhow!("inject on a root mount"));
+    }
+
+    let err = if mounts.is_mount_point(new_path) {
+        // the FUSE mount point may have been removed after it's unmounted
+        std::fs::create_dir_all(original_path)?;
+        // TODO: make the parent mount points private before move mount points
+        // mounts.move_mount(new_path, original_path, retry_policy)?;
+        match mounts.bind_mount(new_path, original_path, retry_policy) {
+            Ok(()) => return Ok(()),
+            // only the umount of the new path has failed
+            Err(err) if mount::MountsInfo::parse_mounts()?.is_mount_point(original_path) => {
+                return Err(err)
+            }
+            Err(err) => err,
+        }
+    } else {
+        anyhow!("{} is not a mount point any more", new_path.display())
+    };
+
+    let source = match source {
+        Some(source) => source,
+        None => return Err(err.context("the source of the original mount is unknown")),
+    };
+    warn!(
+        "fail to move back the original mount from {}: {:?}. Mount {} on {} again, the data only \
+         kept by the lost mount, e.g. on a tmpfs, is not recovered",
+        new_path.display(),
+        err,
+        source.source,
+        original_path.display()
+    );
+    source.remount(original_path)
+}
+
+// wait_for_fuse waits until the FUSE mount on `path` shows up in the mount table
+// and serves a stat of its root
+fn wait_for_fuse(path: &Path, retry_policy: RetryPolicy) -> Result<()> {
+    retry(retry_policy.delays(), || {
+        let result = mount::MountsInfo::parse_mounts().and_then(|mounts| {
+            if !mounts.is_fuse(path, FSNAME) {
+                return Err(anyhow!("FUSE is not mounted yet"));
+            }
+            stat::stat(path)?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => OperationResult::Ok(()),
+            Err(err) => {
+                info!("FUSE mount is not ready: {:?}", err);
+                OperationResult::Retry(err)
+            }
+        }
+    })
+    .map_err(|err| {
+        let source = match err {
+            retry::Error::Operation { error, .. } => error,
+            retry::Error::Internal(msg) => anyhow!(msg),
+        };
+        mount::MountError::FuseNotServing {
+            path: path.to_owned(),
+            source,
+        }
+        .into()
+    })
+}
+
 impl MountInjector {
+    // create_injection prepares the injection on `path`. The original mount is
+    // kept under `base_dir` during injection, or beside `path` if it's None.
     pub fn create_injection<P: AsRef<Path>>(
         path: P,
+        base_dir: Option<&Path>,
         injector_config: Vec<InjectorConfig>,
+        retry_policy: RetryPolicy,
     ) -> Result<MountInjector> {
-        let original_path: PathBuf = path.as_ref().to_owned();
-
-        let mut base_path: PathBuf = path.as_ref().to_owned();
-        if !base_path.pop() {
-            return Err(anyhow!("path is the root"));
-        }
-
-        let mut new_path: PathBuf = base_path;
-        let original_filename = original_path
-            .file_name()
-            .ok_or(anyhow!("the path terminates in `..` or `/`"))?
-            .to_str()
-            .ok_or(anyhow!("path with non-UTF-8 character"))?;
-        let new_filename = format!("__chaosfs__{}__", original_filename);
-        new_path.push(new_filename.as_str());
+        let (original_path, new_path) = encode_path(path, base_dir)?;
 
         Ok(MountInjector {
             original_path,
             new_path,
             injector_config,
+            retry_policy,
+            mode: MountMode::default(),
+            fuse_options: FuseOptions::default(),
+            propagation: Propagation::default(),
+            events: None,
         })
     }
 
-    // This method should be called in host namespace
-    pub fn mount(&mut self) -> Result<MountInjectionGuard> {
-        let original_path = self.original_path.clone();
-        let new_path = self.new_path.clone();
+    pub fn with_propagation(mut self, propagation: Propagation) -> Self {
+        self.propagation = propagation;
+        self
+    }
+
+    pub fn with_fuse_options(mut self, fuse_options: FuseOptions) -> Self {
+        self.fuse_options = fuse_options;
+        self
+    }
+
+    pub fn with_events(mut self, events: Option<Arc<EventSink>>) -> Self {
+        self.events = events;
+        self
+    }
+
+    pub fn with_mode(mut self, mode: MountMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    // is_injected returns whether a FUSE mount of toda is on the path
+    pub fn is_injected(&self) -> Result<bool> {
+        Ok(mount::MountsInfo::parse_mounts()?.is_fuse(&self.original_path, FSNAME))
+    }
+
+    // cleanup removes the mounts left on the path by a run which was killed
+    // without resuming, e.g. by SIGKILL, and restores the original mount. The
+    // killed run is assumed to have used the same mode and paths.
+    pub fn cleanup(&self) -> Result<()> {
+        let mounts = mount::MountsInfo::parse_mounts()?;
+        if mounts.is_fuse(&self.original_path, FSNAME) {
+            info!("umount stale FUSE on {}", self.original_path.display());
+            // nobody serves the mount anymore, so it's detached lazily in case
+            // it's still busy
+            umount2(self.original_path.as_path(), MntFlags::MNT_DETACH)
+                .context(format!("umount {}", self.original_path.display()))?;
+        }
+        if mounts.is_mount_point(&self.new_path) {
+            info!(
+                "restore the original mount from {}",
+                self.new_path.display()
+            );
+            // the run which left the mounts didn't tell the source
+            restore_mount(
+                &self.original_path,
+                &self.new_path,
+                self.retry_policy,
+                self.mode,
+                None,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // plan describes the mount operations which `mount` would make
+    pub fn plan(&self) -> Vec<String> {
+        let original_path = self.original_path.display();
+        let new_path = self.new_path.display();
+        let backend = match self.mode {
+            MountMode::Move => format!("move mount {} to {}", original_path, new_path),
+            MountMode::Direct => format!("bind mount {} on {}", original_path, new_path),
+        };
+        vec![
+            backend,
+            format!(
+                "mount FUSE on {} over {} with options {}",
+                original_path,
+                new_path,
+                self.fuse_options.mount_options().join(",")
+            ),
+        ]
+    }
 
+    // mount_backend moves the original mount to the new path, or mirrors it there
+    // in the direct mode, and returns what the original mount is made from, the
+    // id of the mount on the new path, and whether the new path is created by it
+    fn mount_backend(&self) -> Result<(Option<MountSource>, Option<i32>, bool)> {
         let mounts = mount::MountsInfo::parse_mounts()?;
+        let source = mounts.source(&self.original_path);
+        let created_new_path = !self.new_path.exists();
 
-        if mounts.non_root(&original_path)? {
-            // TODO: make the parent mount points private before move mount points
-            mounts.move_mount(original_path, new_path)?;
-        } else {
-            return Err(anyhow!("inject on a root mount"));
+        match self.mode {
+            MountMode::Move if mounts.non_root(&self.original_path)? => {
+                // TODO: make the parent mount points private before move mount points
+                mounts.move_mount(&self.original_path, &self.new_path, self.retry_policy)?;
+            }
+            MountMode::Move => return Err(anyhow!("inject on a root mount")),
+            MountMode::Direct => mounts.mirror_mount(&self.original_path, &self.new_path)?,
         }
+        let moved_mount = mount::MountsInfo::parse_mounts()?.mount_id(&self.new_path);
+
+        Ok((source, moved_mount, created_new_path))
+    }
+
+    // mount_detached sets up the mounts like `mount`, but the FUSE mount is
+    // served by whoever the returned fd is passed to, instead of a thread of
+    // toda. The server is expected to serve the original files on the new path.
+    pub fn mount_detached(&mut self) -> Result<DetachedMount> {
+        let (source, moved_mount, created_new_path) = self.mount_backend()?;
 
-        let injectors = MultiInjector::build(self.injector_config.clone())?;
+        match fuse_device::mount_fd(&self.original_path, &self.fuse_options.mount_options()) {
+            Ok(fd) => Ok(DetachedMount {
+                fd,
+                original_path: self.original_path.clone(),
+                new_path: self.new_path.clone(),
+                moved_mount,
+                created_new_path,
+                retry_policy: self.retry_policy,
+                mode: self.mode,
+                propagation: self.propagation,
+                source,
+                recovered: false,
+            }),
+            Err(err) => {
+                match restore_mount(
+                    &self.original_path,
+                    &self.new_path,
+                    self.retry_policy,
+                    self.mode,
+                    source.as_ref(),
+                ) {
+                    Ok(()) => remove_new_path(&self.new_path, created_new_path),
+                    Err(err) => error!("fail to restore mount: {:?}", err),
+                }
+                Err(err)
+            }
+        }
+    }
 
-        let hookfs = Arc::new(hookfs::HookFs::new(
+    // This method should be called in host namespace
+    pub fn mount(&mut self) -> Result<MountInjectionGuard> {
+        // build injectors first, so an invalid config won't leave the mount moved
+        let injectors = MultiInjector::build_for_mount(
+            self.injector_config.clone(),
             &self.original_path,
-            &self.new_path,
-            injectors,
-        ));
+            self.fuse_options.direct_io,
+        )?;
+        if let Some(threads) = self.fuse_options.worker_threads {
+            hookfs::runtime::set_worker_threads(threads)?;
+        }
+        if let Some(max) = self.fuse_options.max_pending_requests {
+            hookfs::runtime::set_max_pending_requests(max)?;
+        }
+
+        let (source, moved_mount, created_new_path) = self.mount_backend()?;
+
+        let hookfs = Arc::new(
+            hookfs::HookFs::new(&self.original_path, &self.new_path, injectors)
+                .with_direct_io(self.fuse_options.direct_io)
+                .with_events(self.events.clone()),
+        );
 
         let original_path = self.original_path.clone();
         let new_path = self.new_path.clone();
         let cloned_hookfs = hookfs.clone();
+        let options = self.fuse_options.mount_options();
 
         let (before_mount_waiter, before_mount_guard) = stop::lock();
+        // counted before the thread is spawned, so a mount exiting early can't
+        // drop the runtime while this one is coming up
+        let active_mount = ActiveMount::new();
         let handler = std::thread::spawn(box move || {
+            let _active_mount = active_mount;
             let fs = hookfs::AsyncFileSystem::from(cloned_hookfs);
 
             std::fs::create_dir_all(new_path.as_path())?;
 
-            let args = ["allow_other", "fsname=toda", "default_permissions"];
-            let flags: Vec<_> = args
+            let flags: Vec<_> = options
                 .iter()
                 .flat_map(|item| vec![OsStr::new("-o"), OsStr::new(item)])
                 .collect();
@@ -142,20 +704,40 @@ impl MountInjector {
             drop(before_mount_guard);
             fuser::mount(fs, &original_path, &flags)?;
 
-            drop(hookfs::runtime::RUNTIME.write().unwrap().take().unwrap());
-
             Ok(())
         });
-        // TODO: remove this. But wait for FUSE gets up
+        // `fuser::mount` doesn't tell when the mount is ready
         // Related Issue: https://github.com/zargony/fuse-rs/issues/9
         before_mount_waiter.wait();
-        std::thread::sleep(std::time::Duration::from_millis(200));
+        if let Err(err) = wait_for_fuse(&self.original_path, self.retry_policy) {
+            // the FUSE thread may be still alive, so the mount is detached lazily
+            if let Err(err) = umount2(self.original_path.as_path(), MntFlags::MNT_DETACH) {
+                info!("umount returns error: {:?}", err);
+            }
+            if let Err(err) = restore_mount(
+                &self.original_path,
+                &self.new_path,
+                self.retry_policy,
+                self.mode,
+                source.as_ref(),
+            ) {
+                error!("fail to restore mount: {:?}", err);
+            }
+            return Err(err);
+        }
 
         Ok(MountInjectionGuard {
             handler: Some(handler),
             hookfs,
             original_path: self.original_path.clone(),
             new_path: self.new_path.clone(),
+            moved_mount,
+            created_new_path,
+            retry_policy: self.retry_policy,
+            mode: self.mode,
+            propagation: self.propagation,
+            source,
+            recovering: Arc::new(AtomicBool::new(false)),
         })
     }
 }
@@ -0,0 +1,123 @@
+// The registers and the instruction used to run a syscall in the tracee differ
+// on every architecture. Only x86_64 and aarch64 are supported.
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("toda only supports x86_64 and aarch64");
+
+use anyhow::{anyhow, Result};
+use nix::unistd::Pid;
+
+pub type Regs = libc::user_regs_struct;
+
+// the syscall instruction, written at the pc of the tracee. It's written as a
+// whole word in little endian, so the rest of the word is zeroed.
+#[cfg(target_arch = "x86_64")]
+pub const SYSCALL_INSTRUCTION: u64 = 0x050f;
+// svc #0
+#[cfg(target_arch = "aarch64")]
+pub const SYSCALL_INSTRUCTION: u64 = 0xd400_0001;
+
+#[cfg(target_arch = "x86_64")]
+pub fn getregs(pid: Pid) -> Result<Regs> {
+    Ok(nix::sys::ptrace::getregs(pid)?)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn setregs(pid: Pid, regs: Regs) -> Result<()> {
+    Ok(nix::sys::ptrace::setregs(pid, regs)?)
+}
+
+// nix only supports PTRACE_GETREGS on x86, while aarch64 has only the regset
+// requests
+#[cfg(target_arch = "aarch64")]
+pub fn getregs(pid: Pid) -> Result<Regs> {
+    let mut regs: Regs = unsafe { std::mem::zeroed() };
+    regset(libc::PTRACE_GETREGSET, pid, &mut regs)?;
+    Ok(regs)
+}
+
+#[cfg(target_arch = "aarch64")]
+pub fn setregs(pid: Pid, mut regs: Regs) -> Result<()> {
+    regset(libc::PTRACE_SETREGSET, pid, &mut regs)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn regset(request: libc::c_uint, pid: Pid, regs: &mut Regs) -> Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: regs as *mut Regs as *mut libc::c_void,
+        iov_len: std::mem::size_of::<Regs>(),
+    };
+    let ret = unsafe {
+        libc::ptrace(
+            request,
+            pid.as_raw(),
+            libc::NT_PRSTATUS,
+            &mut iov as *mut libc::iovec as *mut libc::c_void,
+        )
+    };
+    nix::errno::Errno::result(ret)?;
+    Ok(())
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn pc(regs: &Regs) -> u64 {
+    regs.rip
+}
+
+#[cfg(target_arch = "aarch64")]
+pub fn pc(regs: &Regs) -> u64 {
+    regs.pc
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn set_pc(regs: &mut Regs, pc: u64) {
+    regs.rip = pc;
+}
+
+#[cfg(target_arch = "aarch64")]
+pub fn set_pc(regs: &mut Regs, pc: u64) {
+    regs.pc = pc;
+}
+
+// set_syscall puts the syscall number into rax and the arguments into rdi, rsi,
+// rdx, r10, r8 and r9
+#[cfg(target_arch = "x86_64")]
+pub fn set_syscall(regs: &mut Regs, id: u64, args: &[u64]) -> Result<()> {
+    if args.len() > 6 {
+        return Err(anyhow!("too many arguments for a syscall"));
+    }
+    regs.rax = id;
+    let mut slots = [
+        &mut regs.rdi,
+        &mut regs.rsi,
+        &mut regs.rdx,
+        &mut regs.r10,
+        &mut regs.r8,
+        &mut regs.r9,
+    ];
+    for (slot, arg) in slots.iter_mut().zip(args) {
+        **slot = *arg;
+    }
+    Ok(())
+}
+
+// set_syscall puts the syscall number into x8 and the arguments into x0-x5
+#[cfg(target_arch = "aarch64")]
+pub fn set_syscall(regs: &mut Regs, id: u64, args: &[u64]) -> Result<()> {
+    if args.len() > 6 {
+        return Err(anyhow!("too many arguments for a syscall"));
+    }
+    regs.regs[8] = id;
+    regs.regs[..args.len()].copy_from_slice(args);
+    Ok(())
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn syscall_ret(regs: &Regs) -> u64 {
+    regs.rax
+}
+
+#[cfg(target_arch = "aarch64")]
+pub fn syscall_ret(regs: &Regs) -> u64 {
+    regs.regs[0]
+}
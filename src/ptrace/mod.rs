@@ -1,8 +1,11 @@
+mod arch;
+
 use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::ffi::CString;
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 use nix::errno::Errno;
@@ -12,36 +15,107 @@ use nix::sys::uio::{process_vm_writev, IoVec, RemoteIoVec};
 use nix::sys::{ptrace, wait};
 use nix::unistd::Pid;
 use nix::Error::Sys;
+use once_cell::sync::OnceCell;
 use procfs::process::Task;
-use procfs::ProcError;
-use retry::delay::Fixed;
-use retry::Error::{self, Operation};
-use retry::OperationResult;
+use thiserror::Error;
 use tracing::{error, info, instrument, trace, warn};
-use Error::Internal;
+
+// the time given to the codes run by the replacers to finish
+pub const RUN_CODES_TIMEOUT: Duration = Duration::from_secs(10);
+
+// the interval to poll the status of a running tracee
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Error, Debug)]
+pub enum PtraceError {
+    #[error("codes in process {pid} didn't finish in {timeout:?}")]
+    TimedOut { pid: i32, timeout: Duration },
+
+    #[error(
+        "ptrace is denied on process {pid}{}, so the fds can't be replaced. Set \
+         kernel.yama.ptrace_scope=0 or run with CAP_SYS_PTRACE",
+        .scope.map(|scope| format!(" (kernel.yama.ptrace_scope is {})", scope)).unwrap_or_default()
+    )]
+    Denied { pid: i32, scope: Option<u32> },
+}
 
 // There should be only one PtraceManager in one thread. But as we don't implement TLS
 // , we cannot use thread-local variables safely.
 #[derive(Debug, Default)]
 pub struct PtraceManager {
     counter: RefCell<HashMap<i32, i32>>,
+    // the tasks attached for each traced process
+    tasks: RefCell<HashMap<i32, HashSet<i32>>>,
 }
 
 thread_local! {
     static PTRACE_MANAGER: PtraceManager = PtraceManager::default()
 }
 
+// the directory to dump the codes injected by `run_codes`. Dumping is disabled
+// unless it's set.
+static CODE_DUMP_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+pub fn set_code_dump_dir<P: AsRef<Path>>(dir: P) -> Result<()> {
+    CODE_DUMP_DIR
+        .set(dir.as_ref().to_owned())
+        .map_err(|_| anyhow!("code dump dir has already been set"))
+}
+
+// dump_codes writes the codes into the dump directory for debugging. It never
+// fails, as a debugging helper shouldn't abort the replacement.
+fn dump_codes(pid: i32, addr: u64, codes: &[u8]) {
+    if let Some(dir) = CODE_DUMP_DIR.get() {
+        let path = dir.join(format!("{}-{:x}.bin", pid, addr));
+        match std::fs::write(&path, codes) {
+            Ok(()) => info!("codes are dumped to {}", path.display()),
+            Err(err) => warn!("fail to dump codes to {}: {:?}", path.display(), err),
+        }
+    }
+}
+
 pub fn trace(pid: i32) -> Result<TracedProcess> {
     PTRACE_MANAGER.with(|pm| pm.trace(pid))
 }
 
+// probe attaches to `pid` and detaches at once, to find out whether ptrace is
+// allowed before any process is replaced. When it's denied by Yama, seccomp or
+// the lack of CAP_SYS_PTRACE, it's denied on every process alike. Other errors,
+// e.g. the process has exited, tell nothing, so they are ignored.
+pub fn probe(pid: i32) -> std::result::Result<(), PtraceError> {
+    match trace(pid) {
+        Ok(_) => Ok(()),
+        Err(err) => match err.downcast_ref::<nix::Error>() {
+            Some(Sys(Errno::EPERM)) => Err(PtraceError::Denied {
+                pid,
+                scope: yama_scope(),
+            }),
+            _ => {
+                info!("ptrace probe on {} is inconclusive: {:?}", pid, err);
+                Ok(())
+            }
+        },
+    }
+}
+
+// yama_scope reads kernel.yama.ptrace_scope, which is None without Yama
+fn yama_scope() -> Option<u32> {
+    std::fs::read_to_string("/proc/sys/kernel/yama/ptrace_scope")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
 fn thread_is_gone(state: char) -> bool {
     // return true if the process is Zombie or Dead
     state == 'Z' || state == 'x' || state == 'X'
 }
 
+// attach_task attaches to the task and waits for it to stop. It returns false if
+// the task has exited before being attached.
 #[instrument]
-fn attach_task(task: &Task) -> Result<()> {
+fn attach_task(task: &Task) -> Result<bool> {
     let pid = Pid::from_raw(task.tid);
     let process = procfs::process::Process::new(task.tid)?;
 
@@ -51,7 +125,8 @@ fn attach_task(task: &Task) -> Result<()> {
             if errno == Errno::ESRCH
                 || (errno == Errno::EPERM && thread_is_gone(process.stat.state)) =>
         {
-            info!("task {} doesn't exist, maybe has stopped", task.tid)
+            info!("task {} doesn't exist, maybe has stopped", task.tid);
+            return Ok(false);
         }
         Err(err) => {
             warn!("attach error: {:?}", err);
@@ -69,7 +144,7 @@ fn attach_task(task: &Task) -> Result<()> {
         Err(err) => warn!("fail to wait for process({}): {:?}", pid, err),
     };
 
-    Ok(())
+    Ok(true)
 }
 
 impl PtraceManager {
@@ -79,6 +154,7 @@ impl PtraceManager {
         let pid = Pid::from_raw(pid);
 
         let mut counter_ref = self.counter.borrow_mut();
+        let mut tasks_ref = self.tasks.borrow_mut();
         match counter_ref.get_mut(&raw_pid) {
             Some(count) => *count += 1,
             None => {
@@ -87,6 +163,8 @@ impl PtraceManager {
                 let mut iterations = 2;
                 let mut traced_tasks = HashSet::<i32>::new();
 
+                // new threads can be created by the threads which haven't been
+                // stopped yet, so keep scanning until no new thread is found
                 while iterations > 0 {
                     let mut new_threads_found = false;
                     let process = procfs::process::Process::new(raw_pid)?;
@@ -96,10 +174,18 @@ impl PtraceManager {
                                 continue;
                             }
 
-                            if let Ok(()) = attach_task(&task) {
-                                trace!("newly traced task: {}", task.tid);
-                                new_threads_found = true;
-                                traced_tasks.insert(task.tid);
+                            match attach_task(&task) {
+                                Ok(true) => {
+                                    trace!("newly traced task: {}", task.tid);
+                                    new_threads_found = true;
+                                    traced_tasks.insert(task.tid);
+                                }
+                                Ok(false) => trace!("task {} has exited", task.tid),
+                                Err(err) => {
+                                    // don't leave the attached tasks stopped
+                                    detach_tasks(&traced_tasks);
+                                    return Err(err);
+                                }
                             }
                         }
                     }
@@ -111,10 +197,13 @@ impl PtraceManager {
 
                 info!("trace process: {} successfully", pid);
                 counter_ref.insert(raw_pid, 1);
+                tasks_ref.insert(raw_pid, traced_tasks);
             }
         }
 
-        Ok(TracedProcess { pid: raw_pid })
+        let mut tids: Vec<_> = tasks_ref[&raw_pid].iter().cloned().collect();
+        tids.sort_unstable();
+        Ok(TracedProcess { pid: raw_pid, tids })
     }
 
     #[instrument(skip(self))]
@@ -128,53 +217,10 @@ impl PtraceManager {
                     counter_ref.remove(&pid);
 
                     info!("detach process: {}", pid);
-                    if let Err(err) = retry::retry::<_, _, _, anyhow::Error, _>(
-                        Fixed::from_millis(500).take(20),
-                        || match procfs::process::Process::new(pid) {
-                            Err(ProcError::NotFound(_)) => {
-                                info!("process {} not found", pid);
-                                OperationResult::Ok(())
-                            }
-                            Err(err) => {
-                                warn!("fail to detach task: {}, retry", pid);
-                                OperationResult::Retry(err.into())
-                            }
-                            Ok(process) => match process.tasks() {
-                                Err(err) => OperationResult::Retry(err.into()),
-                                Ok(tasks) => {
-                                    for task in tasks {
-                                        if let Ok(task) = task {
-                                            match ptrace::detach(Pid::from_raw(task.tid), None) {
-                                                    Ok(()) => {
-                                                        info!("successfully detached task: {}", task.tid);
-                                                    }
-                                                    Err(Sys(Errno::ESRCH)) => trace!(
-                                                        "task {} doesn't exist, maybe has stopped or not traced",
-                                                        task.tid
-                                                    ),
-                                                    Err(err) => {
-                                                        warn!("fail to detach: {:?}", err)
-                                                    },
-                                                }
-                                            trace!("detach task: {} successfully", task.tid);
-                                        }
-                                    }
-                                    info!("detach process: {} successfully", pid);
-                                    OperationResult::Ok(())
-                                }
-                            },
-                        },
-                    ) {
-                        warn!("fail to detach: {:?}", err);
-                        match err {
-                            Operation {
-                                error: e,
-                                total_delay: _,
-                                tries: _,
-                            } => return Err(e),
-                            Internal(err) => error!("internal error: {:?}", err),
-                        }
-                    };
+                    if let Some(tasks) = self.tasks.borrow_mut().remove(&pid) {
+                        detach_tasks(&tasks);
+                    }
+                    info!("detach process: {} successfully", pid);
                 }
 
                 Ok(())
@@ -184,9 +230,23 @@ impl PtraceManager {
     }
 }
 
+// detach_tasks detaches all the tasks which have been attached. The tasks which
+// have exited are ignored.
+fn detach_tasks(tasks: &HashSet<i32>) {
+    for tid in tasks.iter() {
+        match ptrace::detach(Pid::from_raw(*tid), None) {
+            Ok(()) => info!("successfully detached task: {}", tid),
+            Err(Sys(Errno::ESRCH)) => trace!("task {} doesn't exist, maybe has exited", tid),
+            Err(err) => warn!("fail to detach task {}: {:?}", tid, err),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TracedProcess {
     pub pid: i32,
+    // all the threads of the process which are stopped by ptrace
+    pub tids: Vec<i32>,
 }
 
 impl Clone for TracedProcess {
@@ -199,16 +259,16 @@ impl Clone for TracedProcess {
 impl TracedProcess {
     #[instrument]
     fn protect(&self) -> Result<ThreadGuard> {
-        let regs = ptrace::getregs(Pid::from_raw(self.pid))?;
+        let regs = arch::getregs(Pid::from_raw(self.pid))?;
 
-        let rip = regs.rip;
+        let pc = arch::pc(&regs);
         trace!("protecting regs: {:?}", regs);
-        let rip_ins = ptrace::read(Pid::from_raw(self.pid), rip as *mut libc::c_void)?;
+        let pc_ins = ptrace::read(Pid::from_raw(self.pid), pc as *mut libc::c_void)?;
 
         let guard = ThreadGuard {
             tid: self.pid,
             regs,
-            rip_ins,
+            pc_ins,
         };
         Ok(guard)
     }
@@ -217,11 +277,13 @@ impl TracedProcess {
     fn with_protect<R, F: Fn(&Self) -> Result<R>>(&self, f: F) -> Result<R> {
         let guard = self.protect()?;
 
-        let ret = f(self)?;
+        // the guard restores the registers and the instruction on both the
+        // success and the error path
+        let ret = f(self);
 
         drop(guard);
 
-        Ok(ret)
+        ret
     }
 
     #[instrument]
@@ -231,37 +293,19 @@ impl TracedProcess {
         self.with_protect(|thread| -> Result<u64> {
             let pid = Pid::from_raw(thread.pid);
 
-            let mut regs = ptrace::getregs(pid)?;
-            let cur_ins_ptr = regs.rip;
-
-            regs.rax = id;
-            for (index, arg) in args.iter().enumerate() {
-                // All these registers are hard coded for x86 platform
-                if index == 0 {
-                    regs.rdi = *arg
-                } else if index == 1 {
-                    regs.rsi = *arg
-                } else if index == 2 {
-                    regs.rdx = *arg
-                } else if index == 3 {
-                    regs.r10 = *arg
-                } else if index == 4 {
-                    regs.r8 = *arg
-                } else if index == 5 {
-                    regs.r9 = *arg
-                } else {
-                    return Err(anyhow!("too many arguments for a syscall"));
-                }
-            }
+            let mut regs = arch::getregs(pid)?;
+            let cur_ins_ptr = arch::pc(&regs);
+
+            arch::set_syscall(&mut regs, id, args)?;
             trace!("setting regs for pid: {:?}, regs: {:?}", pid, regs);
-            ptrace::setregs(pid, regs)?;
+            arch::setregs(pid, regs)?;
 
-            // We only support x86-64 platform now, so using hard coded `LittleEndian` here is ok.
+            // both x86_64 and aarch64 are little endian
             unsafe {
                 ptrace::write(
                     pid,
                     cur_ins_ptr as *mut libc::c_void,
-                    0x050f as *mut libc::c_void,
+                    arch::SYSCALL_INSTRUCTION as *mut libc::c_void,
                 )?
             };
             ptrace::step(pid, None)?;
@@ -275,11 +319,17 @@ impl TracedProcess {
                 }
             }
 
-            let regs = ptrace::getregs(pid)?;
+            let regs = arch::getregs(pid)?;
+            let ret = arch::syscall_ret(&regs);
+
+            trace!("returned: {:?}", ret);
 
-            trace!("returned: {:?}", regs.rax);
+            // a raw syscall returns the negated errno on failure
+            if (-4095..0).contains(&(ret as i64)) {
+                return Err(Sys(Errno::from_i32(-(ret as i64) as i32)).into());
+            }
 
-            Ok(regs.rax)
+            Ok(ret)
         })
     }
 
@@ -289,25 +339,30 @@ impl TracedProcess {
         let flags = MapFlags::MAP_PRIVATE | MapFlags::MAP_ANON;
 
         self.syscall(
-            9,
+            libc::SYS_mmap as u64,
             &[0, length, prot.bits() as u64, flags.bits() as u64, fd, 0],
         )
     }
 
     #[instrument]
     pub fn munmap(&self, addr: u64, len: u64) -> Result<u64> {
-        self.syscall(11, &[addr, len])
+        self.syscall(libc::SYS_munmap as u64, &[addr, len])
     }
 
     #[instrument(skip(f))]
     pub fn with_mmap<R, F: Fn(&Self, u64) -> Result<R>>(&self, len: u64, f: F) -> Result<R> {
         let addr = self.mmap(len, 0)?;
 
-        let ret = f(self, addr)?;
+        let ret = f(self, addr);
 
-        self.munmap(addr, len)?;
+        // unmap even if `f` fails, so nothing is left in the tracee
+        match (self.munmap(addr, len), &ret) {
+            (Err(err), Ok(_)) => return Err(err),
+            (Err(err), Err(_)) => warn!("fail to unmap {:X} in {}: {:?}", addr, self.pid, err),
+            _ => {}
+        }
 
-        Ok(ret)
+        ret
     }
 
     #[instrument]
@@ -318,7 +373,7 @@ impl TracedProcess {
         self.with_mmap(path.len() as u64, |process, addr| {
             process.write_mem(addr, path)?;
 
-            self.syscall(80, &[addr])?;
+            self.syscall(libc::SYS_chdir as u64, &[addr])?;
             Ok(())
         })
     }
@@ -339,12 +394,19 @@ impl TracedProcess {
         Ok(())
     }
 
+    // run_codes runs the codes in the tracee until they hit the trap at the end.
+    // If the trap isn't hit within `timeout`, the tracee is stopped, its
+    // registers are restored and `PtraceError::TimedOut` is returned.
     #[instrument(skip(codes))]
-    pub fn run_codes<F: Fn(u64) -> Result<(u64, Vec<u8>)>>(&self, codes: F) -> Result<()> {
+    pub fn run_codes<F: Fn(u64) -> Result<(u64, Vec<u8>)>>(
+        &self,
+        codes: F,
+        timeout: Duration,
+    ) -> Result<()> {
         let pid = Pid::from_raw(self.pid);
 
-        let regs = ptrace::getregs(pid)?;
-        let (_, ins) = codes(regs.rip)?; // generate codes to get length
+        let regs = arch::getregs(pid)?;
+        let (_, ins) = codes(arch::pc(&regs))?; // generate codes to get length
 
         self.with_mmap(ins.len() as u64 + 16, |_, addr| {
             self.with_protect(|_| {
@@ -352,26 +414,43 @@ impl TracedProcess {
 
                 let end_addr = addr + ins.len() as u64;
                 trace!("write instructions to addr: {:X}-{:X}", addr, end_addr);
+                dump_codes(self.pid, addr, &ins);
                 self.write_mem(addr, &ins)?;
 
-                let mut regs = ptrace::getregs(pid)?;
-                trace!("modify rip to addr: {:X}", addr + offset);
-                regs.rip = addr + offset;
-                ptrace::setregs(pid, regs)?;
+                let mut regs = arch::getregs(pid)?;
+                trace!("modify pc to addr: {:X}", addr + offset);
+                arch::set_pc(&mut regs, addr + offset);
+                arch::setregs(pid, regs)?;
 
-                let regs = ptrace::getregs(pid)?;
+                let regs = arch::getregs(pid)?;
                 info!("current registers: {:?}", regs);
 
+                let deadline = Instant::now() + timeout;
                 loop {
                     info!("run instructions");
                     ptrace::cont(pid, None)?;
 
                     info!("wait for pid: {:?}", pid);
-                    let status = wait::waitpid(pid, None)?;
+                    let status = match wait_until(pid, deadline)? {
+                        Some(status) => status,
+                        None => {
+                            warn!("codes in process {} timed out", pid);
+                            stop_task(pid, timeout)?;
+                            return Err(PtraceError::TimedOut {
+                                pid: self.pid,
+                                timeout,
+                            }
+                            .into());
+                        }
+                    };
                     info!("wait status: {:?}", status);
+                    if let wait::WaitStatus::Exited(..) | wait::WaitStatus::Signaled(..) = status {
+                        // the tracee has been killed, e.g. by SIGKILL
+                        return Err(Sys(Errno::ESRCH).into());
+                    }
 
                     use nix::sys::signal::SIGTRAP;
-                    let regs = ptrace::getregs(pid)?;
+                    let regs = arch::getregs(pid)?;
 
                     info!("current registers: {:?}", regs);
                     match status {
@@ -387,6 +466,38 @@ impl TracedProcess {
     }
 }
 
+// wait_until polls the status of the task until it changes. It returns None if
+// the status doesn't change before the deadline.
+fn wait_until(pid: Pid, deadline: Instant) -> Result<Option<wait::WaitStatus>> {
+    loop {
+        match wait::waitpid(pid, Some(wait::WaitPidFlag::WNOHANG))? {
+            wait::WaitStatus::StillAlive => {}
+            status => return Ok(Some(status)),
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        std::thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+// stop_task stops a running tracee with SIGSTOP, so its registers can be
+// restored. The signal is suppressed when the tracee is continued or detached.
+fn stop_task(pid: Pid, timeout: Duration) -> Result<()> {
+    // the signal is sent to the thread, as the other threads are already stopped
+    let ret = unsafe { libc::syscall(libc::SYS_tgkill, pid.as_raw(), pid.as_raw(), libc::SIGSTOP) };
+    if ret != 0 {
+        return Err(nix::Error::last().into());
+    }
+
+    match wait_until(pid, Instant::now() + timeout)? {
+        Some(wait::WaitStatus::Stopped(..)) => Ok(()),
+        Some(status) => Err(anyhow!("process {} isn't stopped: {:?}", pid, status)),
+        // the tracee is stuck in an uninterruptible syscall
+        None => Err(anyhow!("fail to stop process {} in {:?}", pid, timeout)),
+    }
+}
+
 impl Drop for TracedProcess {
     fn drop(&mut self) {
         trace!("dropping traced process: {}", self.pid);
@@ -403,21 +514,30 @@ impl Drop for TracedProcess {
 #[derive(Debug)]
 struct ThreadGuard {
     tid: i32,
-    regs: libc::user_regs_struct,
-    rip_ins: i64,
+    regs: arch::Regs,
+    pc_ins: i64,
 }
 
 impl Drop for ThreadGuard {
+    // the tracee may be still running if it can't be stopped after a timeout, so
+    // the failures are logged instead of panicking
     fn drop(&mut self) {
         let pid = Pid::from_raw(self.tid);
-        unsafe {
+        let result = unsafe {
             ptrace::write(
                 pid,
-                self.regs.rip as *mut libc::c_void,
-                self.rip_ins as *mut libc::c_void,
+                arch::pc(&self.regs) as *mut libc::c_void,
+                self.pc_ins as *mut libc::c_void,
             )
-            .unwrap();
+        };
+        if let Err(err) = result {
+            error!(
+                "fail to restore instruction of task {}: {:?}",
+                self.tid, err
+            );
+        }
+        if let Err(err) = arch::setregs(pid, self.regs) {
+            error!("fail to restore registers of task {}: {:?}", self.tid, err);
         }
-        ptrace::setregs(pid, self.regs).unwrap();
     }
 }
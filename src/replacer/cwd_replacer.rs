@@ -4,8 +4,9 @@ use std::path::{Path, PathBuf};
 use anyhow::Result;
 use tracing::{error, info, trace};
 
-use super::utils::all_processes;
-use super::{ptrace, Replacer};
+use super::errors::ReplacerError;
+use super::utils::{all_processes, resolve_path, ProcessFilter};
+use super::{ptrace, ReplaceReport, Replacer};
 
 #[derive(Debug)]
 pub struct CwdReplacer {
@@ -17,10 +18,20 @@ impl CwdReplacer {
     pub fn prepare<P1: AsRef<Path>, P2: AsRef<Path>>(
         detect_path: P1,
         new_path: P2,
+    ) -> Result<CwdReplacer> {
+        Self::prepare_filtered(detect_path, new_path, None)
+    }
+
+    pub fn prepare_filtered<P1: AsRef<Path>, P2: AsRef<Path>>(
+        detect_path: P1,
+        new_path: P2,
+        filter: Option<&ProcessFilter>,
     ) -> Result<CwdReplacer> {
         info!("preparing cmdreplacer");
 
-        let processes = all_processes()?
+        let detect_path = resolve_path(detect_path.as_ref());
+
+        let processes = all_processes(filter)?
             .filter_map(|process| -> Option<_> {
                 let pid = process.pid;
                 trace!("itering proc: {}", pid);
@@ -33,7 +44,7 @@ impl CwdReplacer {
                     }
                 }
             })
-            .filter(|(_, path)| path.starts_with(detect_path.as_ref()))
+            .filter(|(_, path)| path.starts_with(&detect_path))
             .filter_map(|(pid, _)| match ptrace::trace(pid) {
                 Ok(process) => Some(process),
                 Err(err) => {
@@ -51,12 +62,26 @@ impl CwdReplacer {
 }
 
 impl Replacer for CwdReplacer {
-    fn run(&mut self) -> Result<()> {
+    fn run_detailed(&mut self) -> ReplaceReport {
         info!("running cwd replacer");
+        let mut report = ReplaceReport::default();
         for process in self.processes.iter() {
-            process.chdir(&self.new_path)?;
+            match process.chdir(&self.new_path) {
+                Ok(()) => report.replaced(process.pid, "cwd"),
+                Err(err) => {
+                    let err = ReplacerError::with_pid(process.pid, err);
+                    report.failed(process.pid, "cwd", err);
+                }
+            }
         }
 
-        Ok(())
+        report
+    }
+
+    fn plan(&self) -> Vec<String> {
+        self.processes
+            .iter()
+            .map(|process| format!("pid {}: chdir to {}", process.pid, self.new_path.display()))
+            .collect()
     }
 }
@@ -0,0 +1,65 @@
+use nix::errno::Errno;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ReplacerError {
+    #[error("process {pid} has exited")]
+    ProcessGone { pid: i32 },
+
+    #[error("permission denied to ptrace process {pid}")]
+    PtracePermission { pid: i32 },
+
+    #[error("syscall failed in process {pid} with errno {errno}")]
+    Syscall { pid: i32, errno: Errno },
+
+    #[error("fail to replace processes: {}", format_errors(.errors))]
+    Processes { errors: Vec<(i32, ReplacerError)> },
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+pub type Result<T> = std::result::Result<T, ReplacerError>;
+
+impl ReplacerError {
+    // with_pid converts an error returned while replacing the process `pid`,
+    // keeping the errno of a failed ptrace request or syscall
+    pub fn with_pid(pid: i32, err: anyhow::Error) -> ReplacerError {
+        match err.downcast_ref::<nix::Error>() {
+            Some(nix::Error::Sys(Errno::ESRCH)) => ReplacerError::ProcessGone { pid },
+            Some(nix::Error::Sys(Errno::EPERM)) => ReplacerError::PtracePermission { pid },
+            Some(nix::Error::Sys(errno)) => ReplacerError::Syscall { pid, errno: *errno },
+            _ => ReplacerError::Other(err),
+        }
+    }
+
+    // is_fatal returns false if the error only means the process has gone, which
+    // is expected as processes can exit at any time
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            ReplacerError::ProcessGone { .. } => false,
+            ReplacerError::Processes { errors } => errors.iter().any(|(_, err)| err.is_fatal()),
+            _ => true,
+        }
+    }
+
+    // is_ptrace_denied returns true if any process can't be traced because of
+    // the permission, e.g. without CAP_SYS_PTRACE
+    pub fn is_ptrace_denied(&self) -> bool {
+        match self {
+            ReplacerError::PtracePermission { .. } => true,
+            ReplacerError::Processes { errors } => {
+                errors.iter().any(|(_, err)| err.is_ptrace_denied())
+            }
+            _ => false,
+        }
+    }
+}
+
+fn format_errors(errors: &[(i32, ReplacerError)]) -> String {
+    errors
+        .iter()
+        .map(|(pid, err)| format!("{}: {}", pid, err))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
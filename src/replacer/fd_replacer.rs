@@ -1,37 +1,85 @@
 use std::collections::HashMap;
+use std::convert::TryInto;
 use std::fmt::Debug;
 use std::io::{Cursor, Read, Write};
 use std::iter::FromIterator;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi};
-use itertools::Itertools;
-use procfs::process::FDTarget;
-use tracing::{error, info, trace};
-
-use super::utils::all_processes;
-use super::{ptrace, Replacer};
-
+use nix::sys::stat::{self, SFlag};
+use procfs::process::{FDInfo, FDTarget, MMapPath, Process};
+use tracing::{error, info, trace, warn};
+
+use super::errors::ReplacerError;
+use super::namespace::MountNamespaceGuard;
+use super::utils::{all_processes, resolve_path, FsType, ProcessFilter};
+use super::{ptrace, FdReport, ProcessStatus, ReplaceReport, Replacer};
+
+// flags which only make sense when creating a file. They are masked out of the
+// flags returned by F_GETFL before reopening, so the new file is never truncated
+const CREATION_FLAGS: i32 = libc::O_CREAT | libc::O_EXCL | libc::O_NOCTTY | libc::O_TRUNC;
+
+// the max count of fds reopened by the codes injected at once. The time a
+// process is stopped is bounded by the batch, instead of its count of fds.
+pub const DEFAULT_BATCH_SIZE: usize = 256;
+
+// ReplaceCase is read by the injected codes as three qwords at the offsets 0, 8
+// and 16. The cases are placed at the start of the codes, which are mapped on a
+// page boundary, so every field is aligned to 8 bytes though it's packed
 #[derive(Clone, Copy)]
 #[repr(packed)]
 #[repr(C)]
 struct ReplaceCase {
+    // a valid fd from `checked_fd`, so loading it as a qword gives the same
+    // value as the int the syscalls take
     fd: u64,
     new_path_offset: u64,
+    // flags added to the ones returned by F_GETFL
+    extra_flags: u64,
+}
+
+// the stride of the cases in the injected codes
+const _: [(); 24] = [(); std::mem::size_of::<ReplaceCase>()];
+
+// checked_fd converts an fd number read from procfs. A negative one, or one out
+// of the range of RawFd, can't be an fd, so it's rejected instead of being passed
+// to the syscalls of the injected codes
+pub fn checked_fd<T: TryInto<RawFd>>(fd: T) -> Option<u64> {
+    match fd.try_into() {
+        Ok(fd) if fd >= 0 => Some(fd as u64),
+        _ => None,
+    }
 }
 
 impl ReplaceCase {
-    pub fn new(fd: u64, new_path_offset: u64) -> ReplaceCase {
+    pub fn new(fd: u64, new_path_offset: u64, extra_flags: u64) -> ReplaceCase {
         ReplaceCase {
             fd,
             new_path_offset,
+            extra_flags,
         }
     }
 }
 
+// Target is an fd to reopen, and the paths of the file before and after
+struct Target {
+    fd: u64,
+    old_path: PathBuf,
+    new_path: PathBuf,
+    // the filesystem of the old path, if it can be detected
+    fs_type: Option<FsType>,
+    // the fd is opened with O_DIRECT, which is kept on reopening
+    direct: bool,
+    // the fd refers to a directory, which is reopened with O_DIRECTORY
+    directory: bool,
+}
+
 struct ProcessAccessorBuilder {
     cases: Vec<ReplaceCase>,
+    targets: Vec<Target>,
     new_paths: Cursor<Vec<u8>>,
 }
 
@@ -39,6 +87,7 @@ impl ProcessAccessorBuilder {
     pub fn new() -> ProcessAccessorBuilder {
         ProcessAccessorBuilder {
             cases: Vec::new(),
+            targets: Vec::new(),
             new_paths: Cursor::new(Vec::new()),
         }
     }
@@ -48,35 +97,47 @@ impl ProcessAccessorBuilder {
             process,
 
             cases: self.cases,
+            targets: self.targets,
             new_paths: self.new_paths,
+            async_io: Vec::new(),
         })
     }
 
-    pub fn push_case(&mut self, fd: u64, new_path: PathBuf) -> anyhow::Result<()> {
-        info!("push case fd: {}, new_path: {}", fd, new_path.display());
+    pub fn push_case(&mut self, target: Target) -> Result<()> {
+        info!(
+            "push case fd: {}, new_path: {}",
+            target.fd,
+            target.new_path.display()
+        );
 
-        let mut new_path = new_path
-            .to_str()
-            .ok_or(anyhow!("fd contains non-UTF-8 character"))?
-            .as_bytes()
-            .to_vec();
+        // paths are passed to the tracee as raw bytes, so non-UTF-8 paths are fine
+        let mut raw_path = target.new_path.as_os_str().as_bytes().to_vec();
 
-        new_path.push(0);
+        raw_path.push(0);
 
         let offset = self.new_paths.position();
-        self.new_paths.write_all(new_path.as_slice())?;
-
-        self.cases.push(ReplaceCase::new(fd, offset));
+        self.new_paths.write_all(raw_path.as_slice())?;
+
+        // a directory can only be opened for reading, so the access mode from
+        // F_GETFL is always O_RDONLY
+        let extra_flags = if target.directory {
+            libc::O_DIRECTORY as u64
+        } else {
+            0
+        };
+        self.cases
+            .push(ReplaceCase::new(target.fd, offset, extra_flags));
+        self.targets.push(target);
 
         Ok(())
     }
 }
 
-impl FromIterator<(u64, PathBuf)> for ProcessAccessorBuilder {
-    fn from_iter<T: IntoIterator<Item = (u64, PathBuf)>>(iter: T) -> Self {
+impl FromIterator<Target> for ProcessAccessorBuilder {
+    fn from_iter<T: IntoIterator<Item = Target>>(iter: T) -> Self {
         let mut builder = Self::new();
-        for (fd, path) in iter {
-            if let Err(err) = builder.push_case(fd, path) {
+        for target in iter {
+            if let Err(err) = builder.push_case(target) {
                 error!("fail to write to AccessorBuilder. Error: {:?}", err)
             }
         }
@@ -89,7 +150,61 @@ struct ProcessAccessor {
     process: ptrace::TracedProcess,
 
     cases: Vec<ReplaceCase>,
+    // the target of each case
+    targets: Vec<Target>,
     new_paths: Cursor<Vec<u8>>,
+    // the asynchronous IO interfaces used by the process
+    async_io: Vec<AsyncIo>,
+}
+
+// AsyncIo is an asynchronous IO interface, which may keep accessing the original
+// files after the fds are reopened. Only a warning is given for them, as what
+// the kernel holds can't be moved by the replacer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AsyncIo {
+    // the aio of the kernel, e.g. through libaio. Every request is submitted
+    // with an fd, so only the requests in flight during replacement are lost
+    Aio,
+    // io_uring holds the files registered to a ring until they're unregistered,
+    // which may be for the whole life of the process
+    IoUring,
+}
+
+impl AsyncIo {
+    fn describe(&self) -> &'static str {
+        match self {
+            AsyncIo::Aio => {
+                "aio, so the requests submitted before replacement go to the original files"
+            }
+            AsyncIo::IoUring => {
+                "io_uring, so the requests on the files registered to its rings go to the \
+                 original files, bypassing injection"
+            }
+        }
+    }
+}
+
+// detect_async_io finds the asynchronous IO interfaces used by a process: an
+// aio context maps its ring as `/[aio]`, and an io_uring instance is an fd
+fn detect_async_io(process: &Process, fds: &[FDInfo]) -> Vec<AsyncIo> {
+    let mut async_io = Vec::new();
+    let aio_ring = process.maps().map_or(false, |maps| {
+        maps.iter().any(|map| match &map.pathname {
+            MMapPath::Path(path) => path.starts_with("/[aio]"),
+            _ => false,
+        })
+    });
+    if aio_ring {
+        async_io.push(AsyncIo::Aio);
+    }
+    let io_uring = fds.iter().any(|fd| match &fd.target {
+        FDTarget::AnonInode(name) => name.contains("io_uring"),
+        _ => false,
+    });
+    if io_uring {
+        async_io.push(AsyncIo::IoUring);
+    }
+    async_io
 }
 
 impl Debug for ProcessAccessor {
@@ -99,165 +214,532 @@ impl Debug for ProcessAccessor {
 }
 
 impl ProcessAccessor {
-    pub fn run(&mut self) -> anyhow::Result<()> {
+    // run reopens the fds, and returns the ones reopened. The fds which have
+    // been reopened before are not returned, neither are the fds opened with
+    // O_DIRECT unless `direct_io` is set
+    pub fn run(&mut self, batch_size: usize, direct_io: bool) -> anyhow::Result<Vec<u64>> {
         self.new_paths.set_position(0);
 
         let mut new_paths = Vec::new();
         self.new_paths.read_to_end(&mut new_paths)?;
 
-        let (cases_ptr, length, _) = self.cases.clone().into_raw_parts();
-        let size = length * std::mem::size_of::<ReplaceCase>();
-        let cases = unsafe { std::slice::from_raw_parts(cases_ptr as *mut u8, size) };
-
-        self.process.run_codes(|addr| {
-            let mut vec_rt =
-                dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(addr as usize);
-            dynasm!(vec_rt
-                ; .arch x64
-                ; ->cases:
-                ; .bytes cases
-                ; ->cases_length:
-                ; .qword cases.len() as i64
-                ; ->new_paths:
-                ; .bytes new_paths.as_slice()
-                ; nop
-                ; nop
-            );
-
-            trace!("static bytes placed");
-            let replace = vec_rt.offset();
-            dynasm!(vec_rt
-                ; .arch x64
-                // set r15 to 0
-                ; xor r15, r15
-                ; lea r14, [-> cases]
-
-                ; jmp ->end
-                ; ->start:
-                // fcntl
-                ; mov rax, 0x48
-                ; mov rdi, QWORD [r14+r15] // fd
-                ; mov rsi, 0x3
-                ; mov rdx, 0x0
-                ; syscall
-                ; mov rsi, rax
-                // open
-                ; mov rax, 0x2
-                ; lea rdi, [-> new_paths]
-                ; add rdi, QWORD [r14+r15+8] // path
-                ; mov rdx, 0x0
-                ; syscall
-                ; mov r12, rax // store newly opened fd in r12
-                // lseek
-                ; mov rax, 0x8
-                ; mov rdi, QWORD [r14+r15] // fd
-                ; mov rsi, 0
-                ; mov rdx, libc::SEEK_CUR
-                ; syscall
-                ; mov rdi, r12
-                ; mov rsi, rax
-                // lseek
-                ; mov rax, 0x8
-                ; mov rdx, libc::SEEK_SET
-                ; syscall
-                // dup2
-                ; mov rax, 0x21
-                ; mov rdi, r12
-                ; mov rsi, QWORD [r14+r15] // fd
-                ; syscall
-                // close
-                ; mov rax, 0x3
-                ; mov rdi, r12
-                ; syscall
-
-                ; add r15, std::mem::size_of::<ReplaceCase>() as i32
-                ; ->end:
-                ; mov r13, QWORD [->cases_length]
-                ; cmp r15, r13
-                ; jb ->start
-
-                ; int3
-            );
-
-            let instructions = vec_rt.finalize()?;
-
-            Ok((replace.0 as u64, instructions))
-        })?;
+        // the files of the fds are found through /proc before entering the mount
+        // namespace of the process, whose procfs may be of another pid namespace.
+        // The new paths are checked and opened in the namespace of the process.
+        let pid = self.process.pid;
+        let current: Vec<_> = self
+            .targets
+            .iter()
+            .map(|target| fd_file_id(pid, target.fd))
+            .collect();
+        let _namespace = MountNamespaceGuard::enter(pid)?;
+
+        // skip the fds which have been replaced, so running twice (e.g. on retry)
+        // won't reopen them again
+        let (cases, fds): (Vec<_>, Vec<_>) = self
+            .cases
+            .iter()
+            .zip(self.targets.iter())
+            .zip(current.into_iter())
+            .filter(|((_, target), current)| {
+                if target.direct && !direct_io {
+                    info!(
+                        "skip fd({}) of pid({}), as it's opened with O_DIRECT, but the new path doesn't serve direct io",
+                        target.fd, pid
+                    );
+                    return false;
+                }
+                let replaced = is_replaced(*current, &target.new_path);
+                if replaced {
+                    trace!(
+                        "fd {} has been replaced to {}",
+                        target.fd,
+                        target.new_path.display()
+                    );
+                }
+                !replaced
+            })
+            .map(|((case, target), _)| (*case, target.fd))
+            .unzip();
+        if cases.is_empty() {
+            trace!("all fds have been replaced");
+            return Ok(fds);
+        }
+
+        for batch in cases.chunks(batch_size.max(1)) {
+            trace!("reopen {} fds", batch.len());
+            let size = batch.len() * std::mem::size_of::<ReplaceCase>();
+            let batch = unsafe { std::slice::from_raw_parts(batch.as_ptr() as *const u8, size) };
+
+            self.process.run_codes(
+                |addr| generate_codes(addr, batch, new_paths.as_slice()),
+                ptrace::RUN_CODES_TIMEOUT,
+            )?;
+        }
 
         trace!("reopen successfully");
-        Ok(())
+        Ok(fds)
     }
+
+    // fd_reports reports every target with the status of the process. When the
+    // process is replaced, the fds which are not in `reopened` are skipped
+    fn fd_reports(&self, status: &ProcessStatus, reopened: &[u64]) -> Vec<FdReport> {
+        self.targets
+            .iter()
+            .map(|target| FdReport {
+                fd: target.fd,
+                old_path: target.old_path.clone(),
+                new_path: target.new_path.clone(),
+                status: match status {
+                    ProcessStatus::Replaced if !reopened.contains(&target.fd) => {
+                        ProcessStatus::Skipped
+                    }
+                    status => status.clone(),
+                },
+            })
+            .collect()
+    }
+}
+
+// fd_file_id returns the device and inode of the file which the fd refers to
+fn fd_file_id(pid: i32, fd: u64) -> Option<(libc::dev_t, libc::ino_t)> {
+    let stat = stat::stat(fd_link(pid, fd).as_str()).ok()?;
+    Some((stat.st_dev, stat.st_ino))
+}
+
+// fd_link is the link to the file of the fd in /proc. It's followed to the file
+// in any mount namespace, so the fds are read through it rather than their path
+fn fd_link(pid: i32, fd: u64) -> String {
+    format!("/proc/{}/fd/{}", pid, fd)
+}
+
+// is_replaced returns true if the fd, whose file is `current`, already refers to
+// the file at `new_path`. The paths can't be compared directly, as the new path
+// is the same as the old one when the fds are moved onto the FUSE mount.
+fn is_replaced(current: Option<(libc::dev_t, libc::ino_t)>, new_path: &Path) -> bool {
+    match (current, stat::stat(new_path)) {
+        (Some((dev, ino)), Ok(new)) => dev == new.st_dev && ino == new.st_ino,
+        _ => false,
+    }
+}
+
+// is_replaceable returns false if the file can't be reopened safely: opening a
+// FIFO may block the process forever, and a socket can't be opened at all. Any
+// file opened with O_PATH is fine, as it's reopened with O_PATH again.
+fn is_replaceable(pid: i32, fd: u64, path: &Path) -> bool {
+    let file_type = match stat::stat(fd_link(pid, fd).as_str()) {
+        Ok(stat) => SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT,
+        Err(err) => {
+            trace!("skip fd({}) of pid({}): {:?}", fd, pid, err);
+            return false;
+        }
+    };
+    if file_type == SFlag::S_IFREG || file_type == SFlag::S_IFDIR {
+        return true;
+    }
+    if fd_flags(pid, fd).map_or(false, |flags| flags & libc::O_PATH != 0) {
+        return true;
+    }
+
+    info!(
+        "skip fd({}) of pid({}), as {} is not a regular file or directory",
+        fd,
+        pid,
+        path.display()
+    );
+    false
+}
+
+// is_directory returns whether the fd refers to a directory
+fn is_directory(pid: i32, fd: u64) -> bool {
+    stat::stat(fd_link(pid, fd).as_str()).map_or(false, |stat| {
+        SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFDIR
+    })
+}
+
+// fd_flags reads the flags of the fd from /proc/[pid]/fdinfo
+fn fd_flags(pid: i32, fd: u64) -> Option<i32> {
+    let fdinfo = std::fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd)).ok()?;
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .and_then(|flags| i32::from_str_radix(flags.trim(), 8).ok())
+}
+
+#[cfg(target_arch = "x86_64")]
+fn generate_codes(addr: u64, cases: &[u8], new_paths: &[u8]) -> Result<(u64, Vec<u8>)> {
+    let mut vec_rt = dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(addr as usize);
+    dynasm!(vec_rt
+        ; .arch x64
+        ; ->cases:
+        ; .bytes cases
+        ; ->cases_length:
+        ; .qword cases.len() as i64
+        ; ->new_paths:
+        ; .bytes new_paths
+        ; nop
+        ; nop
+    );
+
+    trace!("static bytes placed");
+    let replace = vec_rt.offset();
+    dynasm!(vec_rt
+        ; .arch x64
+        // set r15 to 0
+        ; xor r15, r15
+        ; lea r14, [-> cases]
+
+        ; jmp ->end
+        ; ->start:
+        // fcntl
+        ; mov rax, 0x48
+        ; mov rdi, QWORD [r14+r15] // fd
+        ; mov rsi, 0x3
+        ; mov rdx, 0x0
+        ; syscall
+        ; mov rsi, rax
+        ; and rsi, !CREATION_FLAGS
+        ; or rsi, QWORD [r14+r15+16] // extra flags
+        // open
+        ; mov rax, 0x2
+        ; lea rdi, [-> new_paths]
+        ; add rdi, QWORD [r14+r15+8] // path
+        ; mov rdx, 0x0
+        ; syscall
+        ; mov r12, rax // store newly opened fd in r12
+        // lseek
+        ; mov rax, 0x8
+        ; mov rdi, QWORD [r14+r15] // fd
+        ; mov rsi, 0
+        ; mov rdx, libc::SEEK_CUR
+        ; syscall
+        ; mov rdi, r12
+        ; mov rsi, rax
+        // lseek
+        ; mov rax, 0x8
+        ; mov rdx, libc::SEEK_SET
+        ; syscall
+        // dup2
+        ; mov rax, 0x21
+        ; mov rdi, r12
+        ; mov rsi, QWORD [r14+r15] // fd
+        ; syscall
+        // close
+        ; mov rax, 0x3
+        ; mov rdi, r12
+        ; syscall
+
+        ; add r15, std::mem::size_of::<ReplaceCase>() as i32
+        ; ->end:
+        ; mov r13, QWORD [->cases_length]
+        ; cmp r15, r13
+        ; jb ->start
+
+        ; int3
+    );
+
+    let instructions = vec_rt.finalize()?;
+
+    Ok((replace.0 as u64, instructions))
+}
+
+// aarch64 has no `open` or `dup2` syscalls, so `openat(AT_FDCWD, ..)` and `dup3(.., 0)` are
+// used instead. The syscall number is passed in x8 and the arguments in x0-x5.
+#[cfg(target_arch = "aarch64")]
+fn generate_codes(addr: u64, cases: &[u8], new_paths: &[u8]) -> Result<(u64, Vec<u8>)> {
+    let mut vec_rt =
+        dynasmrt::VecAssembler::<dynasmrt::aarch64::Aarch64Relocation>::new(addr as usize);
+    dynasm!(vec_rt
+        ; .arch aarch64
+        ; ->cases:
+        ; .bytes cases
+        ; ->cases_length:
+        ; .qword cases.len() as i64
+        ; ->new_paths:
+        ; .bytes new_paths
+        // instructions must be aligned to 4 bytes
+        ; .align 4
+        ; nop
+        ; nop
+    );
+
+    trace!("static bytes placed");
+    let replace = vec_rt.offset();
+    dynasm!(vec_rt
+        ; .arch aarch64
+        // set x20 to 0
+        ; movz x20, 0
+        ; adr x19, ->cases
+
+        ; b ->end
+        ; ->start:
+        ; add x9, x19, x20 // x9 points to current case
+        // fcntl
+        ; ldr x0, [x9] // fd
+        ; movz x1, 0x3
+        ; movz x2, 0x0
+        ; movz x8, 0x19
+        ; svc 0
+        ; movn x10, CREATION_FLAGS as u32
+        ; and x2, x0, x10
+        ; ldr x10, [x9, 16] // extra flags
+        ; orr x2, x2, x10
+        // openat
+        ; movn x0, 99 // AT_FDCWD
+        ; adr x1, ->new_paths
+        ; ldr x10, [x9, 8] // path
+        ; add x1, x1, x10
+        ; movz x3, 0x0
+        ; movz x8, 0x38
+        ; svc 0
+        ; mov x22, x0 // store newly opened fd in x22
+        // lseek
+        ; ldr x0, [x9] // fd
+        ; movz x1, 0
+        ; movz x2, libc::SEEK_CUR as u32
+        ; movz x8, 0x3e
+        ; svc 0
+        ; mov x1, x0
+        ; mov x0, x22
+        // lseek
+        ; movz x2, libc::SEEK_SET as u32
+        ; movz x8, 0x3e
+        ; svc 0
+        // dup3
+        ; mov x0, x22
+        ; ldr x1, [x9] // fd
+        ; movz x2, 0x0
+        ; movz x8, 0x18
+        ; svc 0
+        // close
+        ; mov x0, x22
+        ; movz x8, 0x39
+        ; svc 0
+
+        ; add x20, x20, std::mem::size_of::<ReplaceCase>() as u32
+        ; ->end:
+        ; adr x9, ->cases_length
+        ; ldr x21, [x9]
+        ; cmp x20, x21
+        ; b.lo ->start
+
+        ; brk 0
+    );
+
+    let instructions = vec_rt.finalize()?;
+
+    Ok((replace.0 as u64, instructions))
 }
 
 pub struct FdReplacer {
     processes: HashMap<i32, ProcessAccessor>,
+    batch_size: usize,
+    direct_io: bool,
 }
 
 impl FdReplacer {
     pub fn prepare<P1: AsRef<Path>, P2: AsRef<Path>>(
         detect_path: P1,
         new_path: P2,
+    ) -> Result<FdReplacer> {
+        Self::prepare_filtered(detect_path, new_path, None)
+    }
+
+    // prepare_filtered is `prepare` on the processes matched by the filter
+    // only, the others are not traced at all
+    pub fn prepare_filtered<P1: AsRef<Path>, P2: AsRef<Path>>(
+        detect_path: P1,
+        new_path: P2,
+        filter: Option<&ProcessFilter>,
     ) -> Result<FdReplacer> {
         info!("preparing fd replacer");
 
-        let detect_path = detect_path.as_ref();
+        let detect_path = resolve_path(detect_path.as_ref());
+        let detect_path = detect_path.as_path();
         let new_path = new_path.as_ref();
 
-        let processes = all_processes()?
-            .filter_map(|process| -> Option<_> {
-                let pid = process.pid;
-
-                let traced_process = match ptrace::trace(pid) {
-                    Ok(p) => p,
-                    Err(err) => {
-                        error!("fail to trace process: {} {}", pid, err);
+        // every traced process is either owned by `processes` or dropped right
+        // away, so all of them are detached if prepare returns early or panics
+        let mut processes = HashMap::new();
+        for process in all_processes(filter)? {
+            let pid = process.pid;
+
+            // trace the process before reading its fds, so they won't change
+            let traced_process = match ptrace::trace(pid) {
+                Ok(p) => p,
+                Err(err) => {
+                    error!("fail to trace process: {} {}", pid, err);
+                    continue;
+                }
+            };
+            // the fds are read through the procfs of toda, as the one in the
+            // mount namespace of the process may be of another pid namespace.
+            // The paths are only compared, so the namespace isn't entered.
+            let fd = match process.fd() {
+                Ok(fd) => fd,
+                Err(err) => {
+                    trace!("filter out pid({}) because of error: {:?}", pid, err);
+                    continue;
+                }
+            };
+
+            let async_io = detect_async_io(&process, &fd);
+            let builder: ProcessAccessorBuilder = fd
+                .into_iter()
+                .filter_map(|entry| match entry.target {
+                    FDTarget::Path(path) => match checked_fd(entry.fd) {
+                        Some(fd) => Some((fd, path)),
+                        None => {
+                            error!("skip invalid fd({}) of pid({})", entry.fd, pid);
+                            None
+                        }
+                    },
+                    _ => None,
+                })
+                .filter(|(_, path)| path.starts_with(detect_path))
+                .filter(|(fd, path)| is_replaceable(pid, *fd, path))
+                .filter_map(|(fd, path)| {
+                    let fs_type = FsType::of(fd_link(pid, fd));
+                    let fs_name = fs_type.map_or_else(|| "unknown".to_owned(), |fs| fs.name());
+                    if !fs_type.map_or(true, |fs| fs.is_reopenable()) {
+                        info!(
+                            "skip fd({}) of pid({}), as {} is on {}",
+                            fd,
+                            pid,
+                            path.display(),
+                            fs_name
+                        );
                         return None;
                     }
-                };
-                let fd = process.fd().ok()?;
 
-                Some((traced_process, fd))
-            })
-            .flat_map(|(process, fd)| {
-                fd.into_iter()
-                    .filter_map(|entry| match entry.target {
-                        FDTarget::Path(path) => Some((entry.fd as u64, path)),
-                        _ => None,
-                    })
-                    .filter(|(_, path)| path.starts_with(detect_path))
-                    .filter_map(move |(fd, path)| {
-                        trace!("replace fd({}): {}", fd, path.display());
-                        let stripped_path = path.strip_prefix(&detect_path).ok()?;
-                        Some((process.clone(), (fd, new_path.join(stripped_path))))
+                    trace!("replace fd({}) on {}: {}", fd, fs_name, path.display());
+                    let stripped_path = path.strip_prefix(&detect_path).ok()?;
+                    let direct =
+                        fd_flags(pid, fd).map_or(false, |flags| flags & libc::O_DIRECT != 0);
+                    let directory = is_directory(pid, fd);
+                    Some(Target {
+                        fd,
+                        new_path: new_path.join(stripped_path),
+                        old_path: path,
+                        fs_type,
+                        direct,
+                        directory,
                     })
-            })
-            .group_by(|(process, _)| process.pid)
-            .into_iter()
-            .filter_map(|(pid, group)| Some((ptrace::trace(pid).ok()?, group)))
-            .map(|(process, group)| (process, group.map(|(_, group)| group)))
-            .filter_map(|(process, group)| {
-                let pid = process.pid;
-                match group.collect::<ProcessAccessorBuilder>().build(process) {
-                    Ok(accessor) => Some((pid, accessor)),
-                    Err(err) => {
-                        error!("fail to build accessor: {:?}", err);
-                        None
-                    }
-                }
-            })
-            .collect();
+                })
+                .collect();
+            if builder.cases.is_empty() {
+                continue;
+            }
+
+            for kind in async_io.iter() {
+                warn!("process {} uses {}", pid, kind.describe());
+            }
+            let mut accessor = builder.build(traced_process)?;
+            accessor.async_io = async_io;
+            processes.insert(pid, accessor);
+        }
+
+        Ok(FdReplacer {
+            processes,
+            batch_size: DEFAULT_BATCH_SIZE,
+            direct_io: true,
+        })
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
 
-        Ok(FdReplacer { processes })
+    // with_direct_io tells whether the new path serves O_DIRECT. An fd opened
+    // with O_DIRECT is reopened with it, but if the new path ignores it, e.g. a
+    // FUSE mount without direct io, the application would silently lose its
+    // IO semantics, so the fd is skipped instead.
+    pub fn with_direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
     }
 }
 
 impl Replacer for FdReplacer {
-    fn run(&mut self) -> Result<()> {
+    fn run_detailed(&mut self) -> ReplaceReport {
         info!("running fd replacer");
-        for (_, accessor) in self.processes.iter_mut() {
-            accessor.run()?;
+        // The processes are replaced one by one: ptrace requests must be sent by
+        // the thread which attached the tracee, and the traced processes are
+        // shared with the other replacers through a thread-local manager. See
+        // `UnionReplacer::with_concurrency` to replace them on several threads.
+        // A failed process doesn't stop the others from being replaced.
+        let mut report = ReplaceReport::default();
+        for (pid, accessor) in self.processes.iter_mut() {
+            let reopened = match accessor.run(self.batch_size, self.direct_io) {
+                Ok(reopened) if reopened.is_empty() => {
+                    report.skipped(*pid, "fd");
+                    reopened
+                }
+                Ok(reopened) => {
+                    report.replaced(*pid, "fd");
+                    reopened
+                }
+                Err(err) => {
+                    match ReplacerError::with_pid(*pid, err) {
+                        // the process has exited since prepare, so its fds are gone too
+                        ReplacerError::ProcessGone { .. } => {
+                            info!("skip process {}, as it has exited", pid);
+                            report.skipped(*pid, "fd");
+                        }
+                        err => {
+                            error!("fail to replace fds of process {}: {:?}", pid, err);
+                            report.failed(*pid, "fd", err);
+                        }
+                    }
+                    Vec::new()
+                }
+            };
+            if let Some(process) = report.processes.last() {
+                let fds = accessor.fd_reports(&process.status, &reopened);
+                report.set_fds(fds);
+            }
         }
 
-        Ok(())
+        report
+    }
+
+    fn plan(&self) -> Vec<String> {
+        let mut pids: Vec<_> = self.processes.keys().collect();
+        pids.sort_unstable();
+        pids.into_iter()
+            .flat_map(|pid| {
+                let replaced = self.processes[pid].targets.iter().map(move |target| {
+                    let fs_name = target
+                        .fs_type
+                        .map_or_else(|| "unknown".to_owned(), |fs| fs.name());
+                    match (target.direct, self.direct_io) {
+                        (true, false) => format!(
+                            "pid {}: skip fd {} opened with O_DIRECT ({})",
+                            pid, target.fd, fs_name
+                        ),
+                        (true, true) => format!(
+                            "pid {}: reopen fd {} as {} with O_DIRECT ({})",
+                            pid,
+                            target.fd,
+                            target.new_path.display(),
+                            fs_name
+                        ),
+                        _ => format!(
+                            "pid {}: reopen fd {} as {} ({})",
+                            pid,
+                            target.fd,
+                            target.new_path.display(),
+                            fs_name
+                        ),
+                    }
+                });
+                let async_io = self.processes[pid]
+                    .async_io
+                    .iter()
+                    .map(move |kind| format!("pid {}: uses {}", pid, kind.describe()));
+                replaced.chain(async_io)
+            })
+            .collect()
     }
 }
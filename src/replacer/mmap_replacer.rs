@@ -2,17 +2,19 @@ use std::collections::HashMap;
 use std::fmt::Debug;
 use std::io::{Cursor, Read, Write};
 use std::iter::FromIterator;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi};
 use itertools::Itertools;
 use nix::sys::mman::{MapFlags, ProtFlags};
 use procfs::process::MMapPath;
 use tracing::{error, info, trace};
 
-use super::utils::all_processes;
-use super::{ptrace, Replacer};
+use super::errors::ReplacerError;
+use super::utils::{all_processes, resolve_path, ProcessFilter};
+use super::{ptrace, ReplaceReport, Replacer};
 
 #[derive(Clone, Debug)]
 struct ReplaceCase {
@@ -24,6 +26,17 @@ struct ReplaceCase {
     pub offset: u64,
 }
 
+impl ReplaceCase {
+    // is_private_writable returns true for a private mapping which may have been
+    // written. Its pages are copied on write, and the copies are only in the
+    // memory of the process, so they are lost if the file is mapped again.
+    fn is_private_writable(&self) -> bool {
+        let shared = self.flags & MapFlags::MAP_SHARED.bits() as u64 != 0;
+        let writable = self.prot & ProtFlags::PROT_WRITE.bits() as u64 != 0;
+        !shared && writable
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(packed)]
 #[repr(C)]
@@ -34,6 +47,7 @@ struct RawReplaceCase {
     flags: u64,
     new_path_offset: u64,
     offset: u64,
+    open_flags: u64,
 }
 
 impl RawReplaceCase {
@@ -45,6 +59,17 @@ impl RawReplaceCase {
         new_path_offset: u64,
         offset: u64,
     ) -> RawReplaceCase {
+        // A file must be opened with write permission only if the changes on a
+        // writable mapping will be carried through to it. Opening every file
+        // with O_RDWR fails on read-only files and running executables.
+        let shared = flags & MapFlags::MAP_SHARED.bits() as u64 != 0;
+        let writable = prot & ProtFlags::PROT_WRITE.bits() as u64 != 0;
+        let open_flags = if shared && writable {
+            libc::O_RDWR
+        } else {
+            libc::O_RDONLY
+        };
+
         RawReplaceCase {
             memory_addr,
             length,
@@ -52,6 +77,7 @@ impl RawReplaceCase {
             flags,
             new_path_offset,
             offset,
+            open_flags: open_flags as u64,
         }
     }
 }
@@ -90,11 +116,8 @@ impl ProcessAccessorBuilder {
     ) -> anyhow::Result<()> {
         info!("push case");
 
-        let mut new_path = new_path
-            .to_str()
-            .ok_or(anyhow!("fd contains non-UTF-8 character"))?
-            .as_bytes()
-            .to_vec();
+        // paths are passed to the tracee as raw bytes, so non-UTF-8 paths are fine
+        let mut new_path = new_path.as_os_str().as_bytes().to_vec();
 
         new_path.push(0);
 
@@ -158,86 +181,157 @@ impl ProcessAccessor {
         let size = length * std::mem::size_of::<RawReplaceCase>();
         let cases = unsafe { std::slice::from_raw_parts(cases_ptr as *mut u8, size) };
 
-        self.process.run_codes(|addr| {
-            let mut vec_rt =
-                dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(addr as usize);
-            dynasm!(vec_rt
-                ; .arch x64
-                ; ->cases:
-                ; .bytes cases
-                ; ->cases_length:
-                ; .qword cases.len() as i64
-                ; ->new_paths:
-                ; .bytes new_paths.as_slice()
-                ; nop
-                ; nop
-            );
-
-            trace!("static bytes placed");
-            let replace = vec_rt.offset();
-            dynasm!(vec_rt
-                ; .arch x64
-                // set r15 to 0
-                ; xor r15, r15
-                ; lea r14, [-> cases]
-
-                ; jmp ->end
-                ; ->start:
-                // munmap
-                ; mov rax, 0x0B
-                ; mov rdi, QWORD [r14+r15] // addr
-                ; mov rsi, QWORD [r14+r15+8] // length
-                ; mov rdx, 0x0
-                ; syscall
-                // open
-                ; mov rax, 0x2
-
-                ; lea rdi, [-> new_paths]
-                ; add r15, 8 * 4 // set r15 to point to path
-                ; add rdi, QWORD [r14+r15] // path
-                ; sub r15, 8 * 4
-
-                ; mov rsi, libc::O_RDWR
-                ; mov rdx, 0x0
-                ; syscall
-                ; push rax
-                ; mov r8, rax // fd
-                // mmap
-                ; mov rax, 0x9
-                ; add r15, 8
-                ; mov rsi, QWORD [r14+r15] // length
-                ; add r15, 8
-                ; mov rdx, QWORD [r14+r15] // prot
-                ; add r15, 8
-                ; mov r10, QWORD [r14+r15] // flags
-                ; add r15, 16
-                ; mov r9, QWORD [r14+r15] // offset
-                ; syscall
-                ; sub r15, 8 * 5
-                // close
-                ; mov rax, 0x3
-                ; pop rdi
-                ; syscall
-
-                ; add r15, std::mem::size_of::<RawReplaceCase>() as i32
-                ; ->end:
-                ; mov r13, QWORD [->cases_length]
-                ; cmp r15, r13
-                ; jb ->start
-
-                ; int3
-            );
-
-            let instructions = vec_rt.finalize()?;
-
-            Ok((replace.0 as u64, instructions))
-        })?;
+        self.process.run_codes(
+            |addr| generate_codes(addr, cases, &new_paths),
+            ptrace::RUN_CODES_TIMEOUT,
+        )?;
 
         trace!("reopen successfully");
         Ok(())
     }
 }
 
+#[cfg(target_arch = "x86_64")]
+fn generate_codes(addr: u64, cases: &[u8], new_paths: &[u8]) -> Result<(u64, Vec<u8>)> {
+    let mut vec_rt = dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(addr as usize);
+    dynasm!(vec_rt
+        ; .arch x64
+        ; ->cases:
+        ; .bytes cases
+        ; ->cases_length:
+        ; .qword cases.len() as i64
+        ; ->new_paths:
+        ; .bytes new_paths
+        ; nop
+        ; nop
+    );
+
+    trace!("static bytes placed");
+    let replace = vec_rt.offset();
+    dynasm!(vec_rt
+        ; .arch x64
+        // set r15 to 0
+        ; xor r15, r15
+        ; lea r14, [-> cases]
+
+        ; jmp ->end
+        ; ->start:
+        // open
+        ; mov rax, 0x2
+        ; lea rdi, [-> new_paths]
+        ; add rdi, QWORD [r14+r15+32] // path
+        ; mov rsi, QWORD [r14+r15+48] // open flags
+        ; mov rdx, 0x0
+        ; syscall
+        // keep the original mapping if the file cannot be opened
+        ; cmp rax, 0
+        ; jl ->next
+        ; mov r12, rax // fd
+        // mmap with MAP_FIXED replaces the original mapping in place,
+        // so it's never left unmapped
+        ; mov rax, 0x9
+        ; mov rdi, QWORD [r14+r15] // addr
+        ; mov rsi, QWORD [r14+r15+8] // length
+        ; mov rdx, QWORD [r14+r15+16] // prot
+        ; mov r10, QWORD [r14+r15+24] // flags
+        ; mov r8, r12 // fd
+        ; mov r9, QWORD [r14+r15+40] // offset
+        ; syscall
+        // close
+        ; mov rax, 0x3
+        ; mov rdi, r12
+        ; syscall
+
+        ; ->next:
+        ; add r15, std::mem::size_of::<RawReplaceCase>() as i32
+        ; ->end:
+        ; mov r13, QWORD [->cases_length]
+        ; cmp r15, r13
+        ; jb ->start
+
+        ; int3
+    );
+
+    let instructions = vec_rt.finalize()?;
+
+    Ok((replace.0 as u64, instructions))
+}
+
+// aarch64 has no `open` syscall, so `openat(AT_FDCWD, ..)` is used instead. The
+// syscall number is passed in x8 and the arguments in x0-x5.
+#[cfg(target_arch = "aarch64")]
+fn generate_codes(addr: u64, cases: &[u8], new_paths: &[u8]) -> Result<(u64, Vec<u8>)> {
+    let mut vec_rt =
+        dynasmrt::VecAssembler::<dynasmrt::aarch64::Aarch64Relocation>::new(addr as usize);
+    dynasm!(vec_rt
+        ; .arch aarch64
+        ; ->cases:
+        ; .bytes cases
+        ; ->cases_length:
+        ; .qword cases.len() as i64
+        ; ->new_paths:
+        ; .bytes new_paths
+        // instructions must be aligned to 4 bytes
+        ; .align 4
+        ; nop
+        ; nop
+    );
+
+    trace!("static bytes placed");
+    let replace = vec_rt.offset();
+    dynasm!(vec_rt
+        ; .arch aarch64
+        // set x20 to 0
+        ; movz x20, 0
+        ; adr x19, ->cases
+
+        ; b ->end
+        ; ->start:
+        ; add x9, x19, x20 // x9 points to current case
+        // openat
+        ; movn x0, 99 // AT_FDCWD
+        ; adr x1, ->new_paths
+        ; ldr x10, [x9, 32] // path
+        ; add x1, x1, x10
+        ; ldr x2, [x9, 48] // open flags
+        ; movz x3, 0x0
+        ; movz x8, 0x38
+        ; svc 0
+        // keep the original mapping if the file cannot be opened
+        ; cmp x0, 0
+        ; b.lt ->next
+        ; mov x22, x0 // fd
+        // mmap with MAP_FIXED replaces the original mapping in place,
+        // so it's never left unmapped
+        ; ldr x0, [x9] // addr
+        ; ldr x1, [x9, 8] // length
+        ; ldr x2, [x9, 16] // prot
+        ; ldr x3, [x9, 24] // flags
+        ; mov x4, x22 // fd
+        ; ldr x5, [x9, 40] // offset
+        ; movz x8, 0xde
+        ; svc 0
+        // close
+        ; mov x0, x22
+        ; movz x8, 0x39
+        ; svc 0
+
+        ; ->next:
+        ; add x20, x20, std::mem::size_of::<RawReplaceCase>() as u32
+        ; ->end:
+        ; adr x9, ->cases_length
+        ; ldr x21, [x9]
+        ; cmp x20, x21
+        ; b.lo ->start
+
+        ; brk 0
+    );
+
+    let instructions = vec_rt.finalize()?;
+
+    Ok((replace.0 as u64, instructions))
+}
+
 fn get_prot_and_flags_from_perms<S: AsRef<str>>(perms: S) -> (u64, u64) {
     let bytes = perms.as_ref().as_bytes();
     let mut prot = ProtFlags::empty();
@@ -255,6 +349,7 @@ fn get_prot_and_flags_from_perms<S: AsRef<str>>(perms: S) -> (u64, u64) {
     if bytes[3] == b's' {
         flags = MapFlags::MAP_SHARED;
     }
+    flags |= MapFlags::MAP_FIXED;
 
     trace!(
         "perms: {}, prot: {:?}, flags: {:?}",
@@ -273,13 +368,22 @@ impl MmapReplacer {
     pub fn prepare<P1: AsRef<Path>, P2: AsRef<Path>>(
         detect_path: P1,
         new_path: P2,
+    ) -> Result<MmapReplacer> {
+        Self::prepare_filtered(detect_path, new_path, None)
+    }
+
+    pub fn prepare_filtered<P1: AsRef<Path>, P2: AsRef<Path>>(
+        detect_path: P1,
+        new_path: P2,
+        filter: Option<&ProcessFilter>,
     ) -> Result<MmapReplacer> {
         info!("preparing mmap replacer");
 
-        let detect_path = detect_path.as_ref();
+        let detect_path = resolve_path(detect_path.as_ref());
+        let detect_path = detect_path.as_path();
         let new_path = new_path.as_ref();
 
-        let processes = all_processes()?
+        let processes = all_processes(filter)?
             .filter_map(|process| -> Option<_> {
                 let pid = process.pid;
 
@@ -312,6 +416,19 @@ impl MmapReplacer {
                         }
                     })
                     .filter(|(_, case)| case.path.starts_with(detect_path))
+                    .filter(|(process, case)| {
+                        if case.is_private_writable() {
+                            info!(
+                                "skip private writable mapping of {} at {:X} in process {}, as \
+                                 remapping would lose its modified pages",
+                                case.path.display(),
+                                case.memory_addr,
+                                process.pid
+                            );
+                            return false;
+                        }
+                        true
+                    })
                     .filter_map(|(process, mut case)| {
                         let stripped_path = case.path.strip_prefix(&detect_path).ok()?;
                         case.path = new_path.join(stripped_path);
@@ -340,12 +457,16 @@ impl MmapReplacer {
 }
 
 impl Replacer for MmapReplacer {
-    fn run(&mut self) -> Result<()> {
+    fn run_detailed(&mut self) -> ReplaceReport {
         info!("running mmap replacer");
-        for (_, accessor) in self.processes.iter_mut() {
-            accessor.run()?;
+        let mut report = ReplaceReport::default();
+        for (pid, accessor) in self.processes.iter_mut() {
+            match accessor.run() {
+                Ok(()) => report.replaced(*pid, "mmap"),
+                Err(err) => report.failed(*pid, "mmap", ReplacerError::with_pid(*pid, err)),
+            }
         }
 
-        Ok(())
+        report
     }
 }
@@ -1,62 +1,227 @@
 use std::path::Path;
+use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::{anyhow, Error, Result};
+use bitflags::bitflags;
 
 use crate::ptrace;
 
 mod cwd_replacer;
+mod errors;
 mod fd_replacer;
 mod mmap_replacer;
+mod namespace;
+mod report;
 mod utils;
+mod worker;
 
 use tracing::error;
+use worker::ReplacerWorker;
+
+// probe_ptrace tries ptrace on one of the processes which would be replaced,
+// i.e. holding a file, the cwd or a map under the paths, so a host denying
+// ptrace is found before the mount is changed. An unrelated process, e.g. init,
+// is never stopped for it. It passes if there is no process to try.
+pub fn probe_ptrace<P: AsRef<Path>>(paths: &[P], filter: Option<&ProcessFilter>) -> Result<()> {
+    let paths: Vec<_> = paths
+        .iter()
+        .map(|path| utils::resolve_path(path.as_ref()))
+        .collect();
+    match utils::all_processes(filter)?.find(|process| utils::uses_paths(process, &paths)) {
+        Some(process) => Ok(ptrace::probe(process.pid)?),
+        None => Ok(()),
+    }
+}
 
 pub trait Replacer {
-    fn run(&mut self) -> Result<()>;
+    // run_detailed replaces every process, and reports the result of each one
+    fn run_detailed(&mut self) -> ReplaceReport;
+
+    fn run(&mut self) -> errors::Result<()> {
+        self.run_detailed().into_result()
+    }
+
+    // plan describes the replacements which `run` would make, one per item
+    fn plan(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+bitflags! {
+    // ReplacerKind selects the replacers run by `UnionReplacer`
+    pub struct ReplacerKind: u8 {
+        const FD = 1;
+        const CWD = 1<<1;
+        const MMAP = 1<<2;
+    }
+}
+
+const REPLACER_NAMES: [(ReplacerKind, &str); 3] = [
+    (ReplacerKind::FD, "fd"),
+    (ReplacerKind::CWD, "cwd"),
+    (ReplacerKind::MMAP, "mmap"),
+];
+
+// a comma separated list of replacers, e.g. `fd,mmap`
+impl FromStr for ReplacerKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ReplacerKind> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .try_fold(ReplacerKind::empty(), |kinds, name| {
+                REPLACER_NAMES
+                    .iter()
+                    .find(|(_, kind_name)| *kind_name == name)
+                    .map(|(kind, _)| kinds | *kind)
+                    .ok_or_else(|| {
+                        anyhow!("unknown replacer `{}`, valid ones are fd, cwd, mmap", name)
+                    })
+            })
+    }
 }
 
 pub struct UnionReplacer<'a> {
     replacers: Vec<Box<dyn Replacer + 'a>>,
+    kinds: ReplacerKind,
+    direct_io: bool,
+    process_filter: Option<ProcessFilter>,
+    concurrency: usize,
+    workers: Vec<ReplacerWorker>,
 }
 
 impl<'a> UnionReplacer<'a> {
-    pub fn new() -> UnionReplacer<'a> {
+    pub fn new(kinds: ReplacerKind) -> UnionReplacer<'a> {
         UnionReplacer {
             replacers: Vec::new(),
+            kinds,
+            direct_io: true,
+            process_filter: None,
+            concurrency: 1,
+            workers: Vec::new(),
         }
     }
 
+    // with_process_filter only replaces the processes matched by the filter
+    pub fn with_process_filter(mut self, process_filter: Option<ProcessFilter>) -> Self {
+        self.process_filter = process_filter;
+        self
+    }
+
+    // with_direct_io tells whether the new path serves O_DIRECT, see
+    // `FdReplacer::with_direct_io`
+    pub fn with_direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    // with_concurrency splits the processes by pid between `concurrency`
+    // threads, which trace and replace their own share at the same time. With 1,
+    // every process is replaced on the current thread.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
     pub fn prepare<P1: AsRef<Path>, P2: AsRef<Path>>(
         &mut self,
         detect_path: P1,
         new_path: P2,
     ) -> Result<()> {
-        match FdReplacer::prepare(&detect_path, &new_path) {
-            Err(err) => error!("Error while preparing fd replacer: {:?}", err),
-            Ok(replacer) => self.replacers.push(Box::new(replacer)),
+        if self.concurrency > 1 {
+            return self.prepare_workers(detect_path.as_ref(), new_path.as_ref());
         }
-        match CwdReplacer::prepare(&detect_path, &new_path) {
-            Err(err) => error!("Error while preparing cwd replacer: {:?}", err),
-            Ok(replacer) => self.replacers.push(Box::new(replacer)),
+
+        let filter = self.process_filter.as_ref();
+        if self.kinds.contains(ReplacerKind::FD) {
+            match FdReplacer::prepare_filtered(&detect_path, &new_path, filter) {
+                Err(err) => error!("Error while preparing fd replacer: {:?}", err),
+                Ok(replacer) => self
+                    .replacers
+                    .push(Box::new(replacer.with_direct_io(self.direct_io))),
+            }
         }
-        match MmapReplacer::prepare(&detect_path, &new_path) {
-            Err(err) => error!("Error while preparing mmap replacer: {:?}", err),
-            Ok(replacer) => self.replacers.push(Box::new(replacer)),
+        if self.kinds.contains(ReplacerKind::CWD) {
+            match CwdReplacer::prepare_filtered(&detect_path, &new_path, filter) {
+                Err(err) => error!("Error while preparing cwd replacer: {:?}", err),
+                Ok(replacer) => self.replacers.push(Box::new(replacer)),
+            }
+        }
+        if self.kinds.contains(ReplacerKind::MMAP) {
+            match MmapReplacer::prepare_filtered(&detect_path, &new_path, filter) {
+                Err(err) => error!("Error while preparing mmap replacer: {:?}", err),
+                Ok(replacer) => self.replacers.push(Box::new(replacer)),
+            }
+        }
+        Ok(())
+    }
+
+    fn prepare_workers(&mut self, detect_path: &Path, new_path: &Path) -> Result<()> {
+        let filter = self.process_filter.clone().unwrap_or_default();
+        for index in 0..self.concurrency {
+            self.workers.push(ReplacerWorker::spawn(
+                self.kinds,
+                self.direct_io,
+                filter.clone().with_share(index, self.concurrency),
+                detect_path.to_owned(),
+                new_path.to_owned(),
+            )?);
+        }
+        // the workers trace their processes at the same time
+        for worker in self.workers.iter() {
+            worker.prepared()?;
         }
         Ok(())
     }
 }
 
 impl<'a> Replacer for UnionReplacer<'a> {
-    fn run(&mut self) -> Result<()> {
+    fn run_detailed(&mut self) -> ReplaceReport {
+        let mut report = ReplaceReport::default();
         for replacer in self.replacers.iter_mut() {
-            replacer.run()?;
+            report.merge(replacer.run_detailed());
         }
 
-        Ok(())
+        // every worker is started before any report is waited for
+        let started: Vec<_> = self
+            .workers
+            .iter()
+            .map(|worker| worker.start_run())
+            .collect();
+        for (worker, started) in self.workers.iter().zip(started) {
+            match started.and_then(|_| worker.report()) {
+                Ok(worker_report) => report.merge(worker_report),
+                // the processes of a worker which has gone are unknown
+                Err(err) => {
+                    error!("fail to run replacer worker: {:?}", err);
+                    report.failed(0, "worker", err.into());
+                }
+            }
+        }
+
+        report
+    }
+
+    fn plan(&self) -> Vec<String> {
+        let mut plan: Vec<String> = self
+            .replacers
+            .iter()
+            .flat_map(|replacer| replacer.plan())
+            .collect();
+        for worker in self.workers.iter() {
+            match worker.plan() {
+                Ok(worker_plan) => plan.extend(worker_plan),
+                Err(err) => error!("fail to plan replacer worker: {:?}", err),
+            }
+        }
+        plan
     }
 }
 
 pub use cwd_replacer::CwdReplacer;
-pub use fd_replacer::FdReplacer;
+pub use errors::ReplacerError;
+pub use fd_replacer::{checked_fd, FdReplacer, DEFAULT_BATCH_SIZE};
 pub use mmap_replacer::MmapReplacer;
+pub use report::{FdReport, ProcessReport, ProcessStatus, ReplaceReport};
+pub use utils::ProcessFilter;
@@ -0,0 +1,82 @@
+use std::cell::Cell;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::sched::{setns, unshare, CloneFlags};
+use nix::unistd::fchdir;
+use tracing::{error, trace};
+
+thread_local! {
+    // whether the fs attributes of the thread have been unshared
+    static FS_UNSHARED: Cell<bool> = Cell::new(false);
+}
+
+// MountNamespaceGuard moves the current thread into the mount namespace of a
+// process, and back into the original one on drop. Paths on the host may not
+// be valid in a container, so the new paths are checked and opened in the mount
+// namespace of the process. /proc must be read before entering it, as the
+// procfs mounted there may be of another pid namespace. The paths to replace
+// are assumed to be the same in both namespaces, e.g. a volume is mounted on
+// the same path in every container.
+pub struct MountNamespaceGuard {
+    pid: i32,
+    original: File,
+    cwd: File,
+}
+
+impl MountNamespaceGuard {
+    // enter moves the current thread into the mount namespace of `pid`. It
+    // returns None if the process is already in the same namespace.
+    pub fn enter(pid: i32) -> Result<Option<MountNamespaceGuard>> {
+        // the namespace of the thread, which differs from the one of the process
+        // while another thread is in a guard
+        let original = File::open("/proc/thread-self/ns/mnt")?;
+        // the namespaces of a process are gone once it has exited
+        let target = File::open(format!("/proc/{}/ns/mnt", pid)).map_err(|err| {
+            if err.kind() == ErrorKind::NotFound {
+                anyhow::Error::from(nix::Error::Sys(Errno::ESRCH))
+            } else {
+                err.into()
+            }
+        })?;
+        if original.metadata()?.ino() == target.metadata()?.ino() {
+            return Ok(None);
+        }
+
+        // a thread sharing its root and cwd with other threads can't change its
+        // mount namespace, so they are unshared once for every thread. The other
+        // threads are never moved.
+        if !FS_UNSHARED.with(|unshared| unshared.get()) {
+            unshare(CloneFlags::CLONE_FS).context("unshare fs attributes of the thread")?;
+            FS_UNSHARED.with(|unshared| unshared.set(true));
+        }
+
+        // entering a mount namespace changes the cwd to its root
+        let cwd = File::open(".")?;
+        trace!("enter mount namespace of process {}", pid);
+        setns(target.as_raw_fd(), CloneFlags::CLONE_NEWNS)
+            .with_context(|| format!("enter mount namespace of process {}", pid))?;
+
+        Ok(Some(MountNamespaceGuard { pid, original, cwd }))
+    }
+}
+
+impl Drop for MountNamespaceGuard {
+    fn drop(&mut self) {
+        trace!("leave mount namespace of process {}", self.pid);
+        if let Err(err) = setns(self.original.as_raw_fd(), CloneFlags::CLONE_NEWNS) {
+            error!(
+                "fail to leave mount namespace of process {}: {:?}",
+                self.pid, err
+            );
+            return;
+        }
+        if let Err(err) = fchdir(self.cwd.as_raw_fd()) {
+            error!("fail to restore cwd: {:?}", err);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use super::errors::{self, ReplacerError};
+
+// ProcessStatus is the result of replacing a process
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "status", content = "error", rename_all = "camelCase")]
+pub enum ProcessStatus {
+    Replaced,
+    // nothing needs to be replaced, e.g. the fds have been replaced before, or
+    // the process has exited
+    Skipped,
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProcessReport {
+    pub pid: i32,
+    // the kind of the replacer, e.g. `fd`
+    pub replacer: String,
+    #[serde(flatten)]
+    pub status: ProcessStatus,
+    // the fds reopened by the fd replacer
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fds: Vec<FdReport>,
+}
+
+// FdReport is the result of reopening an fd. An fd is skipped if it has been
+// reopened before, and fails with the process
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct FdReport {
+    pub fd: u64,
+    pub old_path: PathBuf,
+    pub new_path: PathBuf,
+    #[serde(flatten)]
+    pub status: ProcessStatus,
+}
+
+// ReplaceReport records the result of every process touched by the replacers
+#[derive(Debug, Default)]
+pub struct ReplaceReport {
+    pub processes: Vec<ProcessReport>,
+    errors: Vec<(i32, ReplacerError)>,
+}
+
+impl ReplaceReport {
+    pub fn replaced(&mut self, pid: i32, replacer: &str) {
+        self.push(pid, replacer, ProcessStatus::Replaced);
+    }
+
+    pub fn skipped(&mut self, pid: i32, replacer: &str) {
+        self.push(pid, replacer, ProcessStatus::Skipped);
+    }
+
+    pub fn failed(&mut self, pid: i32, replacer: &str, err: ReplacerError) {
+        self.push(pid, replacer, ProcessStatus::Failed(err.to_string()));
+        self.errors.push((pid, err));
+    }
+
+    fn push(&mut self, pid: i32, replacer: &str, status: ProcessStatus) {
+        self.processes.push(ProcessReport {
+            pid,
+            replacer: replacer.to_owned(),
+            status,
+            fds: Vec::new(),
+        });
+    }
+
+    // set_fds attaches the fds to the process reported last
+    pub fn set_fds(&mut self, fds: Vec<FdReport>) {
+        if let Some(process) = self.processes.last_mut() {
+            process.fds = fds;
+        }
+    }
+
+    pub fn merge(&mut self, other: ReplaceReport) {
+        self.processes.extend(other.processes);
+        self.errors.extend(other.errors);
+    }
+
+    // into_result returns an error holding every failed process, if any
+    pub fn into_result(self) -> errors::Result<()> {
+        let mut errors = self.errors;
+        if errors.is_empty() {
+            return Ok(());
+        }
+
+        errors.sort_unstable_by_key(|(pid, _)| *pid);
+        Err(ReplacerError::Processes { errors })
+    }
+}
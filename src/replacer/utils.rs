@@ -1,14 +1,178 @@
-use anyhow::Result;
-use procfs::process::{self, Process};
-
-pub fn all_processes() -> Result<impl Iterator<Item = Process>> {
-    Ok(process::all_processes()?
-        .into_iter()
-        .filter(|process| -> bool {
-            if let Ok(cmdline) = process.cmdline() {
-                !cmdline.iter().map(|stat| stat.contains("toda")).any(|x| x)
-            } else {
-                true
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Error, Result};
+use nix::sys::statfs;
+use procfs::process::{self, FDTarget, MMapPath, Process};
+use regex::Regex;
+
+// ProcessFilter limits the replacers to the processes whose comm or cmdline
+// matches a regex, so the others are never traced
+#[derive(Debug, Clone, Default)]
+pub struct ProcessFilter {
+    pattern: Option<Regex>,
+    // the index of the share and the count of shares, which split the processes
+    // by pid between the workers of a replacer
+    share: Option<(usize, usize)>,
+}
+
+impl ProcessFilter {
+    // with_share only matches the processes whose pid modulo `count` is `index`
+    pub fn with_share(mut self, index: usize, count: usize) -> Self {
+        self.share = Some((index, count));
+        self
+    }
+
+    // matches searches the pattern in the comm, and in the cmdline with the
+    // arguments joined by spaces
+    pub fn matches(&self, process: &Process) -> bool {
+        if let Some((index, count)) = self.share {
+            if process.pid as usize % count != index {
+                return false;
             }
-        }))
+        }
+        let pattern = match &self.pattern {
+            Some(pattern) => pattern,
+            None => return true,
+        };
+        if pattern.is_match(&process.stat.comm) {
+            return true;
+        }
+        match process.cmdline() {
+            Ok(cmdline) => pattern.is_match(&cmdline.join(" ")),
+            Err(_) => false,
+        }
+    }
+}
+
+impl FromStr for ProcessFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ProcessFilter> {
+        let regex = Regex::new(s).with_context(|| format!("invalid process pattern `{}`", s))?;
+        Ok(ProcessFilter {
+            pattern: Some(regex),
+            share: None,
+        })
+    }
+}
+
+// all_processes returns the processes which can be traced by replacers. toda
+// itself, its descendants and other toda processes are excluded, as tracing them
+// could deadlock or break the fds pointing at our own FUSE mount. With a filter,
+// only the matched processes are returned.
+pub fn all_processes(filter: Option<&ProcessFilter>) -> Result<impl Iterator<Item = Process> + '_> {
+    let processes = process::all_processes()?;
+
+    let self_pid = std::process::id() as i32;
+    let parents: HashMap<i32, i32> = processes
+        .iter()
+        .map(|process| (process.pid, process.stat.ppid))
+        .collect();
+
+    Ok(processes.into_iter().filter(move |process| -> bool {
+        process.stat.comm != "toda"
+            && !is_descendant_of(process.pid, self_pid, &parents)
+            && filter.map_or(true, |filter| filter.matches(process))
+    }))
+}
+
+// uses_paths returns true if the process holds an fd, the cwd or a map on a
+// file under any of the paths
+pub fn uses_paths(process: &Process, paths: &[PathBuf]) -> bool {
+    let under = |path: &Path| paths.iter().any(|base| path.starts_with(base));
+    if process.cwd().map_or(false, |cwd| under(&cwd)) {
+        return true;
+    }
+    let fds = process.fd().unwrap_or_default();
+    if fds.iter().any(|fd| match &fd.target {
+        FDTarget::Path(path) => under(path),
+        _ => false,
+    }) {
+        return true;
+    }
+    let maps = process.maps().unwrap_or_default();
+    maps.iter().any(|map| match &map.pathname {
+        MMapPath::Path(path) => under(path),
+        _ => false,
+    })
+}
+
+// is_descendant_of returns true if `pid` is `ancestor` or one of its descendants
+fn is_descendant_of(pid: i32, ancestor: i32, parents: &HashMap<i32, i32>) -> bool {
+    let mut pid = pid;
+    // the depth is limited by the count of processes, in case the parents change
+    // while they are read and a loop is formed
+    for _ in 0..=parents.len() {
+        if pid == ancestor {
+            return true;
+        }
+        match parents.get(&pid) {
+            Some(ppid) if *ppid > 0 => pid = *ppid,
+            _ => return false,
+        }
+    }
+
+    false
+}
+
+// resolve_path resolves the symlinks in `path`, as the targets of fds, cwd and
+// maps in procfs never contain symlinks. The path is returned as it is if it
+// can't be resolved.
+pub fn resolve_path(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+// FsType is the type of the filesystem which a file is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsType(pub i64);
+
+// the magic numbers which are not defined by nix
+const BTRFS_SUPER_MAGIC: i64 = 0x9123_683e;
+const CIFS_MAGIC_NUMBER: i64 = 0xff53_4d42;
+const FUSE_SUPER_MAGIC: i64 = 0x6573_5546;
+const SYSFS_MAGIC: i64 = 0x6265_6572;
+const XFS_SUPER_MAGIC: i64 = 0x5846_5342;
+
+impl FsType {
+    // of returns the type of the filesystem which `path` is on. The path can be
+    // a link in /proc/[pid]/fd, so a file in another mount namespace works too
+    pub fn of<P: AsRef<Path>>(path: P) -> Option<FsType> {
+        statfs::statfs(path.as_ref())
+            .ok()
+            .map(|stat| FsType(stat.filesystem_type().0 as i64))
+    }
+
+    pub fn name(&self) -> String {
+        let name = match self.0 {
+            magic if magic == statfs::EXT4_SUPER_MAGIC.0 as i64 => "ext4",
+            magic if magic == statfs::TMPFS_MAGIC.0 as i64 => "tmpfs",
+            magic if magic == statfs::OVERLAYFS_SUPER_MAGIC.0 as i64 => "overlay",
+            magic if magic == statfs::NFS_SUPER_MAGIC.0 as i64 => "nfs",
+            magic if magic == statfs::PROC_SUPER_MAGIC.0 as i64 => "proc",
+            magic if magic == statfs::CGROUP_SUPER_MAGIC.0 as i64 => "cgroup",
+            magic if magic == statfs::CGROUP2_SUPER_MAGIC.0 as i64 => "cgroup2",
+            BTRFS_SUPER_MAGIC => "btrfs",
+            CIFS_MAGIC_NUMBER => "cifs",
+            FUSE_SUPER_MAGIC => "fuse",
+            SYSFS_MAGIC => "sysfs",
+            XFS_SUPER_MAGIC => "xfs",
+            magic => return format!("{:#x}", magic),
+        };
+        name.to_owned()
+    }
+
+    // is_reopenable returns false for the pseudo filesystems, whose files are
+    // generated when they are opened. A reopened file loses the state of the
+    // original one, e.g. the content of a proc file read so far
+    pub fn is_reopenable(&self) -> bool {
+        let pseudo = [
+            statfs::PROC_SUPER_MAGIC.0 as i64,
+            statfs::CGROUP_SUPER_MAGIC.0 as i64,
+            statfs::CGROUP2_SUPER_MAGIC.0 as i64,
+            SYSFS_MAGIC,
+        ];
+        !pseudo.contains(&self.0)
+    }
 }
@@ -0,0 +1,124 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, Result};
+use tracing::error;
+
+use super::{ProcessFilter, ReplaceReport, Replacer, ReplacerKind, UnionReplacer};
+
+enum Command {
+    Run,
+    Plan,
+}
+
+enum Reply {
+    Prepared(Result<()>),
+    Report(ReplaceReport),
+    Plan(Vec<String>),
+}
+
+// ReplacerWorker prepares and runs a `UnionReplacer` on its own thread, for the
+// processes matched by its filter. ptrace requests must be sent by the thread
+// which attached the tracee, so the processes are traced by the worker from
+// prepare until it's dropped.
+pub struct ReplacerWorker {
+    commands: Option<Sender<Command>>,
+    replies: Receiver<Reply>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ReplacerWorker {
+    // spawn starts to prepare the replacer on a new thread. `prepared` waits
+    // for it.
+    pub fn spawn(
+        kinds: ReplacerKind,
+        direct_io: bool,
+        filter: ProcessFilter,
+        detect_path: PathBuf,
+        new_path: PathBuf,
+    ) -> Result<ReplacerWorker> {
+        let (commands, received) = mpsc::channel();
+        let (reply, replies) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("replacer".to_owned())
+            .spawn(move || {
+                let mut replacer = UnionReplacer::new(kinds)
+                    .with_direct_io(direct_io)
+                    .with_process_filter(Some(filter));
+                let prepared = replacer.prepare(&detect_path, &new_path);
+                if reply.send(Reply::Prepared(prepared)).is_err() {
+                    return;
+                }
+                for command in received {
+                    let replied = match command {
+                        Command::Run => Reply::Report(replacer.run_detailed()),
+                        Command::Plan => Reply::Plan(replacer.plan()),
+                    };
+                    if reply.send(replied).is_err() {
+                        break;
+                    }
+                }
+                // the processes are detached as the replacer is dropped
+            })?;
+
+        Ok(ReplacerWorker {
+            commands: Some(commands),
+            replies,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn prepared(&self) -> Result<()> {
+        match self.recv()? {
+            Reply::Prepared(result) => result,
+            _ => Err(anyhow!("unexpected reply from replacer worker")),
+        }
+    }
+
+    // start_run starts to replace the processes, without waiting for the report
+    pub fn start_run(&self) -> Result<()> {
+        self.send(Command::Run)
+    }
+
+    // report waits for the report of the run started by `start_run`
+    pub fn report(&self) -> Result<ReplaceReport> {
+        match self.recv()? {
+            Reply::Report(report) => Ok(report),
+            _ => Err(anyhow!("unexpected reply from replacer worker")),
+        }
+    }
+
+    pub fn plan(&self) -> Result<Vec<String>> {
+        self.send(Command::Plan)?;
+        match self.recv()? {
+            Reply::Plan(plan) => Ok(plan),
+            _ => Err(anyhow!("unexpected reply from replacer worker")),
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        self.commands
+            .as_ref()
+            .and_then(|commands| commands.send(command).ok())
+            .ok_or_else(|| anyhow!("replacer worker has exited"))
+    }
+
+    fn recv(&self) -> Result<Reply> {
+        self.replies
+            .recv()
+            .map_err(|_| anyhow!("replacer worker has exited"))
+    }
+}
+
+impl Drop for ReplacerWorker {
+    // drop waits for the worker to detach its processes
+    fn drop(&mut self) {
+        self.commands.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("replacer worker has panicked");
+            }
+        }
+    }
+}
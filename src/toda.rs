@@ -0,0 +1,703 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use nix::mount::{mount, umount, MsFlags};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, instrument, warn};
+
+use crate::fuse_device;
+use crate::hookfs::{EventSink, HookFs};
+use crate::injector::{InjectorConfig, MultiInjector};
+use crate::jsonrpc::Health;
+use crate::mount::{check_overlapping, MountError, MountsInfo, Propagation, RetryPolicy};
+use crate::mount_injector::{FuseOptions, MountInjectionGuard, MountInjector, MountMode};
+use crate::replacer::{
+    probe_ptrace, ProcessFilter, ProcessReport, ProcessStatus, Replacer, ReplacerKind,
+    UnionReplacer,
+};
+
+// Config describes an injection on a set of paths
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub paths: Vec<PathBuf>,
+    // don't replace the fds opened on the paths before injection
+    pub mount_only: bool,
+    // the replacers moving the fds, cwd and mmaps of processes onto the mount
+    pub replacers: ReplacerKind,
+    // only replace the processes matched by the filter. The fds of all
+    // processes are still moved back on recovery
+    pub target_process: Option<ProcessFilter>,
+    // the count of threads tracing and replacing the processes at the same
+    // time, see `UnionReplacer::with_concurrency`
+    pub replace_concurrency: usize,
+    pub mount_mode: MountMode,
+    pub fuse_options: FuseOptions,
+    // the directory to keep the original mounts during injection. They're kept
+    // beside the injected paths if it's None.
+    pub base_dir: Option<PathBuf>,
+    // restore the original mount if the FUSE server exits while injection is
+    // enabled
+    pub recover_on_crash: bool,
+    // remove the mounts left on the paths by a killed run before injection
+    pub force_cleanup: bool,
+    // create the missing paths, and bind mount a path which is not a mount
+    // point on itself, so it can be injected. The bind mounts are removed on
+    // resume, while the directories created are kept
+    pub create: bool,
+    pub retry_policy: RetryPolicy,
+    // what to do if ptrace is denied, which the replacers need
+    pub on_ptrace_denied: PtraceDenied,
+    pub injectors: Vec<InjectorConfig>,
+    // where the injected faults of all paths are logged
+    pub events: Option<Arc<EventSink>>,
+    // the health of the injection, which is updated on every change of state,
+    // e.g. to be served by the RPC
+    pub health: Option<Arc<Mutex<Health>>>,
+}
+
+impl Config {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        Config {
+            paths,
+            mount_only: false,
+            replacers: ReplacerKind::all(),
+            target_process: None,
+            replace_concurrency: 1,
+            mount_mode: MountMode::default(),
+            fuse_options: FuseOptions::default(),
+            base_dir: None,
+            recover_on_crash: false,
+            force_cleanup: false,
+            create: false,
+            retry_policy: RetryPolicy {
+                interval_ms: 500,
+                times: 20,
+            },
+            on_ptrace_denied: PtraceDenied::default(),
+            injectors: Vec::new(),
+            events: None,
+            health: None,
+        }
+    }
+
+    // replacers returns the replacers to run, which is empty if nothing should be
+    // replaced
+    fn replacers(&self) -> ReplacerKind {
+        if self.mount_only || self.mount_mode == MountMode::Direct {
+            return ReplacerKind::empty();
+        }
+        self.replacers
+    }
+}
+
+// PtraceDenied is what to do if ptrace is denied on the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtraceDenied {
+    // fail the injection before anything is changed
+    Fail,
+    // inject without replacing any process, as if `mount_only` is set
+    MountOnly,
+}
+
+impl Default for PtraceDenied {
+    fn default() -> Self {
+        PtraceDenied::Fail
+    }
+}
+
+impl FromStr for PtraceDenied {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fail" => Ok(PtraceDenied::Fail),
+            "mount-only" => Ok(PtraceDenied::MountOnly),
+            _ => Err(anyhow!(
+                "unknown action `{}`, valid ones are: fail, mount-only",
+                s
+            )),
+        }
+    }
+}
+
+// Toda injects faults into the paths in the config, without any RPC or signal
+// handling, so it can be embedded into other tools
+#[derive(Debug, Clone)]
+pub struct Toda {
+    config: Config,
+}
+
+// Injection is a running injection. The paths are restored by `resume`.
+pub struct Injection {
+    mount_guards: Vec<MountInjectionGuard>,
+    // the processes replaced on each path
+    replaced: Vec<Vec<ProcessReport>>,
+    replacers: ReplacerKind,
+    // the paths bind mounted on themselves to be injected, see `Config::create`
+    bind_mounts: Vec<PathBuf>,
+    health: Option<Arc<Mutex<Health>>>,
+}
+
+// Summary is the outcome of a whole run, for the tools driving toda to check
+// that the injection has taken effect
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    // the count of paths injected
+    pub paths: usize,
+    // a process touched by several replacers, e.g. for its fds and its cwd, is
+    // counted once by each of them
+    pub processes: ReplaceCounts,
+    pub fds: ReplaceCounts,
+    // whether the injection is mounted, and has replaced any process unless
+    // nothing should be replaced
+    pub applied: bool,
+    pub error: Option<String>,
+    pub recovered: bool,
+    pub recovery_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplaceCounts {
+    pub replaced: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl ReplaceCounts {
+    fn add(&mut self, status: &ProcessStatus) {
+        match status {
+            ProcessStatus::Replaced => self.replaced += 1,
+            ProcessStatus::Skipped => self.skipped += 1,
+            ProcessStatus::Failed(_) => self.failed += 1,
+        }
+    }
+}
+
+impl Summary {
+    // failed is the summary of an injection which has failed, so nothing is
+    // left to recover
+    pub fn failed(err: &anyhow::Error) -> Summary {
+        Summary {
+            error: Some(format!("{:#}", err)),
+            recovered: true,
+            ..Summary::default()
+        }
+    }
+
+    pub fn set_recovery(&mut self, result: &Result<()>) {
+        self.recovered = result.is_ok();
+        self.recovery_error = result.as_ref().err().map(|err| format!("{:#}", err));
+    }
+
+    // succeeded returns whether the injection has been applied and recovered
+    pub fn succeeded(&self) -> bool {
+        self.applied && self.recovered
+    }
+}
+
+impl Toda {
+    pub fn new(config: Config) -> Self {
+        Toda { config }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // inject mounts the FUSE filesystem on every path. If any path fails, the
+    // paths which have been injected are restored.
+    #[instrument(skip(self))]
+    pub fn inject(&self) -> Result<Injection> {
+        set_health(&self.config.health, Health::Mounting);
+        let result = self.inject_paths();
+        set_health(
+            &self.config.health,
+            match &result {
+                Ok(_) => Health::Ready,
+                Err(err) => Health::Failed(err.to_string()),
+            },
+        );
+        result
+    }
+
+    fn inject_paths(&self) -> Result<Injection> {
+        if self.config.create {
+            for path in self.config.paths.iter() {
+                std::fs::create_dir_all(path)
+                    .with_context(|| format!("create {}", path.display()))?;
+            }
+        }
+        self.check_paths()?;
+        self.check_mount_points()?;
+        if !self.config.replacers().is_empty() {
+            let probed = probe_ptrace(&self.config.paths, self.config.target_process.as_ref());
+            if let Err(err) = probed {
+                match self.config.on_ptrace_denied {
+                    PtraceDenied::Fail => return Err(err),
+                    PtraceDenied::MountOnly => {
+                        warn!("{:#}. Fall back to mount only", err);
+                        let mut config = self.config.clone();
+                        config.mount_only = true;
+                        return Toda::new(config).inject_paths();
+                    }
+                }
+            }
+        }
+        if self.config.force_cleanup {
+            self.cleanup()?;
+        }
+
+        let mut injection = Injection {
+            mount_guards: Vec::new(),
+            replaced: Vec::new(),
+            replacers: self.config.replacers(),
+            bind_mounts: Vec::new(),
+            health: self.config.health.clone(),
+        };
+        if !injection.replacers.contains(ReplacerKind::FD) {
+            warn!(
+                "fd replacement is disabled, the files opened before injection won't be injected"
+            );
+        }
+        for path in self.config.paths.iter() {
+            let result = if self.config.create {
+                make_mount_point(path, &mut injection.bind_mounts)
+            } else {
+                Ok(())
+            };
+            match result.and_then(|_| self.inject_path(path)) {
+                Ok((mount_guard, replaced)) => {
+                    injection.mount_guards.push(mount_guard);
+                    injection.replaced.push(replaced);
+                }
+                Err(err) => {
+                    error!("fail to inject {}: {:?}", path.display(), err);
+                    // don't leave the already injected paths mounted
+                    if let Err(err) = injection.resume() {
+                        error!("fail to recover injected paths: {:?}", err);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(injection)
+    }
+
+    // cleanup removes the mounts left on the paths by a run which was killed
+    // without resuming, e.g. by SIGKILL, and restores the original mounts
+    #[instrument(skip(self))]
+    pub fn cleanup(&self) -> Result<()> {
+        for original_path in self.config.paths.iter() {
+            let path = canonicalize_mount_point(original_path)?;
+            self.create_injection(&path)?.cleanup()?;
+        }
+
+        Ok(())
+    }
+
+    // plan describes what `inject` would do on every path. The processes are
+    // traced to read their fds, but nothing is mounted or replaced.
+    #[instrument(skip(self))]
+    pub fn plan(&self) -> Result<Vec<String>> {
+        self.check_paths()?;
+        let mut plan = Vec::new();
+        let mounts = MountsInfo::parse_mounts()?;
+        for original_path in self.config.paths.iter() {
+            if self.config.create && !original_path.exists() {
+                // nothing can be opened on the path yet, so only the mounts of
+                // an empty directory would be made
+                plan.push(format!("{}:", original_path.display()));
+                plan.push(format!("  create {}", original_path.display()));
+                plan.push(format!(
+                    "  bind mount {} on itself",
+                    original_path.display()
+                ));
+                continue;
+            }
+            let path = canonicalize(original_path)?;
+            let bind_mount = !mounts.is_mount_point(&path);
+            if bind_mount && !self.config.create {
+                return Err(MountError::NotMountPoint { path }.into());
+            }
+            let injection = self.create_injection(&path)?;
+            // fail on the configs which would fail the injection
+            MultiInjector::build_for_mount(
+                self.config.injectors.clone(),
+                &path,
+                self.config.fuse_options.direct_io,
+            )?;
+
+            plan.push(format!("{}:", original_path.display()));
+            if bind_mount {
+                plan.push(format!("  bind mount {} on itself", path.display()));
+            }
+            plan.push(format!("  make {} private", path.display()));
+            plan.push(format!("  bind mount {} on itself", path.display()));
+            for operation in injection.plan() {
+                plan.push(format!("  {}", operation));
+            }
+
+            if !self.config.replacers().is_empty() {
+                let mut replacer = UnionReplacer::new(self.config.replacers())
+                    .with_direct_io(self.config.fuse_options.direct_io)
+                    .with_process_filter(self.config.target_process.clone())
+                    .with_concurrency(self.config.replace_concurrency);
+                replacer.prepare(&path, &path)?;
+                for replacement in replacer.plan() {
+                    plan.push(format!("  {}", replacement));
+                }
+            }
+        }
+
+        Ok(plan)
+    }
+
+    // check_paths rejects nested paths before anything is mounted
+    fn check_paths(&self) -> Result<()> {
+        let paths = self
+            .config
+            .paths
+            .iter()
+            .map(|path| canonicalize_mount_point(path))
+            .collect::<Result<Vec<_>>>()?;
+        check_overlapping(&paths)?;
+        Ok(())
+    }
+
+    // check_mount_points rejects the paths which are not mount points before
+    // ptrace is probed, unless they are made ones by `create`
+    fn check_mount_points(&self) -> Result<()> {
+        if self.config.create {
+            return Ok(());
+        }
+        let mounts = MountsInfo::parse_mounts()?;
+        for path in self.config.paths.iter() {
+            let path = canonicalize_mount_point(path)?;
+            if !mounts.is_mount_point(&path) {
+                return Err(MountError::NotMountPoint { path }.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn create_injection(&self, path: &Path) -> Result<MountInjector> {
+        Ok(MountInjector::create_injection(
+            path,
+            self.config.base_dir.as_deref(),
+            self.config.injectors.clone(),
+            self.config.retry_policy,
+        )?
+        .with_mode(self.config.mount_mode)
+        .with_fuse_options(self.config.fuse_options)
+        .with_events(self.config.events.clone()))
+    }
+
+    #[instrument(skip(self))]
+    fn inject_path(
+        &self,
+        original_path: &Path,
+    ) -> Result<(MountInjectionGuard, Vec<ProcessReport>)> {
+        info!("inject with config {:?}", self.config.injectors);
+
+        info!("canonicalizing path {}", original_path.display());
+        let path = canonicalize_mount_point(original_path)?;
+        if self.create_injection(&path)?.is_injected()? {
+            return Err(MountError::AlreadyInjected { path }.into());
+        }
+        let path = canonicalize(&path)?;
+
+        // the propagation type can only be changed on a mount point
+        let mounts = MountsInfo::parse_mounts()?;
+        if !mounts.is_mount_point(&path) {
+            return Err(MountError::NotMountPoint { path }.into());
+        }
+        // fail before anything is changed, if FUSE can't be mounted at all
+        fuse_device::preflight()?;
+
+        // 1. Set mount properties.
+        // 2. Mirror mount. Only the mirror is moved away later, so the mount
+        // on the path stays where it is.
+        let propagation = mounts.propagation(&path);
+        const NONE: Option<&'static [u8]> = None;
+        mount(NONE, path.as_path(), NONE, MsFlags::MS_PRIVATE, NONE)
+            .context(format!("make-private {}", path.display()))?;
+        if let Err(err) = mount(
+            Some(path.as_path()),
+            path.as_path(),
+            NONE,
+            MsFlags::MS_BIND,
+            NONE,
+        ) {
+            restore_propagation(&path, propagation);
+            return Err(err).context(format!("mount bind {}", path.display()));
+        }
+
+        match self.mount_hookfs(&path, propagation) {
+            Ok(mounted) => Ok(mounted),
+            Err(err) => {
+                // undo the mirror mount, so a retried injection starts from a clean state
+                if let Err(err) = umount(path.as_path()) {
+                    error!("fail to umount mirror mount {}: {:?}", path.display(), err);
+                }
+                restore_propagation(&path, propagation);
+                Err(err)
+            }
+        }
+    }
+
+    fn mount_hookfs(
+        &self,
+        path: &Path,
+        propagation: Propagation,
+    ) -> Result<(MountInjectionGuard, Vec<ProcessReport>)> {
+        let replacers = self.config.replacers();
+        let replacer = if !replacers.is_empty() {
+            // the hookfs only keeps O_DIRECT of the opened files with direct io
+            let mut replacer = UnionReplacer::new(replacers)
+                .with_direct_io(self.config.fuse_options.direct_io)
+                .with_process_filter(self.config.target_process.clone())
+                .with_concurrency(self.config.replace_concurrency);
+            replacer.prepare(&path, &path)?;
+
+            Some(replacer)
+        } else {
+            None
+        };
+
+        let mut injection = self.create_injection(path)?.with_propagation(propagation);
+        let mut mount_guard = injection.mount()?;
+        info!("mount successfully");
+
+        let mut replaced = Vec::new();
+        if let Some(mut replacer) = replacer {
+            // At this time, `mount --move` has already been executed.
+            // Our FUSE are mounted on the "path", so we
+            let report = replacer.run_detailed();
+            replaced = report.processes.clone();
+            match report.into_result() {
+                // processes exiting during the replacement don't need to be replaced
+                Err(err) if !err.is_fatal() => info!("some processes have exited: {}", err),
+                Err(err) => {
+                    drop(replacer);
+                    recover_after_failure(replacers, mount_guard);
+                    return Err(err.into());
+                }
+                Ok(()) => {}
+            }
+            drop(replacer);
+            info!("replacer detached");
+        }
+
+        info!("enable injection");
+        mount_guard.enable_injection();
+
+        if self.config.recover_on_crash {
+            let health = self.config.health.clone();
+            let recovered_health = self.config.health.clone();
+            let result = mount_guard.supervise(
+                move |path, new_path| {
+                    set_health(&health, Health::Recovering);
+                    if !replacers.is_empty() {
+                        let mut replacer = UnionReplacer::new(replacers);
+                        if let Err(err) = replacer
+                            .prepare(path, new_path)
+                            .and_then(|_| Ok(replacer.run()?))
+                        {
+                            error!("fail to replace fds back: {:?}", err);
+                        }
+                    }
+                },
+                move |result| {
+                    // the injection is over either way, as the FUSE server is gone
+                    let error = match result {
+                        Ok(()) => "FUSE server exited, the original mount is restored".to_owned(),
+                        Err(err) => format!("FUSE server exited, fail to recover: {}", err),
+                    };
+                    set_health(&recovered_health, Health::Failed(error));
+                },
+            );
+            if let Err(err) = result {
+                recover_after_failure(replacers, mount_guard);
+                return Err(err);
+            }
+        }
+
+        Ok((mount_guard, replaced))
+    }
+}
+
+impl Injection {
+    // hookfs returns the filesystems of the injected paths, in the order of the
+    // paths in the config
+    pub fn hookfs(&self) -> Vec<Arc<HookFs>> {
+        self.mount_guards
+            .iter()
+            .map(|guard| guard.hookfs.clone())
+            .collect()
+    }
+
+    // replaced returns the processes replaced on each path, in the order of the
+    // paths in the config
+    pub fn replaced(&self) -> &[Vec<ProcessReport>] {
+        &self.replaced
+    }
+
+    // summary counts the processes and fds replaced. It's not recovered yet
+    pub fn summary(&self) -> Summary {
+        let mut summary = Summary {
+            paths: self.mount_guards.len(),
+            ..Summary::default()
+        };
+        for process in self.replaced.iter().flatten() {
+            summary.processes.add(&process.status);
+            for fd in process.fds.iter() {
+                summary.fds.add(&fd.status);
+            }
+        }
+        summary.applied =
+            summary.paths > 0 && (self.replacers.is_empty() || summary.processes.replaced > 0);
+        summary
+    }
+
+    // resume disables the injection and restores every path
+    #[instrument(skip(self))]
+    pub fn resume(self) -> Result<()> {
+        set_health(&self.health, Health::Recovering);
+        let mut result = Ok(());
+        // recover in the reverse order of injection, and keep going on error so
+        // that the other paths won't be left mounted
+        for mount_guard in self.mount_guards.into_iter().rev() {
+            let path = mount_guard.original_path().to_owned();
+            if let Err(err) = resume_path(self.replacers, mount_guard) {
+                error!("fail to recover {}: {:?}", path.display(), err);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        for path in self.bind_mounts.iter().rev() {
+            info!("umount bind mount {}", path.display());
+            if let Err(err) = umount(path.as_path()) {
+                error!("fail to umount {}: {:?}", path.display(), err);
+                if result.is_ok() {
+                    result = Err(err).context(format!("umount {}", path.display()));
+                }
+            }
+        }
+        if let Err(err) = &result {
+            set_health(&self.health, Health::Failed(err.to_string()));
+        }
+
+        result
+    }
+}
+
+fn set_health(health: &Option<Arc<Mutex<Health>>>, state: Health) {
+    if let Some(health) = health {
+        *health.lock().unwrap() = state;
+    }
+}
+
+// canonicalize canonicalizes the path, telling a missing path from one which
+// can't be accessed
+fn canonicalize(path: &Path) -> Result<PathBuf> {
+    path.canonicalize().map_err(|source| {
+        let path = path.to_owned();
+        match source.kind() {
+            std::io::ErrorKind::NotFound => MountError::NotFound { path }.into(),
+            _ => MountError::Inaccessible { path, source }.into(),
+        }
+    })
+}
+
+// make_mount_point bind mounts the path on itself if it's not a mount point, and
+// records it to be unmounted on resume
+fn make_mount_point(path: &Path, bind_mounts: &mut Vec<PathBuf>) -> Result<()> {
+    let path = canonicalize(path)?;
+    if MountsInfo::parse_mounts()?.is_mount_point(&path) {
+        return Ok(());
+    }
+
+    info!("bind mount {} on itself", path.display());
+    const NONE: Option<&'static [u8]> = None;
+    mount(
+        Some(path.as_path()),
+        path.as_path(),
+        NONE,
+        MsFlags::MS_BIND,
+        NONE,
+    )
+    .context(format!("mount bind {}", path.display()))?;
+    bind_mounts.push(path);
+    Ok(())
+}
+
+// canonicalize_mount_point canonicalizes the path. If the path itself can't be
+// accessed, e.g. a FUSE mount whose server has been killed is on it, only its
+// parent is canonicalized.
+fn canonicalize_mount_point(path: &Path) -> Result<PathBuf> {
+    if let Ok(path) = path.canonicalize() {
+        return Ok(path);
+    }
+
+    let name = path
+        .file_name()
+        .ok_or(anyhow!("the path terminates in `..` or `/`"))?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Ok(canonicalize(parent)?.join(name))
+}
+
+// restore_propagation restores the propagation type of the mount, which has
+// been made private by injection
+fn restore_propagation(path: &Path, propagation: Propagation) {
+    if let Err(err) = propagation.restore(path) {
+        error!("{:?}", err);
+    }
+}
+
+fn recover_after_failure(replacers: ReplacerKind, mount_guard: MountInjectionGuard) {
+    if let Err(err) = resume_path(replacers, mount_guard) {
+        error!("fail to recover after injection failed: {:?}", err);
+    }
+}
+
+#[instrument(skip(mount_guard))]
+fn resume_path(replacers: ReplacerKind, mount_guard: MountInjectionGuard) -> Result<()> {
+    info!("disable injection");
+    mount_guard.disable_injection();
+
+    let path = mount_guard.original_path().to_owned();
+
+    info!("canonicalizing path {}", path.display());
+    let path = path.canonicalize()?;
+    // the fds are moved back to where the original mount actually is
+    let new_path = mount_guard.moved_path()?;
+
+    let replacer = if !replacers.is_empty() {
+        let mut replacer = UnionReplacer::new(replacers);
+        replacer.prepare(&path, &new_path)?;
+        info!("running replacer");
+        let result = replacer.run();
+        info!("replace result: {:?}", result);
+
+        Some(replacer)
+    } else {
+        None
+    };
+
+    info!("recovering mount");
+    mount_guard.recover_mount()?;
+
+    info!("replacers detached");
+    info!("recover successfully");
+
+    drop(replacer);
+    Ok(())
+}
@@ -1,8 +1,20 @@
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
 
-pub fn encode_path<P: AsRef<Path>>(original_path: P) -> Result<(PathBuf, PathBuf)> {
+// the max length of a file name on Linux
+const NAME_MAX: usize = 255;
+
+// encode_path returns the original path, and the path where the original mount
+// is kept during injection. It's `__chaosfs__<name>__` beside the original path,
+// or under `base_dir` if it's set. Under `base_dir`, the hash of the original
+// path is added to the name, as paths with the same name may be injected at the
+// same time. If the name would be longer than NAME_MAX, only the hash is kept.
+pub fn encode_path<P: AsRef<Path>>(
+    original_path: P,
+    base_dir: Option<&Path>,
+) -> Result<(PathBuf, PathBuf)> {
     let original_path: PathBuf = original_path.as_ref().to_owned();
 
     let mut base_path: PathBuf = original_path.clone();
@@ -10,14 +22,35 @@ pub fn encode_path<P: AsRef<Path>>(original_path: P) -> Result<(PathBuf, PathBuf
         return Err(anyhow!("path is the root"));
     }
 
-    let mut new_path: PathBuf = base_path;
     let original_filename = original_path
         .file_name()
         .ok_or(anyhow!("the path terminates in `..` or `/`"))?
         .to_str()
         .ok_or(anyhow!("path with non-UTF-8 character"))?;
-    let new_filename = format!("__chaosfs__{}__", original_filename);
-    new_path.push(new_filename.as_str());
+    let hash = hash_path(&original_path);
+    let (mut new_path, new_filename) = match base_dir {
+        Some(base_dir) => (
+            base_dir.to_owned(),
+            format!("__chaosfs__{}_{:016x}__", original_filename, hash),
+        ),
+        None => (base_path, format!("__chaosfs__{}__", original_filename)),
+    };
+    if new_filename.len() > NAME_MAX {
+        new_path.push(format!("__chaosfs__{:016x}__", hash));
+    } else {
+        new_path.push(new_filename);
+    }
 
     Ok((original_path, new_path))
 }
+
+// hash_path hashes the path with FNV-1a, so the same path is always encoded to
+// the same name, even by another build of toda
+fn hash_path(path: &Path) -> u64 {
+    path.as_os_str()
+        .as_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100_0000_01b3)
+        })
+}
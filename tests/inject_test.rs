@@ -0,0 +1,219 @@
+// These tests run the whole inject and resume cycle on a tmpfs, so they need
+// root. They're ignored by default, run them with
+// `sudo -E cargo test --test inject_test -- --ignored`
+
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use toda::injector::InjectorConfig;
+use toda::mount::{MountError, MountsInfo, RetryPolicy};
+use toda::mount_injector::MountInjector;
+use toda::utils::encode_path;
+use toda::{Config, Toda};
+
+// Volume is a tmpfs mounted for a test, which is unmounted on drop even if the
+// test fails
+struct Volume {
+    path: PathBuf,
+}
+
+impl Volume {
+    fn tmpfs(name: &str) -> Volume {
+        let path: PathBuf = ["/tmp/test_toda_inject", name].iter().collect();
+        std::fs::create_dir_all(&path).unwrap();
+        umount_all(&path);
+        const NONE: Option<&'static [u8]> = None;
+        mount(Some("tmpfs"), &path, Some("tmpfs"), MsFlags::empty(), NONE).unwrap();
+        Volume { path }
+    }
+
+    fn file(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    fn config(&self, injectors: &str) -> Config {
+        let mut config = Config::new(vec![self.path.clone()]);
+        // the test process itself has nothing opened on the volume to replace
+        config.mount_only = true;
+        config.injectors = serde_json::from_str::<Vec<InjectorConfig>>(injectors).unwrap();
+        config
+    }
+}
+
+impl Drop for Volume {
+    fn drop(&mut self) {
+        umount_all(&self.path);
+        let (_, new_path) = encode_path(&self.path, None).unwrap();
+        umount_all(&new_path);
+    }
+}
+
+// umount_all removes every mount stacked on the path
+fn umount_all(path: &Path) {
+    while MountsInfo::parse_mounts().unwrap().is_mount_point(path) {
+        if umount2(path, MntFlags::MNT_DETACH).is_err() {
+            break;
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn inject_fault_and_resume() {
+    let volume = Volume::tmpfs("fault");
+    write(volume.file("file"), b"data").unwrap();
+
+    let injection =
+        Toda::new(volume.config(r#"[{"type": "fault", "methods": ["read"], "errno": 5}]"#))
+            .inject()
+            .unwrap();
+    assert!(MountsInfo::parse_mounts()
+        .unwrap()
+        .is_fuse(&volume.path, "toda"));
+
+    let err = read_to_string(volume.file("file")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    // the other methods are passed to the original files
+    write(volume.file("written"), b"written").unwrap();
+
+    injection.resume().unwrap();
+
+    let mounts = MountsInfo::parse_mounts().unwrap();
+    assert!(!mounts.is_fuse(&volume.path, "toda"));
+    assert!(mounts.is_mount_point(&volume.path));
+    assert_eq!(read_to_string(volume.file("file")).unwrap(), "data");
+    assert_eq!(read_to_string(volume.file("written")).unwrap(), "written");
+}
+
+#[test]
+#[ignore]
+fn inject_twice_is_rejected() {
+    let volume = Volume::tmpfs("twice");
+
+    let injection = Toda::new(volume.config("[]")).inject().unwrap();
+    let err = Toda::new(volume.config("[]")).inject().err().unwrap();
+    assert!(matches!(
+        err.downcast_ref::<MountError>(),
+        Some(MountError::AlreadyInjected { .. })
+    ));
+
+    injection.resume().unwrap();
+    assert!(!MountsInfo::parse_mounts()
+        .unwrap()
+        .is_fuse(&volume.path, "toda"));
+}
+
+#[test]
+#[ignore]
+fn mount_detached_and_recover() {
+    let volume = Volume::tmpfs("detached");
+    write(volume.file("file"), b"data").unwrap();
+
+    let mut injector =
+        MountInjector::create_injection(&volume.path, None, Vec::new(), RetryPolicy::default())
+            .unwrap();
+    let mount = injector.mount_detached().unwrap();
+    assert!(mount.fd() >= 0);
+    // nobody serves the mount, so only the mount table is checked
+    let mounts = MountsInfo::parse_mounts().unwrap();
+    assert!(mounts.is_fuse(&volume.path, "toda"));
+    assert!(mounts.is_mount_point(mount.new_path()));
+
+    mount.recover_mount().unwrap();
+    assert!(!MountsInfo::parse_mounts()
+        .unwrap()
+        .is_fuse(&volume.path, "toda"));
+    assert_eq!(read_to_string(volume.file("file")).unwrap(), "data");
+}
+
+#[test]
+#[ignore]
+fn detached_mount_recovered_on_drop() {
+    let volume = Volume::tmpfs("detached_drop");
+    write(volume.file("file"), b"data").unwrap();
+
+    let mut injector =
+        MountInjector::create_injection(&volume.path, None, Vec::new(), RetryPolicy::default())
+            .unwrap();
+    let mount = injector.mount_detached().unwrap();
+    let new_path = mount.new_path().to_owned();
+    drop(mount);
+
+    assert!(!MountsInfo::parse_mounts()
+        .unwrap()
+        .is_fuse(&volume.path, "toda"));
+    assert_eq!(read_to_string(volume.file("file")).unwrap(), "data");
+    assert!(!new_path.exists());
+}
+
+#[test]
+#[ignore]
+fn resume_after_original_mount_moved() {
+    let volume = Volume::tmpfs("moved");
+    write(volume.file("file"), b"data").unwrap();
+
+    let injection = Toda::new(volume.config("[]")).inject().unwrap();
+
+    // someone else moves the original mount away during injection
+    let (_, new_path) = encode_path(&volume.path, None).unwrap();
+    let elsewhere = PathBuf::from("/tmp/test_toda_inject/moved_elsewhere");
+    std::fs::create_dir_all(&elsewhere).unwrap();
+    const NONE: Option<&'static [u8]> = None;
+    mount(Some(&new_path), &elsewhere, NONE, MsFlags::MS_MOVE, NONE).unwrap();
+
+    injection.resume().unwrap();
+
+    let mounts = MountsInfo::parse_mounts().unwrap();
+    assert!(!mounts.is_fuse(&volume.path, "toda"));
+    assert!(!mounts.is_mount_point(&elsewhere));
+    // the original tmpfs is moved back, rather than mounted again
+    assert_eq!(read_to_string(volume.file("file")).unwrap(), "data");
+    umount_all(&elsewhere);
+}
+
+#[test]
+#[ignore]
+fn resume_removes_created_directory() {
+    let volume = Volume::tmpfs("cleanup");
+    let (_, new_path) = encode_path(&volume.path, None).unwrap();
+    std::fs::remove_dir(&new_path).ok();
+
+    let injection = Toda::new(volume.config("[]")).inject().unwrap();
+    assert!(new_path.is_dir());
+    injection.resume().unwrap();
+    assert!(!new_path.exists());
+
+    // a directory which existed before injection is kept
+    std::fs::create_dir_all(&new_path).unwrap();
+    let injection = Toda::new(volume.config("[]")).inject().unwrap();
+    injection.resume().unwrap();
+    assert!(new_path.is_dir());
+    std::fs::remove_dir(&new_path).unwrap();
+}
+
+#[test]
+#[ignore]
+fn inject_with_create() {
+    let volume = Volume::tmpfs("create");
+    let path = volume.file("missing");
+
+    let mut config = volume.config(r#"[{"type": "fault", "methods": ["read"], "errno": 5}]"#);
+    config.paths = vec![path.clone()];
+    let err = Toda::new(config.clone()).inject().err().unwrap();
+    assert!(matches!(
+        err.downcast_ref::<MountError>(),
+        Some(MountError::NotFound { .. })
+    ));
+
+    config.create = true;
+    let injection = Toda::new(config).inject().unwrap();
+    assert!(MountsInfo::parse_mounts().unwrap().is_fuse(&path, "toda"));
+    write(path.join("file"), b"data").unwrap();
+    assert!(read_to_string(path.join("file")).is_err());
+
+    injection.resume().unwrap();
+    // the bind mount made for the injection is removed, but not the directory
+    assert!(!MountsInfo::parse_mounts().unwrap().is_mount_point(&path));
+    assert_eq!(read_to_string(path.join("file")).unwrap(), "data");
+}
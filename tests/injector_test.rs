@@ -0,0 +1,652 @@
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use fuser::{FileAttr, FileType};
+use nix::errno::Errno;
+use toda::hookfs::{self, Caller, Data, FileSize, Reply};
+use toda::injector::{Injection, Injector, InjectorConfig, Method, MultiInjector, Probability};
+
+#[test]
+fn test_probability_with_same_seed_is_reproducible() {
+    let first = Probability::new(0.5, Some(42)).unwrap();
+    let second = Probability::new(0.5, Some(42)).unwrap();
+
+    let first: Vec<_> = (0..100).map(|_| first.should_apply()).collect();
+    let second: Vec<_> = (0..100).map(|_| second.should_apply()).collect();
+    assert_eq!(first, second);
+}
+
+#[test]
+fn test_probability_bounds() {
+    let always = Probability::new(1.0, None).unwrap();
+    let never = Probability::new(0.0, None).unwrap();
+    for _ in 0..100 {
+        assert!(always.should_apply());
+        assert!(!never.should_apply());
+    }
+
+    assert!(Probability::new(1.5, None).is_err());
+    assert!(Probability::from_percent(-1, None).is_err());
+}
+
+#[test]
+fn test_attr_override_keeps_unset_fields() {
+    let config: Vec<InjectorConfig> = serde_json::from_str(
+        r#"[{"type": "attrOverride", "path": "/mnt/*", "percent": 100, "ino": 42, "size": 1024, "perm": 292}]"#,
+    )
+    .unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    let time = UNIX_EPOCH + Duration::from_secs(1);
+    let mut attr = FileAttr {
+        ino: 2,
+        size: 10,
+        blocks: 1,
+        atime: time,
+        mtime: time,
+        ctime: time,
+        crtime: time,
+        kind: FileType::RegularFile,
+        perm: 0o644,
+        nlink: 1,
+        uid: 1000,
+        gid: 1000,
+        rdev: 0,
+        blksize: 4096,
+        padding: 0,
+        flags: 0,
+    };
+    injector.inject_attr(&mut attr, Path::new("/mnt/file"));
+
+    assert_eq!(attr.size, 1024);
+    assert_eq!(attr.perm, 0o444);
+    // the inode number is the node id of the kernel, so it's never overridden
+    assert_eq!(attr.ino, 2);
+    assert_eq!(attr.uid, 1000);
+    assert_eq!(attr.mtime, time);
+
+    let mut other = attr;
+    other.size = 10;
+    injector.inject_attr(&mut other, Path::new("/other/file"));
+    assert_eq!(other.size, 10);
+}
+
+fn mistake_injector(filling: &str, seed: u64) -> MultiInjector {
+    let config = format!(
+        r#"[{{"type": "mistake", "methods": ["WRITE"], "seed": {}, "mistake": {{"filling": {}, "maxOccurrences": 3, "maxLength": 4}}}}]"#,
+        seed, filling
+    );
+    MultiInjector::build(serde_json::from_str(&config).unwrap()).unwrap()
+}
+
+#[test]
+fn test_mistake_with_same_seed_is_reproducible() {
+    let first = mistake_injector(r#""random""#, 7);
+    let second = mistake_injector(r#""random""#, 7);
+
+    for _ in 0..10 {
+        let mut first_data = vec![0u8; 64];
+        let mut second_data = vec![0u8; 64];
+        first
+            .inject_write_data(Path::new("/file"), &mut first_data)
+            .unwrap();
+        second
+            .inject_write_data(Path::new("/file"), &mut second_data)
+            .unwrap();
+        assert_eq!(first_data, second_data);
+    }
+}
+
+#[test]
+fn test_mistake_fill_with_byte() {
+    let injector = mistake_injector(r#"{"byte": 255}"#, 7);
+
+    let mut data = vec![0u8; 64];
+    injector
+        .inject_write_data(Path::new("/file"), &mut data)
+        .unwrap();
+
+    let corrupted = data.iter().filter(|byte| **byte == 255).count();
+    assert!((1..=12).contains(&corrupted));
+    assert!(data.iter().all(|byte| *byte == 0 || *byte == 255));
+}
+
+#[test]
+fn test_unknown_method_is_rejected() {
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "fault", "methods": ["read", "methdos"], "errno": 5}]"#)
+            .unwrap();
+    let err = MultiInjector::build(config).unwrap_err().to_string();
+
+    assert!(err.starts_with("unknown method `methdos`, valid methods are: lookup, forget,"));
+}
+
+#[test]
+fn test_relative_path_pattern() {
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "mistake", "path": "data/*.sst", "seed": 1, "mistake": {"filling": "zero", "maxOccurrences": 1, "maxLength": 1}}]"#)
+            .unwrap();
+    let injector = MultiInjector::build_with_root(config, Path::new("/var/db")).unwrap();
+
+    let corrupted = |path: &str| {
+        let mut data = vec![1u8; 4];
+        injector
+            .inject_write_data(Path::new(path), &mut data)
+            .unwrap();
+        data.contains(&0)
+    };
+    assert!(corrupted("/var/db/data/000001.sst"));
+    assert!(!corrupted("/var/db/data/000001.log"));
+    assert!(!corrupted("/var/db/wal/000001.sst"));
+    assert!(!corrupted("/data/000001.sst"));
+}
+
+#[test]
+fn test_invalid_path_pattern_is_rejected() {
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "latency", "path": "[a", "latency": "1ms"}]"#).unwrap();
+
+    assert!(MultiInjector::build(config).is_err());
+}
+
+fn windowed_injector(window: &str) -> MultiInjector {
+    let config = format!(
+        r#"[{{"type": "mistake", {}, "mistake": {{"filling": "zero", "maxOccurrences": 1, "maxLength": 1}}}}]"#,
+        window
+    );
+    MultiInjector::build(serde_json::from_str(&config).unwrap()).unwrap()
+}
+
+fn corrupted(injector: &MultiInjector) -> bool {
+    let mut data = vec![1u8; 4];
+    injector
+        .inject_write_data(Path::new("/file"), &mut data)
+        .unwrap();
+    data.contains(&0)
+}
+
+#[test]
+fn test_time_window() {
+    let delayed = windowed_injector(r#""startDelay": "50ms""#);
+    let limited = windowed_injector(r#""duration": "50ms""#);
+    let now = Instant::now();
+    delayed.enable(now);
+    limited.enable(now);
+    assert!(!corrupted(&delayed));
+    assert!(corrupted(&limited));
+
+    thread::sleep(Duration::from_millis(60));
+    assert!(corrupted(&delayed));
+    assert!(!corrupted(&limited));
+
+    // the window starts again when the injection is enabled again
+    limited.enable(Instant::now());
+    assert!(corrupted(&limited));
+}
+
+#[test]
+fn test_latency_over_limit_is_passed() {
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "latency", "latency": "1h", "maxConcurrent": 0}]"#)
+            .unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    let injection =
+        futures::executor::block_on(injector.inject(&Method::READ, Path::new("/file"))).unwrap();
+    assert_eq!(injection, Injection::Passed);
+}
+
+#[test]
+fn test_fdatasync_fault() {
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "fault", "methods": ["fdatasync"], "errno": 5}]"#)
+            .unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    let inject = |method| futures::executor::block_on(injector.inject(&method, Path::new("/wal")));
+    assert!(inject(Method::FDATASYNC).is_err());
+    assert!(inject(Method::FSYNC).is_ok());
+}
+
+#[test]
+fn test_latency_distribution_validation() {
+    let build = |latency: &str| {
+        let config = format!(r#"[{{"type": "latency", {}}}]"#, latency);
+        MultiInjector::build(serde_json::from_str(&config).unwrap())
+    };
+
+    assert!(build(r#""distribution": {"type": "uniform", "min": "1ms", "max": "10ms"}"#).is_ok());
+    assert!(build(r#""distribution": {"type": "normal", "mean": "5ms", "stddev": "1ms"}"#).is_ok());
+    assert!(build(r#""distribution": {"type": "pareto", "scale": "1ms", "shape": 1.5}"#).is_ok());
+
+    assert!(build(r#""distribution": {"type": "uniform", "min": "10ms", "max": "1ms"}"#).is_err());
+    assert!(build(r#""distribution": {"type": "pareto", "scale": "1ms", "shape": -1}"#).is_err());
+    assert!(build(r#""distribution": {"type": "pareto", "scale": "0ms", "shape": 2}"#).is_err());
+    assert!(
+        build(r#""latency": "1ms", "distribution": {"type": "fixed", "latency": "1ms"}"#).is_err()
+    );
+    assert!(build(r#""methods": ["read"]"#).is_err());
+}
+
+#[test]
+fn test_include_and_exclude_patterns() {
+    let config: Vec<InjectorConfig> = serde_json::from_str(
+        r#"[{"type": "fault", "include": ["data/**"], "exclude": ["data/manifest"], "errno": 5}]"#,
+    )
+    .unwrap();
+    let injector = MultiInjector::build_with_root(config, Path::new("/var/db")).unwrap();
+
+    let inject =
+        |path: &str| futures::executor::block_on(injector.inject(&Method::READ, Path::new(path)));
+    assert!(inject("/var/db/data/000001.sst").is_err());
+    assert!(inject("/var/db/data/manifest").is_ok());
+    assert!(inject("/var/db/wal/000001.log").is_ok());
+}
+
+#[test]
+fn test_exact_path() {
+    // the exact paths are matched literally, even under a root with wildcards
+    let config: Vec<InjectorConfig> = serde_json::from_str(
+        r#"[{"type": "fault", "include": ["data/000001.sst", "/var/log/db.log"], "errno": 5}]"#,
+    )
+    .unwrap();
+    let injector = MultiInjector::build_with_root(config, Path::new("/var/db[1]")).unwrap();
+
+    let inject =
+        |path: &str| futures::executor::block_on(injector.inject(&Method::READ, Path::new(path)));
+    assert!(inject("/var/db[1]/data/000001.sst").is_err());
+    assert!(inject("/var/log/db.log").is_err());
+    assert!(inject("/var/db1/data/000001.sst").is_ok());
+    assert!(inject("/var/db[1]/data/000002.sst").is_ok());
+    assert!(inject("/var/db[1]/data").is_ok());
+}
+
+#[test]
+fn test_space_limit() {
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "spaceLimit", "bytes": 10}]"#).unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    let mut data = vec![0u8; 6];
+    injector
+        .inject_write_data(Path::new("/file"), &mut data)
+        .unwrap();
+    assert_eq!(data.len(), 6);
+
+    // only the bytes which fit are written
+    let mut data = vec![0u8; 6];
+    injector
+        .inject_write_data(Path::new("/file"), &mut data)
+        .unwrap();
+    assert_eq!(data.len(), 4);
+
+    let mut data = vec![0u8; 6];
+    assert!(injector
+        .inject_write_data(Path::new("/file"), &mut data)
+        .is_err());
+}
+
+#[test]
+fn test_throttle() {
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "throttle", "rate": 1000, "burst": 100}]"#).unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let start = Instant::now();
+    let injection = runtime
+        .block_on(injector.inject_transfer(&Method::WRITE, Path::new("/file"), 100))
+        .unwrap();
+    assert_eq!(injection, Injection::Passed);
+    // 100 bytes over the burst take 0.1s at 1000 bytes per second
+    let injection = runtime
+        .block_on(injector.inject_transfer(&Method::WRITE, Path::new("/file"), 100))
+        .unwrap();
+    assert_eq!(injection, Injection::Delayed);
+    assert!(start.elapsed() >= Duration::from_millis(90));
+
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "throttle", "rate": 0}]"#).unwrap();
+    assert!(MultiInjector::build(config).is_err());
+}
+
+#[test]
+fn test_latency_per_byte() {
+    let config: Vec<InjectorConfig> = serde_json::from_str(
+        r#"[{"type": "latency", "latency": "10ms", "nanosPerByte": 100000, "methods": ["write"]}]"#,
+    )
+    .unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    // the latency is only known with the size of the transfer
+    let injection = runtime
+        .block_on(injector.inject(&Method::WRITE, Path::new("/file")))
+        .unwrap();
+    assert_eq!(injection, Injection::Passed);
+    // 10ms + 1000 bytes * 0.1ms
+    let start = Instant::now();
+    let injection = runtime
+        .block_on(injector.inject_transfer(&Method::WRITE, Path::new("/file"), 1000))
+        .unwrap();
+    assert_eq!(injection, Injection::Delayed);
+    assert!(start.elapsed() >= Duration::from_millis(110));
+
+    let config: InjectorConfig =
+        serde_json::from_str(r#"{"type": "latency", "nanosPerByte": -1}"#).unwrap();
+    assert!(config.validate().is_err());
+    let config: InjectorConfig =
+        serde_json::from_str(r#"{"type": "latency", "nanosPerByte": 1.5}"#).unwrap();
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_short_io() {
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "shortIO", "maxBytes": 4096}]"#).unwrap();
+    let injector = MultiInjector::build_for_mount(config, Path::new("/"), true).unwrap();
+
+    let mut data = Data::new(vec![0u8; 65536]);
+    injector
+        .inject_reply(
+            &Method::READ,
+            Path::new("/file"),
+            &mut Reply::Data(&mut data),
+        )
+        .unwrap();
+    assert_eq!(data.data.len(), 4096);
+
+    let mut data = vec![0u8; 65536];
+    injector
+        .inject_write_data(Path::new("/file"), &mut data)
+        .unwrap();
+    assert_eq!(data.len(), 4096);
+
+    // the transfers within the limit are untouched
+    let mut data = vec![0u8; 100];
+    injector
+        .inject_write_data(Path::new("/file"), &mut data)
+        .unwrap();
+    assert_eq!(data.len(), 100);
+
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "shortIO", "maxBytes": 0}]"#).unwrap();
+    assert!(MultiInjector::build(config).is_err());
+}
+
+#[test]
+fn test_short_io_without_direct_io() {
+    // reads through the page cache are never shortened
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "shortIO", "maxBytes": 4096}]"#).unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    let mut data = Data::new(vec![0u8; 65536]);
+    injector
+        .inject_reply(
+            &Method::READ,
+            Path::new("/file"),
+            &mut Reply::Data(&mut data),
+        )
+        .unwrap();
+    assert_eq!(data.data.len(), 65536);
+
+    let mut data = vec![0u8; 65536];
+    injector
+        .inject_write_data(Path::new("/file"), &mut data)
+        .unwrap();
+    assert_eq!(data.len(), 4096);
+
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "shortIO", "methods": ["read"], "maxBytes": 4096}]"#)
+            .unwrap();
+    assert!(MultiInjector::build(config.clone()).is_err());
+    assert!(MultiInjector::build_for_mount(config, Path::new("/"), true).is_ok());
+}
+
+#[test]
+fn test_short_io_random() {
+    let config: Vec<InjectorConfig> = serde_json::from_str(
+        r#"[{"type": "shortIO", "maxBytes": 4096, "random": true, "seed": 7}]"#,
+    )
+    .unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    for _ in 0..10 {
+        let mut data = vec![0u8; 65536];
+        injector
+            .inject_write_data(Path::new("/file"), &mut data)
+            .unwrap();
+        assert!((1..=4096).contains(&data.len()));
+    }
+}
+
+#[test]
+fn test_fault_at_occurrence() {
+    let config: Vec<InjectorConfig> = serde_json::from_str(
+        r#"[{"type": "fault", "methods": ["write"], "path": "/wal", "atOccurrence": 3, "errno": 5}]"#,
+    )
+    .unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    let inject = |method, path: &str| {
+        futures::executor::block_on(injector.inject(&method, Path::new(path))).is_err()
+    };
+    assert!(!inject(Method::WRITE, "/wal"));
+    // the requests not matched by the other filters are not counted
+    assert!(!inject(Method::READ, "/wal"));
+    assert!(!inject(Method::WRITE, "/data"));
+    assert!(!inject(Method::WRITE, "/wal"));
+    assert!(inject(Method::WRITE, "/wal"));
+    assert!(!inject(Method::WRITE, "/wal"));
+
+    // the occurrences are counted again once injection is enabled
+    injector.enable(Instant::now());
+    assert!(!inject(Method::WRITE, "/wal"));
+    assert!(!inject(Method::WRITE, "/wal"));
+    assert!(inject(Method::WRITE, "/wal"));
+}
+
+#[test]
+fn test_fault_every_nth() {
+    let config: Vec<InjectorConfig> = serde_json::from_str(
+        r#"[{"type": "fault", "methods": ["read", "write"], "everyNth": 2, "errno": 5}]"#,
+    )
+    .unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    let inject =
+        |method| futures::executor::block_on(injector.inject(&method, Path::new("/file"))).is_err();
+    // each method is counted on its own
+    let injected: Vec<_> = [
+        Method::READ,
+        Method::WRITE,
+        Method::READ,
+        Method::READ,
+        Method::WRITE,
+        Method::READ,
+    ]
+    .iter()
+    .map(|method| inject(*method))
+    .collect();
+    assert_eq!(injected, vec![false, false, true, false, true, true]);
+
+    let config: InjectorConfig =
+        serde_json::from_str(r#"{"type": "fault", "everyNth": 0, "errno": 5}"#).unwrap();
+    assert_eq!(
+        config.validate().map_err(|err| err.to_string()),
+        Err("`everyNth` must be positive".to_owned())
+    );
+    assert!(MultiInjector::build(vec![config]).is_err());
+}
+
+#[test]
+fn test_fault_by_file_size() {
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "fault", "errno": 5, "minSize": 1024}]"#).unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    let inject = |size| {
+        let injection = FileSize(size).scope(injector.inject(&Method::READ, Path::new("/file")));
+        futures::executor::block_on(injection)
+    };
+    assert!(inject(4096).is_err());
+    assert!(inject(1024).is_err());
+    assert!(inject(100).is_ok());
+
+    // requests not on an opened file are never injected
+    let injection = injector.inject(&Method::LOOKUP, Path::new("/file"));
+    assert!(futures::executor::block_on(injection).is_ok());
+
+    let config: InjectorConfig =
+        serde_json::from_str(r#"{"type": "fault", "errno": 5, "minSize": 1024, "maxSize": 100}"#)
+            .unwrap();
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_fault_for_uid() {
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "fault", "errno": 5, "uid": 1000}]"#).unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    let inject = |uid| {
+        let caller = Caller {
+            uid,
+            gid: 0,
+            pid: 1,
+        };
+        let injection = caller.scope(injector.inject(&Method::WRITE, Path::new("/file")));
+        futures::executor::block_on(injection)
+    };
+    assert!(inject(1000).is_err());
+    assert!(inject(0).is_ok());
+
+    // requests without a caller are never injected
+    let injection = injector.inject(&Method::WRITE, Path::new("/file"));
+    assert!(futures::executor::block_on(injection).is_ok());
+}
+
+#[test]
+fn test_warmup() {
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "warmup", "count": 2, "delay": "10ms"}]"#).unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut inject = |method| {
+        runtime
+            .block_on(injector.inject(&method, Path::new("/file")))
+            .unwrap()
+    };
+    // every method is counted on its own
+    assert_eq!(inject(Method::READ), Injection::Delayed);
+    assert_eq!(inject(Method::WRITE), Injection::Delayed);
+    assert_eq!(inject(Method::READ), Injection::Delayed);
+    assert_eq!(inject(Method::READ), Injection::Passed);
+    assert_eq!(inject(Method::WRITE), Injection::Delayed);
+    assert_eq!(inject(Method::WRITE), Injection::Passed);
+
+    // the count starts again when the injection is enabled
+    injector.enable(Instant::now());
+    assert_eq!(inject(Method::READ), Injection::Delayed);
+}
+
+#[test]
+fn test_config_validation() {
+    let validate = |config: &str| {
+        let config: InjectorConfig = serde_json::from_str(config).unwrap();
+        config.validate().map_err(|err| err.to_string())
+    };
+
+    assert!(validate(r#"{"type": "fault", "path": "/mnt/**", "errno": 5}"#).is_ok());
+    assert_eq!(
+        validate(r#"{"type": "fault", "methods": ["reed"], "errno": 5}"#)
+            .unwrap_err()
+            .split(',')
+            .next(),
+        Some("unknown method `reed`")
+    );
+    assert_eq!(
+        validate(r#"{"type": "fault", "methods": [], "errno": 5}"#),
+        Err("`methods` is empty, while no path is set".to_owned())
+    );
+    assert_eq!(
+        validate(r#"{"type": "fault", "percent": 101, "errno": 5}"#),
+        Err("`percent` must be in [0, 100], got 101".to_owned())
+    );
+    assert_eq!(
+        validate(r#"{"type": "fault", "faults": [{"errno": 5, "weight": -1}]}"#),
+        Err("`faults[0].weight` must be positive".to_owned())
+    );
+    assert_eq!(
+        validate(r#"{"type": "fault"}"#),
+        Err("either `faults` or `errno` is required".to_owned())
+    );
+    assert_eq!(
+        validate(
+            r#"{"type": "latency", "latency": "1ms", "distribution": {"type": "fixed", "latency": "1ms"}}"#
+        ),
+        Err("only one of `latency` and `distribution` can be set".to_owned())
+    );
+    assert!(
+        validate(r#"{"type": "latency", "exclude": ["[a"], "latency": "1ms"}"#)
+            .unwrap_err()
+            .starts_with("invalid pattern `[a` in `exclude`")
+    );
+
+    // negative durations are rejected by the deserializer
+    assert!(
+        serde_json::from_str::<InjectorConfig>(r#"{"type": "latency", "latency": "-1ms"}"#)
+            .is_err()
+    );
+}
+
+#[test]
+fn test_torn_append() {
+    let config: Vec<InjectorConfig> =
+        serde_json::from_str(r#"[{"type": "tornAppend", "methods": ["write"], "sync": true}]"#)
+            .unwrap();
+    let injector = MultiInjector::build(config).unwrap();
+
+    let mut data = vec![1u8; 64];
+    assert!(injector
+        .inject_append(Path::new("/log"), &mut data)
+        .unwrap());
+    assert!(data.len() < 64);
+    assert!(data.iter().all(|byte| *byte == 1));
+
+    // writes in the middle of the file are never torn
+    let mut data = vec![1u8; 64];
+    injector
+        .inject_write_data(Path::new("/log"), &mut data)
+        .unwrap();
+    assert_eq!(data.len(), 64);
+}
+
+#[test]
+fn test_errno_by_name() {
+    let config: Vec<InjectorConfig> = serde_json::from_str(
+        r#"[{"type": "fault", "faults": [{"errno": "EDQUOT", "weight": 1}]}, {"type": "fault", "errno": "estale"}]"#,
+    )
+    .unwrap();
+    for config in config.iter() {
+        config.validate().unwrap();
+    }
+    let injector = MultiInjector::build(config).unwrap();
+
+    let injection = futures::executor::block_on(injector.inject(&Method::READ, Path::new("/file")));
+    assert!(matches!(injection, Err(hookfs::Error::Sys(Errno::EDQUOT))));
+
+    let parse = |errno: &str| {
+        serde_json::from_str::<InjectorConfig>(&format!(
+            r#"{{"type": "fault", "errno": {}}}"#,
+            errno
+        ))
+    };
+    assert!(parse(r#""ENOTANERRNO""#).is_err());
+    assert!(parse("0").unwrap().validate().is_err());
+    assert!(parse("4096").unwrap().validate().is_err());
+    assert!(parse("122").unwrap().validate().is_ok());
+}
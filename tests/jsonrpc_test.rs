@@ -1,15 +1,57 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::sync::mpsc::channel;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 use anyhow::anyhow;
-use toda::jsonrpc::{self, new_handler, Comm};
+use jsonrpc_core::IoHandler;
+use nix::errno::Errno;
+use toda::hookfs;
+use toda::injector::{Injector, Method, MultiInjector};
+use toda::jsonrpc::{self, new_handler, start_server, Comm, Health, ListenAddr, MountState};
+use toda::mount::MountError;
+use toda::mount_injector::MountMode;
+use toda::ptrace::PtraceError;
+use toda::replacer::{FdReport, ProcessReport, ProcessStatus, ReplacerError};
+
+// handler serves a hookfs on /tmp/test_mnt/<name>, with the injectors of the
+// json `config`
+fn handler(name: &str, config: &str) -> (IoHandler, Arc<hookfs::HookFs>) {
+    let (rpc, hookfs) = rpc(name, config);
+    (new_handler(rpc), hookfs)
+}
+
+fn rpc(name: &str, config: &str) -> (jsonrpc::RpcImpl, Arc<hookfs::HookFs>) {
+    let (tx, _rx) = channel();
+    let hookfs = Arc::new(hookfs::HookFs::new(
+        Path::new("/tmp/test_mnt").join(name),
+        Path::new("/tmp/test_mnt_backend").join(name),
+        MultiInjector::build(serde_json::from_str(config).unwrap()).unwrap(),
+    ));
+    let rpc = jsonrpc::RpcImpl::new(Mutex::new(Ok(())), Mutex::new(tx), vec![hookfs.clone()]);
+    (rpc, hookfs)
+}
+
+// call sends `method` with the json `params`, and returns the response
+fn call(io: &IoHandler, method: &str, params: &str) -> serde_json::Value {
+    let request = format!(
+        r#"{{"jsonrpc": "2.0","method":"{}","params":{},"id":1}}"#,
+        method, params
+    );
+    serde_json::from_str(&io.handle_request_sync(&request).unwrap()).unwrap()
+}
+
 #[test]
 fn test_status_good() {
     let (tx, _rx) = channel();
     let io = new_handler(jsonrpc::RpcImpl::new(
         Mutex::new(Ok(())),
         Mutex::new(tx),
-        None,
+        Vec::new(),
     ));
     let request = r#"{"jsonrpc": "2.0","method":"get_status","params":[""],"id":1}"#;
     let response = r#"{"jsonrpc":"2.0","result":"ok","id":1}"#;
@@ -22,7 +64,7 @@ fn test_status_bad() {
     let io = new_handler(jsonrpc::RpcImpl::new(
         Mutex::new(Err(anyhow!("Not good"))),
         Mutex::new(tx),
-        None,
+        Vec::new(),
     ));
     let request = r#"{"jsonrpc": "2.0","method":"get_status","params":[""],"id":1}"#;
     let response = r#"{"jsonrpc":"2.0","result":"Not good","id":1}"#;
@@ -34,11 +76,11 @@ fn test_status_bad() {
 fn test_should_not_update_config_if_status_is_failed() {
     let (tx, _rx) = channel();
     let request = r#"{"jsonrpc": "2.0","method":"update","params":[[]],"id":1}"#;
-    let response = r#"{"jsonrpc":"2.0","result":"Not good","id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","error":{"code":-32603,"message":"Not good"},"id":1}"#;
     let io = new_handler(jsonrpc::RpcImpl::new(
         Mutex::new(Err(anyhow!("Not good"))),
         Mutex::new(tx),
-        None,
+        Vec::new(),
     ));
     assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
 }
@@ -51,7 +93,423 @@ fn test_should_fail_if_config_is_bad() {
     let io = new_handler(jsonrpc::RpcImpl::new(
         Mutex::new(Ok(())),
         Mutex::new(tx),
-        None,
+        Vec::new(),
     ));
     assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
 }
+
+#[test]
+fn test_update_latency_config() {
+    let (io, _) = handler("update_latency", "[]");
+    let params = r#"[[{"type":"latency","methods":["read"],"latency":"100ms"}]]"#;
+    assert_eq!(
+        call(&io, "update", params),
+        serde_json::json!({"jsonrpc": "2.0", "result": 1, "id": 1})
+    );
+}
+
+#[test]
+fn test_should_reject_invalid_injector() {
+    let (io, _) = handler("invalid_injector", "[]");
+    let params = r#"[[{"type":"latency","percent":200,"latency":"100ms"}]]"#;
+    let response = call(&io, "update", params);
+    assert_eq!(response["error"]["code"], -32004);
+    assert_eq!(
+        response["error"]["message"],
+        "invalid injector config at index 0: `percent` must be in [0, 100], got 200"
+    );
+}
+
+#[test]
+fn test_error_codes() {
+    let code = |err: anyhow::Error| jsonrpc::error_code(&err).code();
+    let err = ReplacerError::Processes {
+        errors: vec![(1, ReplacerError::PtracePermission { pid: 1 })],
+    };
+    assert_eq!(
+        code(anyhow::Error::from(err).context("replace fds")),
+        jsonrpc::PTRACE_DENIED
+    );
+    let err = MountError::NotMountPoint {
+        path: "/tmp/test_mnt".into(),
+    };
+    assert_eq!(code(err.into()), jsonrpc::MOUNT_FAILED);
+    assert_eq!(code(anyhow!("Not good")), -32603);
+    let err = PtraceError::Denied {
+        pid: 1,
+        scope: Some(3),
+    };
+    assert_eq!(
+        err.to_string(),
+        "ptrace is denied on process 1 (kernel.yama.ptrace_scope is 3), so the fds can't be \
+         replaced. Set kernel.yama.ptrace_scope=0 or run with CAP_SYS_PTRACE"
+    );
+    assert_eq!(code(err.into()), jsonrpc::PTRACE_DENIED);
+
+    // the status error is returned with its code
+    let (tx, _rx) = channel();
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Err(ReplacerError::PtracePermission { pid: 1 }.into())),
+        Mutex::new(tx),
+        Vec::new(),
+    ));
+    let request = r#"{"jsonrpc": "2.0","method":"update","params":[[]],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","error":{"code":-32003,"message":"permission denied to ptrace process 1"},"id":1}"#;
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+
+    let (tx, _rx) = channel();
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        Vec::new(),
+    ));
+    let response =
+        r#"{"jsonrpc":"2.0","error":{"code":-32005,"message":"hookfs is not mounted"},"id":1}"#;
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+#[test]
+fn test_injection_status() {
+    let (rpc, _) = rpc("injection_status", "[]");
+    let replaced = vec![
+        ProcessReport {
+            pid: 1,
+            replacer: "fd".to_owned(),
+            status: ProcessStatus::Replaced,
+            fds: Vec::new(),
+        },
+        ProcessReport {
+            pid: 2,
+            replacer: "cwd".to_owned(),
+            status: ProcessStatus::Failed("process 2 has exited".to_owned()),
+            fds: Vec::new(),
+        },
+    ];
+    let io = new_handler(
+        rpc.with_mount_mode(MountMode::Direct)
+            .with_replaced(vec![replaced]),
+    );
+    let response: serde_json::Value = serde_json::from_str(
+        r#"{"jsonrpc":"2.0","result":{"mounted":true,"error":null,"mountMode":"direct","paused":false,"mounts":[{"path":"/tmp/test_mnt/injection_status","injectionEnabled":false,"enabledAt":null,"disabledAt":null,"activeDuration":null,"injectors":[],"counters":{},"replaced":[{"pid":1,"replacer":"fd","status":"replaced"},{"pid":2,"replacer":"cwd","status":"failed","error":"process 2 has exited"}]}]},"id":1}"#,
+    )
+    .unwrap();
+    assert_eq!(call(&io, "get_injection_status", "[]"), response);
+}
+
+#[test]
+fn test_injection_timestamps() {
+    let (io, hookfs) = handler("injection_timestamps", "[]");
+    let status = || call(&io, "get_injection_status", "[]")["result"]["mounts"][0].clone();
+
+    hookfs.enable_injection();
+    let enabled = status();
+    assert!(enabled["enabledAt"].as_str().unwrap().ends_with('Z'));
+    assert!(enabled["disabledAt"].is_null());
+    assert!(enabled["activeDuration"].is_string());
+
+    hookfs.disable_injection();
+    let disabled = status();
+    assert_eq!(disabled["enabledAt"], enabled["enabledAt"]);
+    assert!(disabled["disabledAt"].is_string());
+    assert!(disabled["activeDuration"].is_null());
+}
+
+#[test]
+fn test_disable_injector() {
+    let (io, hookfs) = handler(
+        "disable_injector",
+        r#"[{"type": "fault", "id": "eio", "methods": ["read"], "errno": 5}, {"type": "fault", "methods": ["write"], "errno": 28}]"#,
+    );
+    let inject = |method| {
+        futures::executor::block_on(async {
+            let injector = hookfs.injector.load();
+            injector.inject(&method, Path::new("/file")).await
+        })
+    };
+
+    assert_eq!(
+        call(&io, "disable_injector", r#"["eio"]"#)["result"],
+        serde_json::Value::Null
+    );
+    assert!(inject(Method::READ).is_ok());
+    assert!(inject(Method::WRITE).is_err());
+
+    // the injector without an id is identified by its index
+    let response = call(&io, "get_injection_status", "[]");
+    let injectors = &response["result"]["mounts"][0]["injectors"];
+    assert_eq!(injectors[0]["id"], "eio");
+    assert_eq!(injectors[0]["enabled"], false);
+    assert_eq!(injectors[1]["id"], "1");
+    assert_eq!(injectors[1]["enabled"], true);
+
+    call(&io, "enable_injector", r#"["eio"]"#);
+    assert!(inject(Method::READ).is_err());
+    assert_eq!(
+        call(&io, "disable_injector", r#"["missing"]"#),
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "error": {"code": -32006, "message": "injector `missing` is not found"},
+            "id": 1
+        })
+    );
+}
+
+#[test]
+fn test_pause_injection() {
+    let (io, hookfs) = handler("pause_injection", "[]");
+    hookfs.enable_injection();
+
+    assert_eq!(call(&io, "pause", "[]")["result"], serde_json::Value::Null);
+    assert!(hookfs.injection_paused());
+    // the injection is still enabled, so it's resumed at once
+    assert!(hookfs.injection_enabled());
+    let status = call(&io, "get_injection_status", "[]");
+    assert_eq!(status["result"]["paused"], true);
+
+    call(&io, "resume_injection", "[]");
+    assert!(!hookfs.injection_paused());
+    let status = call(&io, "get_injection_status", "[]");
+    assert_eq!(status["result"]["paused"], false);
+
+    let (tx, _rx) = channel();
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        Vec::new(),
+    ));
+    let request = r#"{"jsonrpc": "2.0","method":"pause","params":[],"id":1}"#;
+    let response =
+        r#"{"jsonrpc":"2.0","error":{"code":-32005,"message":"hookfs is not mounted"},"id":1}"#;
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+#[test]
+fn test_list_replacements() {
+    let (tx, _rx) = channel();
+    let fd = |fd, status| FdReport {
+        fd,
+        old_path: format!("/var/db/{}", fd).into(),
+        new_path: format!("/var/db/{}", fd).into(),
+        status,
+    };
+    let replaced = vec![
+        ProcessReport {
+            pid: 1,
+            replacer: "fd".to_owned(),
+            status: ProcessStatus::Replaced,
+            fds: vec![
+                fd(3, ProcessStatus::Replaced),
+                fd(4, ProcessStatus::Skipped),
+            ],
+        },
+        ProcessReport {
+            pid: 1,
+            replacer: "cwd".to_owned(),
+            status: ProcessStatus::Replaced,
+            fds: Vec::new(),
+        },
+    ];
+    let io = new_handler(
+        jsonrpc::RpcImpl::new(Mutex::new(Ok(())), Mutex::new(tx), Vec::new())
+            .with_replaced(vec![replaced]),
+    );
+    let request = r#"{"jsonrpc": "2.0","method":"list_replacements","params":[],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":[{"pid":1,"fd":3,"oldPath":"/var/db/3","newPath":"/var/db/3","status":"replaced"},{"pid":1,"fd":4,"oldPath":"/var/db/4","newPath":"/var/db/4","status":"skipped"}],"id":1}"#;
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+#[test]
+fn test_health() {
+    let (tx, _rx) = channel();
+    let request = r#"{"jsonrpc": "2.0","method":"health","params":[],"id":1}"#;
+
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Err(anyhow!("Not good"))),
+        Mutex::new(tx.clone()),
+        Vec::new(),
+    ));
+    let response = r#"{"jsonrpc":"2.0","result":{"state":"Failed","error":"Not good"},"id":1}"#;
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+
+    let health = Arc::new(Mutex::new(Health::Mounting));
+    let io = new_handler(
+        jsonrpc::RpcImpl::new(Mutex::new(Ok(())), Mutex::new(tx), Vec::new())
+            .with_health(health.clone()),
+    );
+    let response = r#"{"jsonrpc":"2.0","result":{"state":"Mounting"},"id":1}"#;
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+
+    *health.lock().unwrap() = Health::Ready;
+    let response = r#"{"jsonrpc":"2.0","result":{"state":"Ready"},"id":1}"#;
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+#[test]
+fn test_mount_state_set_after_start() {
+    let (tx, _rx) = channel();
+    let health = Arc::new(Mutex::new(Health::Mounting));
+    let state = Arc::new(RwLock::new(MountState::default()));
+    let io = new_handler(
+        jsonrpc::RpcImpl::new(Mutex::new(Ok(())), Mutex::new(tx), Vec::new())
+            .with_health(health.clone())
+            .with_mount_state(state.clone()),
+    );
+    let call = |method| {
+        let request = format!(
+            r#"{{"jsonrpc": "2.0","method":"{}","params":[],"id":1}}"#,
+            method
+        );
+        io.handle_request_sync(&request).unwrap()
+    };
+
+    assert_eq!(
+        call("get_status"),
+        r#"{"jsonrpc":"2.0","result":"mounting","id":1}"#
+    );
+    assert_eq!(
+        call("pause"),
+        r#"{"jsonrpc":"2.0","error":{"code":-32005,"message":"hookfs is not mounted"},"id":1}"#
+    );
+
+    *health.lock().unwrap() = Health::Failed("mount failed".to_owned());
+    state.write().unwrap().status = Err(MountError::NotMountPoint {
+        path: "/var/db".into(),
+    }
+    .into());
+    let response: serde_json::Value = serde_json::from_str(&call("pause")).unwrap();
+    assert_eq!(response["error"]["code"], jsonrpc::MOUNT_FAILED);
+}
+
+#[test]
+fn test_counters_record_faults_and_latency() {
+    let counters = hookfs::Counters::default();
+    counters.intercept(&Method::READ);
+    counters.delay(&Method::READ, Duration::from_millis(20));
+    counters.intercept(&Method::READ);
+    counters.fail(&Method::READ, &hookfs::Error::Sys(Errno::EIO));
+
+    let snapshot = counters.snapshot();
+    let read = &snapshot["read"];
+    assert_eq!(read.intercepted, 2);
+    assert_eq!(read.delayed, 1);
+    assert_eq!(read.failed, 1);
+    assert_eq!(read.latency, Duration::from_millis(20));
+    assert_eq!(read.errnos.get(&(Errno::EIO as i32)), Some(&1));
+}
+
+#[test]
+fn test_listen_addr() {
+    assert_eq!("stdio".parse::<ListenAddr>().unwrap(), ListenAddr::Stdio);
+    assert_eq!(
+        "unix:/run/toda.sock".parse::<ListenAddr>().unwrap(),
+        ListenAddr::UnixPath("/run/toda.sock".into())
+    );
+    let addr: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+    assert_eq!(
+        "127.0.0.1:6000".parse::<ListenAddr>().unwrap(),
+        ListenAddr::TcpAddr(addr)
+    );
+    assert_eq!(
+        "tcp:127.0.0.1:6000".parse::<ListenAddr>().unwrap(),
+        ListenAddr::TcpAddr(addr)
+    );
+    assert!("unix:".parse::<ListenAddr>().is_err());
+    assert!("localhost".parse::<ListenAddr>().is_err());
+}
+
+#[test]
+fn test_serve_on_unix_socket() {
+    let path = std::env::temp_dir().join(format!("toda-rpc-{}.sock", std::process::id()));
+    let (tx, _rx) = channel();
+    let rpc = jsonrpc::RpcImpl::new(Mutex::new(Ok(())), Mutex::new(tx), Vec::new());
+    let addr = ListenAddr::UnixPath(path.clone());
+    std::thread::spawn(move || {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(start_server(rpc, addr)).unwrap();
+    });
+
+    let mut stream = loop {
+        match UnixStream::connect(&path) {
+            Ok(stream) => break stream,
+            Err(_) => std::thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    let request = r#"{"jsonrpc": "2.0","method":"get_status","params":[""],"id":1}"#;
+    writeln!(stream, "{}", request).unwrap();
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response).unwrap();
+    assert_eq!(
+        response,
+        "{\"jsonrpc\":\"2.0\",\"result\":\"ok\",\"id\":1}\n"
+    );
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // the private directory the socket is bound in is removed
+    let dir = std::env::temp_dir().join(format!(".toda-rpc-{}", std::process::id()));
+    assert!(!dir.exists());
+    std::fs::remove_file(&path).unwrap();
+}
+
+// SharedBuf is a writer whose data can be read while it's written by another
+// thread
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_event_sink() {
+    let buf = SharedBuf::default();
+    let sink = hookfs::EventSink::new(buf.clone(), 16);
+    sink.send(
+        &Method::READ,
+        Path::new("/var/db/file"),
+        hookfs::Action::Delayed {
+            latency: Duration::from_millis(20),
+        },
+    );
+    sink.send(
+        &Method::WRITE,
+        Path::new("/var/db/file"),
+        hookfs::Action::failed(&hookfs::Error::Sys(Errno::EIO)),
+    );
+    let corrupted = hookfs::Action::corrupted(b"abcd", b"abXd!").unwrap();
+    assert_eq!(corrupted, hookfs::Action::Corrupted { bytes: 2 });
+    assert!(hookfs::Action::corrupted(b"abcd", b"abcd").is_none());
+    sink.send(&Method::READ, Path::new("/var/db/file"), corrupted);
+    assert_eq!(sink.dropped(), 0);
+
+    // the events are written by a background thread
+    let lines = loop {
+        let data = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = data.lines().map(str::to_owned).collect();
+        if lines.len() == 3 {
+            break lines;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    let events: Vec<hookfs::Event> = lines
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(events[0].method, "read");
+    assert_eq!(events[0].pid, None);
+    assert_eq!(events[0].path, Path::new("/var/db/file"));
+    let event: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(event["action"], "delayed");
+    assert_eq!(event["latency"], "20ms");
+    let event: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+    assert_eq!(event["action"], "failed");
+    assert_eq!(event["errno"], Errno::EIO as i32);
+    assert_eq!(events[2].action, hookfs::Action::Corrupted { bytes: 2 });
+}
@@ -11,23 +11,33 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::ffi::OsStr;
+use std::ffi::{CString, OsStr};
 use std::fs::{read_link, read_to_string, write, File, OpenOptions};
 use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::symlink;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Once};
 
+use nix::errno::Errno;
 use nix::sys::stat;
 use nix::{fcntl, unistd};
 use toda::hookfs;
-use toda::injector::MultiInjector;
+use toda::injector::{InjectorConfig, MultiInjector};
 
 // These tests are port from go-fuse test
 
 static INIT: Once = Once::new();
 
 fn init(name: &str) -> (PathBuf, fuser::BackgroundSession) {
+    let (test_path, _, session) = init_with_injectors(name, Vec::new());
+    (test_path, session)
+}
+
+fn init_with_injectors(
+    name: &str,
+    config: Vec<InjectorConfig>,
+) -> (PathBuf, Arc<hookfs::HookFs>, fuser::BackgroundSession) {
     let test_path_backend: PathBuf = ["/tmp/test_mnt_backend", name].iter().collect();
     let test_path: PathBuf = ["/tmp/test_mnt", name].iter().collect();
 
@@ -44,10 +54,10 @@ fn init(name: &str) -> (PathBuf, fuser::BackgroundSession) {
     let hookfs = Arc::new(hookfs::HookFs::new(
         &test_path,
         &test_path_backend,
-        MultiInjector::build(Vec::new()).unwrap(),
+        MultiInjector::build(config).unwrap(),
     ));
 
-    let fs = hookfs::AsyncFileSystem::from(hookfs);
+    let fs = hookfs::AsyncFileSystem::from(hookfs.clone());
 
     let args = [
         "allow_other",
@@ -62,7 +72,7 @@ fn init(name: &str) -> (PathBuf, fuser::BackgroundSession) {
 
     let session = fuser::spawn_mount(fs, &test_path, &flags).unwrap();
     std::thread::sleep(std::time::Duration::from_secs(1));
-    (test_path, session)
+    (test_path, hookfs, session)
 }
 
 #[test]
@@ -348,6 +358,180 @@ fn append_unlink_write() {
     assert_eq!(&output, "hello world");
 }
 
+#[test]
+fn append_with_overridden_size() {
+    let config = serde_json::from_str(r#"[{"type": "attrOverride", "size": 0}]"#).unwrap();
+    let (test_path, hookfs, _session) = init_with_injectors("append_overridden_size", config);
+    let path = test_path.join("file");
+    write(&path, b"hello").unwrap();
+
+    // the kernel is told the file is empty, while appends still go to the end
+    hookfs.enable_injection();
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b" world").unwrap();
+    drop(file);
+    hookfs.disable_injection();
+
+    let backend_path = Path::new("/tmp/test_mnt_backend/append_overridden_size/file");
+    assert_eq!(read_to_string(backend_path).unwrap(), "hello world");
+}
+
+#[test]
+fn write_fault_leaves_file_unchanged() {
+    let config =
+        serde_json::from_str(r#"[{"type": "fault", "methods": ["write"], "errno": 5}]"#).unwrap();
+    let (test_path, hookfs, _session) = init_with_injectors("write_fault", config);
+    let path = test_path.join("file");
+    write(&path, b"hello").unwrap();
+
+    hookfs.enable_injection();
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    let err = file.write_all(b" world").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    drop(file);
+    hookfs.disable_injection();
+
+    let backend_path = Path::new("/tmp/test_mnt_backend/write_fault/file");
+    assert_eq!(read_to_string(backend_path).unwrap(), "hello");
+}
+
+#[test]
+fn namespace_faults_leave_backend_unchanged() {
+    let config = serde_json::from_str(
+        r#"[
+            {"type": "fault", "methods": ["mkdir"], "errno": 28},
+            {"type": "fault", "methods": ["mknod"], "errno": 28},
+            {"type": "fault", "methods": ["symlink"], "errno": 17},
+            {"type": "fault", "methods": ["link"], "errno": 31},
+            {"type": "fault", "methods": ["rename"], "errno": 30},
+            {"type": "fault", "methods": ["unlink"], "errno": 2},
+            {"type": "fault", "methods": ["rmdir"], "errno": 16}
+        ]"#,
+    )
+    .unwrap();
+    let (test_path, hookfs, _session) = init_with_injectors("namespace_faults", config);
+    let backend_path = Path::new("/tmp/test_mnt_backend/namespace_faults");
+    write(test_path.join("file"), b"data").unwrap();
+    std::fs::create_dir(test_path.join("dir")).unwrap();
+
+    hookfs.enable_injection();
+    let errno = |result: std::io::Result<()>| result.unwrap_err().raw_os_error().unwrap();
+    assert_eq!(
+        errno(std::fs::create_dir(test_path.join("new_dir"))),
+        libc::ENOSPC
+    );
+    assert_eq!(
+        unistd::mkfifo(&test_path.join("fifo"), stat::Mode::S_IRWXU),
+        Err(nix::Error::Sys(Errno::ENOSPC))
+    );
+    assert_eq!(
+        errno(symlink("file", test_path.join("symlink"))),
+        libc::EEXIST
+    );
+    assert_eq!(
+        errno(std::fs::hard_link(
+            test_path.join("file"),
+            test_path.join("hard_link")
+        )),
+        libc::EMLINK
+    );
+    assert_eq!(
+        errno(std::fs::rename(
+            test_path.join("file"),
+            test_path.join("renamed")
+        )),
+        libc::EROFS
+    );
+    assert_eq!(
+        errno(std::fs::remove_file(test_path.join("file"))),
+        libc::ENOENT
+    );
+    assert_eq!(
+        errno(std::fs::remove_dir(test_path.join("dir"))),
+        libc::EBUSY
+    );
+    hookfs.disable_injection();
+
+    let mut entries: Vec<_> = std::fs::read_dir(backend_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    entries.sort();
+    assert_eq!(entries, vec!["dir", "file"]);
+    assert_eq!(read_to_string(backend_path.join("file")).unwrap(), "data");
+}
+
+fn cpath(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).unwrap()
+}
+
+fn setxattr(path: &Path, name: &str, value: &[u8]) -> nix::Result<()> {
+    let name = CString::new(name).unwrap();
+    let ret = unsafe {
+        libc::lsetxattr(
+            cpath(path).as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    Errno::result(ret).map(drop)
+}
+
+fn getxattr(path: &Path, name: &str) -> nix::Result<Vec<u8>> {
+    let name = CString::new(name).unwrap();
+    let mut buf = vec![0u8; 256];
+    let ret = unsafe {
+        libc::lgetxattr(
+            cpath(path).as_ptr(),
+            name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    };
+    buf.truncate(Errno::result(ret)? as usize);
+    Ok(buf)
+}
+
+fn listxattr(path: &Path) -> nix::Result<Vec<u8>> {
+    let mut buf = vec![0u8; 256];
+    let ret = unsafe {
+        libc::llistxattr(
+            cpath(path).as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+        )
+    };
+    buf.truncate(Errno::result(ret)? as usize);
+    Ok(buf)
+}
+
+#[test]
+fn xattr_passthrough() {
+    let (test_path, _) = init("xattr_passthrough");
+    let backend_path = PathBuf::from("/tmp/test_mnt_backend/xattr_passthrough/file");
+    let path = test_path.join("file");
+    write(&path, "content").unwrap();
+
+    setxattr(&path, "user.toda", b"value").unwrap();
+    assert_eq!(getxattr(&backend_path, "user.toda").unwrap(), b"value");
+    assert_eq!(getxattr(&path, "user.toda").unwrap(), b"value");
+
+    // an empty value is still an attribute
+    setxattr(&path, "user.empty", b"").unwrap();
+    assert_eq!(getxattr(&backend_path, "user.empty").unwrap(), b"");
+    assert_eq!(listxattr(&path).unwrap(), listxattr(&backend_path).unwrap());
+
+    let name = CString::new("user.toda").unwrap();
+    let ret = unsafe { libc::lremovexattr(cpath(&path).as_ptr(), name.as_ptr()) };
+    Errno::result(ret).unwrap();
+    assert_eq!(
+        getxattr(&backend_path, "user.toda"),
+        Err(nix::Error::Sys(Errno::ENODATA))
+    );
+}
+
 // func RenameOpenDir(t *testing.T, mnt string) {
 // 	if err := os.Mkdir(mnt+"/dir1", 0755); err != nil {
 // 		t.Fatalf("Mkdir: %v", err)
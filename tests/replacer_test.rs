@@ -0,0 +1,589 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cell::Cell;
+use std::ffi::OsStr;
+use std::fs::{read_link, read_to_string, write, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::signal::{kill, Signal};
+use nix::sys::stat::Mode;
+use nix::unistd::{self, mkfifo, Pid};
+use procfs::process::Process;
+use toda::ptrace::{self, PtraceError};
+use toda::replacer::{
+    checked_fd, FdReplacer, FdReport, ProcessFilter, ProcessStatus, Replacer, ReplacerKind,
+    UnionReplacer,
+};
+
+// These tests attach to every process on the host with ptrace, so they need
+// CAP_SYS_PTRACE and are ignored by default.
+
+fn init(name: &str) -> (PathBuf, PathBuf) {
+    let base: PathBuf = ["/tmp/test_replacer", name].iter().collect();
+    let old_path = base.join("old");
+    let new_path = base.join("new");
+
+    std::fs::remove_dir_all(&base).ok();
+    std::fs::create_dir_all(&old_path).unwrap();
+    std::fs::create_dir_all(&new_path).unwrap();
+
+    (old_path, new_path)
+}
+
+// Detached is a process started outside the process tree of the tests, as the
+// replacers skip the descendants of the current process. It's killed on drop.
+struct Detached {
+    pid: i32,
+}
+
+impl Detached {
+    // spawn runs `command` in the background of a shell which exits at once, so
+    // the process is adopted by init. The shell prints its pid to stderr.
+    fn spawn(command: &str, stdin: Stdio, stdout: Stdio) -> Detached {
+        let mut shell = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{{ {}; }} <&0 & echo $! >&2", command))
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut pid = String::new();
+        BufReader::new(shell.stderr.take().unwrap())
+            .read_line(&mut pid)
+            .unwrap();
+        shell.wait().unwrap();
+
+        Detached {
+            pid: pid.trim().parse().unwrap(),
+        }
+    }
+
+    fn id(&self) -> i32 {
+        self.pid
+    }
+
+    // wait waits for the process to exit. A zombie counts as exited, as it may
+    // never be reaped in a container without a proper init.
+    fn wait(&self) {
+        while let Ok(process) = Process::new(self.pid) {
+            if process.stat.state == 'Z' {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for Detached {
+    fn drop(&mut self) {
+        kill(Pid::from_raw(self.pid), Signal::SIGKILL).ok();
+    }
+}
+
+// spawn_with_stdin starts a process which holds `file` as its stdin
+fn spawn_with_stdin(file: File) -> Detached {
+    Detached::spawn("exec sleep 100", Stdio::from(file), Stdio::null())
+}
+
+#[test]
+#[ignore]
+fn fd_replacer_non_utf8_path() {
+    let (old_path, new_path) = init("non_utf8_path");
+
+    let name = OsStr::from_bytes(b"file\xff\xfe");
+    write(old_path.join(name), b"old").unwrap();
+    write(new_path.join(name), b"new").unwrap();
+
+    let child = spawn_with_stdin(File::open(old_path.join(name)).unwrap());
+
+    {
+        let mut replacer = FdReplacer::prepare(&old_path, &new_path).unwrap();
+        replacer.run().unwrap();
+    }
+
+    let fd_path = format!("/proc/{}/fd/0", child.id());
+    assert_eq!(read_link(fd_path).unwrap(), new_path.join(name));
+}
+
+#[test]
+fn checked_fd_boundaries() {
+    assert_eq!(checked_fd(0u32), Some(0));
+    assert_eq!(checked_fd(i32::MAX as u32), Some(i32::MAX as u64));
+    assert_eq!(checked_fd(i32::MAX as u32 + 1), None);
+    assert_eq!(checked_fd(u32::MAX), None);
+    assert_eq!(checked_fd(-1i32), None);
+    assert_eq!(checked_fd(i32::MIN), None);
+    assert_eq!(checked_fd(1i64 << 32), None);
+}
+
+#[test]
+#[ignore]
+fn fd_replacer_high_fd() {
+    let (old_path, new_path) = init("high_fd");
+    write(old_path.join("file"), b"old").unwrap();
+    write(new_path.join("file"), b"new").unwrap();
+
+    // the fd is near the default soft limit of open files. It's inherited by
+    // the child, as dup2 doesn't set FD_CLOEXEC
+    let file = File::open(old_path.join("file")).unwrap();
+    unistd::dup2(file.as_raw_fd(), 1000).unwrap();
+    let child = Detached::spawn("exec sleep 100", Stdio::null(), Stdio::null());
+    unistd::close(1000).unwrap();
+
+    {
+        let mut replacer = FdReplacer::prepare(&old_path, &new_path).unwrap();
+        replacer.run().unwrap();
+    }
+
+    let fd_path = format!("/proc/{}/fd/1000", child.id());
+    assert_eq!(read_link(fd_path).unwrap(), new_path.join("file"));
+}
+
+#[test]
+fn process_filter_matches_comm_or_cmdline() {
+    let myself = Process::myself().unwrap();
+    let matches = |pattern: &str| pattern.parse::<ProcessFilter>().unwrap().matches(&myself);
+    assert!(matches(&regex::escape(&myself.stat.comm)));
+    assert!(matches("replacer_test"));
+    assert!(!matches("^no-such-process$"));
+    assert!("[a".parse::<ProcessFilter>().is_err());
+}
+
+#[test]
+fn process_filter_share() {
+    let myself = Process::myself().unwrap();
+    let shares = (0..3)
+        .filter(|index| {
+            ProcessFilter::default()
+                .with_share(*index, 3)
+                .matches(&myself)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(shares, vec![myself.pid as usize % 3]);
+}
+
+#[test]
+#[ignore]
+fn union_replacer_concurrency() {
+    let (old_path, new_path) = init("concurrency");
+    write(old_path.join("file"), b"old").unwrap();
+    write(new_path.join("file"), b"new").unwrap();
+
+    let open = || Stdio::from(File::open(old_path.join("file")).unwrap());
+    let children = (0..4)
+        .map(|_| Detached::spawn("exec sleep 100", open(), Stdio::null()))
+        .collect::<Vec<_>>();
+    thread::sleep(Duration::from_millis(100));
+
+    {
+        let mut replacer = UnionReplacer::new(ReplacerKind::FD).with_concurrency(3);
+        replacer.prepare(&old_path, &new_path).unwrap();
+        let report = replacer.run_detailed();
+        assert!(report.processes.len() >= children.len());
+        report.into_result().unwrap();
+    }
+
+    for child in children.iter() {
+        let stdin = read_link(format!("/proc/{}/fd/0", child.id())).unwrap();
+        assert_eq!(stdin, new_path.join("file"));
+    }
+}
+
+#[test]
+#[ignore]
+fn fd_replacer_target_process() {
+    let (old_path, new_path) = init("target_process");
+    write(old_path.join("file"), b"old").unwrap();
+    write(new_path.join("file"), b"new").unwrap();
+
+    let open = || Stdio::from(File::open(old_path.join("file")).unwrap());
+    let target = Detached::spawn("exec sleep 101", open(), Stdio::null());
+    let other = Detached::spawn("exec sleep 100", open(), Stdio::null());
+    thread::sleep(Duration::from_millis(100));
+
+    {
+        let filter: ProcessFilter = "sleep 101".parse().unwrap();
+        let mut replacer =
+            FdReplacer::prepare_filtered(&old_path, &new_path, Some(&filter)).unwrap();
+        replacer.run().unwrap();
+    }
+
+    let stdin = |pid: i32| read_link(format!("/proc/{}/fd/0", pid)).unwrap();
+    assert_eq!(stdin(target.id()), new_path.join("file"));
+    assert_eq!(stdin(other.id()), old_path.join("file"));
+}
+
+#[test]
+#[ignore]
+fn fd_replacer_preserve_offset() {
+    let (old_path, new_path) = init("preserve_offset");
+
+    write(old_path.join("file"), b"0123456789").unwrap();
+    write(new_path.join("file"), b"abcdefghij").unwrap();
+
+    // the offset is shared with the child through the open file description
+    let mut file = File::open(old_path.join("file")).unwrap();
+    let mut buf = [0u8; 5];
+    file.read_exact(&mut buf).unwrap();
+    let child = spawn_with_stdin(file);
+
+    {
+        let mut replacer = FdReplacer::prepare(&old_path, &new_path).unwrap();
+        replacer.run().unwrap();
+    }
+
+    let fd_path = format!("/proc/{}/fd/0", child.id());
+    assert_eq!(read_link(fd_path).unwrap(), new_path.join("file"));
+
+    let fdinfo = read_to_string(format!("/proc/{}/fdinfo/0", child.id())).unwrap();
+    assert!(fdinfo.lines().any(|line| line == "pos:\t5"));
+}
+
+#[test]
+#[ignore]
+fn fd_replacer_preserve_flags() {
+    let (old_path, new_path) = init("preserve_flags");
+
+    write(old_path.join("file"), b"old\n").unwrap();
+    write(new_path.join("file"), b"new\n").unwrap();
+
+    let file = OpenOptions::new()
+        .append(true)
+        .open(old_path.join("file"))
+        .unwrap();
+    // the child writes once a line is written to the fifo, which is outside of
+    // the replaced path
+    let fifo = old_path.parent().unwrap().join("fifo");
+    mkfifo(&fifo, Mode::S_IRWXU).unwrap();
+    let child = Detached::spawn(
+        &format!("read line < {}; echo written", fifo.display()),
+        Stdio::null(),
+        Stdio::from(file),
+    );
+
+    {
+        let mut replacer = FdReplacer::prepare(&old_path, &new_path).unwrap();
+        replacer.run().unwrap();
+    }
+
+    // the reopened fd must still be writable and append to the new file
+    write(&fifo, b"\n").unwrap();
+    child.wait();
+    assert_eq!(
+        read_to_string(new_path.join("file")).unwrap(),
+        "new\nwritten\n"
+    );
+    assert_eq!(read_to_string(old_path.join("file")).unwrap(), "old\n");
+}
+
+#[test]
+#[ignore]
+fn fd_replacer_directory() {
+    let (old_path, new_path) = init("directory");
+
+    std::fs::create_dir(old_path.join("dir")).unwrap();
+    std::fs::create_dir(new_path.join("dir")).unwrap();
+    write(new_path.join("dir/file"), b"new").unwrap();
+
+    // the child holds a dirfd, like the base of `openat`
+    let dir = File::open(old_path.join("dir")).unwrap();
+    let child = spawn_with_stdin(dir);
+    {
+        let mut replacer = FdReplacer::prepare(&old_path, &new_path).unwrap();
+        replacer.run().unwrap();
+    }
+
+    let fd_path = format!("/proc/{}/fd/0", child.id());
+    assert_eq!(read_link(&fd_path).unwrap(), new_path.join("dir"));
+    let fdinfo = read_to_string(format!("/proc/{}/fdinfo/0", child.id())).unwrap();
+    let flags = fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .unwrap();
+    let flags = i32::from_str_radix(flags.trim(), 8).unwrap();
+    assert_ne!(flags & libc::O_DIRECTORY, 0);
+    assert_eq!(flags & libc::O_ACCMODE, libc::O_RDONLY);
+    // the reopened dirfd resolves relative paths in the new directory
+    let file = format!("/proc/{}/fd/0/file", child.id());
+    assert_eq!(read_to_string(file).unwrap(), "new");
+}
+
+#[test]
+#[ignore]
+fn fd_replacer_direct_io() {
+    let (old_path, new_path) = init("direct_io");
+
+    write(old_path.join("file"), b"old").unwrap();
+    write(new_path.join("file"), b"new").unwrap();
+
+    let open_direct = || {
+        OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(old_path.join("file"))
+    };
+    // tmpfs doesn't support O_DIRECT
+    let file = match open_direct() {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("skip as O_DIRECT is not supported: {}", err);
+            return;
+        }
+    };
+    let child = spawn_with_stdin(file);
+    let fd_path = format!("/proc/{}/fd/0", child.id());
+
+    // the fd is kept on the old path if the new path doesn't serve direct io
+    {
+        let mut replacer = FdReplacer::prepare(&old_path, &new_path)
+            .unwrap()
+            .with_direct_io(false);
+        let report = replacer.run_detailed();
+        assert_eq!(report.processes[0].fds[0].status, ProcessStatus::Skipped);
+    }
+    assert_eq!(read_link(&fd_path).unwrap(), old_path.join("file"));
+
+    {
+        let mut replacer = FdReplacer::prepare(&old_path, &new_path)
+            .unwrap()
+            .with_direct_io(true);
+        replacer.run().unwrap();
+    }
+    assert_eq!(read_link(&fd_path).unwrap(), new_path.join("file"));
+    let fdinfo = read_to_string(format!("/proc/{}/fdinfo/0", child.id())).unwrap();
+    let flags = fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .unwrap();
+    let flags = i32::from_str_radix(flags.trim(), 8).unwrap();
+    assert_ne!(flags & libc::O_DIRECT, 0);
+}
+
+#[test]
+#[ignore]
+fn fd_replacer_skip_replaced_fds() {
+    let (old_path, _) = init("skip_replaced_fds");
+
+    write(old_path.join("file"), b"0123456789").unwrap();
+
+    // the child shares the open file description with `file`
+    let mut file = File::open(old_path.join("file")).unwrap();
+    let child = spawn_with_stdin(file.try_clone().unwrap());
+
+    // the fd already refers to the new path, so it shouldn't be reopened
+    {
+        let mut replacer = FdReplacer::prepare(&old_path, &old_path).unwrap();
+        replacer.run().unwrap();
+    }
+
+    file.seek(SeekFrom::Start(7)).unwrap();
+    let fdinfo = read_to_string(format!("/proc/{}/fdinfo/0", child.id())).unwrap();
+    assert!(fdinfo.lines().any(|line| line == "pos:\t7"));
+}
+
+#[test]
+#[ignore]
+fn fd_replacer_symlinked_path() {
+    let (old_path, new_path) = init("symlinked_path");
+
+    write(old_path.join("file"), b"old").unwrap();
+    write(new_path.join("file"), b"new").unwrap();
+
+    let link_path = old_path.with_file_name("link");
+    symlink(&old_path, &link_path).unwrap();
+
+    let child = spawn_with_stdin(File::open(link_path.join("file")).unwrap());
+
+    {
+        let mut replacer = FdReplacer::prepare(&link_path, &new_path).unwrap();
+        replacer.run().unwrap();
+    }
+
+    let fd_path = format!("/proc/{}/fd/0", child.id());
+    assert_eq!(read_link(fd_path).unwrap(), new_path.join("file"));
+}
+
+#[test]
+#[ignore]
+fn fd_replacer_skip_fifo() {
+    let (old_path, new_path) = init("skip_fifo");
+
+    for path in [&old_path, &new_path].iter() {
+        mkfifo(&path.join("fifo"), Mode::S_IRWXU).unwrap();
+    }
+
+    // opening a FIFO for both reading and writing doesn't block
+    let fifo = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(old_path.join("fifo"))
+        .unwrap();
+    let child = spawn_with_stdin(fifo);
+
+    {
+        let mut replacer = FdReplacer::prepare(&old_path, &new_path).unwrap();
+        replacer.run().unwrap();
+    }
+
+    let fd_path = format!("/proc/{}/fd/0", child.id());
+    assert_eq!(read_link(fd_path).unwrap(), old_path.join("fifo"));
+}
+
+#[test]
+#[ignore]
+fn fd_replacer_skip_exited_process() {
+    let (old_path, new_path) = init("skip_exited_process");
+
+    write(old_path.join("file"), b"old").unwrap();
+    write(new_path.join("file"), b"new").unwrap();
+
+    let exited = spawn_with_stdin(File::open(old_path.join("file")).unwrap());
+    let running = spawn_with_stdin(File::open(old_path.join("file")).unwrap());
+
+    {
+        let mut replacer = FdReplacer::prepare(&old_path, &new_path).unwrap();
+        // a traced process can still be killed by SIGKILL
+        kill(Pid::from_raw(exited.id()), Signal::SIGKILL).unwrap();
+        let report = replacer.run_detailed();
+
+        let status = |pid| {
+            report
+                .processes
+                .iter()
+                .find(|process| process.pid == pid)
+                .map(|process| process.status.clone())
+        };
+        assert_eq!(status(exited.id()), Some(ProcessStatus::Skipped));
+        assert_eq!(status(running.id()), Some(ProcessStatus::Replaced));
+
+        // every fd is reported with its paths
+        let running_fds = report
+            .processes
+            .iter()
+            .find(|process| process.pid == running.id())
+            .map(|process| process.fds.clone())
+            .unwrap();
+        assert_eq!(
+            running_fds,
+            vec![FdReport {
+                fd: 0,
+                old_path: old_path.join("file"),
+                new_path: new_path.join("file"),
+                status: ProcessStatus::Replaced,
+            }]
+        );
+        report.into_result().unwrap();
+    }
+
+    let fd_path = format!("/proc/{}/fd/0", running.id());
+    assert_eq!(read_link(fd_path).unwrap(), new_path.join("file"));
+}
+
+#[test]
+#[ignore]
+fn fd_replacer_other_mount_namespace() {
+    let (old_path, new_path) = init("other_mount_namespace");
+
+    write(old_path.join("file"), b"old").unwrap();
+    write(new_path.join("file"), b"new").unwrap();
+
+    // the mounts are copied into the new namespace, so the paths are the same
+    let child = Detached::spawn(
+        "exec unshare -m sleep 100",
+        Stdio::from(File::open(old_path.join("file")).unwrap()),
+        Stdio::null(),
+    );
+    thread::sleep(Duration::from_millis(100));
+    let namespace = |pid: &str| read_link(format!("/proc/{}/ns/mnt", pid)).unwrap();
+    assert_ne!(namespace(&child.id().to_string()), namespace("self"));
+
+    {
+        let mut replacer = FdReplacer::prepare(&old_path, &new_path).unwrap();
+        replacer.run().unwrap();
+    }
+
+    let fd_path = format!("/proc/{}/fd/0", child.id());
+    assert_eq!(read_link(fd_path).unwrap(), new_path.join("file"));
+    // the namespace of the tests is restored
+    assert_eq!(namespace("thread-self"), namespace("self"));
+}
+
+#[test]
+#[ignore]
+#[cfg(target_arch = "x86_64")]
+fn run_codes_timeout() {
+    let mut child = Command::new("sleep").arg("100").spawn().unwrap();
+
+    {
+        let process = ptrace::trace(child.id() as i32).unwrap();
+        // `pause` blocks until a signal arrives, so the trap is never hit:
+        // mov eax, 34; syscall; int3
+        let codes =
+            |_: u64| Ok::<_, anyhow::Error>((0, vec![0xb8, 0x22, 0, 0, 0, 0x0f, 0x05, 0xcc]));
+        let err = process
+            .run_codes(codes, Duration::from_millis(200))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PtraceError>(),
+            Some(PtraceError::TimedOut { .. })
+        ));
+    }
+
+    // the registers are restored before detaching, so the child is still alive
+    thread::sleep(Duration::from_millis(100));
+    assert!(child.try_wait().unwrap().is_none());
+
+    child.kill().unwrap();
+    assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+}
+
+#[test]
+#[ignore]
+fn run_codes_restore_on_error() {
+    let mut child = Command::new("sleep").arg("100").spawn().unwrap();
+    let maps_path = format!("/proc/{}/maps", child.id());
+    thread::sleep(Duration::from_millis(100));
+    let maps = read_to_string(&maps_path).unwrap();
+
+    {
+        let process = ptrace::trace(child.id() as i32).unwrap();
+        // the codes are generated twice, and fail after the memory is mapped
+        let calls = Cell::new(0);
+        let codes = |_: u64| {
+            calls.set(calls.get() + 1);
+            match calls.get() {
+                1 => Ok((0, vec![0xcc])),
+                _ => Err(anyhow::anyhow!("fail to generate codes")),
+            }
+        };
+        assert!(process.run_codes(codes, Duration::from_secs(1)).is_err());
+    }
+
+    // the mapped memory is released and the child keeps running
+    assert_eq!(read_to_string(&maps_path).unwrap(), maps);
+    thread::sleep(Duration::from_millis(100));
+    assert!(child.try_wait().unwrap().is_none());
+
+    child.kill().unwrap();
+    assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+}
@@ -0,0 +1,44 @@
+// The runtime is shared by the whole process, so the tests changing it are kept
+// in their own binary.
+
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+use toda::hookfs::runtime;
+
+#[test]
+fn test_max_pending_requests() {
+    assert!(runtime::set_max_pending_requests(0).is_err());
+    runtime::set_max_pending_requests(2).unwrap();
+
+    let first = runtime::acquire_request().unwrap();
+    let _second = runtime::acquire_request().unwrap();
+
+    let (tx, rx) = channel();
+    let waiter = std::thread::spawn(move || {
+        let permit = runtime::acquire_request();
+        tx.send(()).unwrap();
+        permit
+    });
+    // the third request waits until one of the pending ones finishes
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    drop(first);
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert!(waiter.join().unwrap().is_some());
+}
+
+#[test]
+fn test_runtime_built_again_after_shutdown() {
+    let first = runtime::spawn(async { 1 });
+    assert_eq!(futures::executor::block_on(first).unwrap(), 1);
+
+    // the runtime is kept while it's in use
+    runtime::shutdown(|| true);
+    let second = runtime::spawn(async { 2 });
+    assert_eq!(futures::executor::block_on(second).unwrap(), 2);
+
+    // the next injection in the process builds a new one
+    runtime::shutdown(|| false);
+    let third = runtime::spawn_blocking(|| 3);
+    assert_eq!(futures::executor::block_on(third).unwrap(), 3);
+}
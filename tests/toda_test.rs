@@ -0,0 +1,200 @@
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use toda::jsonrpc::Health;
+use toda::mount::{MountError, MountsInfo};
+use toda::mount_injector::MountMode;
+use toda::replacer::ReplacerKind;
+use toda::utils::encode_path;
+use toda::{fuse_device, Config, Summary, Toda};
+
+#[test]
+fn test_config_defaults() {
+    let config = Config::new(vec![PathBuf::from("/tmp/test_toda")]);
+    assert!(!config.mount_only);
+    assert_eq!(config.replacers, ReplacerKind::all());
+    assert_eq!(config.mount_mode, MountMode::Move);
+    assert!(config.fuse_options.allow_other);
+    assert!(config.fuse_options.worker_threads.is_none());
+    assert!(config.injectors.is_empty());
+    assert!(!config.force_cleanup);
+}
+
+#[test]
+fn test_parse_replacers() {
+    let parse = |s: &str| s.parse::<ReplacerKind>();
+    assert_eq!(
+        parse("fd,mmap").unwrap(),
+        ReplacerKind::FD | ReplacerKind::MMAP
+    );
+    assert_eq!(parse("").unwrap(), ReplacerKind::empty());
+    assert!(parse("fd,ptrace").is_err());
+}
+
+#[test]
+fn test_zero_worker_threads() {
+    assert!(toda::hookfs::runtime::set_worker_threads(0).is_err());
+}
+
+#[test]
+fn test_fuse_device_preflight() {
+    // the FUSE tests need the device as well
+    fuse_device::preflight().unwrap();
+}
+
+#[test]
+fn test_inject_missing_path() {
+    let toda = Toda::new(Config::new(vec![PathBuf::from("/tmp/test_toda/missing")]));
+    assert!(toda.inject().is_err());
+    let err = toda.plan().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<MountError>(),
+        Some(MountError::NotFound { .. })
+    ));
+}
+
+#[test]
+fn test_health_of_failed_injection() {
+    let health = Arc::new(Mutex::new(Health::Mounting));
+    let mut config = Config::new(vec![PathBuf::from("/tmp/test_toda/missing")]);
+    config.health = Some(health.clone());
+    assert!(Toda::new(config).inject().is_err());
+    assert!(matches!(*health.lock().unwrap(), Health::Failed(_)));
+}
+
+#[test]
+fn test_summary_of_failed_injection() {
+    let toda = Toda::new(Config::new(vec![PathBuf::from("/tmp/test_toda/missing")]));
+    let mut summary = Summary::failed(&toda.inject().err().unwrap());
+    summary.set_recovery(&Ok(()));
+    assert!(summary.error.is_some());
+    assert!(summary.recovered);
+    assert!(!summary.succeeded());
+
+    let summary: serde_json::Value = serde_json::to_value(&summary).unwrap();
+    assert_eq!(summary["paths"], 0);
+    assert_eq!(summary["processes"]["replaced"], 0);
+    assert_eq!(summary["applied"], false);
+    assert_eq!(summary["recoveryError"], serde_json::Value::Null);
+}
+
+#[test]
+fn test_cleanup_without_stale_mounts() {
+    let path = PathBuf::from("/tmp/test_toda/cleanup");
+    std::fs::create_dir_all(&path).unwrap();
+
+    // nothing is mounted on the path, so nothing is touched
+    let toda = Toda::new(Config::new(vec![path]));
+    toda.cleanup().unwrap();
+}
+
+#[test]
+fn test_inject_not_mount_point() {
+    let path = PathBuf::from("/tmp/test_toda/not_mount_point");
+    std::fs::create_dir_all(&path).unwrap();
+
+    let toda = Toda::new(Config::new(vec![path]));
+    for err in [toda.inject().err(), toda.plan().err()].iter() {
+        let err = err.as_ref().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<MountError>(),
+            Some(MountError::NotMountPoint { .. })
+        ));
+    }
+}
+
+#[test]
+fn test_inject_overlapping_paths() {
+    let path = PathBuf::from("/tmp/test_toda/overlapping");
+    std::fs::create_dir_all(path.join("inner")).unwrap();
+
+    for paths in [
+        vec![path.clone(), path.join("inner")],
+        vec![path.join("inner"), path.join("../overlapping")],
+    ]
+    .iter()
+    {
+        let toda = Toda::new(Config::new(paths.clone()));
+        for err in [toda.inject().err(), toda.plan().err()].iter() {
+            let err = err.as_ref().unwrap();
+            assert!(matches!(
+                err.downcast_ref::<MountError>(),
+                Some(MountError::Overlapping { .. })
+            ));
+        }
+    }
+}
+
+// umount_all removes every mount stacked on the path
+fn umount_all(path: &Path) {
+    while MountsInfo::parse_mounts().unwrap().is_mount_point(path) {
+        umount2(path, MntFlags::MNT_DETACH).unwrap();
+    }
+}
+
+// This test mounts a tmpfs and a FUSE filesystem, so it needs CAP_SYS_ADMIN
+#[test]
+#[ignore]
+fn inject_volume_mount_point() {
+    let path = PathBuf::from("/tmp/test_toda/volume");
+    std::fs::create_dir_all(&path).unwrap();
+    umount_all(&path);
+    const NONE: Option<&'static [u8]> = None;
+    mount(Some("tmpfs"), &path, Some("tmpfs"), MsFlags::empty(), NONE).unwrap();
+    write(path.join("file"), b"data").unwrap();
+
+    let mut config = Config::new(vec![path.clone()]);
+    config.mount_only = true;
+    let injection = Toda::new(config).inject().unwrap();
+
+    let mounts = MountsInfo::parse_mounts().unwrap();
+    assert!(mounts.is_fuse(&path, "toda"));
+    assert_eq!(read_to_string(path.join("file")).unwrap(), "data");
+    write(path.join("file"), b"written").unwrap();
+
+    injection.resume().unwrap();
+
+    // the volume is still mounted on the path, and nothing is left elsewhere
+    let mounts = MountsInfo::parse_mounts().unwrap();
+    assert!(!mounts.is_fuse(&path, "toda"));
+    assert!(mounts.is_mount_point(&path));
+    let (_, new_path) = encode_path(&path, None).unwrap();
+    assert!(!mounts.is_mount_point(&new_path));
+    assert_eq!(read_to_string(path.join("file")).unwrap(), "written");
+
+    umount_all(&path);
+    assert!(!path.join("file").exists());
+}
+
+// This test mounts a tmpfs and a FUSE filesystem, so it needs CAP_SYS_ADMIN
+#[test]
+#[ignore]
+fn recover_deleted_moved_mount() {
+    let path = PathBuf::from("/tmp/test_toda/deleted_moved_mount");
+    std::fs::create_dir_all(&path).unwrap();
+    umount_all(&path);
+    const NONE: Option<&'static [u8]> = None;
+    mount(Some("tmpfs"), &path, Some("tmpfs"), MsFlags::empty(), NONE).unwrap();
+
+    let mut config = Config::new(vec![path.clone()]);
+    config.mount_only = true;
+    let injection = Toda::new(config).inject().unwrap();
+
+    // the moved mount is lost with its directory
+    let (_, new_path) = encode_path(&path, None).unwrap();
+    umount2(&new_path, MntFlags::MNT_DETACH).unwrap();
+    std::fs::remove_dir(&new_path).unwrap();
+
+    injection.resume().unwrap();
+
+    // a tmpfs is mounted on the path again, though the data is lost
+    let mounts = MountsInfo::parse_mounts().unwrap();
+    assert!(!mounts.is_fuse(&path, "toda"));
+    assert!(mounts.is_mount_point(&path));
+    assert_eq!(mounts.source(&path).unwrap().fs_type, "tmpfs");
+    write(path.join("file"), b"data").unwrap();
+
+    umount_all(&path);
+}
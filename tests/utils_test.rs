@@ -0,0 +1,42 @@
+use std::path::{Path, PathBuf};
+
+use toda::utils::encode_path;
+
+#[test]
+fn test_encode_path_beside_original() {
+    let (original_path, new_path) = encode_path("/var/lib/data", None).unwrap();
+    assert_eq!(original_path, PathBuf::from("/var/lib/data"));
+    assert_eq!(new_path, PathBuf::from("/var/lib/__chaosfs__data__"));
+}
+
+#[test]
+fn test_encode_path_long_name() {
+    let name = "a".repeat(250);
+    let path = Path::new("/var/lib").join(&name);
+    let base_dir = Path::new("/run/toda");
+
+    for base_dir in [None, Some(base_dir)].iter() {
+        let (_, new_path) = encode_path(&path, *base_dir).unwrap();
+        let new_name = new_path.file_name().unwrap();
+        assert!(new_name.len() <= 255);
+        // the same path is always encoded to the same name
+        assert_eq!(encode_path(&path, *base_dir).unwrap().1, new_path);
+    }
+}
+
+#[test]
+fn test_encode_path_base_dir() {
+    let base_dir = Path::new("/run/toda");
+    let (_, first) = encode_path("/mnt/a/data", Some(base_dir)).unwrap();
+    let (_, second) = encode_path("/mnt/b/data", Some(base_dir)).unwrap();
+
+    assert_eq!(first.parent(), Some(base_dir));
+    assert_eq!(second.parent(), Some(base_dir));
+    assert_ne!(first, second);
+}
+
+#[test]
+fn test_encode_path_root() {
+    assert!(encode_path("/", None).is_err());
+    assert!(encode_path("/", Some(Path::new("/run/toda"))).is_err());
+}